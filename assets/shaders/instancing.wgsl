@@ -5,6 +5,10 @@
 //[[block]]
 struct CustomMaterial {
     color: vec4<f32>;
+    fog_color: vec4<f32>;
+    // x mode (0 off, 1 linear, 2 exponential), y start or density, z end
+    fog_params: vec4<f32>;
+    alpha_cutoff: f32;
 };
 [[group(1), binding(0)]]
 var<uniform> material: CustomMaterial;
@@ -47,6 +51,9 @@ struct VertexOutput {
     [[location(1)]] index: u32;
     [[location(2)]] color: vec3<f32>;
     [[location(3)]] normal: vec3<f32>;
+    [[location(4)]] world_position: vec3<f32>;
+    // instance alpha, used to fade in chunks
+    [[location(5)]] fade: f32;
 };
 
 
@@ -62,6 +69,8 @@ fn vertex(vertex: Vertex, instance: InstanceInput) -> VertexOutput {
     out.clip_position = view.view_proj * world_position;
     out.color = instance.color.rgb * vertex.color.rgb;
     out.normal = vertex.normal.xyz;
+    out.world_position = world_position.xyz;
+    out.fade = instance.color.a;
     
     let uv = vec2<f32>(f32(vertex.uv_i.x) / 255.0,f32(vertex.uv_i.y)/255.0);
     let index = (vertex.uv_i.b << u32(8)) | vertex.uv_i.a;
@@ -79,6 +88,11 @@ fn vertex(vertex: Vertex, instance: InstanceInput) -> VertexOutput {
         out.color = out.color * light_l;
     # endif
 
+    // only the light level the mesher baked into the vertex color
+    # ifdef LIGHT_LEVEL_VIEW
+        out.color = vertex.color.rgb;
+    # endif
+
     return out;
 }
 
@@ -89,17 +103,118 @@ var base_color_texture: texture_2d_array<f32>;
 var base_color_sampler: sampler;
 
 struct FragIn {
+    [[builtin(position)]] frag_coord: vec4<f32>;
     [[location(0)]] uv: vec2<f32>;
     [[location(1)]] index: u32;
     [[location(2)]] color: vec3<f32>;
     [[location(3)]] normal: vec3<f32>;
+    [[location(4)]] world_position: vec3<f32>;
+    [[location(5)]] fade: f32;
 };
 
+fn fog_amount(distance: f32) -> f32 {
+    let mode = material.fog_params.x;
+    if (mode > 1.5) {
+        return 1.0 - exp(-material.fog_params.y * distance);
+    }
+    if (mode > 0.5) {
+        return clamp((distance - material.fog_params.y) / (material.fog_params.z - material.fog_params.y), 0.0, 1.0);
+    }
+    return 0.0;
+}
+
+// screen space noise for dithered fading of opaque geometry
+fn interleaved_gradient_noise(p: vec2<f32>) -> f32 {
+    return fract(52.9829189 * fract(dot(p, vec2<f32>(0.06711056, 0.00583715))));
+}
+
+// directional shadow map lookup, same as fetch_directional_shadow in bevy's pbr.wgsl
+fn fetch_chunk_shadow(light_id: u32, frag_position: vec4<f32>, surface_normal: vec3<f32>) -> f32 {
+    let light = lights.directional_lights[light_id];
+
+    // The normal bias is scaled to the texel size.
+    let normal_offset = light.shadow_normal_bias * surface_normal.xyz;
+    let depth_offset = light.shadow_depth_bias * light.direction_to_light.xyz;
+    let offset_position = vec4<f32>(frag_position.xyz + normal_offset + depth_offset, frag_position.w);
+
+    let offset_position_clip = light.view_projection * offset_position;
+    if (offset_position_clip.w <= 0.0) {
+        return 1.0;
+    }
+    let offset_position_ndc = offset_position_clip.xyz / offset_position_clip.w;
+    // No shadow outside the orthographic projection volume
+    if (any(offset_position_ndc.xy < vec2<f32>(-1.0)) || offset_position_ndc.z < 0.0
+            || any(offset_position_ndc > vec3<f32>(1.0))) {
+        return 1.0;
+    }
+
+    // compute texture coordinates for shadow lookup, compensating for the Y-flip difference
+    // between the NDC and texture coordinates
+    let flip_correction = vec2<f32>(0.5, -0.5);
+    let light_local = offset_position_ndc.xy * flip_correction + vec2<f32>(0.5, 0.5);
+
+    let depth = offset_position_ndc.z;
+    // do the lookup, using HW PCF and comparison
+    return textureSampleCompareLevel(directional_shadow_textures, directional_shadow_textures_sampler, light_local, i32(light_id), depth);
+}
+
+// 1.0 fully lit, 0.0 in the shadow of every directional light that casts shadows
+fn chunk_shadow(world_position: vec3<f32>, normal: vec3<f32>) -> f32 {
+    var shadow = 1.0;
+    let n_directional_lights = lights.n_directional_lights;
+    for (var i: u32 = 0u; i < n_directional_lights; i = i + 1u) {
+        if ((lights.directional_lights[i].flags & 1u) != 0u) {
+            shadow = min(shadow, fetch_chunk_shadow(i, vec4<f32>(world_position, 1.0), normal));
+        }
+    }
+    return shadow;
+}
+
 [[stage(fragment)]]
 fn fragment(in: FragIn) -> [[location(0)]] vec4<f32> {
-    let out = textureSample(base_color_texture, base_color_sampler, in.uv, i32( in.index)) * vec4<f32>( in.color,1.0);
-    //if (out.a <= 0.5) {
-    //    discard;
-    //}
+    var base = vec4<f32>(1.0, 1.0, 1.0, 1.0);
+    # ifdef COLOR_TEXTURE
+        base = textureSample(base_color_texture, base_color_sampler, in.uv, i32( in.index));
+    # endif
+    var out = base * vec4<f32>( in.color,1.0) * material.color;
+
+    # ifdef IS_LIGHTING
+        // only the direct part of the light gets shadowed
+        let shadow = chunk_shadow(in.world_position, normalize(in.normal));
+        out = vec4<f32>(out.rgb * mix(0.55, 1.0, shadow), out.a);
+    # endif
+
+    # ifdef NORMALS_VIEW
+        out = vec4<f32>(in.normal * 0.5 + vec3<f32>(0.5, 0.5, 0.5), out.a);
+    # endif
+    # ifdef LIGHT_LEVEL_VIEW
+        out = vec4<f32>(in.color, out.a);
+    # endif
+    // outline the quad edges, the uv goes 0 to 1 across every quad
+    # ifdef WIREFRAME
+        let edge = min(min(in.uv.x, 1.0 - in.uv.x), min(in.uv.y, 1.0 - in.uv.y));
+        if (edge < 0.03) {
+            out = vec4<f32>(0.05, 0.05, 0.05, 1.0);
+        }
+    # endif
+
+    let fog = fog_amount(distance(in.world_position, view.world_position));
+    out = vec4<f32>(mix(out.rgb, material.fog_color.rgb, fog), out.a);
+
+    # ifdef ALPHA_MASK
+        if (out.a < material.alpha_cutoff) {
+            discard;
+        }
+    # endif
+    # ifdef ALPHA_BLEND
+        out.a = out.a * in.fade;
+    # endif
+    # ifdef OPAQUE_PASS
+        // opaque passes can not blend, so chunks fade in with a dither pattern
+        if (in.fade < 1.0 && in.fade <= interleaved_gradient_noise(in.frag_coord.xy)) {
+            discard;
+        }
+        out.a = 1.0;
+    # endif
     return out;
 }
@@ -0,0 +1,88 @@
+#import bevy_pbr::mesh_struct
+
+// depth only pass of instanced meshes into the light shadow maps
+// with ALPHA_MASK the fragments below the alpha cutoff of the chunk material are discarded
+
+// only the first field of bevy's View is needed
+struct View {
+    view_proj: mat4x4<f32>;
+};
+[[group(0), binding(0)]]
+var<uniform> view: View;
+
+[[group(1), binding(0)]]
+var<uniform> mesh: Mesh;
+
+# ifdef ALPHA_MASK
+// has to match CustomMaterial in instancing.wgsl
+struct CustomMaterial {
+    color: vec4<f32>;
+    fog_color: vec4<f32>;
+    fog_params: vec4<f32>;
+    alpha_cutoff: f32;
+};
+[[group(2), binding(0)]]
+var<uniform> material: CustomMaterial;
+[[group(2), binding(1)]]
+var base_color_texture: texture_2d_array<f32>;
+[[group(2), binding(2)]]
+var base_color_sampler: sampler;
+# endif
+
+// in instance
+struct InstanceInput {
+    // transfrom
+    [[location(5)]] model_matrix_0: vec4<f32>;
+    [[location(6)]] model_matrix_1: vec4<f32>;
+    [[location(7)]] model_matrix_2: vec4<f32>;
+    [[location(8)]] model_matrix_3: vec4<f32>;
+    // other
+    [[location(9)]] color: vec4<f32>;
+};
+fn transform_from_instance(instance: InstanceInput) -> mat4x4<f32> {
+  return mat4x4<f32>(
+        instance.model_matrix_0,
+        instance.model_matrix_1,
+        instance.model_matrix_2,
+        instance.model_matrix_3,
+    );
+}
+
+// every shared mesh vertex type starts with the position
+struct Vertex {
+    [[location(0)]] position: vec3<f32>;
+    # ifdef ALPHA_MASK
+    [[location(3)]] uv_i: vec4<u32>;
+    # endif
+};
+
+struct VertexOutput {
+    [[builtin(position)]] clip_position: vec4<f32>;
+    # ifdef ALPHA_MASK
+    [[location(0)]] uv: vec2<f32>;
+    [[location(1)]] index: u32;
+    # endif
+};
+
+[[stage(vertex)]]
+fn vertex(vertex: Vertex, instance: InstanceInput) -> VertexOutput {
+    let world_matrix = mesh.model * transform_from_instance(instance);
+
+    var out: VertexOutput;
+    out.clip_position = view.view_proj * world_matrix * vec4<f32>(vertex.position, 1.0);
+    # ifdef ALPHA_MASK
+        out.uv = vec2<f32>(f32(vertex.uv_i.x) / 255.0, f32(vertex.uv_i.y) / 255.0);
+        out.index = (vertex.uv_i.b << u32(8)) | vertex.uv_i.a;
+    # endif
+    return out;
+}
+
+# ifdef ALPHA_MASK
+[[stage(fragment)]]
+fn fragment(in: VertexOutput) {
+    let base = textureSample(base_color_texture, base_color_sampler, in.uv, i32(in.index));
+    if (base.a * material.color.a < material.alpha_cutoff) {
+        discard;
+    }
+}
+# endif
@@ -0,0 +1,48 @@
+// headless dedicated server, `cargo run --bin server -- [config path]`
+// the config defaults to server_config.ron in the working directory, the built in defaults are
+// only used when no path was given and that file does not exist.
+
+use std::{path::Path, sync::atomic::Ordering, time::Duration};
+
+use bevy::{app::ScheduleRunnerSettings, prelude::*};
+use vox_net::net::{
+    plugin::{NetServerConfig, NetServerPlugin, ShutdownRequested},
+    server::DEFAULT_TICK_RATE,
+};
+
+const DEFAULT_CONFIG: &str = "server_config.ron";
+
+fn main() {
+    let config = match std::env::args().nth(1) {
+        Some(path) => NetServerConfig::load(&path),
+        None if !Path::new(DEFAULT_CONFIG).exists() => {
+            println!("no {}, using the default config", DEFAULT_CONFIG);
+            Ok(NetServerConfig::default())
+        }
+        None => NetServerConfig::load(DEFAULT_CONFIG),
+    };
+    // a bad config would serve and save the wrong world
+    let config = match config {
+        Ok(c) => c,
+        Err(e) => {
+            println!("{:#}", e);
+            std::process::exit(1);
+        }
+    };
+    println!("{:?}", config);
+
+    let shutdown = ShutdownRequested::default();
+    let flag = shutdown.0.clone();
+    ctrlc::set_handler(move || flag.store(true, Ordering::SeqCst))
+        .expect("could not set the ctrl-c handler");
+
+    App::new()
+        .insert_resource(ScheduleRunnerSettings::run_loop(Duration::from_secs_f64(
+            1.0 / DEFAULT_TICK_RATE as f64,
+        )))
+        .add_plugins(MinimalPlugins)
+        .insert_resource(config)
+        .insert_resource(shutdown)
+        .add_plugin(NetServerPlugin)
+        .run();
+}
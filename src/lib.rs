@@ -0,0 +1,5 @@
+pub mod net;
+pub mod physics;
+pub mod rendering;
+pub mod voxel;
+pub mod world;
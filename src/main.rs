@@ -1,20 +1,24 @@
-use std::path::Path;
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    sync::{Arc, RwLock},
+};
 
 use bevy::{
     asset::{AssetLoader, AssetPath, BoxedFuture, LoadContext, LoadedAsset},
+    core::FixedTimestep,
     diagnostic::{FrameTimeDiagnosticsPlugin, LogDiagnosticsPlugin},
     ecs::system::{lifetimeless::SRes, SystemParamItem},
     pbr::SpecializedMaterial,
     prelude::*,
     reflect::TypeUuid,
     render::{
-        render_asset::{PrepareAssetError, RenderAsset, RenderAssets},
+        render_asset::{PrepareAssetError, RenderAsset},
         render_resource::{
             std140, BindGroup, BindGroupDescriptor, BindGroupEntry, BindGroupLayout,
-            BindGroupLayoutDescriptor, BindGroupLayoutEntry, BindingResource, BindingType, Buffer,
+            BindGroupLayoutDescriptor, BindGroupLayoutEntry, BindingType, Buffer,
             BufferBindingType, BufferInitDescriptor, BufferSize, BufferUsages, Extent3d,
-            PrimitiveTopology, RenderPipelineDescriptor, SamplerBindingType, ShaderStages,
-            TextureDimension, TextureSampleType, TextureViewDimension,
+            PrimitiveTopology, RenderPipelineDescriptor, ShaderStages, TextureDimension,
         },
         renderer::{RenderDevice, RenderQueue},
         texture::ImageType,
@@ -26,31 +30,45 @@ use bevy_egui::{
     EguiContext, EguiPlugin,
 };
 use bevy_fly_camera::FlyCamera;
-use serde::Deserialize;
 
 //use chunk_pipeline::ChunkMesh;
-use noise::{NoiseFn, OpenSimplex};
-use rand::prelude::*;
-use rendering::{
-    instancing::{InstanceModelPlugin, InstanceRaw, ModelInstanceList},
-    mesh::{ChunkMeshvertex, LineMeshvertex, SharedMesh, SharedMeshPlugin},
-    model_draw_pipeline::{ModelDrawMaterialPipeline, ModelInstanceMaterialPlugin},
+use vox_net::rendering::{
+    chunk_material::{
+        fade_in_chunks, sync_chunk_material_settings, ChunkCutoutMaterial, ChunkFadeIn, ChunkFog,
+        ChunkLayer, ChunkOpaqueMaterial, ChunkShaderFlags, ChunkTransparentMaterial, FogFalloff,
+    },
+    instancing::{Instance, InstanceModelPlugin, InstanceRaw, ModelInstance, ModelInstanceList},
+    mesh::{ChunkMeshvertex, LineMeshvertex, SharedMesh, SharedMeshPlugin, SubMeshHandel},
+    model_draw_pipeline::{
+        AlphaMaskShadow, ModelDrawMaterialPipeline, ModelInstanceMaterialPlugin,
+        ModelInstanceShadowPlugin, ModelMaskShadowPlugin, NoModelShadow,
+    },
 };
 
-use voxel::voxel::{Chunk, ChunkKey, VoxelMap};
-
-use crate::rendering::instancing::Instance;
-
-mod rendering;
-mod voxel;
+use vox_net::{
+    net::plugin::{NetClientConfig, NetClientPlugin, NetTransport, NetViewer, RemotePlayers},
+    physics::plugin::{PhysicsPlugin, PlayerBody, TOGGLE_MOVE_MODE},
+    voxel::{
+        block::{BlockRegistry, ImageArrayConfig, RenderLayer, GRAVEL, SAND, WATER},
+        falling::FallingBlocks,
+        fluid::{FluidSim, FLUID_TICK_RATE, MAX_FLUID_LEVEL},
+        ticks::{TickHandlers, RANDOM_TICKS_PER_CHUNK},
+        voxel::{ChunkKey, VolumeMap, VoxelMap},
+    },
+    world::{
+        decoration::{generate_again, generate_into, PendingWrites},
+        generator::WorldGenerator,
+        preset::{WorldPreset, WORLD_PRESET},
+        structure::{load_structures, STRUCTURE_DIR},
+    },
+};
 
-// world size in chunks
-const WORLD_SIZE_XZ: i32 = 4;
-const WORLD_SIZE_Y: i32 = 9;
+/// random and scheduled block ticks per second.
+const BLOCK_TICK_RATE: f64 = 20.0;
 
 fn main() {
-    App::new()
-        .add_plugins(DefaultPlugins)
+    let mut app = App::new();
+    app.add_plugins(DefaultPlugins)
         .insert_resource(ClearColor(Color::BLACK))
         .insert_resource(WindowDescriptor {
             // uncomment for unthrottled FPS
@@ -59,8 +77,26 @@ fn main() {
         })
         .add_plugin(LogDiagnosticsPlugin::default())
         .add_plugin(FrameTimeDiagnosticsPlugin::default())
-        .add_plugin(TestPlugin)
-        .run();
+        .add_plugin(TestPlugin);
+
+    // `--connect <address>` gets the world from a server instead of generating it, over udp unless `--tcp` is given
+    let args: Vec<String> = std::env::args().collect();
+    if let Some(i) = args.iter().position(|a| a == "--connect") {
+        app.insert_resource(NetClientConfig {
+            address: args
+                .get(i + 1)
+                .cloned()
+                .unwrap_or_else(|| "127.0.0.1:7878".to_string()),
+            name: "player".to_string(),
+            transport: if args.iter().any(|a| a == "--tcp") {
+                NetTransport::Tcp
+            } else {
+                NetTransport::Udp
+            },
+        })
+        .add_plugin(NetClientPlugin);
+    }
+    app.run();
 }
 
 struct TestPlugin;
@@ -72,14 +108,45 @@ impl Plugin for TestPlugin {
         app.add_plugin(InstanceModelPlugin);
         // material needs both shared mesh and instancing
         app.add_plugin(ModelInstanceMaterialPlugin::<ChunkOpaqueMaterial>::default());
+        app.add_plugin(ModelInstanceMaterialPlugin::<ChunkCutoutMaterial>::default());
+        app.add_plugin(ModelInstanceMaterialPlugin::<ChunkTransparentMaterial>::default());
         app.add_plugin(ModelInstanceMaterialPlugin::<LineMaterial>::default());
+        app.add_plugin(ModelInstanceShadowPlugin);
+        app.add_plugin(ModelMaskShadowPlugin::<ChunkCutoutMaterial>::default());
 
         //
         app.add_asset::<ImageArray>()
             .init_asset_loader::<CustomAssetLoader>()
+            .add_asset::<WorldPresetAsset>()
+            .init_asset_loader::<WorldPresetLoader>()
+            .add_system(reload_world_preset)
             .add_startup_system(set_up_scene)
             .add_system(consume_image_array)
+            .init_resource::<ChunkShaderFlags>()
+            .init_resource::<ChunkFog>()
+            .add_system(sync_chunk_material_settings)
+            .add_system(fade_in_chunks)
+            .add_system(remesh_dirty_chunks)
+            .init_resource::<FluidSim>()
+            .init_resource::<FallingBlocks>()
+            .insert_resource(TickHandlers::with_default_behaviors(
+                &BlockRegistry::default(),
+            ))
+            .add_system_set(
+                SystemSet::new()
+                    .with_run_criteria(FixedTimestep::step(1.0 / BLOCK_TICK_RATE))
+                    .with_system(run_block_ticks),
+            )
+            .add_system(update_falling_blocks)
+            .add_system(draw_falling_blocks)
+            .add_system_set(
+                SystemSet::new()
+                    .with_run_criteria(FixedTimestep::step(1.0 / FLUID_TICK_RATE))
+                    .with_system(step_fluids),
+            )
+            .add_system(draw_remote_players)
             .add_plugin(bevy_fly_camera::FlyCameraPlugin)
+            .add_plugin(PhysicsPlugin)
             .add_plugin(EguiPlugin)
             .add_system(ui_info);
     }
@@ -90,6 +157,10 @@ fn ui_info(
     time: ResMut<Time>,
     q: Query<(&Handle<SharedMesh>, &ModelInstanceList)>,
     shard_meshes: ResMut<Assets<SharedMesh>>,
+    mut shader_flags: ResMut<ChunkShaderFlags>,
+    mut chunk_fog: ResMut<ChunkFog>,
+    bodies: Query<(&Transform, &PlayerBody)>,
+    generator: Option<Res<WorldGenerator>>,
     //mut debug_materials: ResMut<Assets<LineMaterial>>,
 ) {
     let dt = time.delta_seconds();
@@ -104,16 +175,96 @@ fn ui_info(
         ui.label(format!("fps: {:?}", 1.0 / dt));
         ui.label(format!("sub_meshes: {:?}", num_sub_meshs));
         ui.label(format!("objects_to_draw: {:?}", num_of_objects_to_draw));
+        for (transform, body) in bodies.iter() {
+            ui.label(format!("{:?}, {:?} to switch", body.mode, TOGGLE_MOVE_MODE));
+
+            ui.separator();
+            let generator = match &generator {
+                Some(g) => g,
+                None => {
+                    ui.label("biome: unknown, the world comes from the server");
+                    continue;
+                }
+            };
+            let (x, z) = (
+                transform.translation.x.floor() as i32,
+                transform.translation.z.floor() as i32,
+            );
+            let (temperature, humidity) = generator.biomes.climate(x, z);
+            ui.label(format!("biome: {}", generator.biome(x, z).name));
+            ui.label(format!(
+                "temperature: {:.2}, humidity: {:.2}, ground: {:.1}",
+                temperature,
+                humidity,
+                generator.surface(x, z).0
+            ));
+            // more then one line means the camera is over a border, where the heights are blended
+            for (i, w) in generator.biomes.weights((temperature, humidity)) {
+                ui.label(format!("  {} {:.2}", generator.biomes.biomes[i].name, w));
+            }
+        }
+
+        // edit a copy so the flags only count as changed when a box is actually toggled
+        let mut flags = *shader_flags;
+        ui.separator();
+        ui.label("chunk shader");
+        ui.checkbox(&mut flags.texture, "texture");
+        ui.checkbox(&mut flags.lighting, "lighting");
+        ui.checkbox(&mut flags.debug_uv, "debug uv");
+        ui.checkbox(&mut flags.normals_view, "normals view");
+        ui.checkbox(&mut flags.light_level_view, "light level view");
+        ui.checkbox(&mut flags.wireframe, "wireframe");
+        if flags != *shader_flags {
+            *shader_flags = flags;
+        }
+
+        let mut fog = *chunk_fog;
+        ui.separator();
+        ui.label("fog");
+        ui.horizontal(|ui| {
+            if ui.radio(fog.falloff == FogFalloff::Off, "off").clicked() {
+                fog.falloff = FogFalloff::Off;
+            }
+            if ui
+                .radio(matches!(fog.falloff, FogFalloff::Linear { .. }), "linear")
+                .clicked()
+            {
+                fog.falloff = FogFalloff::Linear {
+                    start: 60.0,
+                    end: 140.0,
+                };
+            }
+            if ui
+                .radio(
+                    matches!(fog.falloff, FogFalloff::Exponential { .. }),
+                    "exponential",
+                )
+                .clicked()
+            {
+                fog.falloff = FogFalloff::Exponential { density: 0.015 };
+            }
+        });
+        match &mut fog.falloff {
+            FogFalloff::Off => {}
+            FogFalloff::Linear { start, end } => {
+                ui.add(egui::Slider::new(start, 0.0..=500.0).text("start"));
+                ui.add(egui::Slider::new(end, 0.0..=500.0).text("end"));
+            }
+            FogFalloff::Exponential { density } => {
+                ui.add(egui::Slider::new(density, 0.0..=0.1).text("density"));
+            }
+        }
+        let mut fog_color = [fog.color.r(), fog.color.g(), fog.color.b()];
+        ui.color_edit_button_rgb(&mut fog_color);
+        fog.color = Color::rgb(fog_color[0], fog_color[1], fog_color[2]);
+        if fog != *chunk_fog {
+            *chunk_fog = fog;
+        }
     });
 }
 
 //-----------------------------
 
-#[derive(Component)]
-pub struct VolumeMap {
-    pub val: VoxelMap,
-}
-
 fn set_up_scene(
     mut com: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
@@ -125,6 +276,8 @@ fn set_up_scene(
     asset_server: ResMut<AssetServer>,
     queue: Res<RenderQueue>,
     device: Res<RenderDevice>,
+    net_config: Option<Res<NetClientConfig>>,
+    mut fluids: ResMut<FluidSim>,
 ) {
     let mut volm = VolumeMap {
         val: VoxelMap::new((16, 16, 16)),
@@ -140,6 +293,28 @@ fn set_up_scene(
         instance_list: vec![],
     };
 
+    // cutout faces get their own shared mesh so the opaque path stays free of alpha testing
+    let mut cutout_shared_mesh = SharedMesh::new::<ChunkMeshvertex>(
+        "chunk_cutout_mesh".into(),
+        &device,
+        (6_000 * 4028, 9_000 * 4028), // buffer_size (vertex, index)
+        PrimitiveTopology::TriangleList,
+    );
+    let mut cutout_draw_list = ModelInstanceList {
+        instance_list: vec![],
+    };
+
+    // transparent faces are kept in their own shared mesh so they can be drawn sorted after the opaque ones
+    let mut transparent_shared_mesh = SharedMesh::new::<ChunkMeshvertex>(
+        "chunk_transparent_mesh".into(),
+        &device,
+        (1_000 * 4028, 1_500 * 4028), // buffer_size (vertex, index)
+        PrimitiveTopology::TriangleList,
+    );
+    let mut transparent_draw_list = ModelInstanceList {
+        instance_list: vec![],
+    };
+
     let mut debug_mesh = SharedMesh::new::<LineMeshvertex>(
         "test_mesh_s".into(),
         &device,
@@ -210,134 +385,79 @@ fn set_up_scene(
 
     let debug_box_mesh = debug_mesh.get_handel(&l_v, &l_i, &queue);
 
-    // ---------------------------- chunk gen range
-    // world size in chunks
-    let rr = WORLD_SIZE_XZ; // x and z size
-    let ry = WORLD_SIZE_Y; // y size
-    for x in -rr..=rr {
-        for y in -ry..=ry {
-            for z in -rr..=rr {
-                let mut ch = Chunk::new(16);
-                volm.val.add_chunk(x, y, z, ch);
-            }
+    // other players are drawn with the chunk textures, so their model lives in the opaque chunk mesh
+    let (p_v, p_i) = box_mesh(0);
+    let player_mesh = chunk_shared_mesh.get_handel(&p_v, &p_i, &queue);
+
+    // a box per falling block id, textured like the block
+    let mut falling_models = FallingBlockModels::default();
+    for id in 1..=volm.val.blocks.len() as u16 {
+        if volm.val.blocks.falls(id) {
+            let (f_v, f_i) = box_mesh(id - 1);
+            let mesh = chunk_shared_mesh.get_handel(&f_v, &f_i, &queue);
+            falling_models.meshes.insert(id, mesh);
         }
     }
 
-    let p_n = noise::SuperSimplex::new();
-    let s_n = OpenSimplex::new();
-    let scl = 0.0731;
-    let scl_2 = 0.00613;
-    let scl_3 = 0.0313;
-
-    let mut r_n = rand::thread_rng();
-
-    // world gen
-    let crr = rr * 32;
-    let cry = ry * 32;
-    for x in -crr..crr {
-        println!("w_gen x::{}", x);
-
-        for z in -crr..crr {
-            let s = (s_n.get([x as f64 * scl_2, z as f64 * scl_2]) * 10.0).exp();
-            let mut s2 = s_n.get([x as f64 * scl_3, z as f64 * scl_3]) * 8.0;
-
-            for y in -cry..cry {
-                let mut o = 0;
-
-                let d_n = voxel::voxel::noise_3d(x as f32 * 0.2, 0.0, z as f32 * 0.09) as f64;
-                if d_n >= 0.0 {
-                    s2 = s2.abs()
-                }
-                if (s + s2) >= (y as f64) {
-                    o = r_n.gen_range(14..=15); // <--- grass layer
-                    if (s + s2) >= (y as f64) + ((d_n * 2.0) + 1.0).abs() {
-                        o = r_n.gen_range(1..=5); // dirt
-                    }
-                    let p =
-                        p_n.get([x as f64 * scl, y as f64 * scl, z as f64 * scl]) * (32.0 * d_n);
-                    if (p) <= -(y as f64 + 20.3).clamp(0.6, 20.0) {
-                        o = 0;
-                    }
-                }
-                if y as f32 <= ((d_n * 6.0) - 10.0) as f32 && o != 0 {
-                    if r_n.gen_ratio(1, 38) {
-                        o = r_n.gen_range(11..=13); // <-- ore
-                    } else {
-                        o = r_n.gen_range(6..=8); // <-- stone
-                    }
-
-                    if y as f32 <= ((d_n * 26.0) - 100.0) as f32 && o != 0 {
-                        if r_n.gen_ratio(4, 36) {
-                            o = r_n.gen_range(11..=13); // <-- ore
-                        } else {
-                            o = r_n.gen_range(9..=10); // <-- cobble stone
-                        }
-                    }
+    // a client gets its chunks from the server, see NetClientPlugin
+    if net_config.is_none() {
+        // read here so the world is there from the first frame, the asset is only for reloading
+        let preset =
+            WorldPreset::load(Path::new("assets").join(WORLD_PRESET)).unwrap_or_else(|e| {
+                println!("{:#}, using the default world preset", e);
+                WorldPreset::default()
+            });
+        let (rr, ry) = preset.world_size;
+        let seed = preset.seed.unwrap_or_else(rand::random);
+        let mut generator = WorldGenerator::from_preset(seed, preset);
+        match load_structures(STRUCTURE_DIR, &volm.val.blocks) {
+            Ok(structures) => generator.structures = structures,
+            Err(e) => println!("no structures: {:#}", e),
+        }
+        // the world does not grow, features reaching out of it are dropped with this
+        let mut pending = PendingWrites::new();
+        for x in -rr..=rr {
+            println!("w_gen x::{}", x);
+            for y in -ry..=ry {
+                for z in -rr..=rr {
+                    generate_into(&mut volm.val, &generator, &mut pending, (x, y, z));
                 }
-
-                volm.val.set_voxel(x, y, z, o);
             }
         }
-    }
 
-    // chunk meshing
-    for x in -rr..=rr {
-        println!("mesh x::{}", x);
-        chunk_shared_mesh.print_size();
-        for y in -ry..=ry {
-            for z in -rr..=rr {
-                let mut c_verts = Vec::new();
-                let mut c_index = Vec::new();
-                let mut s_i = 0;
-                volm.val.update_chunk_mesh(
-                    ChunkKey::new((x, y, z)),
-                    &mut c_verts,
-                    &mut c_index,
-                    &mut s_i,
-                    false,
-                );
+        // ------- test glass
+        for x in -3..=3 {
+            for y in 20..=24 {
+                volm.val.edit_voxel(x, y, 0, 16);
+            }
+        }
 
-                if c_verts.is_empty() {
-                    continue;
-                }
-                let mut n_cmv = vec![];
-                for tv in c_verts.iter() {
-                    n_cmv.push(ChunkMeshvertex::new(
-                        tv.position,
-                        tv.normal,
-                        tv.color,
-                        tv.uv_0,
-                        tv.index,
-                    ))
+        // ------- test water, falls onto the terrain and spreads
+        for x in 6..=8 {
+            for y in 30..=32 {
+                for z in 6..=8 {
+                    volm.val.edit_fluid(x, y, z, WATER, MAX_FLUID_LEVEL);
+                    fluids.wake((x, y, z));
                 }
-
-                let inst = Instance {
-                    position: Vec3::new(x as f32 * 16.0, y as f32 * 16.0, z as f32 * 16.0),
-                    scale: Vec3::new(1., 1., 1.),
-                    color: Color::WHITE.into(),
-                    rotation: Quat::from_axis_angle(Vec3::new(0., 0., 0.), 0.0),
-                };
-                let sub_mesh_handel = chunk_shared_mesh.get_handel(&n_cmv, &c_index, &queue);
-                draw_list
-                    .instance_list
-                    .push(rendering::instancing::ModelInstance {
-                        mesh: sub_mesh_handel,
-                        instance: inst.clone(),
-                        inst_index: 0,
-                    });
-
-                debug_draw_list
-                    .instance_list
-                    .push(rendering::instancing::ModelInstance {
-                        mesh: debug_box_mesh.clone(),
-                        instance: inst.clone(),
-                        inst_index: 0,
-                    });
             }
         }
+
+        // ------- test sand, starts in the air and drops as a column
+        volm.val.enable_falling();
+        for y in 30..=35 {
+            let id = if y % 2 == 0 { SAND } else { GRAVEL };
+            volm.val.edit_voxel(-6, y, 6, id);
+        }
+        // for the biome debug view and reloading the preset
+        com.insert_resource(generator);
+        asset_server.watch_for_changes().unwrap();
+        com.insert_resource(WorldPresetHandle(asset_server.load(WORLD_PRESET)));
     }
 
+    // chunks are meshed by remesh_dirty_chunks once they are dirty
     chunk_shared_mesh.print_size();
+    com.spawn()
+        .insert_bundle((volm, ChunkMeshHandels::default()));
 
     // ------------------------------------------------------------------------------------------------------
 
@@ -345,7 +465,11 @@ fn set_up_scene(
 
     // instance render
     com.spawn().insert_bundle((
-        img_array,
+        img_array.clone(),
+        ChunkLayer(RenderLayer::Opaque),
+        ChunkFadeIn { duration: 1.5 },
+        RemotePlayerModel { mesh: player_mesh },
+        falling_models,
         shard_meshes.add(chunk_shared_mesh),
         draw_list,
         Transform::from_rotation(Quat::from_axis_angle(Vec3::new(1.0, 0.0, 0.0), 0.0)),
@@ -353,6 +477,30 @@ fn set_up_scene(
         Visibility::default(),
         ComputedVisibility::default(),
     ));
+    com.spawn().insert_bundle((
+        img_array.clone(),
+        ChunkLayer(RenderLayer::Cutout),
+        ChunkFadeIn { duration: 1.5 },
+        AlphaMaskShadow,
+        shard_meshes.add(cutout_shared_mesh),
+        cutout_draw_list,
+        Transform::from_rotation(Quat::from_axis_angle(Vec3::new(1.0, 0.0, 0.0), 0.0)),
+        GlobalTransform::default(),
+        Visibility::default(),
+        ComputedVisibility::default(),
+    ));
+    com.spawn().insert_bundle((
+        img_array,
+        ChunkLayer(RenderLayer::Transparent),
+        ChunkFadeIn { duration: 1.5 },
+        NoModelShadow,
+        shard_meshes.add(transparent_shared_mesh),
+        transparent_draw_list,
+        Transform::from_rotation(Quat::from_axis_angle(Vec3::new(1.0, 0.0, 0.0), 0.0)),
+        GlobalTransform::default(),
+        Visibility::default(),
+        ComputedVisibility::default(),
+    ));
 
     // debug bonding box
     com.spawn().insert_bundle((
@@ -362,6 +510,9 @@ fn set_up_scene(
             color: Color::WHITE.into(),
             b_draw: false,
         }),
+        ChunkDebugBoxes {
+            mesh: debug_box_mesh,
+        },
         Transform::from_rotation(Quat::from_axis_angle(Vec3::new(1.0, 0.0, 0.0), 0.0)),
         GlobalTransform::default(),
         Visibility::default(),
@@ -377,6 +528,17 @@ fn set_up_scene(
     com.spawn_bundle(DirectionalLightBundle {
         transform: Transform::from_rotation(Quat::from_axis_angle(Vec3::new(1.0, 0.1, 0.0), 30.0)),
         directional_light: DirectionalLight {
+            shadows_enabled: true,
+            // has to cover the whole generated world
+            shadow_projection: OrthographicProjection {
+                left: -160.0,
+                right: 160.0,
+                bottom: -160.0,
+                top: 160.0,
+                near: -300.0,
+                far: 300.0,
+                ..Default::default()
+            },
             ..Default::default()
         },
         ..Default::default()
@@ -384,35 +546,482 @@ fn set_up_scene(
 
     com.spawn()
         .insert_bundle(PerspectiveCameraBundle::new_3d())
-        .insert(FlyCamera::default());
+        .insert(FlyCamera::default())
+        .insert(PlayerBody::default())
+        .insert(NetViewer);
+}
+
+/// the model drawn for every remote player, on the entity whose ModelInstanceList draws it.
+#[derive(Component)]
+pub struct RemotePlayerModel {
+    pub mesh: Arc<RwLock<SubMeshHandel>>,
+}
+
+/// the models drawn for blocks while they fall, by block id.
+#[derive(Component, Default)]
+pub struct FallingBlockModels {
+    pub meshes: HashMap<u16, Arc<RwLock<SubMeshHandel>>>,
+}
+
+/// a unit box standing on the origin with the texture array layer `index` on every face.
+/// scaled to player size by the instance for remote players.
+fn box_mesh(index: u16) -> (Vec<ChunkMeshvertex>, Vec<u32>) {
+    // (normal, 4 corners counter clockwise seen from outside)
+    let faces: [([f32; 3], [[f32; 3]; 4]); 6] = [
+        (
+            [0.0, 1.0, 0.0],
+            [
+                [-0.5, 1.0, -0.5],
+                [-0.5, 1.0, 0.5],
+                [0.5, 1.0, 0.5],
+                [0.5, 1.0, -0.5],
+            ],
+        ),
+        (
+            [0.0, -1.0, 0.0],
+            [
+                [-0.5, 0.0, -0.5],
+                [0.5, 0.0, -0.5],
+                [0.5, 0.0, 0.5],
+                [-0.5, 0.0, 0.5],
+            ],
+        ),
+        (
+            [1.0, 0.0, 0.0],
+            [
+                [0.5, 0.0, -0.5],
+                [0.5, 1.0, -0.5],
+                [0.5, 1.0, 0.5],
+                [0.5, 0.0, 0.5],
+            ],
+        ),
+        (
+            [-1.0, 0.0, 0.0],
+            [
+                [-0.5, 0.0, -0.5],
+                [-0.5, 0.0, 0.5],
+                [-0.5, 1.0, 0.5],
+                [-0.5, 1.0, -0.5],
+            ],
+        ),
+        (
+            [0.0, 0.0, 1.0],
+            [
+                [-0.5, 0.0, 0.5],
+                [0.5, 0.0, 0.5],
+                [0.5, 1.0, 0.5],
+                [-0.5, 1.0, 0.5],
+            ],
+        ),
+        (
+            [0.0, 0.0, -1.0],
+            [
+                [-0.5, 0.0, -0.5],
+                [-0.5, 1.0, -0.5],
+                [0.5, 1.0, -0.5],
+                [0.5, 0.0, -0.5],
+            ],
+        ),
+    ];
+    let uvs = [[0.0, 1.0], [1.0, 1.0], [1.0, 0.0], [0.0, 0.0]];
+    let mut vertices = vec![];
+    let mut indices = vec![];
+    for (normal, corners) in faces.iter() {
+        let start = vertices.len() as u32;
+        for (corner, uv) in corners.iter().zip(uvs.iter()) {
+            vertices.push(ChunkMeshvertex::new(*corner, *normal, [1.0; 3], *uv, index));
+        }
+        indices.extend_from_slice(&[start, start + 1, start + 2, start, start + 2, start + 3]);
+    }
+    (vertices, indices)
+}
+
+/// replaces the remote player instances with where the players are this frame.
+fn draw_remote_players(
+    remote: Option<Res<RemotePlayers>>,
+    mut models: Query<(&RemotePlayerModel, &mut ModelInstanceList)>,
+) {
+    let remote = match remote {
+        Some(r) => r,
+        None => return,
+    };
+    for (model, mut list) in models.iter_mut() {
+        list.instance_list
+            .retain(|i| !Arc::ptr_eq(&i.mesh, &model.mesh));
+        for (_, player) in remote.players.iter() {
+            let (p, r) = (player.pos, player.rotation);
+            // only the heading turns the body
+            let forward = Quat::from_xyzw(r.0, r.1, r.2, r.3) * Vec3::Z;
+            list.instance_list.push(ModelInstance {
+                mesh: model.mesh.clone(),
+                instance: Instance {
+                    // the camera is at eye height, the model stands below it
+                    position: Vec3::new(p.0, p.1 - 1.6, p.2),
+                    rotation: Quat::from_rotation_y(forward.x.atan2(forward.z)),
+                    scale: Vec3::new(0.6, 1.8, 0.6),
+                    color: [0.9, 0.6, 0.5, 1.0],
+                },
+                center: Vec3::new(0.0, 0.5, 0.0),
+                inst_index: 0,
+            });
+        }
+    }
+}
+
+/// the sub mesh of every chunk and render layer, so chunks can be remeshed when they get dirty.
+#[derive(Component, Default)]
+pub struct ChunkMeshHandels {
+    pub handels: HashMap<((i32, i32, i32), RenderLayer), Arc<RwLock<SubMeshHandel>>>,
+    /// chunks that already got a debug bounding box
+    pub boxed: HashSet<(i32, i32, i32)>,
+}
+
+/// the debug line entity, gets a box for every chunk with faces.
+#[derive(Component)]
+pub struct ChunkDebugBoxes {
+    pub mesh: Arc<RwLock<SubMeshHandel>>,
+}
+
+fn update_falling_blocks(
+    time: Res<Time>,
+    mut falling: ResMut<FallingBlocks>,
+    mut maps: Query<&mut VolumeMap>,
+) {
+    // a long frame is cut, so a hitch does not drop blocks through thin floors
+    let dt = time.delta_seconds().min(0.1);
+    for mut volm in maps.iter_mut() {
+        falling.step(&mut volm.val, dt);
+    }
+}
+
+/// replaces the falling block instances with where the blocks are this frame.
+fn draw_falling_blocks(
+    falling: Res<FallingBlocks>,
+    mut models: Query<(&FallingBlockModels, &mut ModelInstanceList)>,
+) {
+    for (models, mut list) in models.iter_mut() {
+        list.instance_list
+            .retain(|i| !models.meshes.values().any(|m| Arc::ptr_eq(&i.mesh, m)));
+        for block in falling.blocks.iter() {
+            let mesh = match models.meshes.get(&block.id) {
+                Some(m) => m,
+                None => continue,
+            };
+            let (x, z) = block.column;
+            list.instance_list.push(ModelInstance {
+                mesh: mesh.clone(),
+                instance: Instance {
+                    position: Vec3::new(x as f32 + 0.5, block.y, z as f32 + 0.5),
+                    rotation: Quat::IDENTITY,
+                    scale: Vec3::ONE,
+                    color: [1.0, 1.0, 1.0, 1.0],
+                },
+                center: Vec3::new(0.0, 0.5, 0.0),
+                inst_index: 0,
+            });
+        }
+    }
+}
+
+/// a client gets the changes of block ticks from the server, see NetServerPlugin.
+fn run_block_ticks(
+    handlers: Res<TickHandlers>,
+    net_config: Option<Res<NetClientConfig>>,
+    mut maps: Query<&mut VolumeMap>,
+) {
+    if net_config.is_some() {
+        return;
+    }
+    for mut volm in maps.iter_mut() {
+        handlers.run(&mut volm.val, RANDOM_TICKS_PER_CHUNK);
+    }
+}
+
+/// only the local world has fluid, on a client the server owns the voxels.
+fn step_fluids(mut fluids: ResMut<FluidSim>, mut maps: Query<&mut VolumeMap>) {
+    if fluids.is_settled() {
+        return;
+    }
+    for mut volm in maps.iter_mut() {
+        fluids.step(&mut volm.val);
+    }
+}
+
+/// rebuilds the sub meshes of dirty chunks and drops the ones of chunks that left the map.
+fn remesh_dirty_chunks(
+    mut maps: Query<(&mut VolumeMap, &mut ChunkMeshHandels)>,
+    mut layers: Query<(&ChunkLayer, &Handle<SharedMesh>, &mut ModelInstanceList)>,
+    mut debug_boxes: Query<(&ChunkDebugBoxes, &mut ModelInstanceList), Without<ChunkLayer>>,
+    mut shard_meshes: ResMut<Assets<SharedMesh>>,
+    queue: Res<RenderQueue>,
+) {
+    for (mut volm, mut mesh_handels) in maps.iter_mut() {
+        let removed: Vec<((i32, i32, i32), RenderLayer)> = mesh_handels
+            .handels
+            .keys()
+            .filter(|(key, _)| !volm.val.chunk_list.contains_key(key))
+            .copied()
+            .collect();
+        for (layer_c, mesh_h, mut list) in layers.iter_mut() {
+            for k in removed.iter().filter(|(_, l)| *l == layer_c.0) {
+                if let Some(old) = mesh_handels.handels.remove(k) {
+                    if let Some(shared_mesh) = shard_meshes.get_mut(mesh_h) {
+                        shared_mesh.remove_handel(&old);
+                    }
+                    list.instance_list.retain(|i| !Arc::ptr_eq(&i.mesh, &old));
+                }
+            }
+        }
+        if !removed.is_empty() {
+            let chunk_list = &volm.val.chunk_list;
+            mesh_handels.boxed.retain(|k| chunk_list.contains_key(k));
+            for (_, mut list) in debug_boxes.iter_mut() {
+                list.instance_list.retain(|i| {
+                    let p = i.instance.position;
+                    let key = (
+                        (p.x / 16.0) as i32,
+                        (p.y / 16.0) as i32,
+                        (p.z / 16.0) as i32,
+                    );
+                    chunk_list.contains_key(&key)
+                });
+            }
+        }
+
+        let dirty: Vec<(i32, i32, i32)> = volm
+            .val
+            .chunk_list
+            .iter()
+            .filter(|(_, c)| c.is_dirty())
+            .map(|(k, _)| *k)
+            .collect();
+
+        for (x, y, z) in dirty {
+            let mut inst = Instance {
+                position: Vec3::new(x as f32 * 16.0, y as f32 * 16.0, z as f32 * 16.0),
+                scale: Vec3::new(1., 1., 1.),
+                // alpha 0 so ChunkFadeIn fades the chunk in
+                color: [1.0, 1.0, 1.0, 0.0],
+                rotation: Quat::from_axis_angle(Vec3::new(0., 0., 0.), 0.0),
+            };
+            let mut has_faces = false;
+
+            for (layer_c, mesh_h, mut list) in layers.iter_mut() {
+                let shared_mesh = match shard_meshes.get_mut(mesh_h) {
+                    Some(m) => m,
+                    None => continue,
+                };
+                let layer = layer_c.0;
+
+                // a chunk that was already on screen keeps its fade
+                if let Some(old) = mesh_handels.handels.remove(&((x, y, z), layer)) {
+                    shared_mesh.remove_handel(&old);
+                    if let Some(i) = list
+                        .instance_list
+                        .iter()
+                        .find(|i| Arc::ptr_eq(&i.mesh, &old))
+                    {
+                        inst.color[3] = inst.color[3].max(i.instance.color[3]);
+                    }
+                    list.instance_list.retain(|i| !Arc::ptr_eq(&i.mesh, &old));
+                }
+
+                let mut c_verts = Vec::new();
+                let mut c_index = Vec::new();
+                let mut s_i = 0;
+                volm.val.update_chunk_mesh(
+                    ChunkKey::new((x, y, z)),
+                    layer,
+                    &mut c_verts,
+                    &mut c_index,
+                    &mut s_i,
+                    false,
+                );
+
+                if c_verts.is_empty() {
+                    continue;
+                }
+                has_faces = true;
+                let mut n_cmv = vec![];
+                for tv in c_verts.iter() {
+                    n_cmv.push(ChunkMeshvertex::new(
+                        tv.position,
+                        tv.normal,
+                        tv.color,
+                        tv.uv_0,
+                        tv.index,
+                    ))
+                }
+
+                let sub_mesh_handel = shared_mesh.get_handel(&n_cmv, &c_index, &queue);
+                mesh_handels
+                    .handels
+                    .insert(((x, y, z), layer), sub_mesh_handel.clone());
+                list.instance_list.push(ModelInstance {
+                    mesh: sub_mesh_handel,
+                    instance: inst,
+                    center: Vec3::splat(8.0),
+                    inst_index: 0,
+                });
+            }
+
+            if has_faces && mesh_handels.boxed.insert((x, y, z)) {
+                for (boxes, mut list) in debug_boxes.iter_mut() {
+                    list.instance_list.push(ModelInstance {
+                        mesh: boxes.mesh.clone(),
+                        instance: Instance {
+                            color: Color::WHITE.into(),
+                            ..inst
+                        },
+                        center: Vec3::splat(8.0),
+                        inst_index: 0,
+                    });
+                }
+            }
+
+            if let Some(c) = volm.val.chunk_list.get_mut(&(x, y, z)) {
+                c.set_is_dirty(false);
+            }
+        }
+    }
 }
 
 // extract image array out to material
 fn consume_image_array(
-    q: Query<(Entity, &Handle<ImageArray>), Without<Handle<ChunkOpaqueMaterial>>>,
+    q: Query<(Entity, &Handle<ImageArray>, &ChunkLayer)>,
     mut imagearrays: ResMut<Assets<ImageArray>>,
     mut images: ResMut<Assets<Image>>,
     mut materials: ResMut<Assets<ChunkOpaqueMaterial>>,
+    mut cutout_materials: ResMut<Assets<ChunkCutoutMaterial>>,
+    mut transparent_materials: ResMut<Assets<ChunkTransparentMaterial>>,
+    shader_flags: Res<ChunkShaderFlags>,
+    chunk_fog: Res<ChunkFog>,
 
     mut com: Commands,
 ) {
-    for (e, ih) in q.iter() {
+    for (e, ih, layer) in q.iter() {
         match imagearrays.get_mut(ih) {
             Some(img) => {
                 //img.img.reinterpret_stacked_2d_as_array(16);
                 let h = images.add(img.img.clone());
-                com.get_or_spawn(e)
-                    .insert(materials.add(ChunkOpaqueMaterial {
-                        color: Color::WHITE.into(),
-                        base_color_texture: Some(h.clone()),
-                    }))
-                    .remove::<Handle<ImageArray>>();
+                let mut e_com = com.get_or_spawn(e);
+                match layer.0 {
+                    RenderLayer::Opaque => {
+                        e_com.insert(materials.add(ChunkOpaqueMaterial {
+                            color: Color::WHITE.into(),
+                            base_color_texture: Some(h.clone()),
+                            flags: *shader_flags,
+                            fog: *chunk_fog,
+                        }));
+                    }
+                    RenderLayer::Cutout => {
+                        e_com.insert(cutout_materials.add(ChunkCutoutMaterial {
+                            color: Color::WHITE.into(),
+                            base_color_texture: Some(h.clone()),
+                            alpha_cutoff: 0.5,
+                            flags: *shader_flags,
+                            fog: *chunk_fog,
+                        }));
+                    }
+                    RenderLayer::Transparent => {
+                        e_com.insert(transparent_materials.add(ChunkTransparentMaterial {
+                            color: Color::WHITE.into(),
+                            base_color_texture: Some(h.clone()),
+                            flags: *shader_flags,
+                            fog: *chunk_fog,
+                        }));
+                    }
+                }
+                e_com.remove::<Handle<ImageArray>>();
             }
             None => {}
         }
     }
 }
 
+// world preset asset loading
+//---------------------------------------------------------------
+
+#[derive(Debug, TypeUuid)]
+#[uuid = "b3f1c0de-5a2e-4c71-9d64-7e0f3a8c2b19"]
+pub struct WorldPresetAsset(pub WorldPreset);
+
+/// keeps the preset loaded, so the asset server reports when the file changes.
+pub struct WorldPresetHandle(pub Handle<WorldPresetAsset>);
+
+#[derive(Default)]
+pub struct WorldPresetLoader;
+
+impl AssetLoader for WorldPresetLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let preset = WorldPreset::parse(std::str::from_utf8(bytes)?)?;
+            load_context.set_default_asset(LoadedAsset::new(WorldPresetAsset(preset)));
+            Ok(())
+        })
+    }
+
+    // tried before the "ron" of CustomAssetLoader
+    fn extensions(&self) -> &[&str] {
+        &["preset.ron"]
+    }
+}
+
+/// generates every loaded chunk again when the preset file changed.
+fn reload_world_preset(
+    mut events: EventReader<AssetEvent<WorldPresetAsset>>,
+    presets: Res<Assets<WorldPresetAsset>>,
+    generator: Option<ResMut<WorldGenerator>>,
+    mut fluids: ResMut<FluidSim>,
+    mut falling: ResMut<FallingBlocks>,
+    mut maps: Query<&mut VolumeMap>,
+) {
+    let mut generator = match generator {
+        Some(g) => g,
+        None => return,
+    };
+    for event in events.iter() {
+        let handle = match event {
+            AssetEvent::Created { handle } | AssetEvent::Modified { handle } => handle,
+            AssetEvent::Removed { .. } => continue,
+        };
+        let preset = match presets.get(handle) {
+            Some(p) => &p.0,
+            // the first load is the file set_up_scene already generated from
+            None => continue,
+        };
+        if *preset == generator.preset {
+            continue;
+        }
+        // a preset without a seed keeps the one the world was started with
+        let seed = preset.seed.unwrap_or(generator.seed);
+        let structures = std::mem::take(&mut generator.structures);
+        *generator = WorldGenerator::from_preset(seed, preset.clone());
+        generator.structures = structures;
+
+        for mut volm in maps.iter_mut() {
+            let map = &mut volm.val;
+            // chunks the player changed are kept, the local world has no store to load them from
+            let keys = generate_again(map, &generator);
+            *fluids = FluidSim::new();
+            let all: Vec<(i32, i32, i32)> = map.chunk_list.keys().copied().collect();
+            for key in all {
+                fluids.wake_chunk(map, key);
+            }
+            println!(
+                "world preset changed, {} chunks generated again, {} changed ones kept",
+                keys.len(),
+                map.chunk_list.len() - keys.len()
+            );
+        }
+        *falling = FallingBlocks::new();
+    }
+}
+
 // image asset loading
 //---------------------------------------------------------------
 
@@ -423,14 +1032,6 @@ pub struct ImageArray {
     pub config: ImageArrayConfig,
 }
 
-#[derive(Debug, Deserialize, TypeUuid, Clone)]
-#[uuid = "77cadc56-aa9c-4543-8640-a018b74b5052"]
-pub struct ImageArrayConfig {
-    pub pixel_size: u32,
-    pub layer_depth: u32,
-    pub paths_id: Vec<(String, u32)>,
-}
-
 #[derive(Default)]
 pub struct CustomAssetLoader;
 
@@ -493,165 +1094,6 @@ impl AssetLoader for CustomAssetLoader {
 // defining materials
 // ---------------------------------------------------------------
 
-#[derive(Debug, Clone, TypeUuid)]
-#[uuid = "4ee9c363-1124-4113-890e-199d81b00281"]
-pub struct ChunkOpaqueMaterial {
-    pub color: Color,
-    pub base_color_texture: Option<Handle<Image>>,
-}
-
-#[derive(Clone)]
-pub struct GpuNewMaterial {
-    _buffer: Buffer,
-    pub bind_group: BindGroup,
-    pub base_color_texture: Option<Handle<Image>>,
-}
-
-impl RenderAsset for ChunkOpaqueMaterial {
-    type ExtractedAsset = ChunkOpaqueMaterial;
-    type PreparedAsset = GpuNewMaterial;
-
-    type Param = (
-        SRes<RenderDevice>,
-        SRes<ModelDrawMaterialPipeline<Self>>, // <- pipeline
-        SRes<RenderAssets<Image>>,
-    );
-    fn extract_asset(&self) -> Self::ExtractedAsset {
-        self.clone()
-    }
-
-    fn prepare_asset(
-        extracted_asset: Self::ExtractedAsset,
-        (render_device, custom_pipeline, gpu_image): &mut SystemParamItem<Self::Param>,
-    ) -> Result<Self::PreparedAsset, PrepareAssetError<Self::ExtractedAsset>> {
-        let (base_color_texture_view, base_color_sampler) = if let Some(result) = custom_pipeline
-            .mesh_pipeline
-            .get_image_texture(gpu_image, &extracted_asset.base_color_texture)
-        {
-            result
-        } else {
-            return Err(PrepareAssetError::RetryNextUpdate(extracted_asset));
-        };
-
-        let color: Vec4 = extracted_asset.color.as_rgba_linear().into();
-        let buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
-            contents: std140::Std140::as_bytes(&std140::AsStd140::as_std140(&color)),
-            label: None,
-            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
-        });
-        let bind_group = render_device.create_bind_group(&BindGroupDescriptor {
-            entries: &[
-                BindGroupEntry {
-                    binding: 0,
-                    resource: buffer.as_entire_binding(),
-                },
-                BindGroupEntry {
-                    binding: 1,
-                    resource: BindingResource::TextureView(base_color_texture_view),
-                },
-                BindGroupEntry {
-                    binding: 2,
-                    resource: BindingResource::Sampler(base_color_sampler),
-                },
-            ],
-            label: None,
-            layout: &custom_pipeline.material_layout,
-        });
-
-        Ok(GpuNewMaterial {
-            _buffer: buffer,
-            bind_group,
-            base_color_texture: extracted_asset.base_color_texture,
-        })
-    }
-}
-
-impl SpecializedMaterial for ChunkOpaqueMaterial {
-    type Key = ();
-
-    fn key(_: &<ChunkOpaqueMaterial as RenderAsset>::PreparedAsset) -> Self::Key {}
-
-    fn specialize(_: Self::Key, descriptor: &mut RenderPipelineDescriptor) {
-        descriptor.vertex.buffers = vec![ChunkMeshvertex::desc()];
-        descriptor.vertex.buffers.push(InstanceRaw::desc());
-
-        let mut shader_defs = vec![];
-        if false {
-            shader_defs.push("DEBUG_UV".to_string());
-        }
-        if true {
-            shader_defs.push("IS_LIGHTING".to_string());
-        }
-
-        if true {
-            shader_defs.push("COLOR_TEXTURE".to_string());
-        }
-
-        descriptor.vertex.shader_defs.append(&mut shader_defs);
-
-        if let Some(frag) = &mut descriptor.fragment {
-            frag.shader_defs.append(&mut shader_defs);
-        }
-
-        descriptor.vertex.entry_point = "vertex".into();
-        descriptor.fragment.as_mut().unwrap().entry_point = "fragment".into();
-    }
-
-    fn vertex_shader(asset_server: &AssetServer) -> Option<Handle<Shader>> {
-        asset_server.watch_for_changes().unwrap();
-        Some(asset_server.load("shaders/instancing.wgsl"))
-    }
-
-    fn fragment_shader(asset_server: &AssetServer) -> Option<Handle<Shader>> {
-        asset_server.watch_for_changes().unwrap();
-        Some(asset_server.load("shaders/instancing.wgsl"))
-    }
-
-    fn bind_group(render_asset: &<Self as RenderAsset>::PreparedAsset) -> &BindGroup {
-        &render_asset.bind_group
-    }
-
-    fn bind_group_layout(render_device: &RenderDevice) -> BindGroupLayout {
-        render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
-            entries: &[
-                BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: ShaderStages::VERTEX_FRAGMENT,
-                    ty: BindingType::Buffer {
-                        ty: BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: BufferSize::new(
-                            <Vec4 as std140::AsStd140>::std140_size_static() as u64,
-                        ),
-                    },
-                    count: None,
-                }, // Base Color Texture
-                BindGroupLayoutEntry {
-                    binding: 1,
-                    visibility: ShaderStages::FRAGMENT,
-                    ty: BindingType::Texture {
-                        multisampled: false,
-                        sample_type: TextureSampleType::Float { filterable: true },
-                        view_dimension: TextureViewDimension::D2Array,
-                    },
-                    count: None,
-                },
-                // Base Color Texture Sampler
-                BindGroupLayoutEntry {
-                    binding: 2,
-                    visibility: ShaderStages::FRAGMENT,
-                    ty: BindingType::Sampler(SamplerBindingType::Filtering),
-                    count: None,
-                },
-            ],
-            label: None,
-        })
-    }
-    fn alpha_mode(material: &<Self as RenderAsset>::PreparedAsset) -> AlphaMode {
-        AlphaMode::Opaque
-    }
-}
-
 // -----------------------------------------------------------------
 // debug line mat
 
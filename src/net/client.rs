@@ -0,0 +1,370 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::{
+    content::{ContentManifest, IdRemap},
+    prediction::{PredictedEdit, Predictor, RequestId},
+    presence::{SnapshotBuffer, INTERPOLATION_DELAY_TICKS},
+    protocol::{
+        decode, encode, ChunkDelta, ChunkPos, ClientId, ClientMessage, PlayerState, ServerMessage,
+        VoxelPos, PROTOCOL_VERSION,
+    },
+    transport::Connection,
+    NetError,
+};
+use crate::voxel::voxel::VoxelMap;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConnectionState {
+    /// hello sent, waiting for the welcome.
+    Connecting,
+    Connected {
+        client_id: ClientId,
+    },
+    Refused(String),
+    Disconnected,
+}
+
+/// mirrors the chunks it asked for from the server into a local VoxelMap.
+pub struct VoxelClient {
+    conn: Box<dyn Connection>,
+    state: ConnectionState,
+    /// chunks asked for that have not arrived yet.
+    requested: BTreeSet<ChunkPos>,
+    /// the other players, replaced on welcome once the server tick rate is known.
+    players: SnapshotBuffer,
+    /// edit sequence of every chunk the client has.
+    chunk_seqs: BTreeMap<ChunkPos, u64>,
+    /// deltas that arrived before the ones they follow.
+    pending: BTreeMap<ChunkPos, Vec<ChunkDelta>>,
+    predictor: Predictor,
+    /// what the local voxel ids mean, the hash goes in the hello.
+    content: ContentManifest,
+    /// set on welcome if the server ids are not the local ones.
+    remap: Option<IdRemap>,
+}
+
+impl VoxelClient {
+    /// sends the hello, the client is connected once `update` got the welcome.
+    /// `content` has to describe the blocks of the map passed to `update`.
+    pub fn connect(
+        conn: Box<dyn Connection>,
+        name: &str,
+        content: ContentManifest,
+    ) -> Result<Self, NetError> {
+        let mut client = Self::from_connection(conn);
+        client.send(&ClientMessage::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            name: name.to_string(),
+            content_hash: content.hash(),
+        })?;
+        client.content = content;
+        Ok(client)
+    }
+
+    /// wraps a connection without saying hello, the content is the default one.
+    pub fn from_connection(conn: Box<dyn Connection>) -> Self {
+        Self {
+            conn,
+            state: ConnectionState::Connecting,
+            requested: BTreeSet::new(),
+            players: SnapshotBuffer::new(1, INTERPOLATION_DELAY_TICKS),
+            chunk_seqs: BTreeMap::new(),
+            pending: BTreeMap::new(),
+            predictor: Predictor::default(),
+            content: ContentManifest::default(),
+            remap: None,
+        }
+    }
+
+    pub fn state(&self) -> &ConnectionState {
+        &self.state
+    }
+
+    pub fn client_id(&self) -> Option<ClientId> {
+        match self.state {
+            ConnectionState::Connected { client_id } => Some(client_id),
+            _ => None,
+        }
+    }
+
+    pub fn has_pending_requests(&self) -> bool {
+        !self.requested.is_empty()
+    }
+
+    fn send(&mut self, msg: &ClientMessage) -> Result<(), NetError> {
+        self.conn.send_on(msg.channel(), &encode(msg)?)
+    }
+
+    /// chunks that are already on the way are not asked for again.
+    pub fn request_chunks(&mut self, keys: &[ChunkPos]) -> Result<(), NetError> {
+        let keys: Vec<ChunkPos> = keys
+            .iter()
+            .copied()
+            .filter(|k| self.requested.insert(*k))
+            .collect();
+        if keys.is_empty() {
+            return Ok(());
+        }
+        self.send(&ClientMessage::RequestChunks { keys })
+    }
+
+    /// changes the voxel in `map` right away and asks the server to make the same change.
+    /// the change is undone if the server rejects it.
+    pub fn set_voxel(
+        &mut self,
+        map: &mut VoxelMap,
+        pos: VoxelPos,
+        id: u16,
+    ) -> Result<RequestId, NetError> {
+        let expected = map.get_voxel(pos.0, pos.1, pos.2);
+        let (server_id, server_expected) = match &self.remap {
+            None => (id, expected),
+            Some(remap) => match (remap.to_server(id), remap.to_server(expected)) {
+                (Some(id), Some(expected)) => (id, expected),
+                _ => {
+                    return Err(NetError::ContentMismatch(format!(
+                        "block {} is not known to the server",
+                        id
+                    )))
+                }
+            },
+        };
+        let request = self.predictor.predict(map, pos, id);
+        self.send(&ClientMessage::SetVoxel {
+            request,
+            pos,
+            id: server_id,
+            expected: server_expected,
+        })?;
+        Ok(request)
+    }
+
+    /// edits made locally that the server has not sent back yet.
+    pub fn predicted(&self) -> &[PredictedEdit] {
+        self.predictor.pending()
+    }
+
+    /// tells the server where the player is, it streams the chunks around it and shows it to the others.
+    /// meant to be called at a fixed rate, nothing is sent before the welcome.
+    pub fn send_player_state(&mut self, state: PlayerState) -> Result<(), NetError> {
+        if self.client_id().is_none() {
+            return Ok(());
+        }
+        self.send(&ClientMessage::PlayerState(state))
+    }
+
+    pub fn players(&self) -> &SnapshotBuffer {
+        &self.players
+    }
+
+    /// advance it every frame, then `sample` it for where to draw the other players.
+    pub fn players_mut(&mut self) -> &mut SnapshotBuffer {
+        &mut self.players
+    }
+
+    pub fn disconnect(&mut self) {
+        let _ = self.send(&ClientMessage::Disconnect);
+        self.state = ConnectionState::Disconnected;
+    }
+
+    /// edit sequence of the chunk, None if the client does not have it.
+    pub fn chunk_seq(&self, key: ChunkPos) -> Option<u64> {
+        self.chunk_seqs.get(&key).copied()
+    }
+
+    /// applies everything the server sent since the last call to `map`, changed chunks are marked dirty.
+    pub fn update(&mut self, map: &mut VoxelMap) -> Result<(), NetError> {
+        let msgs = match self.conn.receive() {
+            Ok(m) => m,
+            Err(e) => {
+                if let ConnectionState::Connecting | ConnectionState::Connected { .. } = self.state
+                {
+                    self.state = ConnectionState::Disconnected;
+                }
+                return Err(e);
+            }
+        };
+        for bytes in msgs {
+            let msg: ServerMessage = decode(&bytes)?;
+            self.handle(msg, map)?;
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, msg: ServerMessage, map: &mut VoxelMap) -> Result<(), NetError> {
+        match msg {
+            ServerMessage::Welcome {
+                client_id,
+                chunk_size,
+                tick_rate,
+                content_hash,
+                blocks,
+            } => {
+                if chunk_size != map.chunk_size {
+                    let reason = format!(
+                        "server chunk size {:?} does not match the local {:?}",
+                        chunk_size, map.chunk_size
+                    );
+                    self.disconnect();
+                    return Err(NetError::Refused(reason));
+                }
+                if content_hash != self.content.hash() {
+                    match IdRemap::new(&blocks, &self.content) {
+                        Ok(remap) => self.remap = Some(remap),
+                        Err(reason) => {
+                            self.disconnect();
+                            return Err(NetError::ContentMismatch(reason));
+                        }
+                    }
+                }
+                self.players = SnapshotBuffer::new(tick_rate, INTERPOLATION_DELAY_TICKS);
+                self.state = ConnectionState::Connected { client_id };
+            }
+            ServerMessage::Refused { reason } => {
+                self.state = ConnectionState::Refused(reason.clone());
+                return Err(NetError::Refused(reason));
+            }
+            ServerMessage::ChunkData(mut payload) => {
+                if let Some(remap) = &self.remap {
+                    payload.map_ids(|id| remap.to_local(id));
+                }
+                self.requested.remove(&payload.key);
+                let key = payload.key;
+                // a resend that is older then what we have already
+                if matches!(self.chunk_seqs.get(&key), Some(seq) if *seq > payload.seq) {
+                    return Ok(());
+                }
+                let chunk = payload.to_chunk(map.chunk_size.0)?;
+                map.add_chunk(key.0, key.1, key.2, chunk);
+                map.mark_neighbors_dirty(key);
+                self.predictor.chunk_replaced(map, key);
+                self.chunk_seqs.insert(key, payload.seq);
+                self.apply_pending(key, map);
+            }
+            ServerMessage::ChunkUnavailable { key } => {
+                self.requested.remove(&key);
+            }
+            ServerMessage::ChunkDelta(mut delta) => {
+                if let Some(remap) = &self.remap {
+                    for change in delta.changes.iter_mut() {
+                        change.1 = remap.to_local(change.1);
+                    }
+                }
+                let key = delta.key;
+                self.pending.entry(key).or_default().push(delta);
+                self.apply_pending(key, map);
+            }
+            ServerMessage::EditAccepted { request, seq } => {
+                if let Some((x, y, z)) = self.predictor.accepted(request, seq) {
+                    let key = map.chunk_key(x, y, z);
+                    if let Some(chunk_seq) = self.chunk_seq(key) {
+                        self.predictor.chunk_synced(map, key, chunk_seq);
+                    }
+                }
+            }
+            ServerMessage::Players { tick, players } => {
+                self.players.push(tick, players);
+            }
+            ServerMessage::EditRejected { request, reason } => {
+                println!("edit {} rejected: {}", request, reason);
+                self.predictor.rejected(map, request);
+            }
+            ServerMessage::UnloadChunks { keys } => {
+                for key in keys {
+                    self.requested.remove(&key);
+                    self.chunk_seqs.remove(&key);
+                    self.pending.remove(&key);
+                    self.predictor.forget_chunk(map, key);
+                    if map.remove_chunk(key.0, key.1, key.2).is_some() {
+                        map.mark_neighbors_dirty(key);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// applies the waiting deltas of the chunk in sequence order, stops at the first gap.
+    fn apply_pending(&mut self, key: ChunkPos, map: &mut VoxelMap) {
+        // deltas for a chunk that did not arrive yet wait for it
+        let mut seq = match self.chunk_seqs.get(&key) {
+            Some(s) => *s,
+            None => return,
+        };
+        if let Some(pending) = self.pending.get_mut(&key) {
+            // deltas at or before the chunk sequence are already in it
+            pending.retain(|d| d.seq > seq);
+            while let Some(i) = pending.iter().position(|d| d.base_seq == seq) {
+                let delta = pending.remove(i);
+                for ((x, y, z), id, level) in delta.world_changes(map.chunk_size) {
+                    map.set_voxel_with_level(x, y, z, id, level);
+                    map.mark_border_neighbors_dirty(x, y, z);
+                    self.predictor.server_changed(map, (x, y, z));
+                }
+                seq = delta.seq;
+            }
+            if pending.is_empty() {
+                self.pending.remove(&key);
+            }
+        }
+        self.chunk_seqs.insert(key, seq);
+        self.predictor.chunk_synced(map, key, seq);
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::{
+        net::{protocol::ChunkPayload, transport::MemoryConnection},
+        voxel::voxel::Chunk,
+    };
+
+    fn delta(base_seq: u64, seq: u64, pos: (i32, i32, i32), id: u16) -> ServerMessage {
+        let (x, y, z) = pos;
+        ServerMessage::ChunkDelta(ChunkDelta {
+            key: (0, 0, 0),
+            base_seq,
+            seq,
+            changes: vec![((x + 16 * (y + 16 * z)) as u16, id, 0)],
+        })
+    }
+
+    #[test]
+    fn deltas_apply_in_sequence_order() {
+        let (conn, mut server) = MemoryConnection::pair();
+        let mut client = VoxelClient::from_connection(Box::new(conn));
+        let mut map = VoxelMap::new((16, 16, 16));
+        let mut send = |msg: ServerMessage| server.send(&encode(&msg).unwrap()).unwrap();
+
+        send(ServerMessage::Welcome {
+            client_id: 1,
+            chunk_size: (16, 16, 16),
+            tick_rate: 20,
+            content_hash: ContentManifest::default().hash(),
+            blocks: vec![],
+        });
+        // the delta arrives before the chunk it applies to
+        send(delta(2, 5, (1, 1, 1), 4));
+        send(ServerMessage::ChunkData(ChunkPayload {
+            seq: 2,
+            ..ChunkPayload::from_chunk((0, 0, 0), &Chunk::new(16))
+        }));
+        client.update(&mut map).unwrap();
+        assert_eq!(map.get_voxel(1, 1, 1), 4);
+        assert_eq!(client.chunk_seq((0, 0, 0)), Some(5));
+
+        // 7 -> 9 waits for 5 -> 7, a stale delta is dropped
+        send(delta(7, 9, (3, 3, 3), 9));
+        send(delta(1, 2, (1, 1, 1), 1));
+        client.update(&mut map).unwrap();
+        assert_eq!(map.get_voxel(3, 3, 3), 0);
+        assert_eq!(map.get_voxel(1, 1, 1), 4);
+        assert_eq!(client.chunk_seq((0, 0, 0)), Some(5));
+
+        send(delta(5, 7, (3, 3, 3), 7));
+        client.update(&mut map).unwrap();
+        assert_eq!(map.get_voxel(3, 3, 3), 9);
+        assert_eq!(client.chunk_seq((0, 0, 0)), Some(9));
+    }
+}
@@ -0,0 +1,178 @@
+// what voxel ids mean on each side. the hash is exchanged on connect, if it differs the client
+// maps the server ids to its own by block name, or gives up if it is missing a block or its texture.
+
+use serde::{Deserialize, Serialize};
+
+use crate::voxel::block::{BlockRegistry, ImageArrayConfig};
+
+/// the block names in id order and the texture array they are drawn with.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ContentManifest {
+    /// the first is id 1.
+    pub blocks: Vec<String>,
+    /// None where nothing is drawn, like the headless server without the texture config.
+    pub textures: Option<ImageArrayConfig>,
+}
+
+impl ContentManifest {
+    pub fn new(blocks: &BlockRegistry, textures: Option<ImageArrayConfig>) -> Self {
+        Self {
+            blocks: blocks.names(),
+            textures,
+        }
+    }
+
+    /// fnv-1a of the encoded manifest, the same on every machine and build.
+    pub fn hash(&self) -> u64 {
+        let bytes = bincode::serialize(self).unwrap_or_default();
+        bytes.iter().fold(0xcbf2_9ce4_8422_2325, |h, b| {
+            (h ^ *b as u64).wrapping_mul(0x0100_0000_01b3)
+        })
+    }
+
+    /// local id of the block, 0 if there is none.
+    fn id_of(&self, name: &str) -> u16 {
+        self.blocks
+            .iter()
+            .position(|b| b == name)
+            .map_or(0, |i| i as u16 + 1)
+    }
+}
+
+/// the default blocks without textures.
+impl Default for ContentManifest {
+    fn default() -> Self {
+        Self::new(&BlockRegistry::default(), None)
+    }
+}
+
+/// server ids to local ids and back, air is 0 on both sides.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IdRemap {
+    /// index is the server id.
+    to_local: Vec<u16>,
+    /// index is the local id, 0 for blocks the server does not have.
+    to_server: Vec<u16>,
+}
+
+impl IdRemap {
+    /// fails with a message for the player if a server block is unknown here or has no texture.
+    pub fn new(server_blocks: &[String], local: &ContentManifest) -> Result<Self, String> {
+        let mut to_local = vec![0];
+        let mut to_server = vec![0; local.blocks.len() + 1];
+        for (i, name) in server_blocks.iter().enumerate() {
+            let server_id = i as u16 + 1;
+            let id = local.id_of(name);
+            if id == 0 {
+                return Err(format!(
+                    "the server has block {:?} (id {}) that is not known here",
+                    name, server_id
+                ));
+            }
+            if let Some(textures) = &local.textures {
+                if !textures.has_layer(id as u32 - 1) {
+                    return Err(format!(
+                        "block {:?} has no texture layer {} here",
+                        name,
+                        id - 1
+                    ));
+                }
+            }
+            to_local.push(id);
+            to_server[id as usize] = server_id;
+        }
+        Ok(Self {
+            to_local,
+            to_server,
+        })
+    }
+
+    /// ids the server did not list become air.
+    pub fn to_local(&self, id: u16) -> u16 {
+        self.to_local.get(id as usize).copied().unwrap_or(0)
+    }
+
+    /// None for blocks the server does not have.
+    pub fn to_server(&self, id: u16) -> Option<u16> {
+        match self.to_server.get(id as usize) {
+            Some(0) if id != 0 => None,
+            Some(s) => Some(*s),
+            None => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    fn textures(layers: u32) -> ImageArrayConfig {
+        ImageArrayConfig {
+            pixel_size: 16,
+            layer_depth: layers,
+            paths_id: (0..layers).map(|l| (format!("{}.png", l), l)).collect(),
+        }
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn hash_covers_ids_and_textures() {
+        let a = ContentManifest::default();
+        assert_eq!(a.hash(), ContentManifest::default().hash());
+
+        let mut swapped = a.clone();
+        swapped.blocks.swap(0, 1);
+        assert_ne!(a.hash(), swapped.hash());
+
+        let textured = ContentManifest {
+            textures: Some(textures(16)),
+            ..a.clone()
+        };
+        assert_ne!(a.hash(), textured.hash());
+        let mut moved = textured.clone();
+        moved.textures.as_mut().unwrap().paths_id[0].0 = "other.png".into();
+        assert_ne!(textured.hash(), moved.hash());
+    }
+
+    #[test]
+    fn ids_are_matched_by_name() {
+        let local = ContentManifest {
+            blocks: names(&["dirt", "stone", "glass"]),
+            textures: Some(textures(3)),
+        };
+        let remap = IdRemap::new(&names(&["glass", "dirt"]), &local).unwrap();
+        assert_eq!(remap.to_local(0), 0);
+        assert_eq!(remap.to_local(1), 3);
+        assert_eq!(remap.to_local(2), 1);
+        assert_eq!(remap.to_local(9), 0);
+        assert_eq!(remap.to_server(3), Some(1));
+        assert_eq!(remap.to_server(1), Some(2));
+        assert_eq!(remap.to_server(0), Some(0));
+        // stone is only known here
+        assert_eq!(remap.to_server(2), None);
+    }
+
+    #[test]
+    fn unknown_block_is_refused_by_name() {
+        let local = ContentManifest {
+            blocks: names(&["dirt"]),
+            textures: None,
+        };
+        let err = IdRemap::new(&names(&["dirt", "lava"]), &local).unwrap_err();
+        assert!(err.contains("\"lava\"") && err.contains("id 2"), "{}", err);
+    }
+
+    #[test]
+    fn block_without_texture_is_refused() {
+        let local = ContentManifest {
+            blocks: names(&["dirt", "stone"]),
+            textures: Some(textures(1)),
+        };
+        assert!(IdRemap::new(&names(&["dirt"]), &local).is_ok());
+        let err = IdRemap::new(&names(&["stone"]), &local).unwrap_err();
+        assert!(err.contains("\"stone\""), "{}", err);
+    }
+}
@@ -0,0 +1,285 @@
+// decides which chunks each client gets, only chunks around the client are replicated.
+
+use std::collections::BTreeSet;
+
+use super::protocol::ChunkPos;
+use crate::voxel::voxel::VoxelMap;
+
+#[derive(Debug, Clone, Copy)]
+pub struct InterestConfig {
+    /// in chunks, (x and z, y). x and z are a circle, y is a straight cut.
+    pub view_radius: (i32, i32),
+    /// encoded chunk bytes sent to one client per update, at least one chunk is always sent.
+    pub bytes_per_tick: usize,
+}
+
+impl Default for InterestConfig {
+    fn default() -> Self {
+        Self {
+            view_radius: (4, 9),
+            bytes_per_tick: 256 * 1024,
+        }
+    }
+}
+
+/// squared distance between chunk keys, cheaper then key_distance and orders the same.
+pub fn key_distance_sq(a: ChunkPos, b: ChunkPos) -> i32 {
+    let (x, y, z) = (b.0 - a.0, b.1 - a.1, b.2 - a.2);
+    x * x + y * y + z * z
+}
+
+/// the chunk the world position is in.
+pub fn chunk_of_position(pos: (f32, f32, f32), chunk_size: (i32, i32, i32)) -> ChunkPos {
+    (
+        (pos.0 / chunk_size.0 as f32).floor() as i32,
+        (pos.1 / chunk_size.1 as f32).floor() as i32,
+        (pos.2 / chunk_size.2 as f32).floor() as i32,
+    )
+}
+
+/// where players spawn, requests of a client that did not send its position yet are measured from here.
+pub const SPAWN_CHUNK: ChunkPos = (0, 0, 0);
+
+/// what one client has and where it is.
+#[derive(Debug, Default, Clone)]
+pub struct ClientInterest {
+    /// None until the client sent its position, nothing is pushed before that.
+    pub center: Option<ChunkPos>,
+    /// chunks the client was sent and not told to unload.
+    pub known: BTreeSet<ChunkPos>,
+    /// chunks the client asked for that were not sent yet, they go out with the missing ones.
+    pub requested: BTreeSet<ChunkPos>,
+}
+
+impl ClientInterest {
+    pub fn in_range(center: ChunkPos, key: ChunkPos, config: &InterestConfig) -> bool {
+        let (r, ry) = config.view_radius;
+        let (x, y, z) = (key.0 - center.0, key.1 - center.1, key.2 - center.2);
+        x * x + z * z <= r * r && y.abs() <= ry
+    }
+
+    /// chunks in range that the map has and the client does not, nearest first.
+    /// ties are broken by key so the order never depends on the map.
+    pub fn missing(&self, map: &VoxelMap, config: &InterestConfig) -> Vec<ChunkPos> {
+        let center = match self.center {
+            Some(c) => c,
+            None => return vec![],
+        };
+        let (r, ry) = config.view_radius;
+        let mut keys = vec![];
+        for x in -r..=r {
+            for y in -ry..=ry {
+                for z in -r..=r {
+                    let key = (center.0 + x, center.1 + y, center.2 + z);
+                    if Self::in_range(center, key, config)
+                        && !self.known.contains(&key)
+                        && map.chunk_list.contains_key(&key)
+                    {
+                        keys.push(key);
+                    }
+                }
+            }
+        }
+        keys.sort_by_key(|k| (key_distance_sq(center, *k), *k));
+        keys
+    }
+
+    fn request_center(&self) -> ChunkPos {
+        self.center.unwrap_or(SPAWN_CHUNK)
+    }
+
+    /// queues a chunk the client asked for, false if it is out of range and is not sent.
+    pub fn request(&mut self, key: ChunkPos, config: &InterestConfig) -> bool {
+        if !Self::in_range(self.request_center(), key, config) {
+            return false;
+        }
+        self.requested.insert(key);
+        true
+    }
+
+    /// requested chunks that are out of range now or that the map does not have.
+    /// they are taken out of `requested`.
+    pub fn take_unservable(&mut self, map: &VoxelMap, config: &InterestConfig) -> Vec<ChunkPos> {
+        let center = self.request_center();
+        let (keep, drop): (BTreeSet<ChunkPos>, BTreeSet<ChunkPos>) = self
+            .requested
+            .iter()
+            .partition(|k| Self::in_range(center, **k, config) && map.chunk_list.contains_key(k));
+        self.requested = keep;
+        drop.into_iter().collect()
+    }
+
+    /// the missing chunks and the requested ones, nearest first. requested chunks are sent
+    /// again even if the client has them.
+    pub fn to_send(&self, map: &VoxelMap, config: &InterestConfig) -> Vec<ChunkPos> {
+        let center = self.request_center();
+        let mut keys: BTreeSet<ChunkPos> = self.missing(map, config).into_iter().collect();
+        keys.extend(
+            self.requested
+                .iter()
+                .filter(|k| map.chunk_list.contains_key(k)),
+        );
+        let mut keys: Vec<ChunkPos> = keys.into_iter().collect();
+        keys.sort_by_key(|k| (key_distance_sq(center, *k), *k));
+        keys
+    }
+
+    /// known chunks that are out of range now.
+    pub fn out_of_range(&self, config: &InterestConfig) -> Vec<ChunkPos> {
+        match self.center {
+            Some(center) => self
+                .known
+                .iter()
+                .copied()
+                .filter(|k| !Self::in_range(center, *k, config))
+                .collect(),
+            None => vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::{
+        net::{
+            client::VoxelClient,
+            content::ContentManifest,
+            protocol::{encode, ChunkPayload, PlayerState, ServerMessage},
+            server::VoxelServer,
+            transport::MemoryListener,
+        },
+        voxel::voxel::Chunk,
+    };
+
+    fn world(r: i32) -> VoxelMap {
+        let mut map = VoxelMap::new((16, 16, 16));
+        for x in -r..=r {
+            for y in -1..=1 {
+                for z in -r..=r {
+                    map.add_chunk(x, y, z, Chunk::new(16));
+                }
+            }
+        }
+        // some voxels so the chunks are not all the same size
+        for x in -r * 16..(r + 1) * 16 {
+            map.set_voxel(x, 0, x.rem_euclid(16), 6);
+        }
+        map
+    }
+
+    fn step(
+        server: &mut VoxelServer,
+        server_map: &mut VoxelMap,
+        client: &mut VoxelClient,
+        client_map: &mut VoxelMap,
+    ) {
+        server.update(server_map);
+        client.update(client_map).unwrap();
+    }
+
+    #[test]
+    fn nearest_first() {
+        let map = world(4);
+        let config = InterestConfig {
+            view_radius: (2, 1),
+            ..Default::default()
+        };
+        let interest = ClientInterest {
+            center: Some((0, 0, 0)),
+            ..Default::default()
+        };
+        let keys = interest.missing(&map, &config);
+        assert_eq!(keys[0], (0, 0, 0));
+        assert!(keys
+            .windows(2)
+            .all(|w| key_distance_sq((0, 0, 0), w[0]) <= key_distance_sq((0, 0, 0), w[1])));
+        assert!(keys.iter().all(|k| k.0 * k.0 + k.2 * k.2 <= 4));
+        // 13 columns in the circle, 3 high
+        assert_eq!(keys.len(), 13 * 3);
+    }
+
+    /// one server and one simulated client over the memory transport, stepped by hand.
+    #[test]
+    fn simulated_client_streams_and_unloads() {
+        let mut server_map = world(6);
+        let listener = MemoryListener::default();
+        let chunk_bytes = encode(&ServerMessage::ChunkData(ChunkPayload::from_chunk(
+            (0, 0, 0),
+            &server_map.chunk_list[&(0, 0, 0)],
+        )))
+        .unwrap()
+        .len();
+        let config = InterestConfig {
+            view_radius: (2, 1),
+            // room for a few chunks per update
+            bytes_per_tick: chunk_bytes * 3,
+        };
+        let mut server = VoxelServer::new(Box::new(listener.clone()));
+        server.interest = config;
+
+        let mut client = VoxelClient::connect(
+            Box::new(listener.connect()),
+            "sim",
+            ContentManifest::default(),
+        )
+        .unwrap();
+        let mut client_map = VoxelMap::new((16, 16, 16));
+
+        step(&mut server, &mut server_map, &mut client, &mut client_map);
+        assert!(client.client_id().is_some());
+        // nothing is pushed before the client says where it is
+        assert!(client_map.chunk_list.is_empty());
+
+        let expected = ClientInterest {
+            center: Some((0, 0, 0)),
+            ..Default::default()
+        }
+        .missing(&server_map, &config);
+        // the nearest chunks that fit in one update, the last one may go over the budget
+        let mut first_batch = BTreeSet::new();
+        let mut bytes = 0;
+        for key in expected.iter() {
+            if bytes >= config.bytes_per_tick {
+                break;
+            }
+            bytes += encode(&ServerMessage::ChunkData(ChunkPayload::from_chunk(
+                *key,
+                &server_map.chunk_list[key],
+            )))
+            .unwrap()
+            .len();
+            first_batch.insert(*key);
+        }
+        assert!(first_batch.len() < expected.len());
+
+        client
+            .send_player_state(PlayerState::at((8.0, 8.0, 8.0)))
+            .unwrap();
+        step(&mut server, &mut server_map, &mut client, &mut client_map);
+        let got: BTreeSet<ChunkPos> = client_map.chunk_list.keys().copied().collect();
+        assert_eq!(got, first_batch);
+
+        for _ in 0..20 {
+            step(&mut server, &mut server_map, &mut client, &mut client_map);
+        }
+        let got: BTreeSet<ChunkPos> = client_map.chunk_list.keys().copied().collect();
+        assert_eq!(got, expected.iter().copied().collect());
+
+        // walk 3 chunks along x, the chunks behind get unloaded
+        client
+            .send_player_state(PlayerState::at((3.0 * 16.0 + 8.0, 8.0, 8.0)))
+            .unwrap();
+        for _ in 0..20 {
+            step(&mut server, &mut server_map, &mut client, &mut client_map);
+        }
+        assert!(!client_map.chunk_list.contains_key(&(0, 0, 0)));
+        assert!(!client_map.chunk_list.contains_key(&(-2, 0, 0)));
+        assert!(client_map.chunk_list.contains_key(&(3, 0, 0)));
+        assert!(client_map.chunk_list.contains_key(&(5, 1, 0)));
+        assert!(client_map
+            .chunk_list
+            .keys()
+            .all(|k| ClientInterest::in_range((3, 0, 0), *k, &config)));
+    }
+}
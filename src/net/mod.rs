@@ -0,0 +1,37 @@
+// server authoritative networking, the server owns the VoxelMap and clients mirror the chunks they asked for.
+// server.rs and client.rs do not depend on bevy so they can run headless and in tests.
+
+pub mod client;
+pub mod content;
+pub mod interest;
+pub mod plugin;
+pub mod prediction;
+pub mod presence;
+pub mod protocol;
+pub mod reliable;
+pub mod server;
+pub mod sim;
+pub mod transport;
+pub mod validation;
+
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum NetError {
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("bad message: {0}")]
+    Encoding(#[from] bincode::Error),
+    #[error("connection closed")]
+    Closed,
+    #[error("frame of {0} bytes is too large")]
+    FrameTooLarge(usize),
+    #[error("chunk {0:?} has the wrong number of voxels")]
+    BadChunk((i32, i32, i32)),
+    #[error("refused by server: {0}")]
+    Refused(String),
+    #[error("connection timed out")]
+    TimedOut,
+    #[error("server content does not match: {0}")]
+    ContentMismatch(String),
+}
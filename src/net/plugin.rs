@@ -0,0 +1,467 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::Context;
+use bevy::{
+    app::AppExit,
+    core::{FixedTimestep, Time},
+    prelude::{
+        App, Commands, Component, EventReader, EventWriter, GlobalTransform, Plugin, Query, Res,
+        ResMut, SystemSet, With,
+    },
+};
+use serde::Deserialize;
+
+use super::{
+    client::VoxelClient,
+    content::ContentManifest,
+    interest::{key_distance_sq, ClientInterest, InterestConfig},
+    protocol::{ChunkPos, ClientId, PlayerState},
+    reliable::{ReliableConnection, ReliableListener},
+    server::{VoxelServer, DEFAULT_TICK_RATE},
+    transport::{
+        Connection, Listener, TcpConnection, TcpServerListener, UdpConnection, UdpServerListener,
+    },
+    validation::EditRules,
+    NetError,
+};
+use crate::{
+    voxel::{
+        block::{BlockRegistry, ImageArrayConfig},
+        falling::FallingBlocks,
+        fluid::{FluidSim, FLUID_TICK_RATE},
+        ticks::{TickHandlers, RANDOM_TICKS_PER_CHUNK},
+        voxel::{VolumeMap, VoxelMap},
+    },
+    world::{
+        caves::CaveConfig,
+        decoration::PendingWrites,
+        generator::WorldGenerator,
+        load_or_generate_chunk,
+        preset::{WorldPreset, WORLD_PRESET},
+        store::ChunkStore,
+        structure::{load_structures, STRUCTURE_DIR},
+    },
+};
+
+/// fills the VolumeMap of the app from a server instead of generating the world locally.
+pub struct NetClientPlugin;
+
+impl Plugin for NetClientPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<NetClient>()
+            .init_resource::<RemotePlayers>()
+            .add_startup_system(connect_to_server)
+            .add_system(net_client_update)
+            .add_system(update_remote_players)
+            .add_system_set(
+                SystemSet::new()
+                    .with_run_criteria(FixedTimestep::step(1.0 / DEFAULT_TICK_RATE as f64))
+                    .with_system(send_player_state),
+            );
+    }
+}
+
+/// udp sends player movement unreliable next to the chunk stream, tcp is there for networks that block udp.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum NetTransport {
+    Tcp,
+    #[default]
+    Udp,
+}
+
+/// the texture array the client draws with, part of the content both sides compare on connect.
+pub const TEXTURE_CONFIG: &str = "assets/data/block_texture_config.ron";
+
+/// the blocks and textures of this side, without textures if the config does not load.
+fn load_content(blocks: &BlockRegistry, texture_config: &str) -> ContentManifest {
+    let textures = match ImageArrayConfig::load(texture_config) {
+        Ok(t) => Some(t),
+        Err(e) => {
+            println!("textures are not part of the content: {:#}", e);
+            None
+        }
+    };
+    ContentManifest::new(blocks, textures)
+}
+
+pub struct NetClientConfig {
+    pub address: String,
+    pub name: String,
+    pub transport: NetTransport,
+}
+
+/// the position of this entity is sent to the server, which streams the chunks around it.
+#[derive(Component)]
+pub struct NetViewer;
+
+#[derive(Default)]
+pub struct NetClient {
+    pub client: Option<VoxelClient>,
+}
+
+/// where the other players are drawn this frame, interpolated from the server snapshots.
+#[derive(Default)]
+pub struct RemotePlayers {
+    pub players: Vec<(ClientId, PlayerState)>,
+}
+
+fn open_connection(config: &NetClientConfig) -> Result<Box<dyn Connection>, NetError> {
+    let address = config.address.as_str();
+    let conn: Box<dyn Connection> = match config.transport {
+        NetTransport::Tcp => Box::new(TcpConnection::connect(address)?),
+        NetTransport::Udp => {
+            let socket = UdpConnection::connect(address)?;
+            Box::new(ReliableConnection::new(Box::new(socket)))
+        }
+    };
+    Ok(conn)
+}
+
+fn connect_to_server(config: Res<NetClientConfig>, mut net: ResMut<NetClient>) {
+    // the client map is made with the default blocks
+    let content = load_content(&BlockRegistry::default(), TEXTURE_CONFIG);
+    match open_connection(&config)
+        .and_then(|conn| VoxelClient::connect(conn, &config.name, content))
+    {
+        Ok(client) => {
+            println!("connecting to {}", config.address);
+            net.client = Some(client);
+        }
+        Err(e) => println!("could not connect to {}: {}", config.address, e),
+    }
+}
+
+fn net_client_update(
+    config: Res<NetClientConfig>,
+    mut net: ResMut<NetClient>,
+    mut maps: Query<&mut VolumeMap>,
+) {
+    let mut volm = match maps.iter_mut().next() {
+        Some(m) => m,
+        None => return,
+    };
+    let net = &mut *net;
+    let client = match &mut net.client {
+        Some(c) => c,
+        None => return,
+    };
+
+    if let Err(e) = client.update(&mut volm.val) {
+        println!("disconnected from {}: {}", config.address, e);
+        net.client = None;
+    }
+}
+
+/// runs at the server tick rate so the server gets evenly spaced player states.
+fn send_player_state(
+    mut net: ResMut<NetClient>,
+    viewers: Query<&GlobalTransform, With<NetViewer>>,
+) {
+    let (client, t) = match (&mut net.client, viewers.iter().next()) {
+        (Some(c), Some(t)) => (c, t),
+        _ => return,
+    };
+    let (p, r) = (t.translation, t.rotation);
+    let state = PlayerState {
+        pos: (p.x, p.y, p.z),
+        rotation: (r.x, r.y, r.z, r.w),
+    };
+    if let Err(e) = client.send_player_state(state) {
+        println!("sending player state failed: {}", e);
+    }
+}
+
+fn update_remote_players(
+    time: Res<Time>,
+    mut net: ResMut<NetClient>,
+    mut remote: ResMut<RemotePlayers>,
+) {
+    match &mut net.client {
+        Some(client) => {
+            client.players_mut().advance(time.delta_seconds());
+            remote.players = client.players().sample();
+        }
+        None => remote.players.clear(),
+    }
+}
+
+// server ------------------------------------------------------------------------------
+
+/// runs the world and the server without any window or rendering, see src/bin/server.rs.
+pub struct NetServerPlugin;
+
+impl Plugin for NetServerPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ShutdownRequested>()
+            .add_startup_system(start_server)
+            .add_system(server_update)
+            .add_system(shutdown_server)
+            .add_system(save_on_exit);
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct NetServerConfig {
+    pub port: u16,
+    pub seed: u32,
+    /// chunks are saved here, one file per chunk.
+    pub world_dir: String,
+    /// chunks loaded around the origin at start and streamed around each client, (x and z, y).
+    pub view_radius: (i32, i32),
+    /// encoded chunk bytes sent to one client per update.
+    #[serde(default = "default_chunk_bytes_per_tick")]
+    pub chunk_bytes_per_tick: usize,
+    #[serde(default)]
+    pub transport: NetTransport,
+    /// texture config sent to clients with the block names, so both sides know they draw the same.
+    #[serde(default = "default_texture_config")]
+    pub texture_config: String,
+    /// reach, rate limit and allowed blocks for client edits.
+    #[serde(default)]
+    pub edit_rules: EditRules,
+    /// the world generator preset, its seed is not used. changing it only changes chunks not saved yet.
+    #[serde(default = "default_world_preset")]
+    pub world_preset: String,
+    /// directory of the structure files the decoration pass places.
+    #[serde(default = "default_structure_dir")]
+    pub structure_dir: String,
+    /// deprecated, set the caves of the world preset instead. when given it replaces them.
+    #[serde(default)]
+    pub caves: Option<CaveConfig>,
+}
+
+fn default_chunk_bytes_per_tick() -> usize {
+    InterestConfig::default().bytes_per_tick
+}
+
+fn default_texture_config() -> String {
+    TEXTURE_CONFIG.to_string()
+}
+
+fn default_world_preset() -> String {
+    format!("assets/{}", WORLD_PRESET)
+}
+
+fn default_structure_dir() -> String {
+    STRUCTURE_DIR.to_string()
+}
+
+impl Default for NetServerConfig {
+    fn default() -> Self {
+        Self {
+            port: 7878,
+            seed: 0,
+            world_dir: "world".to_string(),
+            view_radius: (4, 9),
+            chunk_bytes_per_tick: default_chunk_bytes_per_tick(),
+            transport: NetTransport::default(),
+            texture_config: default_texture_config(),
+            edit_rules: EditRules::default(),
+            world_preset: default_world_preset(),
+            structure_dir: default_structure_dir(),
+            caves: None,
+        }
+    }
+}
+
+impl NetServerConfig {
+    pub fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let text =
+            fs::read_to_string(path).with_context(|| format!("could not read {:?}", path))?;
+        ron::de::from_str(&text).with_context(|| format!("bad server config {:?}", path))
+    }
+}
+
+/// set from the SIGINT handler, the server saves and exits on the next update.
+#[derive(Default, Clone)]
+pub struct ShutdownRequested(pub Arc<AtomicBool>);
+
+pub struct NetServer {
+    pub server: VoxelServer,
+    pub store: ChunkStore,
+    pub generator: WorldGenerator,
+    /// clients only see a block leave and land, through the journal, not the fall in between.
+    pub falling: FallingBlocks,
+    /// one game tick per server update.
+    pub ticks: TickHandlers,
+    /// stepped at FLUID_TICK_RATE, woken by every change the server sends and every chunk it loads.
+    pub fluids: FluidSim,
+    /// seconds of server time the fluids were not stepped for yet.
+    pub fluid_time: f64,
+    /// the parts of features that reach into chunks not generated yet.
+    pub pending: PendingWrites,
+}
+
+fn start_server(mut com: Commands, config: Res<NetServerConfig>) {
+    let address = ("0.0.0.0", config.port);
+    let listener: Result<Box<dyn Listener>, NetError> = match config.transport {
+        NetTransport::Tcp => {
+            TcpServerListener::bind(address).map(|l| Box::new(l) as Box<dyn Listener>)
+        }
+        NetTransport::Udp => UdpServerListener::bind(address)
+            .map(|l| Box::new(ReliableListener::new(Box::new(l))) as Box<dyn Listener>),
+    };
+    let listener =
+        listener.unwrap_or_else(|e| panic!("could not listen on port {}: {}", config.port, e));
+    let store = ChunkStore::open(&config.world_dir).unwrap();
+    let mut preset = WorldPreset::load(&config.world_preset)
+        .unwrap_or_else(|e| panic!("could not load the world preset: {:#}", e));
+    if let Some(caves) = &config.caves {
+        println!(
+            "server: caves in the server config is deprecated, move it to {}",
+            config.world_preset
+        );
+        preset.caves = caves.clone();
+    }
+    let mut generator = WorldGenerator::from_preset(config.seed, preset);
+    match load_structures(&config.structure_dir, &BlockRegistry::default()) {
+        Ok(structures) => generator.structures = structures,
+        Err(e) => println!("server: no structures: {:#}", e),
+    }
+    let mut pending = PendingWrites::new();
+
+    let mut map = VoxelMap::new((16, 16, 16));
+    map.enable_falling();
+    let mut fluids = FluidSim::new();
+    let (rr, ry) = config.view_radius;
+    for x in -rr..=rr {
+        for y in -ry..=ry {
+            for z in -rr..=rr {
+                match load_or_generate_chunk(&mut map, &store, &generator, &mut pending, (x, y, z))
+                {
+                    Ok(()) => fluids.wake_chunk(&map, (x, y, z)),
+                    Err(e) => println!("server: chunk {:?} failed to load: {:#}", (x, y, z), e),
+                }
+            }
+        }
+    }
+    println!(
+        "server: {} chunks loaded, listening on {:?} port {}",
+        map.chunk_list.len(),
+        config.transport,
+        config.port
+    );
+
+    let mut server = VoxelServer::new(listener);
+    server.content = load_content(&map.blocks, &config.texture_config);
+    server.edit_rules = config.edit_rules.clone();
+    server.interest = InterestConfig {
+        view_radius: config.view_radius,
+        bytes_per_tick: config.chunk_bytes_per_tick,
+    };
+    let ticks = TickHandlers::with_default_behaviors(&map.blocks);
+    com.spawn().insert(VolumeMap { val: map });
+    com.insert_resource(NetServer {
+        server,
+        store,
+        generator,
+        falling: FallingBlocks::new(),
+        ticks,
+        fluids,
+        fluid_time: 0.0,
+        pending,
+    });
+}
+
+/// chunks loaded or generated around clients per update, so a fast client does not stall the server.
+const CHUNK_LOADS_PER_TICK: usize = 16;
+
+fn server_update(mut net: ResMut<NetServer>, mut maps: Query<&mut VolumeMap>) {
+    let net = &mut *net;
+    for mut volm in maps.iter_mut() {
+        net.server.update(&mut volm.val);
+        for pos in net.server.changed() {
+            net.fluids.wake_around(*pos);
+        }
+        net.ticks.run(&mut volm.val, RANDOM_TICKS_PER_CHUNK);
+        net.falling
+            .step(&mut volm.val, 1.0 / net.server.tick_rate as f32);
+        net.fluid_time += 1.0 / net.server.tick_rate as f64;
+        while net.fluid_time >= 1.0 / FLUID_TICK_RATE {
+            net.fluid_time -= 1.0 / FLUID_TICK_RATE;
+            if !net.fluids.is_settled() {
+                net.fluids.step(&mut volm.val);
+            }
+        }
+        load_around_clients(net, &mut volm.val);
+    }
+}
+
+/// loads the nearest missing chunks around every client, they get streamed on the next update.
+fn load_around_clients(net: &mut NetServer, map: &mut VoxelMap) {
+    let config = net.server.interest;
+    let (rr, ry) = config.view_radius;
+    // nearest distance to any client per missing chunk
+    let mut missing: BTreeMap<ChunkPos, i32> = BTreeMap::new();
+    for center in net.server.client_centers() {
+        for x in -rr..=rr {
+            for y in -ry..=ry {
+                for z in -rr..=rr {
+                    let key = (center.0 + x, center.1 + y, center.2 + z);
+                    if ClientInterest::in_range(center, key, &config)
+                        && !map.chunk_list.contains_key(&key)
+                    {
+                        let d = key_distance_sq(center, key);
+                        let e = missing.entry(key).or_insert(d);
+                        *e = (*e).min(d);
+                    }
+                }
+            }
+        }
+    }
+    let mut missing: Vec<(i32, ChunkPos)> = missing.into_iter().map(|(k, d)| (d, k)).collect();
+    missing.sort_unstable();
+    for (_, key) in missing.into_iter().take(CHUNK_LOADS_PER_TICK) {
+        match load_or_generate_chunk(map, &net.store, &net.generator, &mut net.pending, key) {
+            Ok(()) => net.fluids.wake_chunk(map, key),
+            Err(e) => println!("server: chunk {:?} failed to load: {:#}", key, e),
+        }
+    }
+}
+
+fn save_dirty_chunks(net: &NetServer, maps: &mut Query<&mut VolumeMap>) {
+    for mut volm in maps.iter_mut() {
+        match net.store.save_dirty(&mut volm.val) {
+            Ok(n) => println!("server: saved {} chunks", n),
+            Err(e) => println!("server: saving failed: {:#}", e),
+        }
+    }
+}
+
+fn shutdown_server(
+    shutdown: Res<ShutdownRequested>,
+    net: Option<Res<NetServer>>,
+    mut maps: Query<&mut VolumeMap>,
+    mut exit: EventWriter<AppExit>,
+) {
+    if !shutdown.0.swap(false, Ordering::SeqCst) {
+        return;
+    }
+    println!("server: shutting down");
+    if let Some(net) = net {
+        save_dirty_chunks(&net, &mut maps);
+    }
+    exit.send(AppExit);
+}
+
+/// chunks also get saved when something else ends the app.
+fn save_on_exit(
+    mut exit: EventReader<AppExit>,
+    net: Option<Res<NetServer>>,
+    mut maps: Query<&mut VolumeMap>,
+) {
+    if exit.iter().next().is_some() {
+        if let Some(net) = net {
+            save_dirty_chunks(&net, &mut maps);
+        }
+    }
+}
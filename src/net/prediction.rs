@@ -0,0 +1,299 @@
+// client side prediction of voxel edits, the edit shows up right away and is undone if the server rejects it.
+
+use std::collections::BTreeMap;
+
+use super::protocol::{ChunkPos, VoxelPos};
+use crate::voxel::voxel::VoxelMap;
+
+pub type RequestId = u32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PredictedEdit {
+    pub request: RequestId,
+    pub pos: VoxelPos,
+    pub id: u16,
+    /// set once the server accepted the edit, the sequence of the change it made.
+    /// the edit is dropped once the chunk got that far.
+    pub confirmed: Option<u64>,
+}
+
+/// the edits the server has not answered or not sent back yet,
+/// and what the server last said each of their voxels is.
+#[derive(Default)]
+pub struct Predictor {
+    next_request: RequestId,
+    /// oldest first.
+    edits: Vec<PredictedEdit>,
+    server_values: BTreeMap<VoxelPos, u16>,
+}
+
+impl Predictor {
+    pub fn pending(&self) -> &[PredictedEdit] {
+        &self.edits
+    }
+
+    pub fn is_predicted(&self, pos: VoxelPos) -> bool {
+        self.server_values.contains_key(&pos)
+    }
+
+    /// applies the edit to `map` and returns its request id.
+    /// edits in chunks the client does not have are not predicted, only sent.
+    pub fn predict(&mut self, map: &mut VoxelMap, pos: VoxelPos, id: u16) -> RequestId {
+        self.next_request = self.next_request.wrapping_add(1);
+        let request = self.next_request;
+        let (x, y, z) = pos;
+        if !map.chunk_list.contains_key(&map.chunk_key(x, y, z)) {
+            return request;
+        }
+        // the first prediction on a voxel sees the server value, later ones see the prediction
+        self.server_values
+            .entry(pos)
+            .or_insert_with(|| map.get_voxel(x, y, z));
+        self.edits.push(PredictedEdit {
+            request,
+            pos,
+            id,
+            confirmed: None,
+        });
+        self.refresh(map, pos);
+        request
+    }
+
+    /// returns the voxel of the edit, None if it was not predicted.
+    pub fn accepted(&mut self, request: RequestId, seq: u64) -> Option<VoxelPos> {
+        let e = self.edits.iter_mut().find(|e| e.request == request)?;
+        e.confirmed = Some(seq);
+        Some(e.pos)
+    }
+
+    /// drops the edit and puts back what the voxel would be without it.
+    pub fn rejected(&mut self, map: &mut VoxelMap, request: RequestId) {
+        if let Some(i) = self.edits.iter().position(|e| e.request == request) {
+            let edit = self.edits.remove(i);
+            self.refresh(map, edit.pos);
+        }
+    }
+
+    /// call after the server wrote the voxel in `map`, the predictions on it are put back on top.
+    pub fn server_changed(&mut self, map: &mut VoxelMap, pos: VoxelPos) {
+        if let Some(v) = self.server_values.get_mut(&pos) {
+            *v = map.get_voxel(pos.0, pos.1, pos.2);
+            self.refresh(map, pos);
+        }
+    }
+
+    /// call after the whole chunk was replaced by the server.
+    pub fn chunk_replaced(&mut self, map: &mut VoxelMap, key: ChunkPos) {
+        let positions: Vec<VoxelPos> = self
+            .server_values
+            .keys()
+            .copied()
+            .filter(|p| map.chunk_key(p.0, p.1, p.2) == key)
+            .collect();
+        for pos in positions {
+            self.server_changed(map, pos);
+        }
+    }
+
+    /// call once the chunk is at `seq`, accepted edits it already contains are dropped.
+    pub fn chunk_synced(&mut self, map: &mut VoxelMap, key: ChunkPos, seq: u64) {
+        let mut done = vec![];
+        self.edits.retain(|e| {
+            let synced = map.chunk_key(e.pos.0, e.pos.1, e.pos.2) == key
+                && matches!(e.confirmed, Some(s) if s <= seq);
+            if synced {
+                done.push(e.pos);
+            }
+            !synced
+        });
+        for pos in done {
+            self.refresh(map, pos);
+        }
+    }
+
+    /// forgets every prediction in the chunk, used when the chunk is unloaded.
+    pub fn forget_chunk(&mut self, map: &VoxelMap, key: ChunkPos) {
+        let in_chunk = |p: &VoxelPos| map.chunk_key(p.0, p.1, p.2) == key;
+        self.edits.retain(|e| !in_chunk(&e.pos));
+        self.server_values.retain(|p, _| !in_chunk(p));
+    }
+
+    /// sets the voxel to the newest prediction on it, or the server value if there is none left.
+    fn refresh(&mut self, map: &mut VoxelMap, pos: VoxelPos) {
+        let server = match self.server_values.get(&pos) {
+            Some(v) => *v,
+            None => return,
+        };
+        let value = match self.edits.iter().rev().find(|e| e.pos == pos) {
+            Some(e) => e.id,
+            None => {
+                self.server_values.remove(&pos);
+                server
+            }
+        };
+        let (x, y, z) = pos;
+        if map.get_voxel(x, y, z) != value {
+            map.set_voxel(x, y, z, value);
+            map.mark_border_neighbors_dirty(x, y, z);
+        }
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::{
+        net::{
+            client::VoxelClient,
+            content::ContentManifest,
+            server::VoxelServer,
+            sim::{SimConfig, SimNetwork},
+        },
+        voxel::voxel::Chunk,
+    };
+
+    struct Sim {
+        server: VoxelServer,
+        world: VoxelMap,
+        net: SimNetwork,
+        clients: Vec<(VoxelClient, VoxelMap)>,
+    }
+
+    impl Sim {
+        fn new() -> Self {
+            let mut world = VoxelMap::new((16, 16, 16));
+            world.add_chunk(0, 0, 0, Chunk::new(16));
+            world.add_chunk(1, 0, 0, Chunk::new(16));
+            let net = SimNetwork::new(35);
+            let mut server = VoxelServer::new(Box::new(net.listener()));
+            // no player states are sent, edits are allowed anywhere
+            server.edit_rules.reach = None;
+            Self {
+                server,
+                world,
+                net,
+                clients: vec![],
+            }
+        }
+
+        /// a client with `delay` steps of latency each way that has chunks (0, 0, 0) and (1, 0, 0).
+        fn join(&mut self, delay: u32) -> usize {
+            let conn = self.net.connect(SimConfig {
+                latency: delay - 1,
+                ..SimConfig::perfect()
+            });
+            let client =
+                VoxelClient::connect(Box::new(conn), "sim", ContentManifest::default()).unwrap();
+            self.clients.push((client, VoxelMap::new((16, 16, 16))));
+            let i = self.clients.len() - 1;
+            self.run_until(|s| s.clients[i].0.client_id().is_some());
+            self.clients[i]
+                .0
+                .request_chunks(&[(0, 0, 0), (1, 0, 0)])
+                .unwrap();
+            self.run_until(|s| !s.clients[i].0.has_pending_requests());
+            i
+        }
+
+        fn step(&mut self) {
+            self.net.step();
+            self.server.update(&mut self.world);
+            for (client, map) in self.clients.iter_mut() {
+                client.update(map).unwrap();
+            }
+        }
+
+        fn run_until(&mut self, done: impl Fn(&Sim) -> bool) {
+            for _ in 0..100 {
+                self.step();
+                if done(self) {
+                    return;
+                }
+            }
+            panic!("timed out");
+        }
+
+        fn set_voxel(&mut self, client: usize, pos: VoxelPos, id: u16) -> RequestId {
+            let (client, map) = &mut self.clients[client];
+            client.set_voxel(map, pos, id).unwrap()
+        }
+
+        fn voxel(&self, client: usize, pos: VoxelPos) -> u16 {
+            self.clients[client].1.get_voxel(pos.0, pos.1, pos.2)
+        }
+    }
+
+    #[test]
+    fn edit_shows_before_the_server_confirms_it() {
+        let mut sim = Sim::new();
+        let a = sim.join(3);
+        sim.set_voxel(a, (4, 5, 6), 16);
+        assert_eq!(sim.voxel(a, (4, 5, 6)), 16);
+        assert_eq!(sim.clients[a].0.predicted().len(), 1);
+
+        sim.step();
+        // still on its way to the server
+        assert_eq!(sim.world.get_voxel(4, 5, 6), 0);
+        assert_eq!(sim.voxel(a, (4, 5, 6)), 16);
+
+        sim.run_until(|s| s.clients[a].0.predicted().is_empty());
+        assert_eq!(sim.world.get_voxel(4, 5, 6), 16);
+        assert_eq!(sim.voxel(a, (4, 5, 6)), 16);
+    }
+
+    #[test]
+    fn rejected_edit_rolls_back_to_the_server_value() {
+        let mut sim = Sim::new();
+        let slow = sim.join(4);
+        let fast = sim.join(1);
+
+        // both edit the same voxel, the fast client gets there first
+        sim.set_voxel(slow, (15, 0, 0), 2);
+        sim.set_voxel(fast, (15, 0, 0), 3);
+        assert_eq!(sim.voxel(slow, (15, 0, 0)), 2);
+
+        sim.run_until(|s| s.clients.iter().all(|(c, _)| c.predicted().is_empty()));
+        assert_eq!(sim.world.get_voxel(15, 0, 0), 3);
+        assert_eq!(sim.voxel(slow, (15, 0, 0)), 3);
+        assert_eq!(sim.voxel(fast, (15, 0, 0)), 3);
+        // the voxel is on the border, the neighbor chunk was remeshed with the rollback
+        assert!(sim.clients[slow].1.chunk_list[&(1, 0, 0)].is_dirty());
+    }
+
+    #[test]
+    fn server_changes_stay_under_a_pending_prediction() {
+        let mut sim = Sim::new();
+        let a = sim.join(3);
+        let request = sim.set_voxel(a, (1, 1, 1), 16);
+
+        // the server changes the voxel before the edit arrives, so the edit gets rejected
+        sim.world.set_voxel(1, 1, 1, 5);
+        for _ in 0..4 {
+            sim.step();
+        }
+        // the server change arrived but the answer to the edit did not
+        assert_eq!(sim.clients[a].0.chunk_seq((0, 0, 0)), Some(1));
+        assert_eq!(sim.voxel(a, (1, 1, 1)), 16);
+        assert!(sim.clients[a]
+            .0
+            .predicted()
+            .iter()
+            .any(|e| e.request == request));
+
+        sim.run_until(|s| s.clients[a].0.predicted().is_empty());
+        assert_eq!(sim.voxel(a, (1, 1, 1)), 5);
+    }
+
+    #[test]
+    fn edits_on_the_same_voxel_stack() {
+        let mut sim = Sim::new();
+        let a = sim.join(2);
+        sim.set_voxel(a, (7, 7, 7), 1);
+        sim.set_voxel(a, (7, 7, 7), 2);
+        assert_eq!(sim.voxel(a, (7, 7, 7)), 2);
+
+        sim.run_until(|s| s.clients[a].0.predicted().is_empty());
+        assert_eq!(sim.world.get_voxel(7, 7, 7), 2);
+        assert_eq!(sim.voxel(a, (7, 7, 7)), 2);
+    }
+}
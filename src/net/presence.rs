@@ -0,0 +1,300 @@
+// remote players, the server sends where every player is each update and clients play that back
+// a little in the past so there is always a snapshot on both sides of the time shown.
+
+use std::collections::VecDeque;
+
+use super::protocol::{ClientId, PlayerState};
+
+/// how far behind the newest snapshot remote players are shown, in server ticks.
+/// a snapshot can arrive this late without the motion stopping.
+pub const INTERPOLATION_DELAY_TICKS: f32 = 2.0;
+
+/// snapshots more then this far from where playback should be make it jump instead of catching up.
+const MAX_DRIFT_TICKS: f64 = 10.0;
+
+#[derive(Debug, Clone)]
+struct Snapshot {
+    tick: u64,
+    players: Vec<(ClientId, PlayerState)>,
+}
+
+/// the jitter buffer, snapshots are kept in tick order no matter when they arrive.
+pub struct SnapshotBuffer {
+    tick_rate: f32,
+    delay_ticks: f32,
+    snapshots: VecDeque<Snapshot>,
+    /// the server tick being shown, None until the first snapshot.
+    playback: Option<f64>,
+}
+
+impl SnapshotBuffer {
+    pub fn new(tick_rate: u32, delay_ticks: f32) -> Self {
+        Self {
+            tick_rate: tick_rate as f32,
+            delay_ticks,
+            snapshots: VecDeque::new(),
+            playback: None,
+        }
+    }
+
+    pub fn playback_tick(&self) -> Option<f64> {
+        self.playback
+    }
+
+    /// snapshots older then the one being shown are dropped, so are duplicates.
+    pub fn push(&mut self, tick: u64, players: Vec<(ClientId, PlayerState)>) {
+        if let (Some(p), Some(first)) = (self.playback, self.snapshots.front()) {
+            if (tick as f64) < p && tick < first.tick {
+                return;
+            }
+        }
+        let i = self.snapshots.partition_point(|s| s.tick < tick);
+        if matches!(self.snapshots.get(i), Some(s) if s.tick == tick) {
+            return;
+        }
+        self.snapshots.insert(i, Snapshot { tick, players });
+    }
+
+    /// moves playback forward by `dt` seconds.
+    /// playback runs a little faster or slower to stay `delay_ticks` behind the newest snapshot.
+    pub fn advance(&mut self, dt: f32) {
+        let newest = match self.snapshots.back() {
+            Some(s) => s.tick as f64,
+            None => return,
+        };
+        let target = newest - self.delay_ticks as f64;
+        let playback = match self.playback {
+            Some(p) if (target - p).abs() <= MAX_DRIFT_TICKS => {
+                let speed = 1.0 + ((target - p) * 0.1).clamp(-0.1, 0.1);
+                p + dt as f64 * self.tick_rate as f64 * speed
+            }
+            _ => target,
+        };
+        self.playback = Some(playback);
+
+        // keep the last snapshot at or before playback, it is the start of the interpolation
+        while self.snapshots.len() > 1 && self.snapshots[1].tick as f64 <= playback {
+            self.snapshots.pop_front();
+        }
+    }
+
+    /// every player at the playback tick.
+    /// players that only show up in the later snapshot are placed there, ones that left are gone.
+    pub fn sample(&self) -> Vec<(ClientId, PlayerState)> {
+        let playback = match self.playback {
+            Some(p) => p,
+            None => return vec![],
+        };
+        let a = match self.snapshots.front() {
+            Some(s) => s,
+            None => return vec![],
+        };
+        let b = match self.snapshots.get(1) {
+            Some(b) if a.tick as f64 <= playback => b,
+            // ran out of snapshots, hold the last one
+            _ => return a.players.clone(),
+        };
+        let t = ((playback - a.tick as f64) / (b.tick - a.tick) as f64).clamp(0.0, 1.0) as f32;
+        b.players
+            .iter()
+            .map(|(id, to)| {
+                let state = match a.players.iter().find(|(a_id, _)| a_id == id) {
+                    Some((_, from)) => lerp_state(from, to, t),
+                    None => *to,
+                };
+                (*id, state)
+            })
+            .collect()
+    }
+}
+
+pub fn lerp_state(a: &PlayerState, b: &PlayerState, t: f32) -> PlayerState {
+    let lerp = |a: f32, b: f32| a + (b - a) * t;
+    // the shorter way around
+    let (ax, ay, az, aw) = a.rotation;
+    let mut br = b.rotation;
+    if ax * br.0 + ay * br.1 + az * br.2 + aw * br.3 < 0.0 {
+        br = (-br.0, -br.1, -br.2, -br.3);
+    }
+    let r = (
+        lerp(ax, br.0),
+        lerp(ay, br.1),
+        lerp(az, br.2),
+        lerp(aw, br.3),
+    );
+    let len = (r.0 * r.0 + r.1 * r.1 + r.2 * r.2 + r.3 * r.3).sqrt();
+    let rotation = if len > 0.0 {
+        (r.0 / len, r.1 / len, r.2 / len, r.3 / len)
+    } else {
+        b.rotation
+    };
+    PlayerState {
+        pos: (
+            lerp(a.pos.0, b.pos.0),
+            lerp(a.pos.1, b.pos.1),
+            lerp(a.pos.2, b.pos.2),
+        ),
+        rotation,
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+    use crate::{
+        net::{
+            client::VoxelClient,
+            content::ContentManifest,
+            server::VoxelServer,
+            sim::{SimConfig, SimNetwork},
+        },
+        voxel::voxel::{Chunk, VoxelMap},
+    };
+
+    fn at_x(x: f32) -> Vec<(ClientId, PlayerState)> {
+        vec![(7, PlayerState::at((x, 0.0, 0.0)))]
+    }
+
+    #[test]
+    fn interpolates_between_snapshots() {
+        let mut buffer = SnapshotBuffer::new(10, 2.0);
+        for tick in 0..=4 {
+            buffer.push(tick, at_x(tick as f32 * 10.0));
+        }
+        buffer.advance(0.0);
+        assert_eq!(buffer.playback_tick(), Some(2.0));
+        assert_eq!(buffer.sample()[0].1.pos.0, 20.0);
+
+        // a quarter of a tick
+        buffer.advance(0.025);
+        let x = buffer.sample()[0].1.pos.0;
+        assert!(x > 20.0 && x < 23.0, "{}", x);
+    }
+
+    #[test]
+    fn late_and_out_of_order_snapshots_are_sorted() {
+        let mut buffer = SnapshotBuffer::new(10, 2.0);
+        buffer.push(0, at_x(0.0));
+        buffer.push(3, at_x(30.0));
+        buffer.push(1, at_x(10.0));
+        buffer.push(1, at_x(999.0));
+        buffer.push(2, at_x(20.0));
+        buffer.advance(0.0);
+        assert_eq!(buffer.sample()[0].1.pos.0, 10.0);
+        buffer.advance(0.05);
+        let x = buffer.sample()[0].1.pos.0;
+        assert!(x > 14.0 && x < 16.0, "{}", x);
+    }
+
+    #[test]
+    fn rotation_takes_the_short_way() {
+        let a = PlayerState::at((0.0, 0.0, 0.0));
+        let b = PlayerState {
+            pos: (0.0, 0.0, 0.0),
+            rotation: (0.0, 0.0, 0.0, -1.0),
+        };
+        // the same rotation, so halfway is still the identity
+        let r = lerp_state(&a, &b, 0.5).rotation;
+        assert!((r.3.abs() - 1.0).abs() < 1e-5);
+    }
+
+    /// a walking player seen by a second client over a network with latency, jitter and duplicates.
+    /// reordering past the interpolation delay would stall it, that is left to the buffer tests above.
+    #[test]
+    fn loopback_remote_player_moves_smoothly() {
+        let mut world = VoxelMap::new((16, 16, 16));
+        world.add_chunk(0, 0, 0, Chunk::new(16));
+        // a network step is one frame of the watcher
+        let net = SimNetwork::new(36);
+        let mut server = VoxelServer::new(Box::new(net.listener()));
+        server.tick_rate = 20;
+
+        let mut rng = StdRng::seed_from_u64(36);
+        let mut walker = VoxelClient::connect(
+            Box::new(net.connect(SimConfig {
+                latency: 1,
+                ..SimConfig::perfect()
+            })),
+            "walker",
+            ContentManifest::default(),
+        )
+        .unwrap();
+        let mut watcher = VoxelClient::connect(
+            Box::new(net.connect(SimConfig {
+                latency: 2,
+                jitter: 3,
+                duplicate: 0.1,
+                ..SimConfig::perfect()
+            })),
+            "watcher",
+            ContentManifest::default(),
+        )
+        .unwrap();
+        let (mut walker_map, mut watcher_map) =
+            (VoxelMap::new((16, 16, 16)), VoxelMap::new((16, 16, 16)));
+
+        let walker_id = {
+            let mut id = None;
+            for _ in 0..20 {
+                net.step();
+                server.update(&mut world);
+                walker.update(&mut walker_map).unwrap();
+                watcher.update(&mut watcher_map).unwrap();
+                id = walker.client_id().filter(|_| watcher.client_id().is_some());
+                if id.is_some() {
+                    break;
+                }
+            }
+            id.unwrap()
+        };
+
+        // the walker moves half a unit per tick along x and stays in view, the watcher renders at 60 fps
+        let mut shown = vec![];
+        for tick in 0..120 {
+            let x = tick as f32 * 0.5;
+            walker
+                .send_player_state(PlayerState::at((x, 2.0, 3.0)))
+                .unwrap();
+            watcher
+                .send_player_state(PlayerState::at((0.0, 0.0, 0.0)))
+                .unwrap();
+            server.update(&mut world);
+            walker.update(&mut walker_map).unwrap();
+            // frames between server ticks, with some timing noise
+            for _ in 0..3 {
+                net.step();
+                watcher.update(&mut watcher_map).unwrap();
+                watcher
+                    .players_mut()
+                    .advance(1.0 / 60.0 + rng.gen_range(-0.002..0.002));
+                if let Some((_, s)) = watcher
+                    .players()
+                    .sample()
+                    .iter()
+                    .find(|(id, _)| *id == walker_id)
+                {
+                    shown.push(s.pos);
+                }
+            }
+        }
+
+        assert!(shown.len() > 200);
+        // the watcher never sees itself
+        assert!(watcher
+            .players()
+            .sample()
+            .iter()
+            .all(|(id, _)| Some(*id) != watcher.client_id()));
+        // positions are on the path and never go backwards
+        assert!(shown.iter().all(|p| p.1 == 2.0 && p.2 == 3.0));
+        assert!(shown.windows(2).all(|w| w[1].0 >= w[0].0));
+        // once playing every frame moves about half a unit per 3 frames, no stalls or jumps
+        let steps: Vec<f32> = shown.windows(2).skip(30).map(|w| w[1].0 - w[0].0).collect();
+        assert!(steps.iter().all(|s| *s > 0.0 && *s < 0.5), "{:?}", steps);
+        // the delay stays about what the buffer is set to plus the latency
+        let last = shown.last().unwrap().0;
+        assert!(last > 59.5 - 3.0 && last < 59.5, "{}", last);
+    }
+}
@@ -0,0 +1,381 @@
+// messages sent between the server and clients, every message is bincode encoded and framed by the transport.
+
+use serde::{Deserialize, Serialize};
+
+use super::{transport::Channel, NetError};
+use std::collections::BTreeMap;
+
+use crate::voxel::{
+    layers::Volume,
+    voxel::{decode_runs, encode_runs, Chunk, VoxelChange},
+};
+
+/// bumped every time a message changes, clients with a different version are refused.
+pub const PROTOCOL_VERSION: u32 = 7;
+
+pub type ClientId = u32;
+pub type ChunkPos = (i32, i32, i32);
+pub type VoxelPos = (i32, i32, i32);
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ClientMessage {
+    /// first message of every connection.
+    Hello {
+        protocol_version: u32,
+        name: String,
+        /// ContentManifest::hash of the client.
+        content_hash: u64,
+    },
+    RequestChunks {
+        keys: Vec<ChunkPos>,
+    },
+    /// the server decides if the edit happens and answers with EditAccepted or EditRejected.
+    SetVoxel {
+        /// picked by the client, echoed back in the answer.
+        request: u32,
+        pos: VoxelPos,
+        id: u16,
+        /// the id the client saw, the edit is rejected if the voxel changed since.
+        expected: u16,
+    },
+    /// sent at a fixed rate, the server streams the chunks around the player and shows it to the others.
+    PlayerState(PlayerState),
+    Disconnect,
+}
+
+/// the protocol version of an encoded hello, also of versions with other fields.
+/// bincode writes the variant index and then the fields in order, hello is variant 0 and starts with the version.
+pub fn hello_version(bytes: &[u8]) -> Option<u32> {
+    let (variant, version): (u32, u32) = bincode::deserialize(bytes).ok()?;
+    (variant == 0).then_some(version)
+}
+
+impl ClientMessage {
+    /// player states are sent often enough that a lost one does not matter.
+    pub fn channel(&self) -> Channel {
+        match self {
+            ClientMessage::PlayerState(_) => Channel::Unreliable,
+            _ => Channel::ReliableOrdered,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ServerMessage {
+    Welcome {
+        client_id: ClientId,
+        chunk_size: (i32, i32, i32),
+        /// server updates per second, every update sends a Players snapshot.
+        tick_rate: u32,
+        /// ContentManifest::hash of the server, if it is not the one of the client its ids are remapped with `blocks`.
+        content_hash: u64,
+        /// block names of the server in id order, the first is id 1.
+        blocks: Vec<String>,
+    },
+    Refused {
+        reason: String,
+    },
+    ChunkData(ChunkPayload),
+    /// the server has no chunk at this key.
+    ChunkUnavailable {
+        key: ChunkPos,
+    },
+    ChunkDelta(ChunkDelta),
+    /// the change comes with the delta of sequence `seq`, 0 if the edit changed nothing.
+    EditAccepted {
+        request: u32,
+        seq: u64,
+    },
+    EditRejected {
+        request: u32,
+        reason: String,
+    },
+    /// every other player near the client at server update `tick`.
+    Players {
+        tick: u64,
+        players: Vec<(ClientId, PlayerState)>,
+    },
+    /// the chunks left the view radius of the client and will not get edits anymore.
+    UnloadChunks {
+        keys: Vec<ChunkPos>,
+    },
+}
+
+impl ServerMessage {
+    /// chunks have to arrive in order with their deltas and unloads, edit answers do not depend on each other
+    /// and a lost player snapshot is covered by the next one.
+    pub fn channel(&self) -> Channel {
+        match self {
+            ServerMessage::Players { .. } => Channel::Unreliable,
+            ServerMessage::EditAccepted { .. } | ServerMessage::EditRejected { .. } => {
+                Channel::ReliableUnordered
+            }
+            _ => Channel::ReliableOrdered,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct PlayerState {
+    /// world space.
+    pub pos: (f32, f32, f32),
+    /// quaternion, (x, y, z, w).
+    pub rotation: (f32, f32, f32, f32),
+}
+
+impl PlayerState {
+    pub fn at(pos: (f32, f32, f32)) -> Self {
+        Self {
+            pos,
+            rotation: (0.0, 0.0, 0.0, 1.0),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ChunkPayload {
+    pub key: ChunkPos,
+    /// edit sequence of the chunk when it was sent, deltas up to it are already in the voxels.
+    pub seq: u64,
+    pub voxels: ChunkVoxels,
+    /// run length encoded fluid levels in volume index order like the saved chunk, empty when
+    /// the chunk has no volume. 0 is no level, fluid without one is full.
+    pub fluid: Vec<(u16, u16)>,
+}
+
+/// every change made to one chunk in one server update.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ChunkDelta {
+    pub key: ChunkPos,
+    /// sequence the chunk has to be at for this delta to apply.
+    pub base_seq: u64,
+    /// sequence of the last change in the delta, the chunk is at this sequence once applied.
+    pub seq: u64,
+    /// (local voxel index, new id, fluid level), the index is x + size * (y + size * z).
+    pub changes: Vec<(u16, u16, u8)>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ChunkVoxels {
+    /// the chunk has no volume, all air.
+    Empty,
+    /// run length encoded voxel ids in volume index order, (run length, id).
+    Runs(Vec<(u16, u16)>),
+}
+
+impl ChunkPayload {
+    pub fn from_chunk(key: ChunkPos, chunk: &Chunk) -> Self {
+        let (voxels, fluid) = match &chunk.volume {
+            None => (ChunkVoxels::Empty, vec![]),
+            Some(v) => {
+                let levels: Vec<u16> = v.fluid_level.layer.iter().map(|l| *l as u16).collect();
+                (
+                    ChunkVoxels::Runs(encode_runs(&v.type_id.layer)),
+                    encode_runs(&levels),
+                )
+            }
+        };
+        Self {
+            key,
+            seq: 0,
+            voxels,
+            fluid,
+        }
+    }
+
+    /// replaces every voxel id with `f(id)`, used to turn server ids into local ones.
+    pub fn map_ids(&mut self, f: impl Fn(u16) -> u16) {
+        if let ChunkVoxels::Runs(runs) = &mut self.voxels {
+            for run in runs.iter_mut() {
+                run.1 = f(run.1);
+            }
+        }
+    }
+
+    /// builds a chunk of `size`, the chunk is marked dirty so it gets meshed.
+    pub fn to_chunk(&self, size: i32) -> Result<Chunk, NetError> {
+        let mut chunk = Chunk::new(size);
+        if let ChunkVoxels::Runs(runs) = &self.voxels {
+            let len = (size * size * size) as usize;
+            let layer = decode_runs(runs);
+            if layer.len() != len {
+                return Err(NetError::BadChunk(self.key));
+            }
+            let levels = decode_runs(&self.fluid);
+            if levels.len() != len {
+                return Err(NetError::BadChunk(self.key));
+            }
+            let mut volume = Volume::new(len);
+            volume.type_id.layer = layer;
+            volume.fluid_level.layer = levels.iter().map(|l| *l as u8).collect();
+            chunk.volume = Some(volume);
+        }
+        chunk.set_is_dirty(true);
+        Ok(chunk)
+    }
+}
+
+impl ChunkDelta {
+    /// `changes` are the journal entries of this chunk in sequence order,
+    /// a voxel changed more then once only keeps its last id.
+    pub fn from_changes(
+        key: ChunkPos,
+        base_seq: u64,
+        changes: &[VoxelChange],
+        chunk_size: (i32, i32, i32),
+    ) -> Self {
+        let (sx, sy, sz) = chunk_size;
+        let mut last: BTreeMap<u16, (u16, u8)> = BTreeMap::new();
+        for c in changes {
+            let (x, y, z) = (
+                c.pos.0.rem_euclid(sx),
+                c.pos.1.rem_euclid(sy),
+                c.pos.2.rem_euclid(sz),
+            );
+            last.insert((x + sx * (y + sy * z)) as u16, (c.new, c.level));
+        }
+        Self {
+            key,
+            base_seq,
+            seq: changes.last().map(|c| c.seq).unwrap_or(base_seq),
+            changes: last
+                .into_iter()
+                .map(|(i, (id, level))| (i, id, level))
+                .collect(),
+        }
+    }
+
+    /// the world position, new id and fluid level of every change.
+    pub fn world_changes(
+        &self,
+        chunk_size: (i32, i32, i32),
+    ) -> impl Iterator<Item = (VoxelPos, u16, u8)> + '_ {
+        let (sx, sy, sz) = chunk_size;
+        let key = self.key;
+        self.changes.iter().map(move |(i, id, level)| {
+            let i = *i as i32;
+            (
+                (
+                    key.0 * sx + i % sx,
+                    key.1 * sy + (i / sx) % sy,
+                    key.2 * sz + i / (sx * sy),
+                ),
+                *id,
+                *level,
+            )
+        })
+    }
+}
+
+pub fn encode<T: Serialize>(msg: &T) -> Result<Vec<u8>, NetError> {
+    Ok(bincode::serialize(msg)?)
+}
+
+pub fn decode<'a, T: Deserialize<'a>>(bytes: &'a [u8]) -> Result<T, NetError> {
+    Ok(bincode::deserialize(bytes)?)
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::voxel::{block::WATER, fluid::MAX_FLUID_LEVEL, voxel::VoxelMap};
+
+    #[test]
+    fn runs_round_trip() {
+        let ids = vec![0, 0, 0, 5, 5, 1, 0, 0];
+        let runs = encode_runs(&ids);
+        assert_eq!(runs, vec![(3, 0), (2, 5), (1, 1), (2, 0)]);
+        assert_eq!(decode_runs(&runs), ids);
+    }
+
+    #[test]
+    fn chunk_payload_round_trip() {
+        let mut chunk = Chunk::new(16);
+        chunk.set_voxel(1, 2, 3, 16, 7);
+        chunk.set_voxel(15, 15, 15, 16, 16);
+        let payload = ChunkPayload::from_chunk((1, -2, 3), &chunk);
+
+        let bytes = encode(&ServerMessage::ChunkData(payload.clone())).unwrap();
+        let back = match decode::<ServerMessage>(&bytes).unwrap() {
+            ServerMessage::ChunkData(p) => p,
+            m => panic!("wrong message {:?}", m),
+        };
+        assert_eq!(back, payload);
+
+        let out = back.to_chunk(16).unwrap();
+        assert_eq!(out.get_voxel(1, 2, 3, 16), 7);
+        assert_eq!(out.get_voxel(15, 15, 15, 16), 16);
+        assert_eq!(out.get_voxel(0, 0, 0, 16), 0);
+        assert!(out.is_dirty());
+    }
+
+    #[test]
+    fn delta_keeps_last_change_per_voxel() {
+        let change = |pos, new, seq| VoxelChange {
+            pos,
+            old: 0,
+            new,
+            level: 0,
+            seq,
+        };
+        let delta = ChunkDelta::from_changes(
+            (-1, 0, 2),
+            4,
+            &[
+                change((-16, 3, 47), 7, 5),
+                change((-1, 15, 32), 2, 6),
+                change((-16, 3, 47), 9, 8),
+            ],
+            (16, 16, 16),
+        );
+        assert_eq!(delta.base_seq, 4);
+        assert_eq!(delta.seq, 8);
+        assert_eq!(delta.changes.len(), 2);
+        let mut world: Vec<(VoxelPos, u16, u8)> = delta.world_changes((16, 16, 16)).collect();
+        world.sort();
+        assert_eq!(world, vec![((-16, 3, 47), 9, 0), ((-1, 15, 32), 2, 0)]);
+    }
+
+    #[test]
+    fn fluid_levels_round_trip() {
+        let mut map = VoxelMap::new((16, 16, 16));
+        map.add_chunk(0, 0, 0, Chunk::new(16));
+        map.enable_journal();
+        map.set_fluid(2, 2, 2, WATER, 3);
+        map.set_voxel(3, 2, 2, WATER);
+        // only the level changes
+        map.set_fluid(2, 2, 2, WATER, 5);
+
+        let payload = ChunkPayload::from_chunk((0, 0, 0), &map.chunk_list[&(0, 0, 0)]);
+        let bytes = encode(&ServerMessage::ChunkData(payload)).unwrap();
+        let chunk = match decode::<ServerMessage>(&bytes).unwrap() {
+            ServerMessage::ChunkData(p) => p.to_chunk(16).unwrap(),
+            m => panic!("wrong message {:?}", m),
+        };
+        assert_eq!(chunk.get_fluid_level(2, 2, 2, 16), 5);
+        assert_eq!(chunk.get_fluid_level(3, 2, 2, 16), 0);
+
+        let changes = map.drain_journal();
+        assert_eq!(changes.len(), 3);
+        let delta = ChunkDelta::from_changes((0, 0, 0), 0, &changes, (16, 16, 16));
+        let bytes = encode(&ServerMessage::ChunkDelta(delta)).unwrap();
+        let delta = match decode::<ServerMessage>(&bytes).unwrap() {
+            ServerMessage::ChunkDelta(d) => d,
+            m => panic!("wrong message {:?}", m),
+        };
+        let mut client = VoxelMap::new((16, 16, 16));
+        client.add_chunk(0, 0, 0, Chunk::new(16));
+        for ((x, y, z), id, level) in delta.world_changes((16, 16, 16)) {
+            client.set_voxel_with_level(x, y, z, id, level);
+        }
+        assert_eq!(client.get_fluid_level(2, 2, 2), 5);
+        assert_eq!(client.get_fluid_level(3, 2, 2), MAX_FLUID_LEVEL);
+    }
+
+    #[test]
+    fn empty_chunk_stays_empty() {
+        let payload = ChunkPayload::from_chunk((0, 0, 0), &Chunk::new(16));
+        assert_eq!(payload.voxels, ChunkVoxels::Empty);
+        assert!(payload.to_chunk(16).unwrap().volume.is_none());
+    }
+}
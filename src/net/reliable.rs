@@ -0,0 +1,843 @@
+// reliability on top of a connection that can lose, duplicate and reorder messages, like udp or the sim.
+// every message goes out on a channel and is split in fragments that fit in one packet,
+// packets ack the ones received so far and reliable fragments that were not acked in time are sent again.
+// how much is in flight is limited by a congestion window that grows while packets get through
+// and halves when they get lost.
+//
+// time is counted in updates, one update is one `receive` call, so rtt and timeouts are in the
+// update rate of each side and the sim stays deterministic.
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    transport::{Channel, Connection, Listener},
+    NetError,
+};
+
+/// the first bytes of every packet, packets without it are ignored.
+pub const PACKET_MAGIC: u32 = 0x564f_584e;
+
+/// packets are kept under this so they are not split on the way, the same as quic and enet use.
+pub const MAX_PACKET_SIZE: usize = 1200;
+
+/// message bytes per fragment, leaves room for the headers.
+pub const FRAGMENT_SIZE: usize = 1024;
+
+/// messages bigger then this are refused, the other end keeps room for a message this big.
+pub const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+/// fragments of the biggest message, fragments of more are dropped.
+const MAX_FRAGMENTS: usize = MAX_MESSAGE_SIZE.div_ceil(FRAGMENT_SIZE);
+
+/// the peer is gone if nothing arrived for this many updates.
+pub const TIMEOUT_UPDATES: u64 = 600;
+
+/// an empty packet is sent after this many updates without sending, so the peer does not time out.
+const KEEPALIVE_UPDATES: u64 = 10;
+
+/// resend timeout before there is any rtt sample, and its bounds.
+const INITIAL_RTO: u64 = 10;
+const MIN_RTO: u64 = 2;
+const MAX_RTO: u64 = 60;
+
+/// a packet counts as lost once this many packets sent after it were acked.
+const REORDER_THRESHOLD: u32 = 3;
+
+/// bounds of the congestion window in bytes.
+const MIN_WINDOW: usize = 4 * MAX_PACKET_SIZE;
+const INITIAL_WINDOW: usize = 16 * MAX_PACKET_SIZE;
+const MAX_WINDOW: usize = 1024 * 1024;
+
+/// unreliable messages that are only partly here are dropped once this many newer ones started arriving.
+const MAX_PARTIAL_UNRELIABLE: usize = 8;
+
+/// reliable messages this far past the first one not delivered yet are refused, and so are new
+/// reliable messages while this many are partly here. the packet they came in is not acked, so the
+/// sender sends them again, a peer can not make the other end hold more then that.
+const MESSAGE_WINDOW: u32 = 1024;
+const MAX_PARTIAL_RELIABLE: usize = 64;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Fragment {
+    channel: Channel,
+    /// counted per channel.
+    message: u32,
+    index: u16,
+    count: u16,
+    data: Vec<u8>,
+}
+
+impl Fragment {
+    /// bytes it adds to an encoded packet.
+    fn encoded_size(&self) -> usize {
+        4 + 4 + 2 + 2 + 8 + self.data.len()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Packet {
+    magic: u32,
+    seq: u32,
+    /// newest packet received from the peer, and the 32 before it as bits.
+    ack: u32,
+    ack_bits: u32,
+    fragments: Vec<Fragment>,
+}
+
+/// encoded size of a packet without fragments.
+const PACKET_HEADER_SIZE: usize = 4 + 4 + 4 + 4 + 8;
+
+struct SentPacket {
+    update: u64,
+    size: usize,
+    /// the reliable fragments in it, sent again if the packet is lost.
+    reliable: Vec<Fragment>,
+}
+
+/// a message that is not all here yet.
+struct Partial {
+    parts: Vec<Option<Vec<u8>>>,
+    missing: usize,
+}
+
+/// how the link is doing, for tests and debug output.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct LinkStats {
+    /// smoothed round trip in updates, None before the first ack.
+    pub rtt: Option<f32>,
+    pub window: usize,
+    pub in_flight: usize,
+    pub packets_sent: u64,
+    pub packets_lost: u64,
+    /// reliable fragments sent again.
+    pub resent: u64,
+    /// fragments from the peer that were not taken, see `MESSAGE_WINDOW`.
+    pub refused: u64,
+}
+
+/// makes any message connection reliable where the channel asks for it, see the top of the file.
+pub struct ReliableConnection {
+    inner: Box<dyn Connection>,
+    update: u64,
+
+    // sending
+    next_seq: u32,
+    next_message: [u32; 3],
+    /// lost reliable fragments, sent before anything new.
+    resend: VecDeque<Fragment>,
+    /// sent ahead of reliable data, see `pump`.
+    unreliable: VecDeque<Fragment>,
+    reliable: VecDeque<Fragment>,
+    in_flight: BTreeMap<u32, SentPacket>,
+    bytes_in_flight: usize,
+    window: usize,
+    slow_start_until: usize,
+    /// losses of packets before this one are from the same congestion event.
+    recovery_seq: u32,
+    srtt: Option<f32>,
+    rttvar: f32,
+    rto: u64,
+    last_sent: u64,
+
+    // receiving
+    ack: Option<u32>,
+    ack_bits: u32,
+    /// something that has to be acked arrived since the last packet sent.
+    ack_pending: bool,
+    last_received: u64,
+    partial: BTreeMap<(Channel, u32), Partial>,
+    next_ordered: u32,
+    ordered: BTreeMap<u32, Vec<u8>>,
+    /// every unordered message below this was delivered, and the ones in `unordered_done`.
+    unordered_below: u32,
+    unordered_done: BTreeSet<u32>,
+    newest_unreliable: Option<u32>,
+
+    stats: LinkStats,
+}
+
+impl ReliableConnection {
+    pub fn new(inner: Box<dyn Connection>) -> Self {
+        Self {
+            inner,
+            update: 0,
+            next_seq: 0,
+            next_message: [0; 3],
+            resend: VecDeque::new(),
+            unreliable: VecDeque::new(),
+            reliable: VecDeque::new(),
+            in_flight: BTreeMap::new(),
+            bytes_in_flight: 0,
+            window: INITIAL_WINDOW,
+            slow_start_until: MAX_WINDOW,
+            recovery_seq: 0,
+            srtt: None,
+            rttvar: 0.0,
+            rto: INITIAL_RTO,
+            last_sent: 0,
+            ack: None,
+            ack_bits: 0,
+            ack_pending: false,
+            last_received: 0,
+            partial: BTreeMap::new(),
+            next_ordered: 0,
+            ordered: BTreeMap::new(),
+            unordered_below: 0,
+            unordered_done: BTreeSet::new(),
+            newest_unreliable: None,
+            stats: LinkStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> LinkStats {
+        LinkStats {
+            rtt: self.srtt,
+            window: self.window,
+            in_flight: self.bytes_in_flight,
+            ..self.stats
+        }
+    }
+
+    /// sends packets while the congestion window has room.
+    /// unreliable messages go out even when it is full, holding them back would only make them late,
+    /// so player movement keeps flowing while chunks are streamed.
+    fn pump(&mut self) -> Result<(), NetError> {
+        loop {
+            let window_full = self.bytes_in_flight + MAX_PACKET_SIZE > self.window;
+            if window_full && self.unreliable.is_empty() {
+                return Ok(());
+            }
+            let mut fragments = vec![];
+            let mut size = PACKET_HEADER_SIZE;
+            let queues = if window_full {
+                vec![&mut self.unreliable]
+            } else {
+                vec![&mut self.unreliable, &mut self.resend, &mut self.reliable]
+            };
+            for queue in queues {
+                while let Some(f) = queue.front() {
+                    if size + f.encoded_size() > MAX_PACKET_SIZE {
+                        break;
+                    }
+                    size += f.encoded_size();
+                    fragments.push(queue.pop_front().unwrap());
+                }
+            }
+            if fragments.is_empty() {
+                return Ok(());
+            }
+            self.send_packet(fragments)?;
+        }
+    }
+
+    fn send_packet(&mut self, fragments: Vec<Fragment>) -> Result<(), NetError> {
+        let seq = self.next_seq;
+        self.next_seq = self.next_seq.wrapping_add(1);
+        let packet = Packet {
+            magic: PACKET_MAGIC,
+            seq,
+            ack: self.ack.unwrap_or(u32::MAX),
+            ack_bits: self.ack_bits,
+            fragments,
+        };
+        let bytes = bincode::serialize(&packet)?;
+        // empty packets only carry acks and are not tracked
+        if !packet.fragments.is_empty() {
+            let reliable = packet
+                .fragments
+                .into_iter()
+                .filter(|f| f.channel != Channel::Unreliable)
+                .collect();
+            self.in_flight.insert(
+                seq,
+                SentPacket {
+                    update: self.update,
+                    size: bytes.len(),
+                    reliable,
+                },
+            );
+            self.bytes_in_flight += bytes.len();
+        }
+        self.stats.packets_sent += 1;
+        self.ack_pending = false;
+        self.last_sent = self.update;
+        self.inner.send(&bytes)
+    }
+
+    fn acked(&mut self, seq: u32) {
+        let sent = match self.in_flight.remove(&seq) {
+            Some(s) => s,
+            None => return,
+        };
+        self.bytes_in_flight -= sent.size;
+
+        let sample = (self.update - sent.update) as f32;
+        match self.srtt {
+            None => {
+                self.srtt = Some(sample);
+                self.rttvar = sample / 2.0;
+            }
+            Some(srtt) => {
+                self.rttvar = 0.75 * self.rttvar + 0.25 * (srtt - sample).abs();
+                self.srtt = Some(0.875 * srtt + 0.125 * sample);
+            }
+        }
+        let rto = self.srtt.unwrap() + 4.0 * self.rttvar + 1.0;
+        self.rto = (rto.ceil() as u64).clamp(MIN_RTO, MAX_RTO);
+
+        // slow start doubles the window every round trip, after that it grows a packet per round trip
+        if self.window < self.slow_start_until {
+            self.window += sent.size;
+        } else {
+            self.window += MAX_PACKET_SIZE * sent.size / self.window;
+        }
+        self.window = self.window.min(MAX_WINDOW);
+    }
+
+    /// packets that took too long or were passed by enough acked ones are lost, their reliable fragments go out again.
+    fn detect_loss(&mut self, newest_acked: Option<u32>) {
+        let lost: Vec<u32> = self
+            .in_flight
+            .iter()
+            .filter(|(seq, p)| {
+                self.update - p.update > self.rto
+                    || matches!(newest_acked, Some(n) if n >= **seq + REORDER_THRESHOLD)
+            })
+            .map(|(seq, _)| *seq)
+            .collect();
+        let timed_out = lost
+            .iter()
+            .any(|seq| self.update - self.in_flight[seq].update > self.rto);
+        for seq in lost.iter().rev() {
+            let sent = self.in_flight.remove(seq).unwrap();
+            self.bytes_in_flight -= sent.size;
+            self.stats.packets_lost += 1;
+            self.stats.resent += sent.reliable.len() as u64;
+            for f in sent.reliable.into_iter().rev() {
+                self.resend.push_front(f);
+            }
+        }
+        // one back off per round trip no matter how many packets of it were lost
+        if let Some(first) = lost.first() {
+            if *first >= self.recovery_seq {
+                self.recovery_seq = self.next_seq;
+                self.window = (self.window / 2).max(MIN_WINDOW);
+                self.slow_start_until = self.window;
+            }
+        }
+        if timed_out {
+            self.rto = (self.rto * 2).min(MAX_RTO);
+        }
+    }
+
+    fn handle_packet(&mut self, packet: Packet, delivered: &mut Vec<Vec<u8>>) {
+        self.last_received = self.update;
+
+        // acks of our packets
+        let mut newest_acked = None;
+        if packet.ack != u32::MAX {
+            newest_acked = Some(packet.ack);
+            self.acked(packet.ack);
+            for i in 0..32 {
+                if packet.ack_bits & (1 << i) != 0 && packet.ack > i {
+                    self.acked(packet.ack - 1 - i);
+                }
+            }
+        }
+        self.detect_loss(newest_acked);
+
+        if packet.fragments.is_empty() {
+            return;
+        }
+        let mut refused = false;
+        for f in packet.fragments {
+            if !self.handle_fragment(f, delivered) {
+                self.stats.refused += 1;
+                refused = true;
+            }
+        }
+        // not acked, so the reliable fragments in it come again
+        if refused {
+            return;
+        }
+        // remember it for our acks
+        match self.ack {
+            None => {
+                self.ack = Some(packet.seq);
+                self.ack_bits = 0;
+            }
+            Some(ack) if packet.seq > ack => {
+                let shift = packet.seq - ack;
+                self.ack_bits = if shift > 32 {
+                    0
+                } else {
+                    ((self.ack_bits as u64) << shift | 1 << (shift - 1)) as u32
+                };
+                self.ack = Some(packet.seq);
+            }
+            Some(ack) if packet.seq < ack && ack - packet.seq <= 32 => {
+                self.ack_bits |= 1 << (ack - packet.seq - 1);
+            }
+            _ => {}
+        }
+        self.ack_pending = true;
+    }
+
+    fn already_delivered(&self, channel: Channel, message: u32) -> bool {
+        match channel {
+            Channel::ReliableOrdered => {
+                message < self.next_ordered || self.ordered.contains_key(&message)
+            }
+            Channel::ReliableUnordered => {
+                message < self.unordered_below || self.unordered_done.contains(&message)
+            }
+            Channel::Unreliable => matches!(self.newest_unreliable, Some(n) if message <= n),
+        }
+    }
+
+    /// how far past what was delivered a message is, None on the unreliable channel.
+    fn ahead(&self, channel: Channel, message: u32) -> Option<u32> {
+        match channel {
+            Channel::ReliableOrdered => Some(message - self.next_ordered),
+            Channel::ReliableUnordered => Some(message - self.unordered_below),
+            Channel::Unreliable => None,
+        }
+    }
+
+    /// false if the fragment was refused, see `MESSAGE_WINDOW`.
+    fn handle_fragment(&mut self, f: Fragment, delivered: &mut Vec<Vec<u8>>) -> bool {
+        if self.already_delivered(f.channel, f.message) {
+            return true;
+        }
+        if f.count == 0 || f.index >= f.count || f.count as usize > MAX_FRAGMENTS {
+            return false;
+        }
+        let reliable = match self.ahead(f.channel, f.message) {
+            Some(ahead) if ahead >= MESSAGE_WINDOW => return false,
+            Some(_) => true,
+            None => false,
+        };
+        let key = (f.channel, f.message);
+        if reliable && f.count > 1 && !self.partial.contains_key(&key) {
+            let partial = self
+                .partial
+                .keys()
+                .filter(|(c, _)| *c != Channel::Unreliable)
+                .count();
+            if partial >= MAX_PARTIAL_RELIABLE {
+                return false;
+            }
+        }
+        let partial = self.partial.entry(key).or_insert_with(|| Partial {
+            parts: vec![None; f.count as usize],
+            missing: f.count as usize,
+        });
+        if partial.parts.len() != f.count as usize {
+            return false;
+        }
+        let slot = &mut partial.parts[f.index as usize];
+        if slot.is_none() {
+            *slot = Some(f.data);
+            partial.missing -= 1;
+        }
+        if partial.missing > 0 {
+            if f.channel == Channel::Unreliable {
+                self.drop_old_unreliable();
+            }
+            return true;
+        }
+        let partial = self.partial.remove(&key).unwrap();
+        let msg: Vec<u8> = partial.parts.into_iter().flatten().flatten().collect();
+
+        match f.channel {
+            Channel::ReliableOrdered => {
+                self.ordered.insert(f.message, msg);
+                while let Some(msg) = self.ordered.remove(&self.next_ordered) {
+                    delivered.push(msg);
+                    self.next_ordered += 1;
+                }
+            }
+            Channel::ReliableUnordered => {
+                delivered.push(msg);
+                self.unordered_done.insert(f.message);
+                while self.unordered_done.remove(&self.unordered_below) {
+                    self.unordered_below += 1;
+                }
+            }
+            Channel::Unreliable => {
+                delivered.push(msg);
+                self.newest_unreliable = Some(f.message);
+                let newest = f.message;
+                self.partial
+                    .retain(|(c, m), _| *c != Channel::Unreliable || *m > newest);
+            }
+        }
+        true
+    }
+
+    fn drop_old_unreliable(&mut self) {
+        let partial: Vec<(Channel, u32)> = self
+            .partial
+            .keys()
+            .filter(|(c, _)| *c == Channel::Unreliable)
+            .copied()
+            .collect();
+        if partial.len() > MAX_PARTIAL_UNRELIABLE {
+            for key in &partial[..partial.len() - MAX_PARTIAL_UNRELIABLE] {
+                self.partial.remove(key);
+            }
+        }
+    }
+}
+
+impl Connection for ReliableConnection {
+    fn unacked(&self) -> usize {
+        self.resend.len()
+            + self.reliable.len()
+            + self
+                .in_flight
+                .values()
+                .map(|p| p.reliable.len())
+                .sum::<usize>()
+    }
+
+    fn send(&mut self, msg: &[u8]) -> Result<(), NetError> {
+        self.send_on(Channel::ReliableOrdered, msg)
+    }
+
+    fn send_on(&mut self, channel: Channel, msg: &[u8]) -> Result<(), NetError> {
+        if msg.len() > MAX_MESSAGE_SIZE {
+            return Err(NetError::FrameTooLarge(msg.len()));
+        }
+        let n = channel as usize;
+        let message = self.next_message[n];
+        self.next_message[n] = message.wrapping_add(1);
+
+        let count = msg.len().div_ceil(FRAGMENT_SIZE).max(1);
+        let queue = match channel {
+            Channel::Unreliable => &mut self.unreliable,
+            _ => &mut self.reliable,
+        };
+        for index in 0..count {
+            let start = index * FRAGMENT_SIZE;
+            let end = (start + FRAGMENT_SIZE).min(msg.len());
+            queue.push_back(Fragment {
+                channel,
+                message,
+                index: index as u16,
+                count: count as u16,
+                data: msg[start..end].to_vec(),
+            });
+        }
+        self.pump()
+    }
+
+    fn receive(&mut self) -> Result<Vec<Vec<u8>>, NetError> {
+        self.update += 1;
+
+        let mut delivered = vec![];
+        for bytes in self.inner.receive()? {
+            match bincode::deserialize::<Packet>(&bytes) {
+                Ok(p) if p.magic == PACKET_MAGIC => self.handle_packet(p, &mut delivered),
+                // garbage from the network is not an error of the connection
+                _ => {}
+            }
+        }
+        if self.update - self.last_received > TIMEOUT_UPDATES {
+            return Err(NetError::TimedOut);
+        }
+
+        self.detect_loss(None);
+        self.pump()?;
+        if self.ack_pending || self.update - self.last_sent >= KEEPALIVE_UPDATES {
+            self.send_packet(vec![])?;
+        }
+        Ok(delivered)
+    }
+}
+
+/// wraps every connection the inner listener accepts in a ReliableConnection.
+pub struct ReliableListener {
+    inner: Box<dyn Listener>,
+}
+
+impl ReliableListener {
+    pub fn new(inner: Box<dyn Listener>) -> Self {
+        Self { inner }
+    }
+}
+
+impl Listener for ReliableListener {
+    fn accept(&mut self) -> Result<Option<Box<dyn Connection>>, NetError> {
+        Ok(self
+            .inner
+            .accept()?
+            .map(|c| Box::new(ReliableConnection::new(c)) as Box<dyn Connection>))
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::net::sim::{SimConfig, SimNetwork};
+
+    fn lossy() -> SimConfig {
+        SimConfig {
+            latency: 1,
+            jitter: 2,
+            loss: 0.1,
+            duplicate: 0.05,
+            reorder: 0.05,
+        }
+    }
+
+    fn link(config: SimConfig, seed: u64) -> (SimNetwork, ReliableConnection, ReliableConnection) {
+        let net = SimNetwork::new(seed);
+        let a = ReliableConnection::new(Box::new(net.connect(config)));
+        let b = ReliableConnection::new(net.listener().accept().unwrap().unwrap());
+        (net, a, b)
+    }
+
+    /// message `n` is `n` repeated, some of them need several fragments.
+    fn message(n: u32) -> Vec<u8> {
+        let len = [10, 700, 3000, 8200][n as usize % 4];
+        n.to_le_bytes().iter().copied().cycle().take(len).collect()
+    }
+
+    fn number(msg: &[u8]) -> u32 {
+        let mut b = [0; 4];
+        b.copy_from_slice(&msg[..4]);
+        u32::from_le_bytes(b)
+    }
+
+    /// sends `count` messages on `channel` from a to b over `steps` updates, returns what b got.
+    fn transfer(
+        net: &SimNetwork,
+        a: &mut ReliableConnection,
+        b: &mut ReliableConnection,
+        channel: Channel,
+        count: u32,
+        steps: usize,
+    ) -> Vec<Vec<u8>> {
+        let mut got = vec![];
+        for step in 0..steps {
+            if (step as u32) < count {
+                a.send_on(channel, &message(step as u32)).unwrap();
+            }
+            net.step();
+            a.receive().unwrap();
+            got.extend(b.receive().unwrap());
+        }
+        got
+    }
+
+    #[test]
+    fn ordered_channel_survives_a_lossy_network() {
+        let (net, mut a, mut b) = link(lossy(), 1);
+        let got = transfer(&net, &mut a, &mut b, Channel::ReliableOrdered, 100, 1000);
+        let expected: Vec<Vec<u8>> = (0..100).map(message).collect();
+        assert_eq!(got, expected);
+        assert!(a.stats().resent > 0);
+        assert!(net.stats().lost > 0);
+    }
+
+    #[test]
+    fn unordered_channel_delivers_everything_once() {
+        let (net, mut a, mut b) = link(lossy(), 2);
+        let got = transfer(&net, &mut a, &mut b, Channel::ReliableUnordered, 100, 1000);
+        let mut numbers: Vec<u32> = got.iter().map(|m| number(m)).collect();
+        assert!(numbers.windows(2).any(|w| w[0] > w[1]));
+        numbers.sort_unstable();
+        assert_eq!(numbers, (0..100).collect::<Vec<u32>>());
+        for msg in got {
+            assert_eq!(msg, message(number(&msg)));
+        }
+    }
+
+    #[test]
+    fn unreliable_channel_drops_but_never_goes_back() {
+        let (net, mut a, mut b) = link(lossy(), 3);
+        let got = transfer(&net, &mut a, &mut b, Channel::Unreliable, 100, 200);
+        let numbers: Vec<u32> = got.iter().map(|m| number(m)).collect();
+        assert!(
+            numbers.len() > 30 && numbers.len() < 100,
+            "{}",
+            numbers.len()
+        );
+        assert!(numbers.windows(2).all(|w| w[0] < w[1]));
+        for msg in got {
+            assert_eq!(msg, message(number(&msg)));
+        }
+        assert_eq!(a.stats().resent, 0);
+    }
+
+    #[test]
+    fn channels_share_one_connection() {
+        let (net, mut a, mut b) = link(lossy(), 4);
+        let mut got = vec![];
+        for step in 0..1000_u32 {
+            if step < 50 {
+                // a chunk sized message and a player update every step
+                a.send_on(Channel::ReliableOrdered, &message(step * 4 + 3))
+                    .unwrap();
+                a.send_on(Channel::Unreliable, &[0xff; 40]).unwrap();
+            }
+            net.step();
+            a.receive().unwrap();
+            got.extend(b.receive().unwrap());
+        }
+        let chunks: Vec<u32> = got
+            .iter()
+            .filter(|m| m.len() > 40)
+            .map(|m| number(m))
+            .collect();
+        assert_eq!(chunks, (0..50).map(|s| s * 4 + 3).collect::<Vec<u32>>());
+        assert!(got.iter().filter(|m| m.len() == 40).count() > 10);
+    }
+
+    #[test]
+    fn window_shrinks_when_packets_get_lost() {
+        let clean = SimConfig {
+            latency: 2,
+            ..SimConfig::perfect()
+        };
+        let (net, mut a, mut b) = link(clean, 5);
+        transfer(&net, &mut a, &mut b, Channel::ReliableOrdered, 200, 250);
+        let grown = a.stats().window;
+        assert!(grown > INITIAL_WINDOW);
+        assert!(a.stats().rtt.unwrap() >= 2.0);
+
+        let (net, mut a, mut b) = link(SimConfig { loss: 0.3, ..clean }, 5);
+        transfer(&net, &mut a, &mut b, Channel::ReliableOrdered, 200, 250);
+        assert!(a.stats().window < grown);
+        assert!(a.stats().packets_lost > 0);
+    }
+
+    #[test]
+    fn window_limits_what_is_in_flight() {
+        let (net, mut a, _b) = link(
+            SimConfig {
+                latency: 5,
+                ..SimConfig::perfect()
+            },
+            6,
+        );
+        for n in 0..100 {
+            a.send(&message(n * 4 + 3)).unwrap();
+        }
+        assert!(a.stats().in_flight <= INITIAL_WINDOW);
+        assert!(net.stats().sent < 100);
+    }
+
+    /// a packet from a peer that does not play by the rules.
+    fn raw_packet(seq: u32, fragments: Vec<Fragment>) -> Vec<u8> {
+        bincode::serialize(&Packet {
+            magic: PACKET_MAGIC,
+            seq,
+            ack: u32::MAX,
+            ack_bits: 0,
+            fragments,
+        })
+        .unwrap()
+    }
+
+    fn fragment(channel: Channel, message: u32, count: u16) -> Fragment {
+        Fragment {
+            channel,
+            message,
+            index: 0,
+            count,
+            data: vec![1; 8],
+        }
+    }
+
+    #[test]
+    fn a_peer_can_not_make_the_other_end_hold_much() {
+        let net = SimNetwork::new(10);
+        let mut raw = net.connect(SimConfig::perfect());
+        let mut b = ReliableConnection::new(net.listener().accept().unwrap().unwrap());
+
+        let mut seq = 0;
+        let mut send = |fragments| {
+            raw.send(&raw_packet(seq, fragments)).unwrap();
+            seq += 1;
+        };
+        send(vec![fragment(Channel::ReliableOrdered, 0, u16::MAX)]);
+        send(vec![fragment(
+            Channel::ReliableUnordered,
+            MESSAGE_WINDOW,
+            1,
+        )]);
+        send(vec![fragment(Channel::ReliableOrdered, u32::MAX, 1)]);
+        for message in 0..MAX_PARTIAL_RELIABLE as u32 * 2 {
+            send(vec![fragment(Channel::ReliableOrdered, message, 2)]);
+        }
+        net.step();
+        assert!(b.receive().unwrap().is_empty());
+        assert_eq!(b.partial.len(), MAX_PARTIAL_RELIABLE);
+        assert!(b.ordered.is_empty() && b.unordered_done.is_empty());
+        // only the packets that were taken are acked
+        assert_eq!(b.ack, Some(2 + MAX_PARTIAL_RELIABLE as u32));
+    }
+
+    #[test]
+    fn refused_fragments_are_taken_once_there_is_room() {
+        let net = SimNetwork::new(11);
+        let mut raw = net.connect(SimConfig::perfect());
+        let mut b = ReliableConnection::new(net.listener().accept().unwrap().unwrap());
+
+        let full = MAX_PARTIAL_RELIABLE as u32;
+        let mut packets = (0..full)
+            .map(|m| vec![fragment(Channel::ReliableUnordered, m, 2)])
+            .collect::<Vec<_>>();
+        packets.push(vec![fragment(Channel::ReliableUnordered, full, 2)]);
+        for (seq, fragments) in packets.into_iter().enumerate() {
+            raw.send(&raw_packet(seq as u32, fragments)).unwrap();
+        }
+        net.step();
+        b.receive().unwrap();
+        assert_eq!(b.stats().refused, 1);
+        assert_eq!(b.ack, Some(full - 1));
+
+        // the second halves finish the partial messages, then the refused packet comes again
+        let second = (0..full)
+            .map(|m| Fragment {
+                index: 1,
+                ..fragment(Channel::ReliableUnordered, m, 2)
+            })
+            .collect::<Vec<_>>();
+        raw.send(&raw_packet(full + 1, second)).unwrap();
+        raw.send(&raw_packet(
+            full + 2,
+            vec![fragment(Channel::ReliableUnordered, full, 2)],
+        ))
+        .unwrap();
+        net.step();
+        assert_eq!(b.receive().unwrap().len(), full as usize);
+        assert_eq!(b.stats().refused, 1);
+        assert!(b.partial.contains_key(&(Channel::ReliableUnordered, full)));
+    }
+
+    #[test]
+    fn silent_peer_times_out() {
+        let (_net, mut a, b) = link(SimConfig::perfect(), 7);
+        // the other end is still there but never answers
+        let _b = b;
+        for _ in 0..TIMEOUT_UPDATES {
+            a.receive().unwrap();
+        }
+        assert!(matches!(a.receive(), Err(NetError::TimedOut)));
+    }
+
+    #[test]
+    fn same_seed_same_run() {
+        let run = |seed| {
+            let (net, mut a, mut b) = link(lossy(), seed);
+            let got = transfer(&net, &mut a, &mut b, Channel::ReliableOrdered, 40, 200);
+            (got.len(), a.stats(), net.stats())
+        };
+        assert_eq!(run(9), run(9));
+    }
+}
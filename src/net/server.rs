@@ -0,0 +1,1185 @@
+use std::collections::BTreeMap;
+
+use super::{
+    content::ContentManifest,
+    interest::{chunk_of_position, ClientInterest, InterestConfig},
+    protocol::{
+        decode, encode, hello_version, ChunkDelta, ChunkPayload, ChunkPos, ClientId, ClientMessage,
+        PlayerState, ServerMessage, VoxelPos, PROTOCOL_VERSION,
+    },
+    transport::{Connection, Listener},
+    validation::{check_loaded, EditBudget, EditRejection, EditRules, MoveBudget, SPAWN_POSITION},
+    NetError,
+};
+use crate::voxel::voxel::{Chunk, VoxelChange, VoxelMap};
+
+pub struct RemoteClient {
+    pub id: ClientId,
+    /// None until the client said hello.
+    pub name: Option<String>,
+    pub interest: ClientInterest,
+    /// None until the client sent its first PlayerState.
+    pub player: Option<PlayerState>,
+    edit_budget: EditBudget,
+    move_budget: MoveBudget,
+    conn: Box<dyn Connection>,
+}
+
+impl RemoteClient {
+    pub fn send(&mut self, msg: &ServerMessage) -> Result<(), NetError> {
+        self.conn.send_on(msg.channel(), &encode(msg)?)
+    }
+}
+
+/// updates per second the server is expected to run at, sent to clients to play back player snapshots.
+pub const DEFAULT_TICK_RATE: u32 = 20;
+
+/// updates a refused connection is kept so the refusal gets there on transports that resend.
+const CLOSE_LINGER_TICKS: u64 = 100;
+
+/// owns the connections, the world it serves is passed in to `update`.
+pub struct VoxelServer {
+    listener: Box<dyn Listener>,
+    clients: BTreeMap<ClientId, RemoteClient>,
+    next_client_id: ClientId,
+    pub interest: InterestConfig,
+    /// sequence of the last change sent for each chunk, chunks never edited are at 0.
+    chunk_seqs: BTreeMap<ChunkPos, u64>,
+    /// number of updates so far.
+    tick: u64,
+    /// how often `update` is called per second, whoever runs the server has to keep to it.
+    pub tick_rate: u32,
+    /// what the voxel ids of the served world mean, sent to clients in the welcome.
+    pub content: ContentManifest,
+    /// what clients are allowed to edit.
+    pub edit_rules: EditRules,
+    /// refused connections that still have data on the way, and the tick they were refused at.
+    closing: Vec<(Box<dyn Connection>, u64)>,
+    /// every voxel whose change the last update sent, see `changed`.
+    changed: Vec<VoxelPos>,
+}
+
+impl VoxelServer {
+    pub fn new(listener: Box<dyn Listener>) -> Self {
+        Self {
+            listener,
+            clients: BTreeMap::new(),
+            next_client_id: 1,
+            interest: InterestConfig::default(),
+            chunk_seqs: BTreeMap::new(),
+            tick: 0,
+            tick_rate: DEFAULT_TICK_RATE,
+            content: ContentManifest::default(),
+            edit_rules: EditRules::default(),
+            closing: vec![],
+            changed: vec![],
+        }
+    }
+
+    /// clients that finished the handshake.
+    pub fn client_ids(&self) -> Vec<ClientId> {
+        self.clients
+            .values()
+            .filter(|c| c.name.is_some())
+            .map(|c| c.id)
+            .collect()
+    }
+
+    /// the chunk every client that sent its position is in, used to keep the world loaded around them.
+    pub fn client_centers(&self) -> Vec<ChunkPos> {
+        self.clients
+            .values()
+            .filter_map(|c| c.interest.center)
+            .collect()
+    }
+
+    /// the voxels the last update sent changes of, client edits and everything else that changed
+    /// the map, so the simulations running on the map can look at them again.
+    pub fn changed(&self) -> &[VoxelPos] {
+        &self.changed
+    }
+
+    /// accepts new clients, answers everything they sent and streams chunks to them.
+    /// every change to `map` since the last update, from clients or not, is sent to the clients that have the chunk.
+    pub fn update(&mut self, map: &mut VoxelMap) {
+        // edits are replicated from the journal
+        map.enable_journal();
+        loop {
+            match self.listener.accept() {
+                Ok(Some(conn)) => {
+                    let id = self.next_client_id;
+                    self.next_client_id += 1;
+                    self.clients.insert(
+                        id,
+                        RemoteClient {
+                            id,
+                            name: None,
+                            interest: ClientInterest::default(),
+                            player: None,
+                            edit_budget: EditBudget::new(),
+                            move_budget: MoveBudget::new(),
+                            conn,
+                        },
+                    );
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    println!("server accept err: {}", e);
+                    break;
+                }
+            }
+        }
+
+        let tick = self.tick;
+        self.closing = std::mem::take(&mut self.closing)
+            .into_iter()
+            .filter_map(|(mut conn, since)| {
+                let open = conn.receive().is_ok() && conn.unacked() > 0;
+                (open && tick - since < CLOSE_LINGER_TICKS).then_some((conn, since))
+            })
+            .collect();
+
+        // seconds of server time, edit budgets refill with it
+        let now = tick as f64 / self.tick_rate as f64;
+        let mut to_drop = vec![];
+        for client in self.clients.values_mut() {
+            if let Err(e) = Self::update_client(
+                client,
+                map,
+                &self.interest,
+                self.tick_rate,
+                &self.content,
+                &self.edit_rules,
+                now,
+            ) {
+                println!("server dropping client {}: {}", client.id, e);
+                to_drop.push((client.id, matches!(e, NetError::Refused(_))));
+            }
+        }
+        for (id, refused) in to_drop {
+            if let Some(client) = self.clients.remove(&id) {
+                if refused {
+                    self.closing.push((client.conn, self.tick));
+                }
+            }
+        }
+
+        self.send_deltas(map);
+
+        // chunks are streamed after the deltas so a chunk sent now already has every change in it
+        let mut to_drop = vec![];
+        for client in self.clients.values_mut() {
+            if let Err(e) = Self::stream_chunks(client, map, &self.interest, &self.chunk_seqs) {
+                println!("server dropping client {}: {}", client.id, e);
+                to_drop.push(client.id);
+            }
+        }
+        for id in to_drop {
+            self.clients.remove(&id);
+        }
+
+        self.tick += 1;
+        self.send_players();
+    }
+
+    /// every client gets the other players that are in its view radius.
+    fn send_players(&mut self) {
+        let players: Vec<(ClientId, PlayerState, Option<ChunkPos>)> = self
+            .clients
+            .values()
+            .filter_map(|c| c.player.map(|p| (c.id, p, c.interest.center)))
+            .collect();
+        let mut to_drop = vec![];
+        for client in self.clients.values_mut().filter(|c| c.name.is_some()) {
+            let center = match client.interest.center {
+                Some(c) => c,
+                None => continue,
+            };
+            let seen = players
+                .iter()
+                .filter(|(id, _, at)| {
+                    *id != client.id
+                        && matches!(at, Some(at) if ClientInterest::in_range(center, *at, &self.interest))
+                })
+                .map(|(id, p, _)| (*id, *p))
+                .collect();
+            let msg = ServerMessage::Players {
+                tick: self.tick,
+                players: seen,
+            };
+            if let Err(e) = client.send(&msg) {
+                println!("server dropping client {}: {}", client.id, e);
+                to_drop.push(client.id);
+            }
+        }
+        for id in to_drop {
+            self.clients.remove(&id);
+        }
+    }
+
+    /// batches the journal per chunk, each client gets the deltas of the chunks it knows about.
+    /// if the delta is larger then the whole chunk the chunk is sent instead.
+    fn send_deltas(&mut self, map: &mut VoxelMap) {
+        let mut per_chunk: BTreeMap<ChunkPos, Vec<VoxelChange>> = BTreeMap::new();
+        self.changed.clear();
+        for change in map.drain_journal() {
+            let (x, y, z) = change.pos;
+            self.changed.push(change.pos);
+            per_chunk
+                .entry(map.chunk_key(x, y, z))
+                .or_default()
+                .push(change);
+        }
+
+        let mut to_drop = vec![];
+        for (key, changes) in per_chunk {
+            let base_seq = self.chunk_seqs.get(&key).copied().unwrap_or(0);
+            let delta = ChunkDelta::from_changes(key, base_seq, &changes, map.chunk_size);
+            self.chunk_seqs.insert(key, delta.seq);
+
+            let bytes = match Self::delta_or_chunk(delta, map.chunk_list.get(&key)) {
+                Ok(b) => b,
+                Err(e) => {
+                    println!("server encode err: {}", e);
+                    continue;
+                }
+            };
+            for client in self
+                .clients
+                .values_mut()
+                .filter(|c| c.interest.known.contains(&key))
+            {
+                if let Err(e) = client.conn.send(&bytes) {
+                    println!("server dropping client {}: {}", client.id, e);
+                    to_drop.push(client.id);
+                }
+            }
+        }
+        for id in to_drop {
+            self.clients.remove(&id);
+        }
+    }
+
+    /// the encoded delta, or the encoded chunk if that is smaller.
+    fn delta_or_chunk(delta: ChunkDelta, chunk: Option<&Chunk>) -> Result<Vec<u8>, NetError> {
+        let (key, seq) = (delta.key, delta.seq);
+        let delta = encode(&ServerMessage::ChunkDelta(delta))?;
+        if let Some(chunk) = chunk {
+            let full = encode(&ServerMessage::ChunkData(ChunkPayload {
+                seq,
+                ..ChunkPayload::from_chunk(key, chunk)
+            }))?;
+            if full.len() < delta.len() {
+                return Ok(full);
+            }
+        }
+        Ok(delta)
+    }
+
+    fn snapshot(
+        key: ChunkPos,
+        chunk: &Chunk,
+        chunk_seqs: &BTreeMap<ChunkPos, u64>,
+    ) -> ServerMessage {
+        ServerMessage::ChunkData(ChunkPayload {
+            seq: chunk_seqs.get(&key).copied().unwrap_or(0),
+            ..ChunkPayload::from_chunk(key, chunk)
+        })
+    }
+
+    /// unloads chunks that left the view radius, then sends missing and requested chunks nearest
+    /// first until the byte budget for this update is used up.
+    fn stream_chunks(
+        client: &mut RemoteClient,
+        map: &VoxelMap,
+        config: &InterestConfig,
+        chunk_seqs: &BTreeMap<ChunkPos, u64>,
+    ) -> Result<(), NetError> {
+        if client.name.is_none() {
+            return Ok(());
+        }
+
+        let unload = client.interest.out_of_range(config);
+        if !unload.is_empty() {
+            for key in unload.iter() {
+                client.interest.known.remove(key);
+            }
+            client.send(&ServerMessage::UnloadChunks { keys: unload })?;
+        }
+        for key in client.interest.take_unservable(map, config) {
+            client.send(&ServerMessage::ChunkUnavailable { key })?;
+        }
+
+        let mut sent = 0;
+        for key in client.interest.to_send(map, config) {
+            if sent >= config.bytes_per_tick {
+                break;
+            }
+            let bytes = encode(&Self::snapshot(key, &map.chunk_list[&key], chunk_seqs))?;
+            sent += bytes.len();
+            client.conn.send(&bytes)?;
+            client.interest.known.insert(key);
+            client.interest.requested.remove(&key);
+        }
+        Ok(())
+    }
+
+    /// sends to every client that finished the handshake.
+    pub fn broadcast(&mut self, msg: &ServerMessage) {
+        let bytes = match encode(msg) {
+            Ok(b) => b,
+            Err(e) => {
+                println!("server encode err: {}", e);
+                return;
+            }
+        };
+        let mut to_drop = vec![];
+        for client in self.clients.values_mut().filter(|c| c.name.is_some()) {
+            if let Err(e) = client.conn.send_on(msg.channel(), &bytes) {
+                println!("server dropping client {}: {}", client.id, e);
+                to_drop.push(client.id);
+            }
+        }
+        for id in to_drop {
+            self.clients.remove(&id);
+        }
+    }
+
+    /// returns why the edit was rejected, or Ok if it passes every rule.
+    /// the rate limit is checked first so rejected edits count too.
+    fn validate_edit(
+        client: &mut RemoteClient,
+        map: &VoxelMap,
+        pos: VoxelPos,
+        id: u16,
+        content: &ContentManifest,
+        rules: &EditRules,
+        now: f64,
+    ) -> Result<(), EditRejection> {
+        client.edit_budget.take(rules, now)?;
+        rules.check_block(id, content)?;
+        check_loaded(map, pos)?;
+        rules.check_reach(client.player.as_ref(), pos)
+    }
+
+    fn apply_edit(
+        map: &mut VoxelMap,
+        pos: VoxelPos,
+        id: u16,
+        expected: u16,
+    ) -> Result<u64, EditRejection> {
+        let (x, y, z) = pos;
+        // someone else got to the voxel first
+        if map.get_voxel(x, y, z) != expected {
+            return Err(EditRejection::VoxelChanged(pos));
+        }
+        let before = map.edit_seq();
+        map.edit_voxel(x, y, z, id);
+        Ok(if map.edit_seq() != before {
+            map.edit_seq()
+        } else {
+            0
+        })
+    }
+
+    fn update_client(
+        client: &mut RemoteClient,
+        map: &mut VoxelMap,
+        interest: &InterestConfig,
+        tick_rate: u32,
+        content: &ContentManifest,
+        rules: &EditRules,
+        now: f64,
+    ) -> Result<(), NetError> {
+        for bytes in client.conn.receive()? {
+            let msg: ClientMessage = match decode(&bytes) {
+                Ok(m) => m,
+                Err(e) => {
+                    // a hello from another version can have other fields, the version is still readable
+                    match hello_version(&bytes) {
+                        Some(v) if client.name.is_none() && v != PROTOCOL_VERSION => {
+                            return Err(Self::refuse(client, Self::version_mismatch(v)));
+                        }
+                        _ => return Err(e),
+                    }
+                }
+            };
+            match (msg, client.name.is_some()) {
+                (
+                    ClientMessage::Hello {
+                        protocol_version,
+                        name,
+                        content_hash,
+                    },
+                    false,
+                ) => {
+                    if protocol_version != PROTOCOL_VERSION {
+                        return Err(Self::refuse(
+                            client,
+                            Self::version_mismatch(protocol_version),
+                        ));
+                    }
+                    println!("server: client {} joined as {}", client.id, name);
+                    if content_hash != content.hash() {
+                        println!(
+                            "server: client {} has other blocks or textures, it remaps the ids",
+                            client.id
+                        );
+                    }
+                    client.name = Some(name);
+                    client.send(&ServerMessage::Welcome {
+                        client_id: client.id,
+                        chunk_size: map.chunk_size,
+                        tick_rate,
+                        content_hash: content.hash(),
+                        blocks: content.blocks.clone(),
+                    })?;
+                }
+                // the same hello again, transports without a stream can deliver a message twice
+                (ClientMessage::Hello { .. }, true) => {}
+                // sent by stream_chunks under the byte budget, out of range keys get nothing
+                (ClientMessage::RequestChunks { keys }, true) => {
+                    for key in keys {
+                        if !client.interest.request(key, interest) {
+                            client.send(&ServerMessage::ChunkUnavailable { key })?;
+                        }
+                    }
+                }
+                (
+                    ClientMessage::SetVoxel {
+                        request,
+                        pos,
+                        id,
+                        expected,
+                    },
+                    true,
+                ) => {
+                    let result = Self::validate_edit(client, map, pos, id, content, rules, now)
+                        .and_then(|_| Self::apply_edit(map, pos, id, expected));
+                    let msg = match result {
+                        Ok(seq) => ServerMessage::EditAccepted { request, seq },
+                        Err(rejection) => {
+                            println!(
+                                "server: edit {} of client {} at {:?} rejected: {}",
+                                request, client.id, pos, rejection
+                            );
+                            ServerMessage::EditRejected {
+                                request,
+                                reason: rejection.to_string(),
+                            }
+                        }
+                    };
+                    client.send(&msg)?;
+                }
+                (ClientMessage::PlayerState(mut state), true) => {
+                    // reach and the chunks loaded around the client go by this position
+                    let from = client.player.as_ref().map_or(SPAWN_POSITION, |p| p.pos);
+                    state.pos = match client.move_budget.take(from, state.pos, now) {
+                        Some(pos) => pos,
+                        None => continue,
+                    };
+                    client.interest.center = Some(chunk_of_position(state.pos, map.chunk_size));
+                    client.player = Some(state);
+                }
+                (ClientMessage::Disconnect, _) => return Err(NetError::Closed),
+                (msg, _) => {
+                    return Err(Self::refuse(
+                        client,
+                        format!("unexpected message {:?}", msg),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn version_mismatch(version: u32) -> String {
+        format!(
+            "protocol version {} does not match the server version {}",
+            version, PROTOCOL_VERSION
+        )
+    }
+
+    /// tells the client why, the returned error drops it.
+    fn refuse(client: &mut RemoteClient, reason: String) -> NetError {
+        match client.send(&ServerMessage::Refused {
+            reason: reason.clone(),
+        }) {
+            Ok(()) => NetError::Refused(reason),
+            Err(e) => e,
+        }
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use std::{thread, time::Duration};
+
+    use super::*;
+    use crate::net::{
+        client::{ConnectionState, VoxelClient},
+        reliable::{ReliableConnection, ReliableListener},
+        sim::{SimConfig, SimNetwork},
+        transport::{
+            MemoryConnection, MemoryListener, TcpConnection, TcpServerListener, UdpConnection,
+            UdpServerListener,
+        },
+    };
+    use crate::voxel::{
+        block::WATER,
+        fluid::{FluidSim, MAX_FLUID_LEVEL},
+    };
+
+    /// the clients here send no player state, so their edits are allowed from anywhere.
+    fn test_server(listener: Box<dyn Listener>) -> VoxelServer {
+        let mut server = VoxelServer::new(listener);
+        server.edit_rules.reach = None;
+        server
+    }
+
+    fn server_map() -> VoxelMap {
+        let mut map = VoxelMap::new((16, 16, 16));
+        for x in -1..=1 {
+            for z in -1..=1 {
+                map.add_chunk(x, 0, z, Chunk::new(16));
+            }
+        }
+        for x in -16..32 {
+            for z in -16..32 {
+                map.set_voxel(x, 0, z, 6);
+            }
+        }
+        map.set_voxel(3, 1, 4, 16);
+        map
+    }
+
+    /// the suites below run over a real socket and over the simulated network.
+    enum TestNet {
+        Tcp(std::net::SocketAddr),
+        Udp(std::net::SocketAddr),
+        /// `true` if the connections are wrapped in ReliableConnection.
+        Sim(SimNetwork, SimConfig, bool),
+    }
+
+    impl TestNet {
+        fn connection(&self) -> Box<dyn Connection> {
+            match self {
+                TestNet::Tcp(addr) => Box::new(TcpConnection::connect(*addr).unwrap()),
+                TestNet::Udp(addr) => Box::new(ReliableConnection::new(Box::new(
+                    UdpConnection::connect(*addr).unwrap(),
+                ))),
+                TestNet::Sim(net, config, false) => Box::new(net.connect(*config)),
+                TestNet::Sim(net, config, true) => {
+                    Box::new(ReliableConnection::new(Box::new(net.connect(*config))))
+                }
+            }
+        }
+
+        fn wait(&self) {
+            match self {
+                TestNet::Tcp(_) | TestNet::Udp(_) => thread::sleep(Duration::from_millis(1)),
+                TestNet::Sim(net, _, _) => net.step(),
+            }
+        }
+    }
+
+    fn start_server() -> (VoxelServer, TestNet) {
+        let listener = TcpServerListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        (test_server(Box::new(listener)), TestNet::Tcp(addr))
+    }
+
+    fn start_udp_server() -> (VoxelServer, TestNet) {
+        let listener = UdpServerListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let listener = ReliableListener::new(Box::new(listener));
+        (test_server(Box::new(listener)), TestNet::Udp(addr))
+    }
+
+    /// latency, jitter, duplicates and reordering, nothing is lost since the messages go over the sim as they are.
+    fn start_sim_server(seed: u64) -> (VoxelServer, TestNet) {
+        let net = SimNetwork::new(seed);
+        let config = SimConfig {
+            latency: 2,
+            jitter: 3,
+            duplicate: 0.2,
+            reorder: 0.2,
+            ..SimConfig::perfect()
+        };
+        (
+            test_server(Box::new(net.listener())),
+            TestNet::Sim(net, config, false),
+        )
+    }
+
+    /// a fifth of the packets get lost on top, the reliable channels make up for it.
+    fn start_lossy_sim_server(seed: u64) -> (VoxelServer, TestNet) {
+        let net = SimNetwork::new(seed);
+        let config = SimConfig {
+            latency: 2,
+            jitter: 3,
+            loss: 0.2,
+            duplicate: 0.1,
+            reorder: 0.1,
+        };
+        let listener = ReliableListener::new(Box::new(net.listener()));
+        (
+            test_server(Box::new(listener)),
+            TestNet::Sim(net, config, true),
+        )
+    }
+
+    /// runs the server and clients until `done` or a timeout, returns the number of updates it took.
+    fn pump(
+        net: &TestNet,
+        server: &mut VoxelServer,
+        server_map: &mut VoxelMap,
+        clients: &mut [(VoxelClient, VoxelMap)],
+        done: impl Fn(&[(VoxelClient, VoxelMap)]) -> bool,
+    ) -> usize {
+        for i in 0..2000 {
+            server.update(server_map);
+            for (client, map) in clients.iter_mut() {
+                client.update(map).unwrap();
+            }
+            if done(clients) {
+                return i;
+            }
+            net.wait();
+        }
+        panic!("timed out");
+    }
+
+    fn connect(net: &TestNet, name: &str) -> (VoxelClient, VoxelMap) {
+        (
+            VoxelClient::connect(net.connection(), name, ContentManifest::default()).unwrap(),
+            VoxelMap::new((16, 16, 16)),
+        )
+    }
+
+    fn receives_requested_chunks((mut server, net): (VoxelServer, TestNet)) {
+        let mut world = server_map();
+        let mut clients = vec![connect(&net, "a")];
+
+        pump(&net, &mut server, &mut world, &mut clients, |c| {
+            c[0].0.client_id().is_some()
+        });
+        clients[0]
+            .0
+            .request_chunks(&[(0, 0, 0), (1, 0, -1), (5, 5, 5)])
+            .unwrap();
+        pump(&net, &mut server, &mut world, &mut clients, |c| {
+            !c[0].0.has_pending_requests()
+        });
+
+        let map = &clients[0].1;
+        assert_eq!(map.chunk_list.len(), 2);
+        assert_eq!(map.get_voxel(3, 1, 4), 16);
+        assert_eq!(map.get_voxel(3, 0, 4), 6);
+        assert_eq!(map.get_voxel(16, 0, -16), 6);
+        assert!(map.chunk_list[&(0, 0, 0)].is_dirty());
+    }
+
+    /// returns the number of updates the whole run took.
+    fn edits_reach_every_client_over((mut server, net): (VoxelServer, TestNet)) -> usize {
+        let mut world = server_map();
+        let mut clients = vec![connect(&net, "a"), connect(&net, "b")];
+
+        let mut updates = pump(&net, &mut server, &mut world, &mut clients, |c| {
+            c.iter().all(|(client, _)| client.client_id().is_some())
+        });
+        for (client, _) in clients.iter_mut() {
+            client.request_chunks(&[(0, 0, 0)]).unwrap();
+        }
+        updates += pump(&net, &mut server, &mut world, &mut clients, |c| {
+            c.iter().all(|(client, _)| !client.has_pending_requests())
+        });
+
+        let (client, map) = &mut clients[0];
+        client.set_voxel(map, (2, 2, 2), 16).unwrap();
+        client.set_voxel(map, (2, 3, 2), 7).unwrap();
+        updates += pump(&net, &mut server, &mut world, &mut clients, |c| {
+            c.iter().all(|(client, map)| {
+                map.get_voxel(2, 2, 2) == 16
+                    && map.get_voxel(2, 3, 2) == 7
+                    && client.predicted().is_empty()
+            })
+        });
+        assert_eq!(world.get_voxel(2, 2, 2), 16);
+        assert_eq!(world.get_voxel(2, 3, 2), 7);
+        for (client, _) in &clients {
+            assert_eq!(client.chunk_seq((0, 0, 0)), Some(2));
+        }
+        updates
+    }
+
+    fn wrong_protocol_version_is_refused_over((mut server, net): (VoxelServer, TestNet)) {
+        let mut world = server_map();
+        let mut conn = net.connection();
+        conn.send(
+            &encode(&ClientMessage::Hello {
+                protocol_version: PROTOCOL_VERSION + 1,
+                name: "old".into(),
+                content_hash: 0,
+            })
+            .unwrap(),
+        )
+        .unwrap();
+        let mut client = VoxelClient::from_connection(conn);
+        let mut map = VoxelMap::new((16, 16, 16));
+
+        for _ in 0..2000 {
+            server.update(&mut world);
+            if client.update(&mut map).is_err() {
+                break;
+            }
+            net.wait();
+        }
+        assert!(matches!(client.state(), ConnectionState::Refused(_)));
+        assert!(server.client_ids().is_empty());
+    }
+
+    #[test]
+    fn client_receives_requested_chunks() {
+        receives_requested_chunks(start_server());
+        receives_requested_chunks(start_udp_server());
+        receives_requested_chunks(start_sim_server(1));
+        receives_requested_chunks(start_lossy_sim_server(1));
+    }
+
+    #[test]
+    fn edits_reach_every_client() {
+        edits_reach_every_client_over(start_server());
+        edits_reach_every_client_over(start_udp_server());
+        edits_reach_every_client_over(start_sim_server(2));
+        edits_reach_every_client_over(start_lossy_sim_server(2));
+    }
+
+    #[test]
+    fn wrong_protocol_version_is_refused() {
+        wrong_protocol_version_is_refused_over(start_server());
+        wrong_protocol_version_is_refused_over(start_udp_server());
+        wrong_protocol_version_is_refused_over(start_sim_server(3));
+        wrong_protocol_version_is_refused_over(start_lossy_sim_server(3));
+    }
+
+    #[test]
+    fn simulated_runs_are_deterministic() {
+        let run = |start: fn(u64) -> (VoxelServer, TestNet), seed| {
+            let (server, net) = start(seed);
+            let sim = match &net {
+                TestNet::Sim(sim, _, _) => sim.clone(),
+                _ => unreachable!(),
+            };
+            let updates = edits_reach_every_client_over((server, net));
+            (updates, sim.stats())
+        };
+        for start in [start_sim_server, start_lossy_sim_server] {
+            let (updates, stats) = run(start, 4);
+            assert!(stats.duplicated > 0 && stats.reordered > 0);
+            assert_eq!(run(start, 4), (updates, stats));
+            for seed in 5..20 {
+                run(start, seed);
+            }
+        }
+    }
+
+    #[test]
+    fn every_change_is_replicated_in_order() {
+        let listener = MemoryListener::default();
+        let mut server = test_server(Box::new(listener.clone()));
+        let mut world = server_map();
+        let mut client = VoxelClient::connect(
+            Box::new(listener.connect()),
+            "a",
+            ContentManifest::default(),
+        )
+        .unwrap();
+        let mut map = VoxelMap::new((16, 16, 16));
+
+        server.update(&mut world);
+        client.update(&mut map).unwrap();
+        client.request_chunks(&[(0, 0, 0), (-1, 0, 0)]).unwrap();
+        server.update(&mut world);
+        client.update(&mut map).unwrap();
+        assert_eq!(client.chunk_seq((0, 0, 0)), Some(0));
+
+        // several edits in one update, two to the same voxel and one made by the server itself
+        client.set_voxel(&mut map, (2, 2, 2), 16).unwrap();
+        client.set_voxel(&mut map, (2, 2, 2), 7).unwrap();
+        client.set_voxel(&mut map, (0, 1, 0), 16).unwrap();
+        world.set_voxel(-1, 1, 5, 3);
+        server.update(&mut world);
+        client.update(&mut map).unwrap();
+
+        for pos in [(2, 2, 2), (0, 1, 0), (-1, 1, 5)] {
+            assert_eq!(
+                map.get_voxel(pos.0, pos.1, pos.2),
+                world.get_voxel(pos.0, pos.1, pos.2)
+            );
+        }
+        assert_eq!(map.get_voxel(2, 2, 2), 7);
+        // the server edit is recorded first, the client edits only once the server reads them
+        assert_eq!(client.chunk_seq((-1, 0, 0)), Some(1));
+        assert_eq!(client.chunk_seq((0, 0, 0)), Some(4));
+        assert_eq!(world.edit_seq(), 4);
+        // (0, 1, 0) is on the border with (-1, 0, 0)
+        assert!(map.chunk_list[&(-1, 0, 0)].is_dirty());
+    }
+
+    #[test]
+    fn invalid_edits_are_rejected_and_rolled_back() {
+        let listener = MemoryListener::default();
+        let mut server = VoxelServer::new(Box::new(listener.clone()));
+        server.edit_rules = EditRules {
+            reach: Some(6.0),
+            edits_per_second: 2.0,
+            burst: 4,
+            allowed_blocks: Some(vec!["dirt".into(), "glass".into()]),
+        };
+        let mut world = server_map();
+        let mut client = VoxelClient::connect(
+            Box::new(listener.connect()),
+            "a",
+            ContentManifest::default(),
+        )
+        .unwrap();
+        let mut map = VoxelMap::new((16, 16, 16));
+
+        server.update(&mut world);
+        client.update(&mut map).unwrap();
+        client.request_chunks(&[(0, 0, 0), (1, 0, 0)]).unwrap();
+        // without a position nothing is in reach
+        client.set_voxel(&mut map, (3, 3, 3), 16).unwrap();
+        server.update(&mut world);
+        client.update(&mut map).unwrap();
+        assert_eq!(world.get_voxel(3, 3, 3), 0);
+        assert_eq!(map.get_voxel(3, 3, 3), 0);
+
+        client
+            .send_player_state(PlayerState::at((2.5, 2.5, 2.5)))
+            .unwrap();
+        client.set_voxel(&mut map, (3, 3, 3), 16).unwrap();
+        client.set_voxel(&mut map, (30, 3, 3), 16).unwrap();
+        client.set_voxel(&mut map, (3, 3, 4), 6).unwrap();
+        // rejected edits count too, this is the fifth
+        client.set_voxel(&mut map, (3, 3, 5), 1).unwrap();
+        server.update(&mut world);
+        client.update(&mut map).unwrap();
+
+        assert_eq!(world.get_voxel(3, 3, 3), 16);
+        for (pos, id) in [
+            ((30, 3, 3), 0),
+            ((3, 3, 4), 0),
+            ((3, 3, 5), 0),
+            ((3, 3, 3), 16),
+        ] {
+            assert_eq!(world.get_voxel(pos.0, pos.1, pos.2), id);
+            assert_eq!(map.get_voxel(pos.0, pos.1, pos.2), id);
+        }
+        assert!(client.predicted().is_empty());
+
+        // the budget refills with server time, 20 updates are a second
+        for _ in 0..20 {
+            server.update(&mut world);
+        }
+        client.set_voxel(&mut map, (3, 3, 5), 1).unwrap();
+        server.update(&mut world);
+        client.update(&mut map).unwrap();
+        assert_eq!(world.get_voxel(3, 3, 5), 1);
+    }
+
+    #[test]
+    fn positions_can_not_be_made_up() {
+        let listener = MemoryListener::default();
+        let mut server = VoxelServer::new(Box::new(listener.clone()));
+        let mut world = server_map();
+        let mut client = VoxelClient::connect(
+            Box::new(listener.connect()),
+            "a",
+            ContentManifest::default(),
+        )
+        .unwrap();
+        let mut map = VoxelMap::new((16, 16, 16));
+
+        server.update(&mut world);
+        client.update(&mut map).unwrap();
+        client.request_chunks(&[(0, 0, 0)]).unwrap();
+        server.update(&mut world);
+        client.update(&mut map).unwrap();
+
+        // a NaN position is dropped, so the client still has no position
+        client
+            .send_player_state(PlayerState::at((f32::NAN, 2.5, 2.5)))
+            .unwrap();
+        client.set_voxel(&mut map, (3, 3, 3), 16).unwrap();
+        server.update(&mut world);
+        client.update(&mut map).unwrap();
+        assert_eq!(world.get_voxel(3, 3, 3), 0);
+        assert_eq!(map.get_voxel(3, 3, 3), 0);
+
+        // a jump is cut short, so chunks are not loaded around where the client said it is
+        client
+            .send_player_state(PlayerState::at((1000.0, 0.0, 0.0)))
+            .unwrap();
+        server.update(&mut world);
+        assert_eq!(server.client_centers(), vec![(3, 0, 0)]);
+    }
+
+    #[test]
+    fn fluid_flows_reach_clients() {
+        let listener = MemoryListener::default();
+        let mut server = test_server(Box::new(listener.clone()));
+        let mut world = server_map();
+        world.set_fluid(5, 1, 5, WATER, MAX_FLUID_LEVEL);
+        for (x, z) in [(6, 5), (4, 5), (5, 6), (5, 4)] {
+            world.set_voxel(x, 1, z, 6);
+        }
+        let mut fluids = FluidSim::new();
+        let mut client = VoxelClient::connect(
+            Box::new(listener.connect()),
+            "a",
+            ContentManifest::default(),
+        )
+        .unwrap();
+        let mut map = VoxelMap::new((16, 16, 16));
+
+        server.update(&mut world);
+        client.update(&mut map).unwrap();
+        client.request_chunks(&[(0, 0, 0)]).unwrap();
+        server.update(&mut world);
+        client.update(&mut map).unwrap();
+        assert_eq!(map.get_fluid_level(5, 1, 5), MAX_FLUID_LEVEL);
+
+        // opening the wall is the only thing that wakes the water, the same as on the server plugin
+        client.set_voxel(&mut map, (6, 1, 5), 0).unwrap();
+        for _ in 0..30 {
+            server.update(&mut world);
+            for pos in server.changed() {
+                fluids.wake_around(*pos);
+            }
+            fluids.step(&mut world);
+            client.update(&mut map).unwrap();
+        }
+        assert!(fluids.is_settled());
+        assert!(world.get_fluid_level(5, 1, 5) < MAX_FLUID_LEVEL);
+        assert_eq!(world.get_voxel(7, 1, 5), WATER);
+        for x in 0..16 {
+            for z in 0..16 {
+                assert_eq!(map.get_voxel(x, 1, z), world.get_voxel(x, 1, z));
+                assert_eq!(map.get_fluid_level(x, 1, z), world.get_fluid_level(x, 1, z));
+            }
+        }
+    }
+
+    #[test]
+    fn other_block_ids_are_remapped_by_name() {
+        let listener = MemoryListener::default();
+        let mut server = test_server(Box::new(listener.clone()));
+        // the server registered the same blocks the other way round, its last is the local 1
+        server.content.blocks.reverse();
+        let count = server.content.blocks.len() as u16;
+        let flip = |id: u16| count + 1 - id;
+        let mut world = server_map();
+        let mut client = VoxelClient::connect(
+            Box::new(listener.connect()),
+            "a",
+            ContentManifest::default(),
+        )
+        .unwrap();
+        let mut map = VoxelMap::new((16, 16, 16));
+
+        server.update(&mut world);
+        client.update(&mut map).unwrap();
+        client.request_chunks(&[(0, 0, 0)]).unwrap();
+        server.update(&mut world);
+        client.update(&mut map).unwrap();
+        assert_eq!(map.get_voxel(3, 1, 4), flip(16));
+        assert_eq!(map.get_voxel(3, 0, 4), flip(6));
+
+        client.set_voxel(&mut map, (2, 2, 2), 16).unwrap();
+        world.set_voxel(5, 5, 5, 16);
+        server.update(&mut world);
+        client.update(&mut map).unwrap();
+        assert_eq!(world.get_voxel(2, 2, 2), flip(16));
+        assert_eq!(map.get_voxel(2, 2, 2), 16);
+        assert_eq!(map.get_voxel(5, 5, 5), flip(16));
+        assert!(client.predicted().is_empty());
+    }
+
+    #[test]
+    fn unknown_server_block_is_refused() {
+        let listener = MemoryListener::default();
+        let mut server = test_server(Box::new(listener.clone()));
+        server.content.blocks.push("lava".into());
+        let mut world = server_map();
+        let mut client = VoxelClient::connect(
+            Box::new(listener.connect()),
+            "a",
+            ContentManifest::default(),
+        )
+        .unwrap();
+        let mut map = VoxelMap::new((16, 16, 16));
+
+        server.update(&mut world);
+        match client.update(&mut map) {
+            Err(NetError::ContentMismatch(reason)) => assert!(reason.contains("\"lava\"")),
+            r => panic!("expected a content mismatch, got {:?}", r),
+        }
+        assert_eq!(client.state(), &ConnectionState::Disconnected);
+        server.update(&mut world);
+        assert!(server.client_ids().is_empty());
+    }
+
+    #[test]
+    fn hello_of_an_older_layout_is_refused() {
+        let listener = MemoryListener::default();
+        let mut server = test_server(Box::new(listener.clone()));
+        let mut world = server_map();
+        let mut conn = listener.connect();
+        // version 5 had no content hash in the hello
+        conn.send(&bincode::serialize(&(0u32, 5u32, "old".to_string())).unwrap())
+            .unwrap();
+        let mut client = VoxelClient::from_connection(Box::new(conn));
+        let mut map = VoxelMap::new((16, 16, 16));
+
+        server.update(&mut world);
+        assert!(client.update(&mut map).is_err());
+        match client.state() {
+            ConnectionState::Refused(reason) => assert!(reason.contains('5'), "{}", reason),
+            s => panic!("expected a refusal, got {:?}", s),
+        }
+    }
+
+    #[test]
+    fn large_delta_falls_back_to_full_chunk() {
+        let listener = MemoryListener::default();
+        let mut server = test_server(Box::new(listener.clone()));
+        let mut world = server_map();
+        let mut conn = listener.connect();
+        let mut send = |conn: &mut MemoryConnection, msg: ClientMessage| {
+            conn.send(&encode(&msg).unwrap()).unwrap();
+        };
+        send(
+            &mut conn,
+            ClientMessage::Hello {
+                protocol_version: PROTOCOL_VERSION,
+                name: "raw".into(),
+                content_hash: ContentManifest::default().hash(),
+            },
+        );
+        send(
+            &mut conn,
+            ClientMessage::RequestChunks {
+                keys: vec![(0, 0, 0), (1, 0, 0)],
+            },
+        );
+        server.update(&mut world);
+        conn.receive().unwrap();
+
+        // a whole layer of one chunk changes, a single voxel in the other
+        for x in 0..16 {
+            for z in 0..16 {
+                world.set_voxel(x, 5, z, 2);
+            }
+        }
+        world.set_voxel(20, 5, 3, 2);
+        server.update(&mut world);
+
+        let msgs: Vec<ServerMessage> = conn
+            .receive()
+            .unwrap()
+            .iter()
+            .map(|b| decode(b).unwrap())
+            .collect();
+        assert_eq!(msgs.len(), 2);
+        match &msgs[0] {
+            ServerMessage::ChunkData(payload) => {
+                assert_eq!(payload.key, (0, 0, 0));
+                assert_eq!(payload.seq, 256);
+            }
+            m => panic!("expected the full chunk, got {:?}", m),
+        }
+        match &msgs[1] {
+            ServerMessage::ChunkDelta(delta) => {
+                assert_eq!(delta.key, (1, 0, 0));
+                assert_eq!((delta.base_seq, delta.seq), (0, 257));
+                assert_eq!(delta.changes.len(), 1);
+            }
+            m => panic!("expected a delta, got {:?}", m),
+        }
+    }
+
+    #[test]
+    fn requested_chunks_keep_to_the_view_radius_and_budget() {
+        let mut world = VoxelMap::new((16, 16, 16));
+        for x in -6..=6 {
+            for y in -1..=1 {
+                for z in -6..=6 {
+                    world.add_chunk(x, y, z, Chunk::new(16));
+                }
+            }
+        }
+        for x in -6 * 16..7 * 16 {
+            world.set_voxel(x, 0, x.rem_euclid(16), 6);
+        }
+        let chunk_bytes = encode(&ServerMessage::ChunkData(ChunkPayload::from_chunk(
+            (0, 0, 0),
+            &world.chunk_list[&(0, 0, 0)],
+        )))
+        .unwrap()
+        .len();
+
+        let listener = MemoryListener::default();
+        let mut server = test_server(Box::new(listener.clone()));
+        server.interest = InterestConfig {
+            view_radius: (2, 1),
+            bytes_per_tick: chunk_bytes * 3,
+        };
+        let mut conn = listener.connect();
+        for msg in [
+            ClientMessage::Hello {
+                protocol_version: PROTOCOL_VERSION,
+                name: "greedy".into(),
+                content_hash: ContentManifest::default().hash(),
+            },
+            // every chunk of the map and more
+            ClientMessage::RequestChunks {
+                keys: (-8..=8)
+                    .flat_map(|x| (-2..=2).flat_map(move |y| (-8..=8).map(move |z| (x, y, z))))
+                    .collect(),
+            },
+        ] {
+            conn.send(&encode(&msg).unwrap()).unwrap();
+        }
+
+        let (mut sent, mut unavailable) = (vec![], 0);
+        for _ in 0..40 {
+            server.update(&mut world);
+            let mut bytes = 0;
+            for b in conn.receive().unwrap() {
+                match decode(&b).unwrap() {
+                    ServerMessage::ChunkData(payload) => {
+                        // at least one chunk goes out, the last one may go over the budget
+                        assert!(bytes < server.interest.bytes_per_tick);
+                        bytes += b.len();
+                        sent.push(payload.key);
+                    }
+                    ServerMessage::ChunkUnavailable { .. } => unavailable += 1,
+                    _ => {}
+                }
+            }
+        }
+        // no position was sent, so the spawn chunk is the center
+        let config = server.interest;
+        let mut expected: Vec<ChunkPos> = world
+            .chunk_list
+            .keys()
+            .copied()
+            .filter(|k| ClientInterest::in_range((0, 0, 0), *k, &config))
+            .collect();
+        expected.sort_by_key(|k| (k.0 * k.0 + k.1 * k.1 + k.2 * k.2, *k));
+        assert_eq!(sent, expected);
+        assert_eq!(unavailable, 17 * 5 * 17 - expected.len());
+    }
+}
@@ -0,0 +1,331 @@
+// in process network that makes up latency, jitter, loss, duplication and reordering,
+// so the protocol can be tested on one machine. everything comes from one seeded rng and
+// time only moves on `SimNetwork::step`, the same seed and the same calls give the same run.
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use super::{
+    transport::{Connection, Listener},
+    NetError,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SimConfig {
+    /// steps every message takes at least.
+    pub latency: u32,
+    /// up to this many extra steps, messages still arrive in order.
+    pub jitter: u32,
+    /// chance a message is dropped, 0 to 1.
+    pub loss: f64,
+    /// chance a message arrives twice.
+    pub duplicate: f64,
+    /// chance a message is held back long enough to arrive after ones sent later.
+    pub reorder: f64,
+}
+
+impl SimConfig {
+    /// no latency and no problems, messages arrive on the next step.
+    pub fn perfect() -> Self {
+        Self {
+            latency: 0,
+            jitter: 0,
+            loss: 0.0,
+            duplicate: 0.0,
+            reorder: 0.0,
+        }
+    }
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self::perfect()
+    }
+}
+
+/// what the network did to the messages so far.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SimStats {
+    pub sent: u64,
+    pub delivered: u64,
+    pub lost: u64,
+    pub duplicated: u64,
+    pub reordered: u64,
+}
+
+/// one direction of a connection.
+#[derive(Default)]
+struct Pipe {
+    /// (due step, send order, message)
+    queue: Vec<(u64, u64, Vec<u8>)>,
+    /// due step of the last in order message, later ones are never due before it.
+    last_due: u64,
+}
+
+struct Link {
+    config: SimConfig,
+    /// client to server, server to client
+    pipes: [Pipe; 2],
+    closed: bool,
+}
+
+struct SimState {
+    rng: StdRng,
+    step: u64,
+    next_order: u64,
+    links: Vec<Link>,
+    /// server ends not accepted yet.
+    pending: VecDeque<SimConnection>,
+    stats: SimStats,
+}
+
+impl SimState {
+    fn send(&mut self, link: usize, dir: usize, msg: &[u8]) {
+        self.stats.sent += 1;
+        let config = self.links[link].config;
+        if self.rng.gen_bool(config.loss) {
+            self.stats.lost += 1;
+            return;
+        }
+        let copies = if self.rng.gen_bool(config.duplicate) {
+            self.stats.duplicated += 1;
+            2
+        } else {
+            1
+        };
+        for _ in 0..copies {
+            let base = self.step + 1 + config.latency as u64;
+            let jitter = self.rng.gen_range(0..=config.jitter) as u64;
+            let pipe = &mut self.links[link].pipes[dir];
+            let due = if self.rng.gen_bool(config.reorder) {
+                // held back past the messages that follow it
+                self.stats.reordered += 1;
+                base + jitter + 1 + self.rng.gen_range(1..=config.jitter as u64 + 2)
+            } else {
+                let due = (base + jitter).max(pipe.last_due);
+                pipe.last_due = due;
+                due
+            };
+            let order = self.next_order;
+            self.next_order += 1;
+            self.links[link].pipes[dir]
+                .queue
+                .push((due, order, msg.to_vec()));
+        }
+    }
+
+    fn receive(&mut self, link: usize, dir: usize) -> Vec<Vec<u8>> {
+        let step = self.step;
+        let pipe = &mut self.links[link].pipes[dir];
+        let mut due: Vec<(u64, u64, Vec<u8>)> = vec![];
+        let mut i = 0;
+        while i < pipe.queue.len() {
+            if pipe.queue[i].0 <= step {
+                due.push(pipe.queue.swap_remove(i));
+            } else {
+                i += 1;
+            }
+        }
+        due.sort_by_key(|m| (m.0, m.1));
+        self.stats.delivered += due.len() as u64;
+        due.into_iter().map(|m| m.2).collect()
+    }
+}
+
+/// the network, cheap to clone, every clone is the same network.
+#[derive(Clone)]
+pub struct SimNetwork {
+    state: Arc<Mutex<SimState>>,
+}
+
+impl SimNetwork {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(SimState {
+                rng: StdRng::seed_from_u64(seed),
+                step: 0,
+                next_order: 0,
+                links: vec![],
+                pending: VecDeque::new(),
+                stats: SimStats::default(),
+            })),
+        }
+    }
+
+    /// moves time forward by one step, messages due by then can be received.
+    pub fn step(&self) {
+        self.state.lock().unwrap().step += 1;
+    }
+
+    pub fn stats(&self) -> SimStats {
+        self.state.lock().unwrap().stats
+    }
+
+    /// accepts the server end of every `connect`.
+    pub fn listener(&self) -> SimListener {
+        SimListener { net: self.clone() }
+    }
+
+    /// returns the client end, the server end is accepted from the listener.
+    pub fn connect(&self, config: SimConfig) -> SimConnection {
+        let mut state = self.state.lock().unwrap();
+        let link = state.links.len();
+        state.links.push(Link {
+            config,
+            pipes: [Pipe::default(), Pipe::default()],
+            closed: false,
+        });
+        state.pending.push_back(SimConnection {
+            net: self.clone(),
+            link,
+            dir: 1,
+        });
+        SimConnection {
+            net: self.clone(),
+            link,
+            dir: 0,
+        }
+    }
+}
+
+/// one end of a link, sends on `dir` and receives on the other pipe.
+pub struct SimConnection {
+    net: SimNetwork,
+    link: usize,
+    dir: usize,
+}
+
+impl Drop for SimConnection {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.net.state.lock() {
+            state.links[self.link].closed = true;
+        }
+    }
+}
+
+impl Connection for SimConnection {
+    fn send(&mut self, msg: &[u8]) -> Result<(), NetError> {
+        let mut state = self.net.state.lock().unwrap();
+        if state.links[self.link].closed {
+            return Err(NetError::Closed);
+        }
+        state.send(self.link, self.dir, msg);
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Result<Vec<Vec<u8>>, NetError> {
+        let mut state = self.net.state.lock().unwrap();
+        let msgs = state.receive(self.link, 1 - self.dir);
+        // what was sent before the other end closed still arrives
+        let link = &state.links[self.link];
+        if msgs.is_empty() && link.closed && link.pipes[1 - self.dir].queue.is_empty() {
+            return Err(NetError::Closed);
+        }
+        Ok(msgs)
+    }
+}
+
+pub struct SimListener {
+    net: SimNetwork,
+}
+
+impl Listener for SimListener {
+    fn accept(&mut self) -> Result<Option<Box<dyn Connection>>, NetError> {
+        let mut state = self.net.state.lock().unwrap();
+        Ok(state
+            .pending
+            .pop_front()
+            .map(|c| Box::new(c) as Box<dyn Connection>))
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    fn run(config: SimConfig, seed: u64) -> (Vec<(u64, u32)>, SimStats) {
+        let net = SimNetwork::new(seed);
+        let mut client = net.connect(config);
+        let mut server = net.listener().accept().unwrap().unwrap();
+        let mut got = vec![];
+        for step in 0..200 {
+            if step < 100 {
+                client.send(&(step as u32).to_le_bytes()).unwrap();
+            }
+            net.step();
+            for msg in server.receive().unwrap() {
+                let mut b = [0; 4];
+                b.copy_from_slice(&msg);
+                got.push((step, u32::from_le_bytes(b)));
+            }
+        }
+        (got, net.stats())
+    }
+
+    #[test]
+    fn perfect_network_delivers_in_order() {
+        let (got, stats) = run(SimConfig::perfect(), 1);
+        assert_eq!(got.len(), 100);
+        assert!(got.iter().all(|(step, n)| *step == *n as u64));
+        assert_eq!(stats.delivered, 100);
+    }
+
+    #[test]
+    fn latency_and_jitter_keep_order() {
+        let config = SimConfig {
+            latency: 3,
+            jitter: 4,
+            ..SimConfig::perfect()
+        };
+        let (got, _) = run(config, 2);
+        assert_eq!(got.len(), 100);
+        assert!(got.windows(2).all(|w| w[0].1 < w[1].1));
+        assert!(got.iter().all(|(step, n)| *step >= *n as u64 + 3));
+        // jitter means not every message took the same time
+        let delays: Vec<u64> = got.iter().map(|(s, n)| s - *n as u64).collect();
+        assert!(delays.iter().any(|d| *d != delays[0]));
+    }
+
+    #[test]
+    fn loss_duplication_and_reordering() {
+        let config = SimConfig {
+            latency: 1,
+            jitter: 2,
+            loss: 0.2,
+            duplicate: 0.2,
+            reorder: 0.2,
+        };
+        let (got, stats) = run(config, 3);
+        assert!(stats.lost > 5 && stats.duplicated > 5 && stats.reordered > 5);
+        assert_eq!(stats.delivered, 100 - stats.lost + stats.duplicated);
+        assert_eq!(got.len() as u64, stats.delivered);
+        assert!(got.windows(2).any(|w| w[0].1 > w[1].1));
+    }
+
+    #[test]
+    fn same_seed_same_run() {
+        let config = SimConfig {
+            latency: 2,
+            jitter: 3,
+            loss: 0.1,
+            duplicate: 0.1,
+            reorder: 0.1,
+        };
+        assert_eq!(run(config, 7), run(config, 7));
+        assert_ne!(run(config, 7).0, run(config, 8).0);
+    }
+
+    #[test]
+    fn dropping_an_end_closes_the_link() {
+        let net = SimNetwork::new(0);
+        let client = net.connect(SimConfig::perfect());
+        let mut server = net.listener().accept().unwrap().unwrap();
+        drop(client);
+        assert!(matches!(server.receive(), Err(NetError::Closed)));
+        assert!(matches!(server.send(b"x"), Err(NetError::Closed)));
+    }
+}
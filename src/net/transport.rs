@@ -0,0 +1,388 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket},
+    sync::{Arc, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::{reliable::MAX_PACKET_SIZE, NetError};
+
+/// frames bigger then this are treated as a broken stream.
+pub const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// how a message has to arrive, streams like tcp deliver everything reliable and in order.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Channel {
+    ReliableOrdered,
+    /// every message arrives once, in any order.
+    ReliableUnordered,
+    /// messages can get lost, ones older then the newest that arrived are dropped.
+    Unreliable,
+}
+
+/// a message based connection to one peer, nothing here blocks.
+pub trait Connection: Send {
+    /// queues one message to be sent, reliable and in order.
+    fn send(&mut self, msg: &[u8]) -> Result<(), NetError>;
+    /// queues one message to be sent on `channel`.
+    fn send_on(&mut self, channel: Channel, msg: &[u8]) -> Result<(), NetError> {
+        let _ = channel;
+        self.send(msg)
+    }
+    /// every complete message received since the last call, in order.
+    fn receive(&mut self) -> Result<Vec<Vec<u8>>, NetError>;
+    /// reliable messages not known to have arrived yet, streams hand everything to the os right away.
+    fn unacked(&self) -> usize {
+        0
+    }
+}
+
+/// hands out connections to new peers.
+pub trait Listener: Send {
+    fn accept(&mut self) -> Result<Option<Box<dyn Connection>>, NetError>;
+}
+
+// tcp ------------------------------------------------------------------------------
+
+/// messages are framed with a little endian u32 length.
+pub struct TcpConnection {
+    stream: TcpStream,
+    read_buf: Vec<u8>,
+    write_buf: Vec<u8>,
+    closed: bool,
+}
+
+impl TcpConnection {
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self, NetError> {
+        Self::from_stream(TcpStream::connect(addr)?)
+    }
+
+    pub fn from_stream(stream: TcpStream) -> Result<Self, NetError> {
+        stream.set_nonblocking(true)?;
+        stream.set_nodelay(true)?;
+        Ok(Self {
+            stream,
+            read_buf: vec![],
+            write_buf: vec![],
+            closed: false,
+        })
+    }
+
+    /// writes as much of the queued data as the socket takes without blocking.
+    fn flush(&mut self) -> Result<(), NetError> {
+        while !self.write_buf.is_empty() {
+            match self.stream.write(&self.write_buf) {
+                Ok(0) => return Err(NetError::Closed),
+                Ok(n) => {
+                    self.write_buf.drain(..n);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Connection for TcpConnection {
+    fn send(&mut self, msg: &[u8]) -> Result<(), NetError> {
+        if self.closed {
+            return Err(NetError::Closed);
+        }
+        if msg.len() > MAX_FRAME_SIZE {
+            return Err(NetError::FrameTooLarge(msg.len()));
+        }
+        self.write_buf
+            .extend_from_slice(&(msg.len() as u32).to_le_bytes());
+        self.write_buf.extend_from_slice(msg);
+        self.flush()
+    }
+
+    fn receive(&mut self) -> Result<Vec<Vec<u8>>, NetError> {
+        if self.closed {
+            return Err(NetError::Closed);
+        }
+        self.flush()?;
+
+        let mut tmp = [0_u8; 64 * 1024];
+        loop {
+            match self.stream.read(&mut tmp) {
+                Ok(0) => {
+                    self.closed = true;
+                    break;
+                }
+                Ok(n) => self.read_buf.extend_from_slice(&tmp[..n]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        let mut msgs = vec![];
+        let mut start = 0;
+        while self.read_buf.len() - start >= 4 {
+            let mut len = [0_u8; 4];
+            len.copy_from_slice(&self.read_buf[start..start + 4]);
+            let len = u32::from_le_bytes(len) as usize;
+            if len > MAX_FRAME_SIZE {
+                return Err(NetError::FrameTooLarge(len));
+            }
+            if self.read_buf.len() - start - 4 < len {
+                break;
+            }
+            msgs.push(self.read_buf[start + 4..start + 4 + len].to_vec());
+            start += 4 + len;
+        }
+        self.read_buf.drain(..start);
+
+        // messages that made it before the peer hung up are still handed out
+        if self.closed && msgs.is_empty() {
+            return Err(NetError::Closed);
+        }
+        Ok(msgs)
+    }
+}
+
+pub struct TcpServerListener {
+    listener: TcpListener,
+}
+
+impl TcpServerListener {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<Self, NetError> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(Self { listener })
+    }
+
+    /// useful when bound to port 0.
+    pub fn local_addr(&self) -> Result<SocketAddr, NetError> {
+        Ok(self.listener.local_addr()?)
+    }
+}
+
+impl Listener for TcpServerListener {
+    fn accept(&mut self) -> Result<Option<Box<dyn Connection>>, NetError> {
+        match self.listener.accept() {
+            Ok((stream, _)) => Ok(Some(Box::new(TcpConnection::from_stream(stream)?))),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+// udp ------------------------------------------------------------------------------
+
+// every packet is one message, they can get lost, duplicated or reordered.
+// wrap them in a ReliableConnection, see reliable.rs.
+
+/// the client side, a socket that only talks to the server.
+pub struct UdpConnection {
+    socket: UdpSocket,
+}
+
+impl UdpConnection {
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self, NetError> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.connect(addr)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self { socket })
+    }
+}
+
+impl Connection for UdpConnection {
+    fn send(&mut self, msg: &[u8]) -> Result<(), NetError> {
+        match self.socket.send(msg) {
+            // a full send buffer is the same as a lost packet
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            // the server port is not open yet, or the server restarted
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => Ok(()),
+            Err(e) => Err(e.into()),
+            Ok(_) => Ok(()),
+        }
+    }
+
+    fn receive(&mut self) -> Result<Vec<Vec<u8>>, NetError> {
+        let mut msgs = vec![];
+        let mut buf = [0_u8; MAX_PACKET_SIZE];
+        loop {
+            match self.socket.recv(&mut buf) {
+                Ok(n) => msgs.push(buf[..n].to_vec()),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(msgs)
+    }
+}
+
+type PeerInbox = Arc<Mutex<VecDeque<Vec<u8>>>>;
+
+/// one socket for every client, packets are sorted to peers by address in `accept`.
+/// a packet from a new address is a new peer.
+pub struct UdpServerListener {
+    socket: Arc<UdpSocket>,
+    peers: BTreeMap<SocketAddr, PeerInbox>,
+    new_peers: VecDeque<UdpPeer>,
+}
+
+impl UdpServerListener {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<Self, NetError> {
+        let socket = UdpSocket::bind(addr)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket: Arc::new(socket),
+            peers: BTreeMap::new(),
+            new_peers: VecDeque::new(),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, NetError> {
+        Ok(self.socket.local_addr()?)
+    }
+}
+
+impl Listener for UdpServerListener {
+    /// reads the socket, so it has to be called before the peers are.
+    fn accept(&mut self) -> Result<Option<Box<dyn Connection>>, NetError> {
+        // peers that were dropped are forgotten, their address can connect again
+        self.peers.retain(|_, inbox| Arc::strong_count(inbox) > 1);
+
+        let mut buf = [0_u8; MAX_PACKET_SIZE];
+        loop {
+            match self.socket.recv_from(&mut buf) {
+                Ok((n, addr)) => {
+                    let inbox = match self.peers.get(&addr) {
+                        Some(inbox) => inbox.clone(),
+                        None => {
+                            let inbox = PeerInbox::default();
+                            self.peers.insert(addr, inbox.clone());
+                            self.new_peers.push_back(UdpPeer {
+                                socket: self.socket.clone(),
+                                addr,
+                                inbox: inbox.clone(),
+                            });
+                            inbox
+                        }
+                    };
+                    inbox.lock().unwrap().push_back(buf[..n].to_vec());
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                // an earlier send hit a closed port, that peer will time out
+                Err(e) if e.kind() == io::ErrorKind::ConnectionReset => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(self
+            .new_peers
+            .pop_front()
+            .map(|p| Box::new(p) as Box<dyn Connection>))
+    }
+}
+
+pub struct UdpPeer {
+    socket: Arc<UdpSocket>,
+    addr: SocketAddr,
+    inbox: PeerInbox,
+}
+
+impl Connection for UdpPeer {
+    fn send(&mut self, msg: &[u8]) -> Result<(), NetError> {
+        match self.socket.send_to(msg, self.addr) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            Err(e) => Err(e.into()),
+            Ok(_) => Ok(()),
+        }
+    }
+
+    fn receive(&mut self) -> Result<Vec<Vec<u8>>, NetError> {
+        Ok(self.inbox.lock().unwrap().drain(..).collect())
+    }
+}
+
+// memory ---------------------------------------------------------------------------
+
+type MessageQueue = Arc<Mutex<VecDeque<Vec<u8>>>>;
+
+/// in process connection, messages arrive on the next receive. used for tests and simulations.
+pub struct MemoryConnection {
+    incoming: MessageQueue,
+    outgoing: MessageQueue,
+    /// shared by both ends, set when either end is dropped.
+    closed: Arc<Mutex<bool>>,
+}
+
+impl MemoryConnection {
+    pub fn pair() -> (Self, Self) {
+        let a: MessageQueue = Default::default();
+        let b: MessageQueue = Default::default();
+        let closed = Arc::new(Mutex::new(false));
+        (
+            Self {
+                incoming: a.clone(),
+                outgoing: b.clone(),
+                closed: closed.clone(),
+            },
+            Self {
+                incoming: b,
+                outgoing: a,
+                closed,
+            },
+        )
+    }
+}
+
+impl Drop for MemoryConnection {
+    fn drop(&mut self) {
+        *self.closed.lock().unwrap() = true;
+    }
+}
+
+impl Connection for MemoryConnection {
+    fn send(&mut self, msg: &[u8]) -> Result<(), NetError> {
+        if *self.closed.lock().unwrap() {
+            return Err(NetError::Closed);
+        }
+        self.outgoing.lock().unwrap().push_back(msg.to_vec());
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Result<Vec<Vec<u8>>, NetError> {
+        let msgs: Vec<Vec<u8>> = self.incoming.lock().unwrap().drain(..).collect();
+        if msgs.is_empty() && *self.closed.lock().unwrap() {
+            return Err(NetError::Closed);
+        }
+        Ok(msgs)
+    }
+}
+
+/// hands out the server ends of connections made with `connect`.
+#[derive(Default, Clone)]
+pub struct MemoryListener {
+    pending: Arc<Mutex<VecDeque<MemoryConnection>>>,
+}
+
+impl MemoryListener {
+    /// returns the client end, the server end is accepted on the next update.
+    pub fn connect(&self) -> MemoryConnection {
+        let (client, server) = MemoryConnection::pair();
+        self.pending.lock().unwrap().push_back(server);
+        client
+    }
+}
+
+impl Listener for MemoryListener {
+    fn accept(&mut self) -> Result<Option<Box<dyn Connection>>, NetError> {
+        Ok(self
+            .pending
+            .lock()
+            .unwrap()
+            .pop_front()
+            .map(|c| Box::new(c) as Box<dyn Connection>))
+    }
+}
@@ -0,0 +1,327 @@
+// the server checks every edit a client sends before it touches the map, a client can send anything.
+// each rule is its own check so it can be tested alone, `VoxelServer` runs them in order.
+
+use serde::Deserialize;
+use thiserror::Error;
+
+use super::{
+    content::ContentManifest,
+    protocol::{ChunkPos, PlayerState, VoxelPos},
+};
+use crate::voxel::voxel::VoxelMap;
+
+/// why an edit was not applied, the message is sent to the client.
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum EditRejection {
+    #[error("too many edits, at most {0} per second")]
+    RateLimited(f32),
+    #[error("no player position yet")]
+    PositionUnknown,
+    #[error("voxel is {distance:.1} away, the reach is {reach:.1}")]
+    OutOfReach { distance: f32, reach: f32 },
+    #[error("chunk {0:?} is not loaded")]
+    ChunkNotLoaded(ChunkPos),
+    #[error("block {0} does not exist")]
+    UnknownBlock(u16),
+    #[error("block {0:?} can not be placed")]
+    BlockNotAllowed(String),
+    #[error("voxel {0:?} was changed")]
+    VoxelChanged(VoxelPos),
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct EditRules {
+    /// furthest voxel center from the player position a client can edit, None for anywhere.
+    pub reach: Option<f32>,
+    /// edits a client can make per second on average.
+    pub edits_per_second: f32,
+    /// edits a client can make at once after not editing for a while.
+    pub burst: u32,
+    /// names of the blocks clients can place, None for every block. air can always be placed.
+    pub allowed_blocks: Option<Vec<String>>,
+}
+
+impl Default for EditRules {
+    fn default() -> Self {
+        Self {
+            reach: Some(8.0),
+            edits_per_second: 20.0,
+            burst: 10,
+            allowed_blocks: None,
+        }
+    }
+}
+
+impl EditRules {
+    pub fn check_reach(
+        &self,
+        player: Option<&PlayerState>,
+        pos: VoxelPos,
+    ) -> Result<(), EditRejection> {
+        let reach = match self.reach {
+            Some(r) => r,
+            None => return Ok(()),
+        };
+        let p = player.ok_or(EditRejection::PositionUnknown)?.pos;
+        let d = (
+            pos.0 as f32 + 0.5 - p.0,
+            pos.1 as f32 + 0.5 - p.1,
+            pos.2 as f32 + 0.5 - p.2,
+        );
+        let distance = (d.0 * d.0 + d.1 * d.1 + d.2 * d.2).sqrt();
+        // a NaN distance is never in reach
+        if !distance.is_finite() || distance > reach {
+            return Err(EditRejection::OutOfReach { distance, reach });
+        }
+        Ok(())
+    }
+
+    /// `id` has to be a block of `content`, and an allowed one.
+    pub fn check_block(&self, id: u16, content: &ContentManifest) -> Result<(), EditRejection> {
+        if id == 0 {
+            return Ok(());
+        }
+        let name = content
+            .blocks
+            .get(id as usize - 1)
+            .ok_or(EditRejection::UnknownBlock(id))?;
+        match &self.allowed_blocks {
+            Some(allowed) if !allowed.contains(name) => {
+                Err(EditRejection::BlockNotAllowed(name.clone()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+pub fn check_loaded(map: &VoxelMap, pos: VoxelPos) -> Result<(), EditRejection> {
+    let key = map.chunk_key(pos.0, pos.1, pos.2);
+    if !map.chunk_list.contains_key(&key) {
+        return Err(EditRejection::ChunkNotLoaded(key));
+    }
+    Ok(())
+}
+
+/// per client token bucket, refilled with server time so it does not depend on the wall clock.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EditBudget {
+    /// None until the first edit, the bucket starts full.
+    tokens: Option<f64>,
+    /// seconds at the last edit.
+    last: f64,
+}
+
+impl EditBudget {
+    pub fn new() -> Self {
+        Self {
+            tokens: None,
+            last: 0.0,
+        }
+    }
+
+    /// takes one edit from the budget, `now` is in seconds.
+    pub fn take(&mut self, rules: &EditRules, now: f64) -> Result<(), EditRejection> {
+        let burst = rules.burst as f64;
+        let refill = (now - self.last) * rules.edits_per_second as f64;
+        let tokens = self.tokens.map_or(burst, |t| (t + refill).min(burst));
+        self.last = now;
+        if tokens < 1.0 {
+            self.tokens = Some(tokens);
+            return Err(EditRejection::RateLimited(rules.edits_per_second));
+        }
+        self.tokens = Some(tokens - 1.0);
+        Ok(())
+    }
+}
+
+impl Default for EditBudget {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// where players start, and where a client is until it sent its first position.
+pub const SPAWN_POSITION: (f32, f32, f32) = (0.0, 0.0, 0.0);
+
+/// how fast a player position can move in blocks per second, a client can claim any position.
+pub const MAX_PLAYER_SPEED: f32 = 50.0;
+
+/// per client distance bucket like `EditBudget`, a second of movement can be saved up.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MoveBudget {
+    /// None until the first move, the bucket starts full.
+    distance: Option<f32>,
+    /// seconds at the last move.
+    last: f64,
+}
+
+impl MoveBudget {
+    pub fn new() -> Self {
+        Self {
+            distance: None,
+            last: 0.0,
+        }
+    }
+
+    /// where a player at `from` ends up trying to get to `to`, cut short when it is too far.
+    /// None when `to` is not a position, `now` is in seconds.
+    pub fn take(
+        &mut self,
+        from: (f32, f32, f32),
+        to: (f32, f32, f32),
+        now: f64,
+    ) -> Option<(f32, f32, f32)> {
+        if !(to.0.is_finite() && to.1.is_finite() && to.2.is_finite()) {
+            return None;
+        }
+        let refill = (now - self.last) as f32 * MAX_PLAYER_SPEED;
+        let budget = self
+            .distance
+            .map_or(MAX_PLAYER_SPEED, |d| (d + refill).min(MAX_PLAYER_SPEED));
+        self.last = now;
+        let d = (to.0 - from.0, to.1 - from.1, to.2 - from.2);
+        let distance = (d.0 * d.0 + d.1 * d.1 + d.2 * d.2).sqrt();
+        if distance <= budget {
+            self.distance = Some(budget - distance);
+            return Some(to);
+        }
+        self.distance = Some(0.0);
+        let t = budget / distance;
+        Some((from.0 + d.0 * t, from.1 + d.1 * t, from.2 + d.2 * t))
+    }
+}
+
+impl Default for MoveBudget {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::voxel::voxel::Chunk;
+
+    #[test]
+    fn reach_is_measured_to_the_voxel_center() {
+        let rules = EditRules {
+            reach: Some(4.0),
+            ..EditRules::default()
+        };
+        let player = PlayerState::at((0.5, 0.5, 0.5));
+        assert!(rules.check_reach(Some(&player), (4, 0, 0)).is_ok());
+        assert!(rules.check_reach(Some(&player), (-4, 0, 0)).is_ok());
+        assert!(matches!(
+            rules.check_reach(Some(&player), (3, 3, 0)),
+            Err(EditRejection::OutOfReach { .. })
+        ));
+        assert_eq!(
+            rules.check_reach(None, (0, 0, 0)),
+            Err(EditRejection::PositionUnknown)
+        );
+
+        let nowhere = PlayerState::at((f32::NAN, 0.5, 0.5));
+        assert!(matches!(
+            rules.check_reach(Some(&nowhere), (0, 0, 0)),
+            Err(EditRejection::OutOfReach { .. })
+        ));
+
+        let anywhere = EditRules {
+            reach: None,
+            ..rules
+        };
+        assert!(anywhere.check_reach(None, (1000, 0, 0)).is_ok());
+    }
+
+    #[test]
+    fn only_loaded_chunks_can_be_edited() {
+        let mut map = VoxelMap::new((16, 16, 16));
+        map.add_chunk(0, 0, 0, Chunk::new(16));
+        assert!(check_loaded(&map, (15, 15, 15)).is_ok());
+        assert_eq!(
+            check_loaded(&map, (-1, 0, 0)),
+            Err(EditRejection::ChunkNotLoaded((-1, 0, 0)))
+        );
+        assert_eq!(
+            check_loaded(&map, (0, 16, 0)),
+            Err(EditRejection::ChunkNotLoaded((0, 1, 0)))
+        );
+    }
+
+    #[test]
+    fn blocks_have_to_exist_and_be_allowed() {
+        let content = ContentManifest::default();
+        let rules = EditRules::default();
+        assert!(rules.check_block(0, &content).is_ok());
+        let last = content.blocks.len() as u16;
+        assert!(rules.check_block(last, &content).is_ok());
+        assert_eq!(
+            rules.check_block(last + 1, &content),
+            Err(EditRejection::UnknownBlock(last + 1))
+        );
+
+        let rules = EditRules {
+            allowed_blocks: Some(vec!["dirt".into()]),
+            ..rules
+        };
+        assert!(rules.check_block(1, &content).is_ok());
+        assert!(rules.check_block(0, &content).is_ok());
+        assert_eq!(
+            rules.check_block(16, &content),
+            Err(EditRejection::BlockNotAllowed("glass".into()))
+        );
+    }
+
+    #[test]
+    fn budget_allows_a_burst_then_the_rate() {
+        let rules = EditRules {
+            edits_per_second: 8.0,
+            burst: 3,
+            ..EditRules::default()
+        };
+        let mut budget = EditBudget::new();
+        for _ in 0..3 {
+            assert!(budget.take(&rules, 5.0).is_ok());
+        }
+        assert_eq!(
+            budget.take(&rules, 5.0),
+            Err(EditRejection::RateLimited(8.0))
+        );
+        // one edit comes back every 0.125 seconds
+        assert!(budget.take(&rules, 5.0625).is_err());
+        assert!(budget.take(&rules, 5.125).is_ok());
+        assert!(budget.take(&rules, 5.125).is_err());
+
+        // a long pause does not save up more than the burst
+        for _ in 0..3 {
+            assert!(budget.take(&rules, 50.0).is_ok());
+        }
+        assert!(budget.take(&rules, 50.0).is_err());
+    }
+
+    #[test]
+    fn moves_are_cut_to_the_speed() {
+        let mut budget = MoveBudget::new();
+        assert_eq!(budget.take(SPAWN_POSITION, (f32::NAN, 0.0, 0.0), 1.0), None);
+        assert_eq!(
+            budget.take(SPAWN_POSITION, (0.0, f32::INFINITY, 0.0), 1.0),
+            None
+        );
+        // the first second of movement is there from the start
+        let at = budget
+            .take(SPAWN_POSITION, (1000.0, 0.0, 0.0), 1.0)
+            .unwrap();
+        assert_eq!(at, (MAX_PLAYER_SPEED, 0.0, 0.0));
+        assert_eq!(budget.take(at, (1000.0, 0.0, 0.0), 1.0), Some(at));
+        let at = budget.take(at, (1000.0, 0.0, 0.0), 1.5).unwrap();
+        assert_eq!(at, (MAX_PLAYER_SPEED * 1.5, 0.0, 0.0));
+        // short moves use up the budget bit by bit
+        let mut budget = MoveBudget::new();
+        let mut at = SPAWN_POSITION;
+        for _ in 0..10 {
+            at = budget.take(at, (at.0, at.1 + 10.0, at.2), 0.0).unwrap();
+        }
+        assert_eq!(at, (0.0, MAX_PLAYER_SPEED, 0.0));
+    }
+}
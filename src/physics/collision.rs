@@ -0,0 +1,273 @@
+// swept box against the voxel grid. the move is done one axis at a time, y first, so a box sliding
+// along a wall or over a floor is only stopped on the axis it hits. every voxel but air and fluids is solid,
+// voxel (x, y, z) fills the unit cube from (x, y, z) to (x + 1, y + 1, z + 1).
+
+use prism_math::Vec3;
+
+use crate::voxel::voxel::VoxelMap;
+
+/// boxes closer then this count as touching, so rounding errors do not let a box sink into a wall.
+pub const SKIN: f32 = 1e-4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Aabb {
+    pub fn new(min: Vec3, max: Vec3) -> Self {
+        Self { min, max }
+    }
+
+    /// a box standing on `feet`, `half_width` to each side on x and z.
+    pub fn from_feet(feet: Vec3, half_width: f32, height: f32) -> Self {
+        Self {
+            min: feet - Vec3::new(half_width, 0.0, half_width),
+            max: feet + Vec3::new(half_width, height, half_width),
+        }
+    }
+
+    /// center of the bottom face.
+    pub fn feet(&self) -> Vec3 {
+        Vec3::new(
+            (self.min.x + self.max.x) * 0.5,
+            self.min.y,
+            (self.min.z + self.max.z) * 0.5,
+        )
+    }
+
+    pub fn translate(&self, d: Vec3) -> Self {
+        Self {
+            min: self.min + d,
+            max: self.max + d,
+        }
+    }
+}
+
+/// fluids are walked through.
+pub fn is_solid(map: &VoxelMap, x: i32, y: i32, z: i32) -> bool {
+    map.blocks.is_solid(map.get_voxel(x, y, z))
+}
+
+/// where a move ended and on which axes it was stopped.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sweep {
+    pub aabb: Aabb,
+    /// the part of the move that happened.
+    pub moved: Vec3,
+    pub hit: [bool; 3],
+}
+
+/// moves `aabb` by `delta` until it touches solid voxels, the rest of the move on that axis is dropped.
+/// the box is never moved into a voxel, however long the move.
+pub fn sweep(map: &VoxelMap, aabb: &Aabb, delta: Vec3) -> Sweep {
+    let mut aabb = *aabb;
+    let mut moved = Vec3::ZERO;
+    let mut hit = [false; 3];
+    for axis in [1, 0, 2] {
+        let wanted = delta[axis];
+        if wanted == 0.0 {
+            continue;
+        }
+        let d = clip_axis(map, &aabb, axis, wanted);
+        hit[axis] = d != wanted;
+        let mut step = Vec3::ZERO;
+        step[axis] = d;
+        aabb = aabb.translate(step);
+        moved[axis] = d;
+    }
+    Sweep { aabb, moved, hit }
+}
+
+/// how far `aabb` can move on `axis`, at most `d`.
+fn clip_axis(map: &VoxelMap, aabb: &Aabb, axis: usize, d: f32) -> f32 {
+    // voxels the box passes through, only the ones overlapping it on the other two axes matter
+    let mut lo = [0; 3];
+    let mut hi = [0; 3];
+    for a in 0..3 {
+        let (min, max) = if a == axis {
+            (
+                aabb.min[a].min(aabb.min[a] + d),
+                aabb.max[a].max(aabb.max[a] + d),
+            )
+        } else {
+            (aabb.min[a] + SKIN, aabb.max[a] - SKIN)
+        };
+        lo[a] = min.floor() as i32;
+        hi[a] = max.ceil() as i32 - 1;
+    }
+
+    let mut d = d;
+    for x in lo[0]..=hi[0] {
+        for y in lo[1]..=hi[1] {
+            for z in lo[2]..=hi[2] {
+                if !is_solid(map, x, y, z) {
+                    continue;
+                }
+                let v = [x, y, z][axis] as f32;
+                if d > 0.0 && v >= aabb.max[axis] - SKIN {
+                    d = d.min(v - aabb.max[axis]).max(0.0);
+                } else if d < 0.0 && v + 1.0 <= aabb.min[axis] + SKIN {
+                    d = d.max(v + 1.0 - aabb.min[axis]).min(0.0);
+                }
+            }
+        }
+    }
+    d
+}
+
+/// true if the box overlaps any solid voxel.
+pub fn overlaps_solid(map: &VoxelMap, aabb: &Aabb) -> bool {
+    let lo = (aabb.min + Vec3::splat(SKIN)).floor();
+    let hi = (aabb.max - Vec3::splat(SKIN)).ceil();
+    for x in lo.x as i32..hi.x as i32 {
+        for y in lo.y as i32..hi.y as i32 {
+            for z in lo.z as i32..hi.z as i32 {
+                if is_solid(map, x, y, z) {
+                    return true;
+                }
+            }
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::voxel::{block::WATER, voxel::Chunk};
+
+    /// chunks -1 to 0 on every axis, all air.
+    fn empty_map() -> VoxelMap {
+        let mut map = VoxelMap::new((16, 16, 16));
+        for x in -1..=0 {
+            for y in -1..=0 {
+                for z in -1..=0 {
+                    map.add_chunk(x, y, z, Chunk::new(16));
+                }
+            }
+        }
+        map
+    }
+
+    fn player(feet: (f32, f32, f32)) -> Aabb {
+        Aabb::from_feet(Vec3::new(feet.0, feet.1, feet.2), 0.3, 1.8)
+    }
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-3
+    }
+
+    #[test]
+    fn falls_onto_the_floor() {
+        let mut map = empty_map();
+        map.set_voxel(2, 0, 2, 1);
+        let s = sweep(&map, &player((2.5, 3.0, 2.5)), Vec3::new(0.0, -10.0, 0.0));
+        assert!(close(s.aabb.min.y, 1.0));
+        assert_eq!(s.hit, [false, true, false]);
+
+        // standing on it, a move down goes nowhere
+        let s = sweep(&map, &s.aabb, Vec3::new(0.0, -0.5, 0.0));
+        assert!(close(s.moved.y, 0.0) && s.hit[1]);
+    }
+
+    #[test]
+    fn edge_of_a_block_holds_the_box() {
+        let mut map = empty_map();
+        map.set_voxel(2, 0, 2, 1);
+        // only 0.1 of the box is over the block
+        let s = sweep(&map, &player((3.2, 3.0, 2.5)), Vec3::new(0.0, -10.0, 0.0));
+        assert!(close(s.aabb.min.y, 1.0));
+        // a box touching the side of the block is not held by it
+        let s = sweep(&map, &player((3.3, 3.0, 2.5)), Vec3::new(0.0, -10.0, 0.0));
+        assert!(!s.hit[1]);
+    }
+
+    #[test]
+    fn ceiling_stops_the_head() {
+        let mut map = empty_map();
+        map.set_voxel(0, 4, 0, 1);
+        let s = sweep(&map, &player((0.5, 1.0, 0.5)), Vec3::new(0.0, 3.0, 0.0));
+        assert!(close(s.aabb.max.y, 4.0));
+        assert!(close(s.moved.y, 1.2));
+        assert!(s.hit[1]);
+    }
+
+    #[test]
+    fn slides_along_a_wall() {
+        let mut map = empty_map();
+        for z in 0..6 {
+            for y in 0..2 {
+                map.set_voxel(3, y, z, 1);
+            }
+        }
+        let s = sweep(&map, &player((2.0, 0.0, 1.0)), Vec3::new(2.0, 0.0, 3.0));
+        assert!(close(s.aabb.max.x, 3.0));
+        assert!(close(s.moved.z, 3.0));
+        assert_eq!(s.hit, [true, false, false]);
+    }
+
+    #[test]
+    fn inside_corner_stops_both_axes() {
+        let mut map = empty_map();
+        for i in 0..4 {
+            map.set_voxel(4, 0, i, 1);
+            map.set_voxel(i, 0, 4, 1);
+        }
+        let s = sweep(&map, &player((2.0, 0.0, 2.0)), Vec3::new(5.0, 0.0, 5.0));
+        assert!(close(s.aabb.max.x, 4.0) && close(s.aabb.max.z, 4.0));
+        assert_eq!(s.hit, [true, false, true]);
+    }
+
+    #[test]
+    fn outside_corner_does_not_catch() {
+        let mut map = empty_map();
+        map.set_voxel(3, 0, 3, 1);
+        // passes the corner of the block with the box edge exactly on its side
+        let s = sweep(&map, &player((2.7, 0.0, 1.0)), Vec3::new(0.0, 0.0, 5.0));
+        assert!(close(s.moved.z, 5.0));
+        assert_eq!(s.hit, [false, false, false]);
+        // a little further in and it hits the block face
+        let s = sweep(&map, &player((2.8, 0.0, 1.0)), Vec3::new(0.0, 0.0, 5.0));
+        assert!(close(s.aabb.max.z, 3.0) && s.hit[2]);
+    }
+
+    #[test]
+    fn blocks_across_chunk_borders() {
+        let mut map = empty_map();
+        map.add_chunk(1, 0, 0, Chunk::new(16));
+        // the box is in chunk 0, the wall in chunk 1
+        map.set_voxel(16, 1, 5, 1);
+        let s = sweep(&map, &player((15.0, 1.0, 5.5)), Vec3::new(4.0, 0.0, 0.0));
+        assert!(close(s.aabb.max.x, 16.0) && s.hit[0]);
+
+        // the box is in chunk 0, the floor in chunk -1
+        map.set_voxel(0, -1, 0, 1);
+        let s = sweep(&map, &player((0.5, 2.0, 0.5)), Vec3::new(0.0, -5.0, 0.0));
+        assert!(close(s.aabb.min.y, 0.0) && s.hit[1]);
+
+        // standing over 4 chunks, the only solid voxel is in chunk (-1, -1, -1)
+        map.set_voxel(0, -1, 0, 0);
+        map.set_voxel(-1, -1, -1, 1);
+        let s = sweep(&map, &player((0.0, 2.0, 0.0)), Vec3::new(0.0, -5.0, 0.0));
+        assert!(close(s.aabb.min.y, 0.0) && s.hit[1]);
+
+        // negative coordinates moving the other way
+        map.add_chunk(-2, 0, -1, Chunk::new(16));
+        map.set_voxel(-17, 1, -3, 1);
+        let s = sweep(&map, &player((-14.0, 1.0, -2.5)), Vec3::new(-5.0, 0.0, 0.0));
+        assert!(close(s.aabb.min.x, -16.0) && s.hit[0]);
+    }
+
+    #[test]
+    fn overlap_ignores_touching_faces() {
+        let mut map = empty_map();
+        map.set_voxel(0, 0, 0, 1);
+        assert!(!overlaps_solid(&map, &player((0.5, 1.0, 0.5))));
+        assert!(!overlaps_solid(&map, &player((1.3, 0.0, 0.5))));
+        assert!(overlaps_solid(&map, &player((0.5, 0.9, 0.5))));
+        map.set_voxel(0, 0, 0, WATER);
+        assert!(!overlaps_solid(&map, &player((0.5, 0.9, 0.5))));
+    }
+}
@@ -0,0 +1,6 @@
+// walking movement against the voxel world. collision.rs and walker.rs do not depend on bevy
+// so they can be tested without an app, plugin.rs moves the camera with them.
+
+pub mod collision;
+pub mod plugin;
+pub mod walker;
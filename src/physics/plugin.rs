@@ -0,0 +1,148 @@
+use bevy::{
+    core::Time,
+    input::{mouse::MouseMotion, Input},
+    math::{Quat, Vec3},
+    prelude::{App, Component, EventReader, KeyCode, Plugin, Query, Res, Transform},
+};
+use bevy_fly_camera::FlyCamera;
+
+use super::{
+    collision::overlaps_solid,
+    walker::{WalkConfig, WalkInput, Walker},
+};
+use crate::voxel::voxel::VolumeMap;
+
+/// switches the camera between flying and walking.
+pub const TOGGLE_MOVE_MODE: KeyCode = KeyCode::F;
+
+/// a long frame is cut to this, so a hitch does not throw the body around.
+const MAX_STEP_SECONDS: f32 = 0.1;
+
+/// moves a camera that also has a FlyCamera with the walker while it is in walk mode.
+pub struct PhysicsPlugin;
+
+impl Plugin for PhysicsPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(WalkConfig::default())
+            .add_system(toggle_move_mode)
+            .add_system(walk_look)
+            .add_system(walk);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MoveMode {
+    /// bevy_fly_camera moves the camera, through everything.
+    Fly,
+    Walk,
+}
+
+#[derive(Component)]
+pub struct PlayerBody {
+    pub mode: MoveMode,
+    pub walker: Walker,
+}
+
+impl Default for PlayerBody {
+    fn default() -> Self {
+        Self {
+            mode: MoveMode::Fly,
+            walker: Walker::new(Vec3::ZERO),
+        }
+    }
+}
+
+fn toggle_move_mode(
+    keys: Res<Input<KeyCode>>,
+    config: Res<WalkConfig>,
+    mut bodies: Query<(&Transform, &mut FlyCamera, &mut PlayerBody)>,
+    maps: Query<&VolumeMap>,
+) {
+    if !keys.just_pressed(TOGGLE_MOVE_MODE) {
+        return;
+    }
+    let map = match maps.iter().next() {
+        Some(m) => &m.val,
+        None => return,
+    };
+    for (transform, mut fly, mut body) in bodies.iter_mut() {
+        match body.mode {
+            MoveMode::Fly => {
+                let eye = transform.translation;
+                let walker = Walker::new(eye - Vec3::new(0.0, config.eye_height, 0.0));
+                if overlaps_solid(map, &walker.aabb(&config)) {
+                    println!("can not walk here, the body would be inside blocks");
+                    continue;
+                }
+                body.walker = walker;
+                body.mode = MoveMode::Walk;
+                fly.enabled = false;
+            }
+            MoveMode::Walk => {
+                body.mode = MoveMode::Fly;
+                fly.velocity = Vec3::ZERO;
+                fly.enabled = true;
+            }
+        }
+    }
+}
+
+/// the fly camera does not turn while it is disabled, so walking turns it the same way.
+fn walk_look(
+    time: Res<Time>,
+    mut motion: EventReader<MouseMotion>,
+    mut bodies: Query<(&mut Transform, &mut FlyCamera, &PlayerBody)>,
+) {
+    let delta = motion
+        .iter()
+        .fold(Vec3::ZERO, |d, m| d + m.delta.extend(0.0));
+    for (mut transform, mut fly, body) in bodies.iter_mut() {
+        if body.mode != MoveMode::Walk {
+            continue;
+        }
+        fly.yaw -= delta.x * fly.sensitivity * time.delta_seconds();
+        fly.pitch =
+            (fly.pitch + delta.y * fly.sensitivity * time.delta_seconds()).clamp(-89.0, 89.9);
+        transform.rotation = Quat::from_axis_angle(Vec3::Y, fly.yaw.to_radians())
+            * Quat::from_axis_angle(-Vec3::X, fly.pitch.to_radians());
+    }
+}
+
+fn walk(
+    time: Res<Time>,
+    keys: Res<Input<KeyCode>>,
+    config: Res<WalkConfig>,
+    mut bodies: Query<(&mut Transform, &FlyCamera, &mut PlayerBody)>,
+    maps: Query<&VolumeMap>,
+) {
+    let map = match maps.iter().next() {
+        Some(m) => &m.val,
+        None => return,
+    };
+    let dt = time.delta_seconds().min(MAX_STEP_SECONDS);
+    for (mut transform, fly, mut body) in bodies.iter_mut() {
+        if body.mode != MoveMode::Walk {
+            continue;
+        }
+        // the same keys as flying, on the ground plane
+        let forward = Quat::from_axis_angle(Vec3::Y, fly.yaw.to_radians()) * -Vec3::Z;
+        let right = Vec3::new(-forward.z, 0.0, forward.x);
+        let mut dir = Vec3::ZERO;
+        for (key, d) in [
+            (fly.key_forward, forward),
+            (fly.key_backward, -forward),
+            (fly.key_right, right),
+            (fly.key_left, -right),
+        ] {
+            if keys.pressed(key) {
+                dir += d;
+            }
+        }
+        let input = WalkInput {
+            direction: (dir.x, dir.z),
+            jump: keys.pressed(KeyCode::Space),
+        };
+        body.walker.step(map, &config, input, dt);
+        transform.translation = body.walker.eye(&config);
+    }
+}
@@ -0,0 +1,293 @@
+// a walking body: gravity, jumping and stepping up onto single blocks, moved with `collision::sweep`.
+
+use prism_math::Vec3;
+
+use super::collision::{sweep, Aabb, Sweep};
+use crate::voxel::voxel::VoxelMap;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WalkConfig {
+    /// x and z distance from the center to the sides of the body.
+    pub half_width: f32,
+    pub height: f32,
+    /// camera height above the feet.
+    pub eye_height: f32,
+    /// units per second.
+    pub walk_speed: f32,
+    /// units per second squared.
+    pub gravity: f32,
+    /// upward speed a jump starts with.
+    pub jump_speed: f32,
+    pub max_fall_speed: f32,
+    /// highest ledge walked onto without jumping.
+    pub step_height: f32,
+}
+
+impl Default for WalkConfig {
+    fn default() -> Self {
+        Self {
+            half_width: 0.3,
+            height: 1.8,
+            eye_height: 1.6,
+            walk_speed: 4.5,
+            gravity: 28.0,
+            // a bit over one block high
+            jump_speed: 9.0,
+            max_fall_speed: 50.0,
+            step_height: 1.0,
+        }
+    }
+}
+
+/// what the player wants this step.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct WalkInput {
+    /// x and z direction, scaled by the walk speed, longer then 1 is cut to 1.
+    pub direction: (f32, f32),
+    pub jump: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Walker {
+    /// center of the bottom of the body.
+    pub feet: Vec3,
+    pub velocity: Vec3,
+    pub on_ground: bool,
+}
+
+impl Walker {
+    pub fn new(feet: Vec3) -> Self {
+        Self {
+            feet,
+            velocity: Vec3::ZERO,
+            on_ground: false,
+        }
+    }
+
+    pub fn aabb(&self, config: &WalkConfig) -> Aabb {
+        Aabb::from_feet(self.feet, config.half_width, config.height)
+    }
+
+    pub fn eye(&self, config: &WalkConfig) -> Vec3 {
+        self.feet + Vec3::new(0.0, config.eye_height, 0.0)
+    }
+
+    /// moves the body `dt` seconds. it does not move while the chunk it is in is not loaded,
+    /// so it does not fall through a world that is still streaming in.
+    pub fn step(&mut self, map: &VoxelMap, config: &WalkConfig, input: WalkInput, dt: f32) {
+        let f = self.feet;
+        let key = map.chunk_key(f.x.floor() as i32, f.y.floor() as i32, f.z.floor() as i32);
+        if !map.chunk_list.contains_key(&key) {
+            return;
+        }
+
+        let mut dir = Vec3::new(input.direction.0, 0.0, input.direction.1);
+        if dir.length_squared() > 1.0 {
+            dir = dir.normalize();
+        }
+        self.velocity.x = dir.x * config.walk_speed;
+        self.velocity.z = dir.z * config.walk_speed;
+        if input.jump && self.on_ground {
+            self.velocity.y = config.jump_speed;
+        }
+        self.velocity.y = (self.velocity.y - config.gravity * dt).max(-config.max_fall_speed);
+
+        let aabb = self.aabb(config);
+        let delta = self.velocity * dt;
+        let mut moved = sweep(map, &aabb, delta);
+        let blocked = moved.hit[0] || moved.hit[2];
+        if self.on_ground && blocked && delta.y <= 0.0 {
+            if let Some(stepped) = Self::step_up(map, config, &aabb, delta) {
+                moved = stepped;
+            }
+        }
+
+        self.feet = moved.aabb.feet();
+        self.on_ground = moved.hit[1] && delta.y < 0.0;
+        for axis in 0..3 {
+            if moved.hit[axis] {
+                self.velocity[axis] = 0.0;
+            }
+        }
+    }
+
+    /// the same move lifted by the step height and put down again, None if that gets no further.
+    fn step_up(map: &VoxelMap, config: &WalkConfig, aabb: &Aabb, delta: Vec3) -> Option<Sweep> {
+        let up = sweep(map, aabb, Vec3::new(0.0, config.step_height, 0.0));
+        let across = sweep(map, &up.aabb, Vec3::new(delta.x, 0.0, delta.z));
+        let down = sweep(
+            map,
+            &across.aabb,
+            Vec3::new(0.0, -up.moved.y + delta.y, 0.0),
+        );
+        let flat = sweep(map, aabb, Vec3::new(delta.x, 0.0, delta.z));
+        let gained = |s: &Sweep| s.moved.x.powi(2) + s.moved.z.powi(2);
+        // it has to land on something, not step off into the air
+        if !down.hit[1] || gained(&across) <= gained(&flat) {
+            return None;
+        }
+        Some(Sweep {
+            aabb: down.aabb,
+            moved: down.aabb.min - aabb.min,
+            hit: [across.hit[0], true, across.hit[2]],
+        })
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::voxel::voxel::Chunk;
+
+    /// a floor at y 0 over chunks -1 to 1 on x and z.
+    fn floor() -> VoxelMap {
+        let mut map = VoxelMap::new((16, 16, 16));
+        for x in -1..=1 {
+            for z in -1..=1 {
+                map.add_chunk(x, 0, z, Chunk::new(16));
+                map.add_chunk(x, -1, z, Chunk::new(16));
+            }
+        }
+        for x in -16..32 {
+            for z in -16..32 {
+                map.set_voxel(x, 0, z, 1);
+            }
+        }
+        map
+    }
+
+    /// runs `steps` of a 60th of a second, returns the highest the feet got.
+    fn run(walker: &mut Walker, map: &VoxelMap, input: WalkInput, steps: usize) -> f32 {
+        let config = WalkConfig::default();
+        let mut top = walker.feet.y;
+        for _ in 0..steps {
+            walker.step(map, &config, input, 1.0 / 60.0);
+            top = top.max(walker.feet.y);
+        }
+        top
+    }
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-3
+    }
+
+    fn forward() -> WalkInput {
+        WalkInput {
+            direction: (1.0, 0.0),
+            jump: false,
+        }
+    }
+
+    #[test]
+    fn falls_and_lands() {
+        let map = floor();
+        let mut walker = Walker::new(Vec3::new(0.5, 5.0, 0.5));
+        run(&mut walker, &map, WalkInput::default(), 60);
+        assert!(walker.on_ground);
+        assert!(close(walker.feet.y, 1.0));
+        assert_eq!(walker.velocity.y, 0.0);
+    }
+
+    #[test]
+    fn jump_clears_one_block_but_not_two() {
+        let map = floor();
+        let mut walker = Walker::new(Vec3::new(0.5, 1.0, 0.5));
+        run(&mut walker, &map, WalkInput::default(), 2);
+        let jump = WalkInput {
+            direction: (0.0, 0.0),
+            jump: true,
+        };
+        let top = run(&mut walker, &map, jump, 1);
+        let top = top.max(run(&mut walker, &map, WalkInput::default(), 60));
+        assert!(top > 2.0 && top < 3.0, "{}", top);
+        assert!(walker.on_ground);
+    }
+
+    #[test]
+    fn steps_onto_a_single_block() {
+        let mut map = floor();
+        for x in 3..8 {
+            for z in -2..3 {
+                map.set_voxel(x, 1, z, 1);
+            }
+        }
+        let mut walker = Walker::new(Vec3::new(0.5, 1.0, 0.5));
+        run(&mut walker, &map, forward(), 60);
+        assert!(close(walker.feet.y, 2.0));
+        assert!(walker.feet.x > 3.5);
+        assert!(walker.on_ground);
+    }
+
+    #[test]
+    fn two_blocks_are_a_wall() {
+        let mut map = floor();
+        for z in -2..3 {
+            map.set_voxel(3, 1, z, 1);
+            map.set_voxel(3, 2, z, 1);
+        }
+        let mut walker = Walker::new(Vec3::new(0.5, 1.0, 0.5));
+        run(&mut walker, &map, forward(), 60);
+        assert!(close(walker.feet.y, 1.0));
+        assert!(close(walker.feet.x, 2.7));
+    }
+
+    #[test]
+    fn no_step_up_under_a_low_ceiling() {
+        let mut map = floor();
+        for z in -2..3 {
+            map.set_voxel(3, 1, z, 1);
+            // one block over the step, the body does not fit on it
+            for x in 0..6 {
+                map.set_voxel(x, 3, z, 1);
+            }
+        }
+        let mut walker = Walker::new(Vec3::new(0.5, 1.0, 0.5));
+        run(&mut walker, &map, forward(), 60);
+        assert!(close(walker.feet.y, 1.0));
+        assert!(close(walker.feet.x, 2.7));
+    }
+
+    #[test]
+    fn head_hits_the_ceiling_on_a_jump() {
+        let mut map = floor();
+        map.set_voxel(0, 4, 0, 1);
+        let mut walker = Walker::new(Vec3::new(0.5, 1.0, 0.5));
+        run(&mut walker, &map, WalkInput::default(), 2);
+        let jump = WalkInput {
+            direction: (0.0, 0.0),
+            jump: true,
+        };
+        let top = run(&mut walker, &map, jump, 1);
+        let top = top.max(run(&mut walker, &map, WalkInput::default(), 60));
+        assert!(close(top, 2.2), "{}", top);
+        assert!(walker.on_ground);
+    }
+
+    #[test]
+    fn walks_off_a_ledge_across_a_chunk_border() {
+        let mut map = floor();
+        // a hole two deep in chunks (1, 0, 0) and (1, -1, 0), right at the border with chunk 0
+        for x in 16..20 {
+            for z in 0..2 {
+                map.set_voxel(x, 0, z, 0);
+                map.set_voxel(x, -2, z, 1);
+            }
+        }
+        let mut walker = Walker::new(Vec3::new(15.0, 1.0, 1.0));
+        run(&mut walker, &map, forward(), 30);
+        assert!(walker.feet.y < 1.0);
+        // the side of the hole is too high to step onto
+        run(&mut walker, &map, forward(), 120);
+        assert!(walker.on_ground);
+        assert!(close(walker.feet.y, -1.0));
+        assert!(close(walker.feet.x, 19.7));
+    }
+
+    #[test]
+    fn waits_for_its_chunk() {
+        let map = floor();
+        let mut walker = Walker::new(Vec3::new(0.5, 40.0, 0.5));
+        run(&mut walker, &map, forward(), 30);
+        assert_eq!(walker.feet, Vec3::new(0.5, 40.0, 0.5));
+    }
+}
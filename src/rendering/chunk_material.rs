@@ -0,0 +1,585 @@
+use bevy::{
+    ecs::system::{lifetimeless::SRes, SystemParamItem},
+    pbr::{AlphaMode, MeshPipeline, SpecializedMaterial},
+    prelude::{
+        AssetServer, Assets, ClearColor, Color, Component, Handle, Image, Query, Res, ResMut,
+        Shader, Time,
+    },
+    reflect::TypeUuid,
+    render::{
+        render_asset::{PrepareAssetError, RenderAsset, RenderAssets},
+        render_resource::{
+            BindGroup, BindGroupDescriptor, BindGroupEntry, BindGroupLayout,
+            BindGroupLayoutDescriptor, BindGroupLayoutEntry, BindingResource, BindingType, Buffer,
+            BufferBindingType, BufferInitDescriptor, BufferSize, BufferUsages,
+            RenderPipelineDescriptor, SamplerBindingType, ShaderStages, TextureSampleType,
+            TextureViewDimension,
+        },
+        renderer::RenderDevice,
+    },
+};
+
+use crate::voxel::block::RenderLayer;
+
+use super::{
+    instancing::{InstanceRaw, ModelInstanceList},
+    mesh::ChunkMeshvertex,
+    model_draw_pipeline::ModelDrawMaterialPipeline,
+};
+
+/// tells consume_image_array which chunk material a chunk draw list entity needs.
+#[derive(Component, Clone, Copy)]
+pub struct ChunkLayer(pub RenderLayer);
+
+/// shader features of the chunk materials, each combination gets its own cached pipeline.
+/// as a resource it is the state of the egui toggles, see sync_chunk_material_settings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ChunkShaderFlags {
+    /// color by uv instead of texture
+    pub debug_uv: bool,
+    pub lighting: bool,
+    pub texture: bool,
+    /// color by face normal
+    pub normals_view: bool,
+    /// color by the light level baked in by the mesher
+    pub light_level_view: bool,
+    /// flat untextured faces with outlined quad edges
+    pub wireframe: bool,
+}
+
+impl Default for ChunkShaderFlags {
+    fn default() -> Self {
+        Self {
+            debug_uv: false,
+            lighting: true,
+            texture: true,
+            normals_view: false,
+            light_level_view: false,
+            wireframe: false,
+        }
+    }
+}
+
+impl ChunkShaderFlags {
+    fn shader_defs(&self) -> Vec<String> {
+        let mut shader_defs = vec![];
+        if self.debug_uv {
+            shader_defs.push("DEBUG_UV".to_string());
+        }
+        if self.lighting {
+            shader_defs.push("IS_LIGHTING".to_string());
+        }
+        // the wireframe view is untextured
+        if self.texture && !self.wireframe {
+            shader_defs.push("COLOR_TEXTURE".to_string());
+        }
+        if self.normals_view {
+            shader_defs.push("NORMALS_VIEW".to_string());
+        }
+        if self.light_level_view {
+            shader_defs.push("LIGHT_LEVEL_VIEW".to_string());
+        }
+        if self.wireframe {
+            shader_defs.push("WIREFRAME".to_string());
+        }
+        shader_defs
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FogFalloff {
+    Off,
+    /// fog goes from none at `start` to full at `end` (distance from the camera)
+    Linear {
+        start: f32,
+        end: f32,
+    },
+    /// 1 - e^(-density * distance)
+    Exponential {
+        density: f32,
+    },
+}
+
+/// distance fog of the chunk materials, as a resource it is copied into the material uniforms.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChunkFog {
+    pub color: Color,
+    pub falloff: FogFalloff,
+}
+
+impl Default for ChunkFog {
+    fn default() -> Self {
+        Self {
+            color: Color::rgb(0.52, 0.62, 0.72),
+            falloff: FogFalloff::Linear {
+                start: 60.0,
+                end: 140.0,
+            },
+        }
+    }
+}
+
+impl ChunkFog {
+    /// x mode (0 off, 1 linear, 2 exponential), y start or density, z end
+    fn params(&self) -> [f32; 4] {
+        match self.falloff {
+            FogFalloff::Off => [0.0; 4],
+            FogFalloff::Linear { start, end } => [1.0, start, end, 0.0],
+            FogFalloff::Exponential { density } => [2.0, density, 0.0, 0.0],
+        }
+    }
+}
+
+/// copies the ChunkShaderFlags and ChunkFog resources into every chunk material when they change.
+/// the clear color follows the fog color so the far chunks blend into the background.
+pub fn sync_chunk_material_settings(
+    flags: Res<ChunkShaderFlags>,
+    fog: Res<ChunkFog>,
+    mut clear_color: ResMut<ClearColor>,
+    mut opaque_materials: ResMut<Assets<ChunkOpaqueMaterial>>,
+    mut cutout_materials: ResMut<Assets<ChunkCutoutMaterial>>,
+    mut transparent_materials: ResMut<Assets<ChunkTransparentMaterial>>,
+) {
+    if !flags.is_changed() && !fog.is_changed() {
+        return;
+    }
+    if fog.is_changed() && fog.falloff != FogFalloff::Off {
+        clear_color.0 = fog.color;
+    }
+    // get_mut marks the asset as modified, so only touch the ones that differ
+    let ids: Vec<_> = opaque_materials.ids().collect();
+    for id in ids {
+        let m = opaque_materials.get(id).unwrap();
+        if m.flags != *flags || m.fog != *fog {
+            let m = opaque_materials.get_mut(id).unwrap();
+            m.flags = *flags;
+            m.fog = *fog;
+        }
+    }
+    let ids: Vec<_> = cutout_materials.ids().collect();
+    for id in ids {
+        let m = cutout_materials.get(id).unwrap();
+        if m.flags != *flags || m.fog != *fog {
+            let m = cutout_materials.get_mut(id).unwrap();
+            m.flags = *flags;
+            m.fog = *fog;
+        }
+    }
+    let ids: Vec<_> = transparent_materials.ids().collect();
+    for id in ids {
+        let m = transparent_materials.get(id).unwrap();
+        if m.flags != *flags || m.fog != *fog {
+            let m = transparent_materials.get_mut(id).unwrap();
+            m.flags = *flags;
+            m.fog = *fog;
+        }
+    }
+}
+
+/// chunk draw lists with this fade new instances in, instances are added with a color alpha of 0.
+#[derive(Component, Clone, Copy)]
+pub struct ChunkFadeIn {
+    /// seconds to go from invisible to fully visible
+    pub duration: f32,
+}
+
+pub fn fade_in_chunks(time: Res<Time>, mut query: Query<(&ChunkFadeIn, &mut ModelInstanceList)>) {
+    let dt = time.delta_seconds();
+    for (fade, mut list) in query.iter_mut() {
+        if list
+            .instance_list
+            .iter()
+            .all(|i| i.instance.color[3] >= 1.0)
+        {
+            continue;
+        }
+        for model_instance in list.instance_list.iter_mut() {
+            let alpha = &mut model_instance.instance.color[3];
+            *alpha = (*alpha + dt / fade.duration.max(0.0001)).min(1.0);
+        }
+    }
+}
+
+#[derive(Debug, Clone, TypeUuid)]
+#[uuid = "4ee9c363-1124-4113-890e-199d81b00281"]
+pub struct ChunkOpaqueMaterial {
+    pub color: Color,
+    pub base_color_texture: Option<Handle<Image>>,
+    pub flags: ChunkShaderFlags,
+    pub fog: ChunkFog,
+}
+
+/// used for the cutout chunk sub meshes (leaves, foliage, grates ...).
+/// fragments with an alpha below `alpha_cutoff` are discarded.
+#[derive(Debug, Clone, TypeUuid)]
+#[uuid = "2c8e5a71-9f3d-4b6e-a4c2-7d1e0b9f6a35"]
+pub struct ChunkCutoutMaterial {
+    pub color: Color,
+    pub base_color_texture: Option<Handle<Image>>,
+    pub alpha_cutoff: f32,
+    pub flags: ChunkShaderFlags,
+    pub fog: ChunkFog,
+}
+
+/// used for the transparent chunk sub meshes (glass, water ...).
+#[derive(Debug, Clone, TypeUuid)]
+#[uuid = "b3f4c1a2-61d7-4a0e-9c55-3d2f8e7a1c90"]
+pub struct ChunkTransparentMaterial {
+    pub color: Color,
+    pub base_color_texture: Option<Handle<Image>>,
+    pub flags: ChunkShaderFlags,
+    pub fog: ChunkFog,
+}
+
+#[derive(Clone)]
+pub struct GpuChunkMaterial {
+    _buffer: Buffer,
+    pub bind_group: BindGroup,
+    pub base_color_texture: Option<Handle<Image>>,
+    pub alpha_cutoff: f32,
+    pub flags: ChunkShaderFlags,
+}
+
+// has to match CustomMaterial in instancing.wgsl, std140 so fields are padded out to vec4's
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct ChunkMaterialUniform {
+    color: [f32; 4],
+    fog_color: [f32; 4],
+    fog_params: [f32; 4],
+    alpha_cutoff: f32,
+    _padding: [f32; 3],
+}
+
+// the chunk materials only differ in how the pipeline is specialized, the gpu side is shared.
+
+fn prepare_chunk_material(
+    color: Color,
+    alpha_cutoff: f32,
+    flags: ChunkShaderFlags,
+    fog: ChunkFog,
+    base_color_texture: &Option<Handle<Image>>,
+    render_device: &RenderDevice,
+    material_layout: &BindGroupLayout,
+    mesh_pipeline: &MeshPipeline,
+    gpu_image: &RenderAssets<Image>,
+) -> Option<GpuChunkMaterial> {
+    let (base_color_texture_view, base_color_sampler) =
+        mesh_pipeline.get_image_texture(gpu_image, base_color_texture)?;
+
+    let uniform = ChunkMaterialUniform {
+        color: color.as_rgba_linear().as_rgba_f32(),
+        fog_color: fog.color.as_rgba_linear().as_rgba_f32(),
+        fog_params: fog.params(),
+        alpha_cutoff,
+        _padding: [0.0; 3],
+    };
+    let buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
+        contents: bytemuck::bytes_of(&uniform),
+        label: None,
+        usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+    });
+    let bind_group = render_device.create_bind_group(&BindGroupDescriptor {
+        entries: &[
+            BindGroupEntry {
+                binding: 0,
+                resource: buffer.as_entire_binding(),
+            },
+            BindGroupEntry {
+                binding: 1,
+                resource: BindingResource::TextureView(base_color_texture_view),
+            },
+            BindGroupEntry {
+                binding: 2,
+                resource: BindingResource::Sampler(base_color_sampler),
+            },
+        ],
+        label: None,
+        layout: material_layout,
+    });
+
+    Some(GpuChunkMaterial {
+        _buffer: buffer,
+        bind_group,
+        base_color_texture: base_color_texture.clone(),
+        alpha_cutoff,
+        flags,
+    })
+}
+
+fn chunk_bind_group_layout(render_device: &RenderDevice) -> BindGroupLayout {
+    render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+        entries: &[
+            BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStages::VERTEX_FRAGMENT,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: BufferSize::new(
+                        std::mem::size_of::<ChunkMaterialUniform>() as u64
+                    ),
+                },
+                count: None,
+            }, // Base Color Texture
+            BindGroupLayoutEntry {
+                binding: 1,
+                visibility: ShaderStages::FRAGMENT,
+                ty: BindingType::Texture {
+                    multisampled: false,
+                    sample_type: TextureSampleType::Float { filterable: true },
+                    view_dimension: TextureViewDimension::D2Array,
+                },
+                count: None,
+            },
+            // Base Color Texture Sampler
+            BindGroupLayoutEntry {
+                binding: 2,
+                visibility: ShaderStages::FRAGMENT,
+                ty: BindingType::Sampler(SamplerBindingType::Filtering),
+                count: None,
+            },
+        ],
+        label: None,
+    })
+}
+
+fn specialize_chunk(
+    descriptor: &mut RenderPipelineDescriptor,
+    flags: ChunkShaderFlags,
+    mut shader_defs: Vec<String>,
+) {
+    descriptor.vertex.buffers = vec![ChunkMeshvertex::desc()];
+    descriptor.vertex.buffers.push(InstanceRaw::desc());
+
+    shader_defs.append(&mut flags.shader_defs());
+
+    descriptor
+        .vertex
+        .shader_defs
+        .append(&mut shader_defs.clone());
+
+    if let Some(frag) = &mut descriptor.fragment {
+        frag.shader_defs.append(&mut shader_defs);
+    }
+
+    descriptor.vertex.entry_point = "vertex".into();
+    descriptor.fragment.as_mut().unwrap().entry_point = "fragment".into();
+}
+
+fn chunk_shader(asset_server: &AssetServer) -> Option<Handle<Shader>> {
+    asset_server.watch_for_changes().unwrap();
+    Some(asset_server.load("shaders/instancing.wgsl"))
+}
+
+// ---------------------------------------------------------------
+// opaque
+
+impl RenderAsset for ChunkOpaqueMaterial {
+    type ExtractedAsset = ChunkOpaqueMaterial;
+    type PreparedAsset = GpuChunkMaterial;
+
+    type Param = (
+        SRes<RenderDevice>,
+        SRes<ModelDrawMaterialPipeline<Self>>, // <- pipeline
+        SRes<RenderAssets<Image>>,
+    );
+    fn extract_asset(&self) -> Self::ExtractedAsset {
+        self.clone()
+    }
+
+    fn prepare_asset(
+        extracted_asset: Self::ExtractedAsset,
+        (render_device, custom_pipeline, gpu_image): &mut SystemParamItem<Self::Param>,
+    ) -> Result<Self::PreparedAsset, PrepareAssetError<Self::ExtractedAsset>> {
+        match prepare_chunk_material(
+            extracted_asset.color,
+            0.0,
+            extracted_asset.flags,
+            extracted_asset.fog,
+            &extracted_asset.base_color_texture,
+            render_device,
+            &custom_pipeline.material_layout,
+            &custom_pipeline.mesh_pipeline,
+            gpu_image,
+        ) {
+            Some(material) => Ok(material),
+            None => Err(PrepareAssetError::RetryNextUpdate(extracted_asset)),
+        }
+    }
+}
+
+impl SpecializedMaterial for ChunkOpaqueMaterial {
+    type Key = ChunkShaderFlags;
+
+    fn key(material: &<ChunkOpaqueMaterial as RenderAsset>::PreparedAsset) -> Self::Key {
+        material.flags
+    }
+
+    fn specialize(key: Self::Key, descriptor: &mut RenderPipelineDescriptor) {
+        specialize_chunk(descriptor, key, vec!["OPAQUE_PASS".to_string()]);
+    }
+
+    fn vertex_shader(asset_server: &AssetServer) -> Option<Handle<Shader>> {
+        chunk_shader(asset_server)
+    }
+
+    fn fragment_shader(asset_server: &AssetServer) -> Option<Handle<Shader>> {
+        chunk_shader(asset_server)
+    }
+
+    fn bind_group(render_asset: &<Self as RenderAsset>::PreparedAsset) -> &BindGroup {
+        &render_asset.bind_group
+    }
+
+    fn bind_group_layout(render_device: &RenderDevice) -> BindGroupLayout {
+        chunk_bind_group_layout(render_device)
+    }
+    fn alpha_mode(_material: &<Self as RenderAsset>::PreparedAsset) -> AlphaMode {
+        AlphaMode::Opaque
+    }
+}
+
+// ---------------------------------------------------------------
+// cutout
+
+impl RenderAsset for ChunkCutoutMaterial {
+    type ExtractedAsset = ChunkCutoutMaterial;
+    type PreparedAsset = GpuChunkMaterial;
+
+    type Param = (
+        SRes<RenderDevice>,
+        SRes<ModelDrawMaterialPipeline<Self>>, // <- pipeline
+        SRes<RenderAssets<Image>>,
+    );
+    fn extract_asset(&self) -> Self::ExtractedAsset {
+        self.clone()
+    }
+
+    fn prepare_asset(
+        extracted_asset: Self::ExtractedAsset,
+        (render_device, custom_pipeline, gpu_image): &mut SystemParamItem<Self::Param>,
+    ) -> Result<Self::PreparedAsset, PrepareAssetError<Self::ExtractedAsset>> {
+        match prepare_chunk_material(
+            extracted_asset.color,
+            extracted_asset.alpha_cutoff,
+            extracted_asset.flags,
+            extracted_asset.fog,
+            &extracted_asset.base_color_texture,
+            render_device,
+            &custom_pipeline.material_layout,
+            &custom_pipeline.mesh_pipeline,
+            gpu_image,
+        ) {
+            Some(material) => Ok(material),
+            None => Err(PrepareAssetError::RetryNextUpdate(extracted_asset)),
+        }
+    }
+}
+
+impl SpecializedMaterial for ChunkCutoutMaterial {
+    type Key = ChunkShaderFlags;
+
+    fn key(material: &<ChunkCutoutMaterial as RenderAsset>::PreparedAsset) -> Self::Key {
+        material.flags
+    }
+
+    fn specialize(key: Self::Key, descriptor: &mut RenderPipelineDescriptor) {
+        // cutout quads are double sided
+        descriptor.primitive.cull_mode = None;
+        specialize_chunk(
+            descriptor,
+            key,
+            vec!["ALPHA_MASK".to_string(), "OPAQUE_PASS".to_string()],
+        );
+    }
+
+    fn vertex_shader(asset_server: &AssetServer) -> Option<Handle<Shader>> {
+        chunk_shader(asset_server)
+    }
+
+    fn fragment_shader(asset_server: &AssetServer) -> Option<Handle<Shader>> {
+        chunk_shader(asset_server)
+    }
+
+    fn bind_group(render_asset: &<Self as RenderAsset>::PreparedAsset) -> &BindGroup {
+        &render_asset.bind_group
+    }
+
+    fn bind_group_layout(render_device: &RenderDevice) -> BindGroupLayout {
+        chunk_bind_group_layout(render_device)
+    }
+    fn alpha_mode(material: &<Self as RenderAsset>::PreparedAsset) -> AlphaMode {
+        AlphaMode::Mask(material.alpha_cutoff)
+    }
+}
+
+// ---------------------------------------------------------------
+// transparent
+
+impl RenderAsset for ChunkTransparentMaterial {
+    type ExtractedAsset = ChunkTransparentMaterial;
+    type PreparedAsset = GpuChunkMaterial;
+
+    type Param = (
+        SRes<RenderDevice>,
+        SRes<ModelDrawMaterialPipeline<Self>>, // <- pipeline
+        SRes<RenderAssets<Image>>,
+    );
+    fn extract_asset(&self) -> Self::ExtractedAsset {
+        self.clone()
+    }
+
+    fn prepare_asset(
+        extracted_asset: Self::ExtractedAsset,
+        (render_device, custom_pipeline, gpu_image): &mut SystemParamItem<Self::Param>,
+    ) -> Result<Self::PreparedAsset, PrepareAssetError<Self::ExtractedAsset>> {
+        match prepare_chunk_material(
+            extracted_asset.color,
+            0.0,
+            extracted_asset.flags,
+            extracted_asset.fog,
+            &extracted_asset.base_color_texture,
+            render_device,
+            &custom_pipeline.material_layout,
+            &custom_pipeline.mesh_pipeline,
+            gpu_image,
+        ) {
+            Some(material) => Ok(material),
+            None => Err(PrepareAssetError::RetryNextUpdate(extracted_asset)),
+        }
+    }
+}
+
+impl SpecializedMaterial for ChunkTransparentMaterial {
+    type Key = ChunkShaderFlags;
+
+    fn key(material: &<ChunkTransparentMaterial as RenderAsset>::PreparedAsset) -> Self::Key {
+        material.flags
+    }
+
+    fn specialize(key: Self::Key, descriptor: &mut RenderPipelineDescriptor) {
+        // faces of a transparent block can be seen from the inside too
+        descriptor.primitive.cull_mode = None;
+        specialize_chunk(descriptor, key, vec!["ALPHA_BLEND".to_string()]);
+    }
+
+    fn vertex_shader(asset_server: &AssetServer) -> Option<Handle<Shader>> {
+        chunk_shader(asset_server)
+    }
+
+    fn fragment_shader(asset_server: &AssetServer) -> Option<Handle<Shader>> {
+        chunk_shader(asset_server)
+    }
+
+    fn bind_group(render_asset: &<Self as RenderAsset>::PreparedAsset) -> &BindGroup {
+        &render_asset.bind_group
+    }
+
+    fn bind_group_layout(render_device: &RenderDevice) -> BindGroupLayout {
+        chunk_bind_group_layout(render_device)
+    }
+    fn alpha_mode(_material: &<Self as RenderAsset>::PreparedAsset) -> AlphaMode {
+        AlphaMode::Blend
+    }
+}
@@ -75,15 +75,12 @@ pub struct Instance {
 }
 
 impl Instance {
+    pub fn to_matrix(&self) -> Mat4 {
+        Mat4::from_scale_rotation_translation(self.scale, self.rotation, self.position)
+    }
     pub fn to_raw(&self) -> InstanceRaw {
         InstanceRaw {
-            model: (Mat4::from_scale_rotation_translation(
-                self.scale,
-                self.rotation,
-                self.position,
-            ))
-            .to_cols_array_2d()
-            .into(),
+            model: self.to_matrix().to_cols_array_2d().into(),
             color: self.color,
         }
     }
@@ -208,6 +205,8 @@ fn model_instance_to_draw_indirect_list(
 pub struct ModelInstance {
     pub mesh: Arc<RwLock<SubMeshHandel>>,
     pub instance: Instance,
+    /// local space point used for depth sorting, usually the center of the sub mesh.
+    pub center: Vec3,
 
     pub inst_index: u32,
 }
@@ -1,3 +1,4 @@
+pub mod chunk_material;
 pub mod instancing;
 pub mod mesh;
 pub mod model_draw_pipeline;
@@ -9,37 +9,44 @@ use bevy::{
     },
     pbr::{
         AlphaMode, MeshPipeline, MeshPipelineKey, MeshUniform, SetMeshBindGroup,
-        SetMeshViewBindGroup, SpecializedMaterial,
+        SetMeshViewBindGroup, SetShadowViewBindGroup, Shadow, ShadowPipeline, SpecializedMaterial,
+        ViewLightEntities, SHADOW_FORMAT,
     },
     prelude::{
         AddAsset, App, AssetServer, Commands, Component, Entity, FromWorld, Handle, Msaa, Plugin,
-        Query, Res, ResMut, Shader, With, World,
+        Query, Res, ResMut, Shader, With, Without, World,
     },
     reflect::TypeUuid,
     render::{
         render_asset::{RenderAssetPlugin, RenderAssets},
-        render_component::ExtractComponentPlugin,
+        render_component::{ExtractComponent, ExtractComponentPlugin},
         render_phase::{
             AddRenderCommand, DrawFunctions, EntityRenderCommand, RenderCommandResult, RenderPhase,
             SetItemPipeline, TrackedRenderPass,
         },
         render_resource::{
-            BindGroupLayout, IndexFormat, PrimitiveTopology, RenderPipelineCache,
-            RenderPipelineDescriptor, SpecializedPipeline, SpecializedPipelines,
+            BindGroupLayout, BufferAddress, CompareFunction, DepthBiasState, DepthStencilState,
+            FragmentState, IndexFormat, MultisampleState, PrimitiveState, PrimitiveTopology,
+            RenderPipelineCache, RenderPipelineDescriptor, SpecializedPipeline,
+            SpecializedPipelines, StencilState, VertexAttribute, VertexBufferLayout, VertexFormat,
+            VertexState, VertexStepMode,
         },
         renderer::RenderDevice,
-        view::VisibleEntities,
+        view::{ExtractedView, VisibleEntities},
         RenderApp, RenderStage,
     },
 };
 use prism_math::Vec4;
 
 use super::{
-    instancing::{DrawIndexedIndirectList, InstanceBuffer, ModelInstanceList},
+    instancing::{
+        DrawIndexedIndirect, DrawIndexedIndirectList, InstanceBuffer, InstanceRaw,
+        ModelInstanceList,
+    },
     mesh::SharedMesh,
 };
 
-// note: alpha blending dose not work right as you would need to sort the the instances list
+// note: alpha blended materials get their instances sorted back to front per view, see ViewInstanceOrder
 /// Adds the necessary ECS resources and render logic to enable rendering entities using the given [`SpecializedMaterial`]
 /// asset type (which includes [`Material`] types).
 pub struct ModelInstanceMaterialPlugin<M: SpecializedMaterial>(PhantomData<M>);
@@ -69,6 +76,7 @@ impl<M: SpecializedMaterial> Plugin for ModelInstanceMaterialPlugin<M> {
 
 #[allow(clippy::too_many_arguments)]
 pub fn queue_material_meshes<M: SpecializedMaterial>(
+    mut commands: Commands,
     opaque_draw_functions: Res<DrawFunctions<Opaque3d>>,
     alpha_mask_draw_functions: Res<DrawFunctions<AlphaMask3d>>,
     transparent_draw_functions: Res<DrawFunctions<Transparent3d>>,
@@ -78,17 +86,27 @@ pub fn queue_material_meshes<M: SpecializedMaterial>(
     msaa: Res<Msaa>,
     shared_mesh: Res<RenderAssets<SharedMesh>>,
     render_materials: Res<RenderAssets<M>>,
-    material_meshes: Query<(Entity, &Handle<SharedMesh>, &Handle<M>, &MeshUniform)>,
+    material_meshes: Query<(
+        Entity,
+        &Handle<SharedMesh>,
+        &Handle<M>,
+        &MeshUniform,
+        Option<&ModelInstanceList>,
+    )>,
     mut views: Query<(
-        //Entity,
-        //&ExtractedView,
+        Entity,
+        &ExtractedView,
         //&VisibleEntities,
         &mut RenderPhase<Opaque3d>,
         &mut RenderPhase<AlphaMask3d>,
         &mut RenderPhase<Transparent3d>,
     )>,
 ) {
-    for (mut opaque_phase, mut alpha_mask_phase, mut transparent_phase) in views.iter_mut() {
+    let mut view_orders: HashMap<Entity, ViewInstanceOrder> = HashMap::new();
+
+    for (view_entity, view, mut opaque_phase, mut alpha_mask_phase, mut transparent_phase) in
+        views.iter_mut()
+    {
         let draw_opaque_pbr = opaque_draw_functions
             .read()
             .get_id::<DrawMaterial<M>>()
@@ -102,12 +120,14 @@ pub fn queue_material_meshes<M: SpecializedMaterial>(
             .get_id::<DrawMaterial<M>>()
             .unwrap();
 
-        //let inverse_view_matrix = view.transform.compute_matrix().inverse();
-        //let inverse_view_row_2 = inverse_view_matrix.row(2);
+        let inverse_view_matrix = view.transform.compute_matrix().inverse();
+        let inverse_view_row_2 = inverse_view_matrix.row(2);
         let mesh_key = MeshPipelineKey::from_msaa_samples(msaa.samples);
 
         //for visible_entity in &visible_entities.entities {
-        for (entity, mesh_handel, material_handle, _mesh_uniform) in material_meshes.iter() {
+        for (entity, mesh_handel, material_handle, mesh_uniform, instance_list) in
+            material_meshes.iter()
+        {
             let shard_mesh = shared_mesh.get(mesh_handel).unwrap();
             if let Some(material) = render_materials.get(material_handle) {
                 let mut mesh_key = mesh_key;
@@ -128,7 +148,7 @@ pub fn queue_material_meshes<M: SpecializedMaterial>(
 
                 // NOTE: row 2 of the inverse view matrix dotted with column 3 of the model matrix
                 // gives the z component of translation of the mesh in view space
-                let mesh_z = 0.0; //inverse_view_row_2.dot(mesh_uniform.transform.col(3));
+                let mut mesh_z = inverse_view_row_2.dot(mesh_uniform.transform.col(3));
                 match alpha_mode {
                     AlphaMode::Opaque => {
                         opaque_phase.add(Opaque3d {
@@ -155,6 +175,24 @@ pub fn queue_material_meshes<M: SpecializedMaterial>(
                         });
                     }
                     AlphaMode::Blend => {
+                        // the draw list is one phase item, so the instances inside it have to be sorted here
+                        if let Some(instance_list) = instance_list {
+                            let (order, mean_z) = sort_instances_back_to_front(
+                                instance_list,
+                                mesh_uniform,
+                                inverse_view_row_2,
+                            );
+                            if let Some(mean_z) = mean_z {
+                                mesh_z = mean_z;
+                            }
+                            view_orders
+                                .entry(entity)
+                                .or_insert_with(|| ViewInstanceOrder {
+                                    order: HashMap::new(),
+                                })
+                                .order
+                                .insert(view_entity, order);
+                        }
                         transparent_phase.add(Transparent3d {
                             entity: entity,
                             draw_function: draw_transparent_pbr,
@@ -171,6 +209,45 @@ pub fn queue_material_meshes<M: SpecializedMaterial>(
         }
         //}
     }
+
+    for (entity, order) in view_orders {
+        commands.entity(entity).insert(order);
+    }
+}
+
+/// draw order of the instances in a ModelInstanceList for each view (view entity -> instance indices).
+/// if an entity has no order for a view the instances are drawn in list order.
+#[derive(Component, Clone)]
+pub struct ViewInstanceOrder {
+    pub order: HashMap<Entity, Vec<u32>>,
+}
+
+/// returns instance indices sorted far to near, and the mean view space z of the instances.
+pub fn sort_instances_back_to_front(
+    instance_list: &ModelInstanceList,
+    mesh_uniform: &MeshUniform,
+    inverse_view_row_2: Vec4,
+) -> (Vec<u32>, Option<f32>) {
+    let mut depths: Vec<(u32, f32)> = instance_list
+        .instance_list
+        .iter()
+        .enumerate()
+        .map(|(i, model_instance)| {
+            let model = mesh_uniform.transform * model_instance.instance.to_matrix();
+            let center = model.transform_point3(model_instance.center);
+            (i as u32, inverse_view_row_2.dot(center.extend(1.0)))
+        })
+        .collect();
+
+    // -z is in front of the camera so the most negative z is the furthest away
+    depths.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal));
+
+    let mean_z = if depths.is_empty() {
+        None
+    } else {
+        Some(depths.iter().map(|d| d.1).sum::<f32>() / depths.len() as f32)
+    };
+    (depths.into_iter().map(|d| d.0).collect(), mean_z)
 }
 
 pub struct ModelDrawMaterialPipeline<M: SpecializedMaterial> {
@@ -256,12 +333,13 @@ impl EntityRenderCommand for DrawMeshInstanced {
         SQuery<Read<Handle<SharedMesh>>>,
         SQuery<Read<DrawIndexedIndirectList>>,
         SQuery<Read<InstanceBuffer>>,
+        SQuery<Read<ViewInstanceOrder>>,
     );
     #[inline]
     fn render<'w>(
-        _view: Entity,
+        view: Entity,
         item: Entity,
-        (shared_mesh, h_mesh, draw_indirect_query, instance_buffer_query): SystemParamItem<
+        (shared_mesh, h_mesh, draw_indirect_query, instance_buffer_query, order_query): SystemParamItem<
             'w,
             '_,
             Self::Param,
@@ -272,24 +350,380 @@ impl EntityRenderCommand for DrawMeshInstanced {
         let shard_mesh = shared_mesh.into_inner().get(mesh_handel).unwrap();
         let instance_buffer = instance_buffer_query.get(item).unwrap();
         let draw_indirect_list = draw_indirect_query.get(item).unwrap();
+        if draw_indirect_list.draw_indirect.is_empty() {
+            // nothing to draw, and an empty instance buffer can not be bound
+            return RenderCommandResult::Success;
+        }
 
         pass.set_vertex_buffer(0, shard_mesh.vertex_buffer.slice(..));
         pass.set_vertex_buffer(1, instance_buffer.buffer.slice(..));
         pass.set_index_buffer(shard_mesh.index_buffer.slice(..), 0, IndexFormat::Uint32);
 
+        let view_order = order_query
+            .get(item)
+            .ok()
+            .and_then(|o| o.order.get(&view))
+            .filter(|o| o.len() == draw_indirect_list.draw_indirect.len());
+
         // by setting the vertex, index buffer and instance once you can call draw_indexed multiple times and offset what to use within the buffers
-        for i in draw_indirect_list.draw_indirect.iter() {
+        let mut draw = |i: &DrawIndexedIndirect| {
             pass.draw_indexed(
                 i.base_index..(i.base_index + i.vertex_count),
                 i.vertex_offset,
                 i.base_instance..(i.base_instance + i.instance_count),
             );
+        };
+        match view_order {
+            Some(order) => {
+                for i in order.iter() {
+                    draw(&draw_indirect_list.draw_indirect[*i as usize]);
+                }
+            }
+            None => {
+                for i in draw_indirect_list.draw_indirect.iter() {
+                    draw(i);
+                }
+            }
         }
 
         RenderCommandResult::Success
     }
 }
 
+/// draws every triangle [`SharedMesh`] with a [`ModelInstanceList`] and without [`NoModelShadow`]
+/// or [`AlphaMaskShadow`] into the shadow maps of the lights.
+/// add once, it does not depend on the material of the entity.
+pub struct ModelInstanceShadowPlugin;
+
+/// keeps the entity out of the shadow maps. the shadow pass draws every triangle solid, so
+/// alpha blended models need it or they cast the shadow of their whole mesh.
+#[derive(Component, Clone, Copy, Default)]
+pub struct NoModelShadow;
+
+impl ExtractComponent for NoModelShadow {
+    type Query = &'static NoModelShadow;
+    type Filter = ();
+
+    fn extract_component(item: bevy::ecs::query::QueryItem<Self::Query>) -> Self {
+        *item
+    }
+}
+
+/// the entity casts the shadow of what its material does not discard, see [`ModelMaskShadowPlugin`].
+/// alpha masked models need it or they cast the shadow of their whole mesh.
+#[derive(Component, Clone, Copy, Default)]
+pub struct AlphaMaskShadow;
+
+impl ExtractComponent for AlphaMaskShadow {
+    type Query = &'static AlphaMaskShadow;
+    type Filter = ();
+
+    fn extract_component(item: bevy::ecs::query::QueryItem<Self::Query>) -> Self {
+        *item
+    }
+}
+
+impl Plugin for ModelInstanceShadowPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugin(ExtractComponentPlugin::<NoModelShadow>::default())
+            .add_plugin(ExtractComponentPlugin::<AlphaMaskShadow>::default());
+        if let Ok(render_app) = app.get_sub_app_mut(RenderApp) {
+            render_app
+                .add_render_command::<Shadow, DrawShadowInstanced>()
+                .init_resource::<ModelShadowPipeline>()
+                .init_resource::<SpecializedPipelines<ModelShadowPipeline>>()
+                .add_system_to_stage(RenderStage::Queue, queue_model_shadows);
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn queue_model_shadows(
+    shadow_draw_functions: Res<DrawFunctions<Shadow>>,
+    shadow_pipeline: Res<ModelShadowPipeline>,
+    mut pipelines: ResMut<SpecializedPipelines<ModelShadowPipeline>>,
+    mut pipeline_cache: ResMut<RenderPipelineCache>,
+    shared_mesh: Res<RenderAssets<SharedMesh>>,
+    casters: Query<
+        (Entity, &Handle<SharedMesh>),
+        (
+            With<ModelInstanceList>,
+            With<MeshUniform>,
+            Without<NoModelShadow>,
+            Without<AlphaMaskShadow>,
+        ),
+    >,
+    view_lights: Query<&ViewLightEntities>,
+    mut view_light_shadow_phases: Query<&mut RenderPhase<Shadow>>,
+) {
+    let draw_shadow = shadow_draw_functions
+        .read()
+        .get_id::<DrawShadowInstanced>()
+        .unwrap();
+
+    for view_lights in view_lights.iter() {
+        for view_light_entity in view_lights.lights.iter().copied() {
+            let mut shadow_phase = match view_light_shadow_phases.get_mut(view_light_entity) {
+                Ok(phase) => phase,
+                Err(_) => continue,
+            };
+            for (entity, mesh_handel) in casters.iter() {
+                let shard_mesh = match shared_mesh.get(mesh_handel) {
+                    Some(m) => m,
+                    None => continue,
+                };
+                // debug lines and points do not cast shadows
+                if shard_mesh.primitive_topology != PrimitiveTopology::TriangleList {
+                    continue;
+                }
+                let pipeline_id = pipelines.specialize(
+                    &mut pipeline_cache,
+                    &shadow_pipeline,
+                    ModelShadowPipelineKey {
+                        primitive_topology: shard_mesh.primitive_topology,
+                        vertex_stride: shard_mesh.vertex_size as BufferAddress,
+                    },
+                );
+                shadow_phase.add(Shadow {
+                    draw_function: draw_shadow,
+                    pipeline: pipeline_id,
+                    entity,
+                    distance: 0.0,
+                });
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ModelShadowPipelineKey {
+    pub primitive_topology: PrimitiveTopology,
+    /// size of one vertex of the shared mesh, the position is always the first field.
+    pub vertex_stride: BufferAddress,
+}
+
+#[derive(Clone)]
+pub struct ModelShadowPipeline {
+    pub view_layout: BindGroupLayout,
+    pub mesh_layout: BindGroupLayout,
+    pub shader: Handle<Shader>,
+}
+
+impl FromWorld for ModelShadowPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let shadow_pipeline = world.get_resource::<ShadowPipeline>().unwrap();
+        let view_layout = shadow_pipeline.view_layout.clone();
+        let mesh_layout = world
+            .get_resource::<MeshPipeline>()
+            .unwrap()
+            .mesh_layout
+            .clone();
+        let asset_server = world.get_resource::<AssetServer>().unwrap();
+
+        ModelShadowPipeline {
+            view_layout,
+            mesh_layout,
+            shader: asset_server.load("shaders/instancing_shadow.wgsl"),
+        }
+    }
+}
+
+impl SpecializedPipeline for ModelShadowPipeline {
+    type Key = ModelShadowPipelineKey;
+
+    fn specialize(&self, key: Self::Key) -> RenderPipelineDescriptor {
+        RenderPipelineDescriptor {
+            label: Some("model_shadow_pipeline".into()),
+            layout: Some(vec![self.view_layout.clone(), self.mesh_layout.clone()]),
+            vertex: VertexState {
+                shader: self.shader.clone(),
+                shader_defs: vec![],
+                entry_point: "vertex".into(),
+                buffers: vec![
+                    VertexBufferLayout {
+                        array_stride: key.vertex_stride,
+                        step_mode: VertexStepMode::Vertex,
+                        attributes: vec![VertexAttribute {
+                            // pos
+                            offset: 0,
+                            shader_location: 0,
+                            format: VertexFormat::Float32x3,
+                        }],
+                    },
+                    InstanceRaw::desc(),
+                ],
+            },
+            fragment: None,
+            primitive: PrimitiveState {
+                topology: key.primitive_topology,
+                // chunk faces are single sided, culling would let light leak through the back of a wall
+                cull_mode: None,
+                ..Default::default()
+            },
+            depth_stencil: Some(DepthStencilState {
+                format: SHADOW_FORMAT,
+                depth_write_enabled: true,
+                depth_compare: CompareFunction::GreaterEqual,
+                stencil: StencilState::default(),
+                bias: DepthBiasState::default(),
+            }),
+            multisample: MultisampleState::default(),
+        }
+    }
+}
+
+type DrawShadowInstanced = (
+    SetItemPipeline,
+    SetShadowViewBindGroup<0>,
+    SetMeshBindGroup<1>,
+    DrawMeshInstanced,
+);
+
+/// draws the [`AlphaMaskShadow`] entities with the material `M` into the shadow maps, the fragments
+/// the material would discard for being below its alpha cutoff are discarded there too.
+/// add after [`ModelInstanceShadowPlugin`] and [`ModelInstanceMaterialPlugin<M>`].
+pub struct ModelMaskShadowPlugin<M: SpecializedMaterial>(PhantomData<M>);
+
+impl<M: SpecializedMaterial> Default for ModelMaskShadowPlugin<M> {
+    fn default() -> Self {
+        Self(Default::default())
+    }
+}
+
+impl<M: SpecializedMaterial> Plugin for ModelMaskShadowPlugin<M> {
+    fn build(&self, app: &mut App) {
+        if let Ok(render_app) = app.get_sub_app_mut(RenderApp) {
+            render_app
+                .add_render_command::<Shadow, DrawMaskShadowInstanced<M>>()
+                .init_resource::<ModelMaskShadowPipeline<M>>()
+                .init_resource::<SpecializedPipelines<ModelMaskShadowPipeline<M>>>()
+                .add_system_to_stage(RenderStage::Queue, queue_model_mask_shadows::<M>);
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn queue_model_mask_shadows<M: SpecializedMaterial>(
+    shadow_draw_functions: Res<DrawFunctions<Shadow>>,
+    shadow_pipeline: Res<ModelMaskShadowPipeline<M>>,
+    mut pipelines: ResMut<SpecializedPipelines<ModelMaskShadowPipeline<M>>>,
+    mut pipeline_cache: ResMut<RenderPipelineCache>,
+    shared_mesh: Res<RenderAssets<SharedMesh>>,
+    render_materials: Res<RenderAssets<M>>,
+    casters: Query<
+        (Entity, &Handle<SharedMesh>, &Handle<M>),
+        (
+            With<ModelInstanceList>,
+            With<MeshUniform>,
+            With<AlphaMaskShadow>,
+            Without<NoModelShadow>,
+        ),
+    >,
+    view_lights: Query<&ViewLightEntities>,
+    mut view_light_shadow_phases: Query<&mut RenderPhase<Shadow>>,
+) {
+    let draw_shadow = shadow_draw_functions
+        .read()
+        .get_id::<DrawMaskShadowInstanced<M>>()
+        .unwrap();
+
+    for view_lights in view_lights.iter() {
+        for view_light_entity in view_lights.lights.iter().copied() {
+            let mut shadow_phase = match view_light_shadow_phases.get_mut(view_light_entity) {
+                Ok(phase) => phase,
+                Err(_) => continue,
+            };
+            for (entity, mesh_handel, material_handle) in casters.iter() {
+                let (shard_mesh, material) = match (
+                    shared_mesh.get(mesh_handel),
+                    render_materials.get(material_handle),
+                ) {
+                    (Some(m), Some(mat)) => (m, mat),
+                    _ => continue,
+                };
+                if shard_mesh.primitive_topology != PrimitiveTopology::TriangleList {
+                    continue;
+                }
+                let pipeline_id = pipelines.specialize(
+                    &mut pipeline_cache,
+                    &shadow_pipeline,
+                    (
+                        ModelShadowPipelineKey {
+                            primitive_topology: shard_mesh.primitive_topology,
+                            vertex_stride: shard_mesh.vertex_size as BufferAddress,
+                        },
+                        M::key(material),
+                    ),
+                );
+                shadow_phase.add(Shadow {
+                    draw_function: draw_shadow,
+                    pipeline: pipeline_id,
+                    entity,
+                    distance: 0.0,
+                });
+            }
+        }
+    }
+}
+
+/// the shadow pipeline with the material bind group of `M` added, specialized by `M` so the
+/// shadow shader gets the vertex layout of the mesh and the ALPHA_MASK def.
+pub struct ModelMaskShadowPipeline<M: SpecializedMaterial> {
+    pub shadow_pipeline: ModelShadowPipeline,
+    pub material_layout: BindGroupLayout,
+    marker: PhantomData<M>,
+}
+
+impl<M: SpecializedMaterial> FromWorld for ModelMaskShadowPipeline<M> {
+    fn from_world(world: &mut World) -> Self {
+        let shadow_pipeline = world.get_resource::<ModelShadowPipeline>().unwrap().clone();
+        // the same layout the material bind groups are made with
+        let material_layout = world
+            .get_resource::<ModelDrawMaterialPipeline<M>>()
+            .unwrap()
+            .material_layout
+            .clone();
+
+        ModelMaskShadowPipeline {
+            shadow_pipeline,
+            material_layout,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<M: SpecializedMaterial> SpecializedPipeline for ModelMaskShadowPipeline<M> {
+    type Key = (ModelShadowPipelineKey, M::Key);
+
+    fn specialize(&self, key: Self::Key) -> RenderPipelineDescriptor {
+        let mut descriptor = self.shadow_pipeline.specialize(key.0);
+        descriptor.label = Some("model_mask_shadow_pipeline".into());
+        descriptor.vertex.shader_defs.push("ALPHA_MASK".to_string());
+        // nothing is drawn but the depth, the fragment stage only discards
+        descriptor.fragment = Some(FragmentState {
+            shader: self.shadow_pipeline.shader.clone(),
+            shader_defs: vec!["ALPHA_MASK".to_string()],
+            entry_point: "fragment".into(),
+            targets: vec![],
+        });
+        descriptor.layout = Some(vec![
+            self.shadow_pipeline.view_layout.clone(),
+            self.shadow_pipeline.mesh_layout.clone(),
+            self.material_layout.clone(),
+        ]);
+        M::specialize(key.1, &mut descriptor);
+        descriptor
+    }
+}
+
+type DrawMaskShadowInstanced<M> = (
+    SetItemPipeline,
+    SetShadowViewBindGroup<0>,
+    SetMeshBindGroup<1>,
+    SetMaterialBindGroup<M, 2>,
+    DrawMeshInstanced,
+);
+
 // can work on doing latter, on the user side there is no different
 // could try to order the instancing so you could use more then one instance at a time in the draw loop
 // get all material and mesh combos as keys and merge all instances together to reduce number of gpu state changes
@@ -311,3 +745,68 @@ fn merge_entitys<M: SpecializedMaterial>(
         }
     }
 }
+
+#[cfg(test)]
+mod testing {
+    use std::sync::{Arc, RwLock};
+
+    use prism_math::{Mat4, Quat, Vec3};
+
+    use super::*;
+    use crate::rendering::{
+        instancing::{Instance, ModelInstance},
+        mesh::SubMeshHandel,
+    };
+
+    fn instance_at(position: Vec3, center: Vec3) -> ModelInstance {
+        ModelInstance {
+            mesh: Arc::new(RwLock::new(SubMeshHandel::default())),
+            instance: Instance {
+                position,
+                rotation: Quat::IDENTITY,
+                scale: Vec3::ONE,
+                color: [1.0; 4],
+            },
+            center,
+            inst_index: 0,
+        }
+    }
+
+    #[test]
+    fn instances_are_sorted_far_to_near() {
+        // the camera is at z 10 looking down -z, the mesh moves everything 2 further away
+        let inverse_view_row_2 = Mat4::from_translation(Vec3::new(0.0, 0.0, 10.0))
+            .inverse()
+            .row(2);
+        let transform = Mat4::from_translation(Vec3::new(0.0, 0.0, -2.0));
+        let mesh_uniform = MeshUniform {
+            transform,
+            inverse_transpose_model: transform.inverse().transpose(),
+            flags: 0,
+        };
+        let instance_list = ModelInstanceList {
+            instance_list: vec![
+                instance_at(Vec3::ZERO, Vec3::ZERO),
+                instance_at(Vec3::new(0.0, 0.0, -5.0), Vec3::ZERO),
+                instance_at(Vec3::new(0.0, 0.0, 5.0), Vec3::ZERO),
+                // the same distance as the first, once to the side and once through the center
+                instance_at(Vec3::new(4.0, 0.0, 0.0), Vec3::ZERO),
+                instance_at(Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.0, 0.0, 1.0)),
+            ],
+        };
+
+        let (order, mean_z) =
+            sort_instances_back_to_front(&instance_list, &mesh_uniform, inverse_view_row_2);
+        // equal distances keep the list order
+        assert_eq!(order, vec![1, 0, 3, 4, 2]);
+        assert_eq!(mean_z, Some(-12.0));
+
+        let empty = ModelInstanceList {
+            instance_list: vec![],
+        };
+        assert_eq!(
+            sort_instances_back_to_front(&empty, &mesh_uniform, inverse_view_row_2),
+            (vec![], None)
+        );
+    }
+}
@@ -0,0 +1,234 @@
+// per voxel id properties, id 0 is always air and has no entry.
+// the texture array layer of a block is its id - 1 (see block_texture_config.ron)
+
+use std::{fs, path::Path};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+/// which sub mesh / render pass the faces of a block end up in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RenderLayer {
+    Opaque,
+    /// alpha tested and double sided, for foliage, grates and leaves.
+    Cutout,
+    /// alpha blended, drawn back to front after the opaque pass.
+    Transparent,
+}
+
+/// id of water in the default registry.
+pub const WATER: u16 = 17;
+pub const SAND: u16 = 18;
+pub const GRAVEL: u16 = 19;
+pub const LOG: u16 = 20;
+pub const LEAVES: u16 = 21;
+
+#[derive(Clone, Debug)]
+pub struct BlockProperties {
+    pub name: String,
+    pub render_layer: RenderLayer,
+    /// flows with the fluid simulation and has a level, nothing collides with it.
+    pub fluid: bool,
+    /// drops when the voxel under it is air or fluid, see falling.rs.
+    pub falls: bool,
+}
+
+impl BlockProperties {
+    pub fn new(name: &str, render_layer: RenderLayer) -> Self {
+        Self {
+            name: name.to_string(),
+            render_layer,
+            fluid: false,
+            falls: false,
+        }
+    }
+
+    pub fn fluid(name: &str) -> Self {
+        Self {
+            fluid: true,
+            ..Self::new(name, RenderLayer::Transparent)
+        }
+    }
+
+    pub fn falling(name: &str) -> Self {
+        Self {
+            falls: true,
+            ..Self::new(name, RenderLayer::Opaque)
+        }
+    }
+}
+
+pub struct BlockRegistry {
+    /// index 0 is id 1.
+    blocks: Vec<BlockProperties>,
+}
+
+impl BlockRegistry {
+    pub fn empty() -> Self {
+        Self { blocks: vec![] }
+    }
+
+    /// adds a block and returns its voxel id.
+    pub fn register(&mut self, properties: BlockProperties) -> u16 {
+        self.blocks.push(properties);
+        self.blocks.len() as u16
+    }
+
+    pub fn get(&self, id: u16) -> Option<&BlockProperties> {
+        if id == 0 {
+            return None;
+        }
+        self.blocks.get(id as usize - 1)
+    }
+
+    /// None for air and unknown ids.
+    pub fn render_layer(&self, id: u16) -> Option<RenderLayer> {
+        self.get(id).map(|b| b.render_layer)
+    }
+
+    pub fn is_fluid(&self, id: u16) -> bool {
+        matches!(self.get(id), Some(b) if b.fluid)
+    }
+
+    pub fn falls(&self, id: u16) -> bool {
+        matches!(self.get(id), Some(b) if b.falls)
+    }
+
+    /// air and fluids are not solid, bodies and falling blocks go through them.
+    pub fn is_solid(&self, id: u16) -> bool {
+        id != 0 && !self.is_fluid(id)
+    }
+
+    /// true if the face of `id` that touches `neighbor` can be skipped.
+    pub fn face_hidden(&self, id: u16, neighbor: u16) -> bool {
+        match self.render_layer(neighbor) {
+            None => false,
+            Some(RenderLayer::Opaque) => true,
+            // see through neighbors only hide faces of the same block, so glass next to glass has no inner faces
+            Some(RenderLayer::Cutout) | Some(RenderLayer::Transparent) => id == neighbor,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// None for names that are not registered.
+    pub fn id_of(&self, name: &str) -> Option<u16> {
+        self.blocks
+            .iter()
+            .position(|b| b.name == name)
+            .map(|i| i as u16 + 1)
+    }
+
+    /// block names in id order, the first is id 1.
+    pub fn names(&self) -> Vec<String> {
+        self.blocks.iter().map(|b| b.name.clone()).collect()
+    }
+}
+
+impl Default for BlockRegistry {
+    fn default() -> Self {
+        let mut reg = Self::empty();
+        for name in [
+            "dirt",
+            "dirt1",
+            "dirt2",
+            "dirt3",
+            "dirt4",
+            "stone",
+            "stone1",
+            "stone2",
+            "cobblestone",
+            "cobblestone2",
+            "coal_ore",
+            "iron_ore",
+            "iron_ore1",
+        ] {
+            reg.register(BlockProperties::new(name, RenderLayer::Opaque));
+        }
+        // the grass layer of the terrain, only leaves_oak is see through foliage
+        reg.register(BlockProperties::new("leaves_big_oak", RenderLayer::Opaque)); // 14
+        reg.register(BlockProperties::new("leaves_big_oak1", RenderLayer::Opaque)); // 15
+        reg.register(BlockProperties::new("glass", RenderLayer::Transparent)); // 16
+        reg.register(BlockProperties::fluid("water")); // 17
+        reg.register(BlockProperties::falling("sand")); // 18
+        reg.register(BlockProperties::falling("gravel")); // 19
+        reg.register(BlockProperties::new("log_oak", RenderLayer::Opaque)); // 20
+        reg.register(BlockProperties::new("leaves_oak", RenderLayer::Cutout)); // 21
+        reg
+    }
+}
+
+/// the images of the block texture array, loaded from block_texture_config.ron.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ImageArrayConfig {
+    pub pixel_size: u32,
+    pub layer_depth: u32,
+    /// image path and the array layer it goes in.
+    pub paths_id: Vec<(String, u32)>,
+}
+
+impl ImageArrayConfig {
+    /// reads the config without the asset server, for the headless server and the handshake.
+    pub fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let text =
+            fs::read_to_string(path).with_context(|| format!("could not read {:?}", path))?;
+        ron::de::from_str(&text).with_context(|| format!("bad texture config {:?}", path))
+    }
+
+    pub fn has_layer(&self, layer: u32) -> bool {
+        self.paths_id.iter().any(|(_, l)| *l == layer)
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::voxel::voxel::{Chunk, ChunkKey, VoxelMap};
+
+    /// faces of the chunk at the origin in `layer`, every face is a quad of 4 vertices.
+    fn faces(map: &VoxelMap, layer: RenderLayer) -> usize {
+        let mut vertices = vec![];
+        let (mut indices, mut step) = (vec![], 0);
+        map.update_chunk_mesh(
+            ChunkKey::new((0, 0, 0)),
+            layer,
+            &mut vertices,
+            &mut indices,
+            &mut step,
+            false,
+        );
+        vertices.len() / 4
+    }
+
+    #[test]
+    fn only_the_outside_of_a_surface_is_meshed() {
+        let mut map = VoxelMap::new((16, 16, 16));
+        map.add_chunk(0, 0, 0, Chunk::new(16));
+        // the two grass variants mixed like the terrain mixes them
+        for x in 1..5 {
+            for z in 1..5 {
+                map.set_voxel(x, 1, z, 14 + ((x + z) % 2) as u16);
+            }
+        }
+        assert_eq!(faces(&map, RenderLayer::Opaque), 16 + 16 + 4 * 4);
+        assert_eq!(faces(&map, RenderLayer::Cutout), 0);
+
+        // leaves only hide each other
+        for x in 8..10 {
+            for y in 8..10 {
+                for z in 8..10 {
+                    map.set_voxel(x, y, z, LEAVES);
+                }
+            }
+        }
+        assert_eq!(faces(&map, RenderLayer::Cutout), 6 * 4);
+        assert_eq!(faces(&map, RenderLayer::Opaque), 16 + 16 + 4 * 4);
+    }
+}
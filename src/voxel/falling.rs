@@ -0,0 +1,234 @@
+// blocks with `falls` set drop when the voxel under them is not solid. `VoxelMap::set_voxel` queues
+// the voxels to look at once `enable_falling` was called, `FallingBlocks::step` takes the unheld
+// ones out of the map, moves them down as bodies and puts them back as voxels where they land.
+// everything is in world positions, so a block falls across chunk borders like anywhere else.
+
+use super::voxel::VoxelMap;
+
+/// units per second squared, the same as the walker.
+pub const FALL_GRAVITY: f32 = 28.0;
+pub const MAX_FALL_SPEED: f32 = 50.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FallingBlock {
+    pub id: u16,
+    /// x and z of the column it falls down.
+    pub column: (i32, i32),
+    /// bottom of the block.
+    pub y: f32,
+    /// units per second, negative is down.
+    pub velocity: f32,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct FallingBlocks {
+    pub blocks: Vec<FallingBlock>,
+}
+
+impl FallingBlocks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// takes the queued blocks that have nothing under them out of the map, returns how many.
+    /// a block starting to fall leaves air behind, so a whole column goes in one call.
+    pub fn release(&mut self, map: &mut VoxelMap) -> usize {
+        let mut started = 0;
+        loop {
+            let checks = map.drain_fall_checks();
+            if checks.is_empty() {
+                return started;
+            }
+            for (x, y, z) in checks {
+                let id = map.get_voxel(x, y, z);
+                if !map.blocks.falls(id) || held(map, x, y - 1, z) {
+                    continue;
+                }
+                map.set_voxel(x, y, z, 0);
+                map.mark_border_neighbors_dirty(x, y, z);
+                self.blocks.push(FallingBlock {
+                    id,
+                    column: (x, z),
+                    y: y as f32,
+                    velocity: 0.0,
+                });
+                started += 1;
+            }
+        }
+    }
+
+    /// releases unheld blocks and moves every falling block `dt` seconds.
+    /// returns the voxels blocks landed in this step, they are voxels in the map again.
+    pub fn step(&mut self, map: &mut VoxelMap, dt: f32) -> Vec<(i32, i32, i32)> {
+        self.release(map);
+        let mut landed = vec![];
+        let mut still_falling = Vec::with_capacity(self.blocks.len());
+        for mut block in std::mem::take(&mut self.blocks) {
+            let (x, z) = block.column;
+            let bottom = block.y.floor() as i32;
+            // waits while its chunk is not loaded, like the walker
+            if !map.chunk_list.contains_key(&map.chunk_key(x, bottom, z)) {
+                still_falling.push(block);
+                continue;
+            }
+            block.velocity = (block.velocity - FALL_GRAVITY * dt).max(-MAX_FALL_SPEED);
+            let target = block.y + block.velocity * dt;
+
+            // the highest voxel it passes that holds it, checked one by one so a fast block does not skip any
+            let floor = (target.floor() as i32..bottom)
+                .rev()
+                .find(|y| held(map, x, *y, z));
+            match floor {
+                Some(y) => {
+                    let pos = place(map, x, y + 1, z, block.id);
+                    landed.push(pos);
+                }
+                None => {
+                    block.y = target;
+                    still_falling.push(block);
+                }
+            }
+        }
+        self.blocks = still_falling;
+        // landing can fill a hole under a queued block, or land on one that was just removed
+        self.release(map);
+        landed
+    }
+}
+
+/// a solid voxel holds a block up, so does a chunk that is not loaded.
+fn held(map: &VoxelMap, x: i32, y: i32, z: i32) -> bool {
+    !map.chunk_list.contains_key(&map.chunk_key(x, y, z))
+        || map.blocks.is_solid(map.get_voxel(x, y, z))
+}
+
+/// puts the block in the first voxel from `y` up that is not solid, something may have been built there.
+fn place(map: &mut VoxelMap, x: i32, y: i32, z: i32, id: u16) -> (i32, i32, i32) {
+    let mut y = y;
+    while map.blocks.is_solid(map.get_voxel(x, y, z)) {
+        y += 1;
+    }
+    map.set_voxel(x, y, z, id);
+    map.mark_border_neighbors_dirty(x, y, z);
+    (x, y, z)
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::voxel::{
+        block::{GRAVEL, SAND, WATER},
+        voxel::Chunk,
+    };
+
+    /// chunks 0 to 1 on y at x and z 0, with a stone floor at y 0.
+    fn ground() -> VoxelMap {
+        let mut map = VoxelMap::new((16, 16, 16));
+        map.add_chunk(0, 0, 0, Chunk::new(16));
+        map.add_chunk(0, 1, 0, Chunk::new(16));
+        for x in 0..16 {
+            for z in 0..16 {
+                map.set_voxel(x, 0, z, 6);
+            }
+        }
+        map.enable_falling();
+        map
+    }
+
+    /// steps a 60th of a second until nothing is falling, at most `max` steps.
+    fn settle(map: &mut VoxelMap, falling: &mut FallingBlocks, max: usize) -> Vec<(i32, i32, i32)> {
+        let mut landed = vec![];
+        for _ in 0..max {
+            landed.extend(falling.step(map, 1.0 / 60.0));
+            if falling.blocks.is_empty() {
+                return landed;
+            }
+        }
+        panic!("still falling after {} steps", max);
+    }
+
+    #[test]
+    fn placed_in_the_air_it_falls_and_lands() {
+        let mut map = ground();
+        let mut falling = FallingBlocks::new();
+        map.set_voxel(3, 8, 3, SAND);
+        // stone does not fall
+        map.set_voxel(5, 8, 5, 6);
+        falling.step(&mut map, 1.0 / 60.0);
+        assert_eq!(map.get_voxel(3, 8, 3), 0);
+        assert_eq!(falling.blocks.len(), 1);
+
+        assert_eq!(settle(&mut map, &mut falling, 120), vec![(3, 1, 3)]);
+        assert_eq!(map.get_voxel(3, 1, 3), SAND);
+        assert_eq!(map.get_voxel(5, 8, 5), 6);
+    }
+
+    #[test]
+    fn removing_the_support_drops_the_column() {
+        let mut map = ground();
+        let mut falling = FallingBlocks::new();
+        map.set_voxel(2, 1, 2, 6);
+        map.set_voxel(2, 2, 2, SAND);
+        map.set_voxel(2, 3, 2, GRAVEL);
+        map.set_voxel(2, 4, 2, SAND);
+        assert_eq!(falling.release(&mut map), 0);
+
+        map.set_voxel(2, 1, 2, 0);
+        assert_eq!(falling.release(&mut map), 3);
+        settle(&mut map, &mut falling, 120);
+        // the column is one lower, in the same order
+        assert_eq!(map.get_voxel(2, 1, 2), SAND);
+        assert_eq!(map.get_voxel(2, 2, 2), GRAVEL);
+        assert_eq!(map.get_voxel(2, 3, 2), SAND);
+        assert_eq!(map.get_voxel(2, 4, 2), 0);
+    }
+
+    #[test]
+    fn falls_through_water_and_across_a_chunk_border() {
+        let mut map = ground();
+        let mut falling = FallingBlocks::new();
+        map.set_voxel(4, 1, 4, WATER);
+        map.set_voxel(4, 20, 4, SAND);
+        for c in map.chunk_list.values_mut() {
+            c.set_is_dirty(false);
+        }
+        assert_eq!(settle(&mut map, &mut falling, 120), vec![(4, 1, 4)]);
+        assert_eq!(map.get_voxel(4, 20, 4), 0);
+        assert_eq!(map.get_voxel(4, 1, 4), SAND);
+        assert!(map.chunk_list.get(&(0, 1, 0)).unwrap().is_dirty());
+        assert!(map.chunk_list.get(&(0, 0, 0)).unwrap().is_dirty());
+    }
+
+    #[test]
+    fn unloaded_chunks_hold_blocks_up() {
+        let mut map = ground();
+        let mut falling = FallingBlocks::new();
+        // the bottom of chunk (0, 0, 0), chunk (0, -1, 0) is not loaded
+        map.set_voxel(1, 0, 1, 0);
+        map.set_voxel(1, 0, 1, SAND);
+        assert_eq!(falling.release(&mut map), 0);
+        assert_eq!(map.get_voxel(1, 0, 1), SAND);
+    }
+
+    #[test]
+    fn lands_on_top_of_what_was_built_under_it() {
+        let mut map = ground();
+        let mut falling = FallingBlocks::new();
+        map.set_voxel(6, 10, 6, GRAVEL);
+        falling.step(&mut map, 1.0 / 60.0);
+        // built into the voxel it is about to land in
+        map.set_voxel(6, 1, 6, 6);
+        map.set_voxel(6, 2, 6, 6);
+        settle(&mut map, &mut falling, 120);
+        assert_eq!(map.get_voxel(6, 3, 6), GRAVEL);
+    }
+
+    #[test]
+    fn nothing_is_queued_unless_enabled() {
+        let mut map = VoxelMap::new((16, 16, 16));
+        map.add_chunk(0, 0, 0, Chunk::new(16));
+        map.set_voxel(1, 5, 1, SAND);
+        assert!(map.drain_fall_checks().is_empty());
+        assert_eq!(FallingBlocks::new().release(&mut map), 0);
+    }
+}
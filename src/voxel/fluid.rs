@@ -0,0 +1,335 @@
+// cellular automaton for fluid blocks. every fluid voxel has a level from 1 to MAX_FLUID_LEVEL, a
+// step moves level down first and then one level at a time to lower sides. level is never made or
+// lost, so a spill spreads until no side is 2 levels lower and then stops being stepped.
+// only voxels that changed, and their neighbors, are looked at in the next step.
+
+use std::collections::BTreeSet;
+
+use super::voxel::VoxelMap;
+
+/// level of a full fluid voxel.
+pub const MAX_FLUID_LEVEL: u8 = 8;
+
+/// fluid steps per second, on the local world and on the server.
+pub const FLUID_TICK_RATE: f64 = 8.0;
+
+/// the order sideways flow goes in, fixed so a step always gives the same result.
+const SIDES: [(i32, i32, i32); 4] = [(1, 0, 0), (-1, 0, 0), (0, 0, 1), (0, 0, -1)];
+
+const NEIGHBORS: [(i32, i32, i32); 6] = [
+    (1, 0, 0),
+    (-1, 0, 0),
+    (0, 1, 0),
+    (0, -1, 0),
+    (0, 0, 1),
+    (0, 0, -1),
+];
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct FluidSim {
+    /// voxels to look at in the next step, sorted so the step does not depend on insert order.
+    active: BTreeSet<(i32, i32, i32)>,
+}
+
+impl FluidSim {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// the voxel is looked at in the next step, call it after editing fluid or the blocks around it.
+    pub fn wake(&mut self, pos: (i32, i32, i32)) {
+        self.active.insert(pos);
+    }
+
+    /// wakes the voxel and its 6 neighbors.
+    pub fn wake_around(&mut self, pos: (i32, i32, i32)) {
+        self.wake(pos);
+        for (dx, dy, dz) in NEIGHBORS {
+            self.wake((pos.0 + dx, pos.1 + dy, pos.2 + dz));
+        }
+    }
+
+    /// wakes every fluid voxel of a chunk, for chunks that were just loaded or generated.
+    pub fn wake_chunk(&mut self, map: &VoxelMap, key: (i32, i32, i32)) {
+        let size = map.chunk_size.0;
+        let chunk = match map.chunk_list.get(&key) {
+            Some(c) if c.volume.is_some() => c,
+            _ => return,
+        };
+        for x in 0..size {
+            for y in 0..size {
+                for z in 0..size {
+                    if map.blocks.is_fluid(chunk.get_voxel(x, y, z, size)) {
+                        self.wake((key.0 * size + x, key.1 * size + y, key.2 * size + z));
+                    }
+                }
+            }
+        }
+    }
+
+    /// true when no fluid is left to move.
+    pub fn is_settled(&self) -> bool {
+        self.active.is_empty()
+    }
+
+    /// runs one tick of the simulation, returns the voxels it changed in order.
+    /// the chunks of changed voxels, and neighbor chunks they border, are marked dirty.
+    pub fn step(&mut self, map: &mut VoxelMap) -> Vec<(i32, i32, i32)> {
+        let cells = std::mem::take(&mut self.active);
+        let mut changed = BTreeSet::new();
+        for pos in cells {
+            let id = map.get_voxel(pos.0, pos.1, pos.2);
+            if !map.blocks.is_fluid(id) {
+                continue;
+            }
+            let mut level = map.get_fluid_level(pos.0, pos.1, pos.2);
+
+            let below = (pos.0, pos.1 - 1, pos.2);
+            let mut falling = false;
+            if let Some(there) = room_for(map, below, id) {
+                let moved = level.min(MAX_FLUID_LEVEL - there);
+                if moved > 0 {
+                    level -= moved;
+                    set_level(map, below, id, there + moved, &mut changed);
+                    set_level(map, pos, id, level, &mut changed);
+                }
+                falling = there + moved < MAX_FLUID_LEVEL;
+            }
+            if falling {
+                continue;
+            }
+
+            for (dx, _, dz) in SIDES {
+                if level <= 1 {
+                    break;
+                }
+                let side = (pos.0 + dx, pos.1, pos.2 + dz);
+                if let Some(there) = room_for(map, side, id) {
+                    if there + 2 <= level {
+                        level -= 1;
+                        set_level(map, side, id, there + 1, &mut changed);
+                        set_level(map, pos, id, level, &mut changed);
+                    }
+                }
+            }
+        }
+
+        for pos in changed.iter() {
+            self.wake_around(*pos);
+            map.mark_border_neighbors_dirty(pos.0, pos.1, pos.2);
+        }
+        changed.into_iter().collect()
+    }
+}
+
+/// level of `id` at `pos` if fluid can flow there, 0 for air. None for other blocks and unloaded chunks.
+fn room_for(map: &VoxelMap, pos: (i32, i32, i32), id: u16) -> Option<u8> {
+    if !map
+        .chunk_list
+        .contains_key(&map.chunk_key(pos.0, pos.1, pos.2))
+    {
+        return None;
+    }
+    match map.get_voxel(pos.0, pos.1, pos.2) {
+        0 => Some(0),
+        v if v == id => Some(map.get_fluid_level(pos.0, pos.1, pos.2)),
+        _ => None,
+    }
+}
+
+fn set_level(
+    map: &mut VoxelMap,
+    pos: (i32, i32, i32),
+    id: u16,
+    level: u8,
+    changed: &mut BTreeSet<(i32, i32, i32)>,
+) {
+    map.set_fluid(pos.0, pos.1, pos.2, id, level);
+    changed.insert(pos);
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::voxel::{
+        block::{RenderLayer, WATER},
+        voxel::{Chunk, ChunkKey},
+    };
+
+    /// chunks -1 to 0 on x and z at y 0, with a stone floor at y 0.
+    fn basin() -> VoxelMap {
+        let mut map = VoxelMap::new((16, 16, 16));
+        for x in -1..=0 {
+            for z in -1..=0 {
+                map.add_chunk(x, 0, z, Chunk::new(16));
+            }
+        }
+        for x in -16..16 {
+            for z in -16..16 {
+                map.set_voxel(x, 0, z, 6);
+            }
+        }
+        map
+    }
+
+    fn pour(map: &mut VoxelMap, sim: &mut FluidSim, pos: (i32, i32, i32), level: u8) {
+        map.set_fluid(pos.0, pos.1, pos.2, WATER, level);
+        sim.wake(pos);
+    }
+
+    /// steps until settled, at most `max` steps, returns how many it took.
+    fn settle(map: &mut VoxelMap, sim: &mut FluidSim, max: usize) -> usize {
+        for i in 0..max {
+            if sim.is_settled() {
+                return i;
+            }
+            sim.step(map);
+        }
+        assert!(sim.is_settled(), "not settled after {} steps", max);
+        max
+    }
+
+    /// every water voxel and its level, in order.
+    fn water(map: &VoxelMap) -> Vec<((i32, i32, i32), u8)> {
+        let mut out = vec![];
+        for x in -16..16 {
+            for y in 0..16 {
+                for z in -16..16 {
+                    if map.get_voxel(x, y, z) == WATER {
+                        out.push(((x, y, z), map.get_fluid_level(x, y, z)));
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    fn total(map: &VoxelMap) -> u32 {
+        water(map).iter().map(|(_, l)| *l as u32).sum()
+    }
+
+    #[test]
+    fn falls_to_the_floor() {
+        let mut map = basin();
+        let mut sim = FluidSim::new();
+        pour(&mut map, &mut sim, (3, 5, 3), 4);
+        sim.step(&mut map);
+        assert_eq!(map.get_voxel(3, 5, 3), 0);
+        assert_eq!(map.get_fluid_level(3, 4, 3), 4);
+        settle(&mut map, &mut sim, 20);
+        // 4 levels spread over the floor, none is left in the air
+        assert!(water(&map).iter().all(|(p, _)| p.1 == 1));
+        assert_eq!(total(&map), 4);
+    }
+
+    #[test]
+    fn spreads_into_a_puddle_and_settles() {
+        let mut map = basin();
+        let mut sim = FluidSim::new();
+        pour(&mut map, &mut sim, (0, 1, 0), MAX_FLUID_LEVEL);
+        pour(&mut map, &mut sim, (0, 2, 0), MAX_FLUID_LEVEL);
+        settle(&mut map, &mut sim, 200);
+
+        let cells = water(&map);
+        assert_eq!(total(&map), 16);
+        assert!(cells.len() > 4);
+        // settled means no neighbor is 2 or more lower
+        for (p, l) in cells.iter() {
+            for (dx, _, dz) in SIDES {
+                let side = map.get_fluid_level(p.0 + dx, p.1, p.2 + dz);
+                assert!(side + 2 > *l, "{:?} {} next to {}", p, l, side);
+            }
+        }
+        // a settled puddle stays as it is
+        for key in [(0, 0, 0), (-1, 0, 0), (0, 0, -1), (-1, 0, -1)] {
+            sim.wake_chunk(&map, key);
+        }
+        assert!(sim.step(&mut map).is_empty());
+        assert_eq!(water(&map), cells);
+    }
+
+    #[test]
+    fn same_map_steps_the_same() {
+        let build = || {
+            let mut map = basin();
+            let mut sim = FluidSim::new();
+            map.set_voxel(2, 1, 0, 6);
+            map.set_voxel(-2, 1, 1, 6);
+            pour(&mut map, &mut sim, (0, 4, 0), 7);
+            pour(&mut map, &mut sim, (1, 1, 0), MAX_FLUID_LEVEL);
+            pour(&mut map, &mut sim, (-1, 2, 1), 5);
+            (map, sim)
+        };
+        let (mut a, mut sim_a) = build();
+        let (mut b, mut sim_b) = build();
+        for _ in 0..40 {
+            assert_eq!(sim_a.step(&mut a), sim_b.step(&mut b));
+            assert_eq!(water(&a), water(&b));
+        }
+        assert_eq!(total(&a), 20);
+    }
+
+    #[test]
+    fn walls_and_unloaded_chunks_hold_it() {
+        let mut map = basin();
+        let mut sim = FluidSim::new();
+        // a 1 wide trench along x, open at the x = 15 end where no chunk is loaded
+        for x in 10..16 {
+            map.set_voxel(x, 1, 4, 6);
+            map.set_voxel(x, 1, 6, 6);
+        }
+        map.set_voxel(10, 1, 5, 6);
+        pour(&mut map, &mut sim, (15, 1, 5), MAX_FLUID_LEVEL);
+        settle(&mut map, &mut sim, 100);
+        assert_eq!(total(&map), MAX_FLUID_LEVEL as u32);
+        assert!(water(&map)
+            .iter()
+            .all(|(p, _)| p.0 > 10 && p.0 < 16 && p.2 == 5));
+    }
+
+    #[test]
+    fn flows_across_chunk_borders_and_marks_them_dirty() {
+        let mut map = basin();
+        let mut sim = FluidSim::new();
+        for c in map.chunk_list.values_mut() {
+            c.set_is_dirty(false);
+        }
+        // the corner of 4 chunks
+        pour(&mut map, &mut sim, (0, 1, 0), MAX_FLUID_LEVEL);
+        sim.step(&mut map);
+        assert_eq!(map.get_voxel(-1, 1, 0), WATER);
+        assert_eq!(map.get_voxel(0, 1, -1), WATER);
+        // (-1, 0, -1) got no water, but its border faces changed
+        for key in [(0, 0, 0), (-1, 0, 0), (0, 0, -1), (-1, 0, -1)] {
+            assert!(map.chunk_list.get(&key).unwrap().is_dirty(), "{:?}", key);
+        }
+        settle(&mut map, &mut sim, 200);
+        assert_eq!(total(&map), MAX_FLUID_LEVEL as u32);
+    }
+
+    #[test]
+    fn partial_levels_mesh_lower() {
+        let top_of = |level: u8| {
+            let mut map = basin();
+            map.set_fluid(3, 1, 3, WATER, level);
+            let mut vertices = vec![];
+            let (mut indices, mut step) = (vec![], 0);
+            map.update_chunk_mesh(
+                ChunkKey::new((0, 0, 0)),
+                RenderLayer::Transparent,
+                &mut vertices,
+                &mut indices,
+                &mut step,
+                false,
+            );
+            assert!(!vertices.is_empty());
+            vertices
+                .iter()
+                .map(|v| v.position[1])
+                .fold(f32::MIN, f32::max)
+        };
+        assert_eq!(top_of(MAX_FLUID_LEVEL), 2.0);
+        assert_eq!(top_of(4), 1.5);
+        assert_eq!(top_of(1), 1.125);
+    }
+}
@@ -6,6 +6,8 @@ use packed_struct::prelude::*;
 
 pub struct Volume {
     pub type_id: AttributeLayer<u16>,
+    /// how full a fluid voxel is, 1 to fluid::MAX_LEVEL. 0 on a fluid voxel means full.
+    pub fluid_level: AttributeLayer<u8>,
 }
 
 impl Volume {
@@ -13,7 +15,13 @@ impl Volume {
         let type_id = AttributeLayer {
             layer: vec![0; size],
         };
-        Self { type_id }
+        let fluid_level = AttributeLayer {
+            layer: vec![0; size],
+        };
+        Self {
+            type_id,
+            fluid_level,
+        }
     }
 }
 
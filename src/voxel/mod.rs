@@ -1,3 +1,7 @@
 //pub mod volume;
+pub mod block;
+pub mod falling;
+pub mod fluid;
 pub mod layers;
+pub mod ticks;
 pub mod voxel;
@@ -0,0 +1,305 @@
+// block behavior over time. every game tick some random voxels of each loaded chunk get a random
+// tick, and voxels scheduled with `VoxelMap::schedule_tick` get their scheduled tick when it is due.
+// what a tick does is up to the handler registered for the block id in the voxel at that moment,
+// ids without a handler are skipped. the random picks are seeded from the game tick and chunk key,
+// so the same map with the same handlers always changes the same way.
+
+use std::collections::HashMap;
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use super::{
+    block::BlockRegistry,
+    voxel::{index_to_xyz, VoxelMap},
+};
+
+/// random ticks per loaded chunk and game tick.
+pub const RANDOM_TICKS_PER_CHUNK: usize = 3;
+
+/// `pos` is the world position of the ticked voxel, `rng` is for whatever the behavior needs.
+pub type TickHandler = fn(map: &mut VoxelMap, pos: (i32, i32, i32), rng: &mut StdRng);
+
+#[derive(Default, Clone)]
+pub struct TickHandlers {
+    random: HashMap<u16, TickHandler>,
+    scheduled: HashMap<u16, TickHandler>,
+}
+
+impl TickHandlers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// replaces the random tick handler of `id`.
+    pub fn on_random(&mut self, id: u16, handler: TickHandler) -> &mut Self {
+        self.random.insert(id, handler);
+        self
+    }
+
+    /// replaces the scheduled tick handler of `id`.
+    pub fn on_scheduled(&mut self, id: u16, handler: TickHandler) -> &mut Self {
+        self.scheduled.insert(id, handler);
+        self
+    }
+
+    /// moves the game tick on and runs the scheduled ticks due by then, then the random ticks.
+    /// returns how many handlers ran.
+    pub fn run(&self, map: &mut VoxelMap, random_per_chunk: usize) -> usize {
+        let now = map.advance_game_tick();
+        let mut ran = 0;
+
+        // ticks scheduled by these handlers are at least one tick away, so they wait for the next run
+        for pos in map.take_due_ticks() {
+            let id = map.get_voxel(pos.0, pos.1, pos.2);
+            if let Some(handler) = self.scheduled.get(&id) {
+                let mut rng = StdRng::seed_from_u64(tick_seed(now, pos));
+                handler(map, pos, &mut rng);
+                ran += 1;
+            }
+        }
+
+        if self.random.is_empty() || random_per_chunk == 0 {
+            return ran;
+        }
+        let size = map.chunk_size.0;
+        let keys: Vec<(i32, i32, i32)> = map
+            .chunk_list
+            .iter()
+            .filter(|(_, c)| c.volume.is_some())
+            .map(|(k, _)| *k)
+            .collect();
+        for key in keys {
+            let mut rng = StdRng::seed_from_u64(tick_seed(now, key));
+            for _ in 0..random_per_chunk {
+                let (lx, ly, lz) = index_to_xyz(rng.gen_range(0..size * size * size), size);
+                let pos = (key.0 * size + lx, key.1 * size + ly, key.2 * size + lz);
+                let id = map.get_voxel(pos.0, pos.1, pos.2);
+                if let Some(handler) = self.random.get(&id) {
+                    handler(map, pos, &mut rng);
+                    ran += 1;
+                }
+            }
+        }
+        ran
+    }
+}
+
+/// mixes the game tick with a position, for a random stream per chunk or voxel and tick.
+fn tick_seed(tick: u64, pos: (i32, i32, i32)) -> u64 {
+    let mut h = tick ^ 0x9e37_79b9_7f4a_7c15;
+    for v in [pos.0, pos.1, pos.2] {
+        h = (h ^ v as u32 as u64).wrapping_mul(0x0000_0100_0000_01b3);
+        h ^= h >> 29;
+    }
+    h
+}
+
+// default behaviors ---------------------------------------------------------------------------
+
+/// names of the blocks of the grass layer of the world generator.
+pub const GRASS: [&str; 2] = ["leaves_big_oak", "leaves_big_oak1"];
+/// names of the dirt blocks grass spreads over, covered grass turns into the first.
+pub const DIRT: [&str; 5] = ["dirt", "dirt1", "dirt2", "dirt3", "dirt4"];
+/// ticks covered grass lasts before it turns to dirt.
+pub const GRASS_DIES_AFTER: u64 = 40;
+
+impl TickHandlers {
+    /// grass spreading onto dirt next to it, and dying when it is covered.
+    /// the grass ids are looked up by name, blocks missing from `blocks` get no behavior.
+    pub fn with_default_behaviors(blocks: &BlockRegistry) -> Self {
+        let mut handlers = Self::new();
+        for id in GRASS.iter().filter_map(|name| blocks.id_of(name)) {
+            handlers
+                .on_random(id, grass_random_tick)
+                .on_scheduled(id, grass_scheduled_tick);
+        }
+        handlers
+    }
+}
+
+fn is_dirt(map: &VoxelMap, id: u16) -> bool {
+    matches!(map.blocks.get(id), Some(b) if DIRT.contains(&b.name.as_str()))
+}
+
+fn is_covered(map: &VoxelMap, pos: (i32, i32, i32)) -> bool {
+    map.blocks.is_solid(map.get_voxel(pos.0, pos.1 + 1, pos.2))
+}
+
+/// spreads to a random dirt voxel up to one away on each axis that has air over it.
+/// covered grass schedules its own death instead, a random tick landing on it again changes nothing.
+fn grass_random_tick(map: &mut VoxelMap, pos: (i32, i32, i32), rng: &mut StdRng) {
+    if is_covered(map, pos) {
+        map.schedule_tick(pos.0, pos.1, pos.2, GRASS_DIES_AFTER);
+        return;
+    }
+    let id = map.get_voxel(pos.0, pos.1, pos.2);
+    let to = (
+        pos.0 + rng.gen_range(-1..=1),
+        pos.1 + rng.gen_range(-1..=1),
+        pos.2 + rng.gen_range(-1..=1),
+    );
+    if is_dirt(map, map.get_voxel(to.0, to.1, to.2)) && !is_covered(map, to) {
+        map.set_voxel(to.0, to.1, to.2, id);
+        map.mark_border_neighbors_dirty(to.0, to.1, to.2);
+    }
+}
+
+/// still covered after the wait, the grass turns to dirt.
+fn grass_scheduled_tick(map: &mut VoxelMap, pos: (i32, i32, i32), _rng: &mut StdRng) {
+    if !is_covered(map, pos) {
+        return;
+    }
+    if let Some(dirt) = map.blocks.id_of(DIRT[0]) {
+        map.set_voxel(pos.0, pos.1, pos.2, dirt);
+        map.mark_border_neighbors_dirty(pos.0, pos.1, pos.2);
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::voxel::voxel::Chunk;
+
+    fn id(name: &str) -> u16 {
+        BlockRegistry::default().id_of(name).unwrap()
+    }
+
+    /// one chunk, dirt at y 0 and y 1 under air, grass at (8, 1, 8).
+    fn field() -> VoxelMap {
+        let mut map = VoxelMap::new((16, 16, 16));
+        map.add_chunk(0, 0, 0, Chunk::new(16));
+        for x in 0..16 {
+            for z in 0..16 {
+                map.set_voxel(x, 0, z, 1);
+                map.set_voxel(x, 1, z, 1);
+            }
+        }
+        map.set_voxel(8, 1, 8, id(GRASS[0]));
+        map
+    }
+
+    fn count(map: &VoxelMap, id: u16) -> usize {
+        let mut n = 0;
+        for x in 0..16 {
+            for y in 0..16 {
+                for z in 0..16 {
+                    if map.get_voxel(x, y, z) == id {
+                        n += 1;
+                    }
+                }
+            }
+        }
+        n
+    }
+
+    fn mark(map: &mut VoxelMap, pos: (i32, i32, i32), _rng: &mut StdRng) {
+        map.set_voxel(pos.0, pos.1 + 1, pos.2, 6);
+    }
+
+    #[test]
+    fn grass_spreads_over_dirt_and_only_where_it_is_open() {
+        let mut map = field();
+        // a lid over the corner, dirt under it stays dirt
+        for x in 10..16 {
+            for z in 10..16 {
+                map.set_voxel(x, 2, z, 6);
+            }
+        }
+        let handlers = TickHandlers::with_default_behaviors(&BlockRegistry::default());
+        // every voxel gets a random tick about every 8 game ticks
+        for _ in 0..400 {
+            handlers.run(&mut map, 512);
+        }
+        assert!(count(&map, id(GRASS[0])) > 100);
+        for x in 10..16 {
+            for z in 10..16 {
+                assert_eq!(map.get_voxel(x, 1, z), 1, "{:?}", (x, z));
+            }
+        }
+        // grass only spreads over dirt with air above, the bottom layer stays dirt
+        for x in 0..16 {
+            for z in 0..16 {
+                assert_eq!(map.get_voxel(x, 0, z), 1);
+            }
+        }
+    }
+
+    #[test]
+    fn same_map_ticks_the_same() {
+        let handlers = TickHandlers::with_default_behaviors(&BlockRegistry::default());
+        let (mut a, mut b) = (field(), field());
+        for _ in 0..100 {
+            handlers.run(&mut a, 512);
+            handlers.run(&mut b, 512);
+        }
+        assert_eq!(
+            a.chunk_list[&(0, 0, 0)]
+                .volume
+                .as_ref()
+                .unwrap()
+                .type_id
+                .layer,
+            b.chunk_list[&(0, 0, 0)]
+                .volume
+                .as_ref()
+                .unwrap()
+                .type_id
+                .layer
+        );
+        assert!(count(&a, id(GRASS[0])) > 1);
+    }
+
+    #[test]
+    fn scheduled_ticks_run_once_when_due_and_dedup() {
+        let mut map = field();
+        let mut handlers = TickHandlers::new();
+        handlers.on_scheduled(1, mark);
+
+        assert!(map.schedule_tick(3, 1, 3, 5));
+        // the earlier of two ticks of the same voxel is kept
+        assert!(map.schedule_tick(3, 1, 3, 9));
+        assert!(map.schedule_tick(3, 1, 3, 2));
+        assert!(map.schedule_tick(5, 1, 5, 4));
+        // not loaded
+        assert!(!map.schedule_tick(3, 20, 3, 1));
+
+        assert_eq!(handlers.run(&mut map, 0), 0);
+        assert_eq!(handlers.run(&mut map, 0), 1);
+        assert_eq!(map.get_voxel(3, 2, 3), 6);
+        assert_eq!(handlers.run(&mut map, 0), 0);
+        assert_eq!(handlers.run(&mut map, 0), 1);
+        assert_eq!(map.get_voxel(5, 2, 5), 6);
+        for _ in 0..10 {
+            assert_eq!(handlers.run(&mut map, 0), 0);
+        }
+    }
+
+    #[test]
+    fn handlers_go_by_the_block_in_the_voxel_when_due() {
+        let mut map = field();
+        let mut handlers = TickHandlers::new();
+        handlers.on_scheduled(id(GRASS[0]), mark);
+        map.schedule_tick(8, 1, 8, 3);
+        // no handler for stone
+        map.set_voxel(8, 1, 8, 6);
+        for _ in 0..3 {
+            handlers.run(&mut map, 0);
+        }
+        assert_eq!(map.get_voxel(8, 2, 8), 0);
+    }
+
+    #[test]
+    fn covered_grass_dies_after_a_while() {
+        let mut map = field();
+        map.set_voxel(8, 2, 8, 6);
+        let handlers = TickHandlers::with_default_behaviors(&BlockRegistry::default());
+        let mut rng = StdRng::seed_from_u64(0);
+        grass_random_tick(&mut map, (8, 1, 8), &mut rng);
+        for _ in 0..GRASS_DIES_AFTER - 1 {
+            handlers.run(&mut map, 0);
+        }
+        assert_eq!(map.get_voxel(8, 1, 8), id(GRASS[0]));
+        handlers.run(&mut map, 0);
+        assert_eq!(map.get_voxel(8, 1, 8), id(DIRT[0]));
+    }
+}
@@ -1,5 +1,5 @@
 use std::{
-    collections::BTreeMap,
+    collections::{btree_map::Entry, BTreeMap},
     fs::{self, File},
     io::Write,
     path::Path,
@@ -8,17 +8,17 @@ use std::{
 };
 
 //use profiling::register_thread;
+use bevy::prelude::Component;
 use serde::{Deserialize, Serialize};
 
-use noise::{NoiseFn, OpenSimplex};
 use prism_math::{min, vec3, xyz_to_index};
 use rayon::prelude::*;
 
-use super::layers::Volume;
-
-pub fn noise_3d(x: f32, y: f32, z: f32) -> f32 {
-    ((x as f32 * 0.36).sin() + (z as f32 * 0.36).cos() + (y as f32 * 0.36).sin() * 2.0) * 1.0
-}
+use super::{
+    block::{BlockRegistry, RenderLayer},
+    fluid::MAX_FLUID_LEVEL,
+    layers::Volume,
+};
 
 pub fn key_distance(a: (i32, i32, i32), b: (i32, i32, i32)) -> i32 {
     let dis = ((((b.0 - a.0) * (b.0 - a.0))
@@ -31,9 +31,36 @@ pub fn key_distance(a: (i32, i32, i32), b: (i32, i32, i32)) -> i32 {
 // do not use entitys for per chunk
 // one entity is one map with its own chunk managemet
 
+#[derive(Component)]
+pub struct VolumeMap {
+    pub val: VoxelMap,
+}
+
 pub struct VoxelMap {
     pub chunk_size: (i32, i32, i32),
     pub chunk_list: BTreeMap<(i32, i32, i32), Chunk>,
+    pub blocks: BlockRegistry,
+    /// None unless `enable_journal` was called, nothing is recorded by default.
+    journal: Option<Vec<VoxelChange>>,
+    /// sequence of the last recorded change.
+    edit_seq: u64,
+    /// None unless `enable_falling` was called, voxels whose falling block may have lost its support.
+    fall_checks: Option<Vec<(i32, i32, i32)>>,
+    /// block ticks run so far, see ticks.rs. scheduled ticks are due at a value of it.
+    game_tick: u64,
+}
+
+/// one change made with `VoxelMap::set_voxel` or `set_fluid` while the journal is on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VoxelChange {
+    /// world position of the voxel.
+    pub pos: (i32, i32, i32),
+    pub old: u16,
+    pub new: u16,
+    /// fluid level after the change, 0 for none.
+    pub level: u8,
+    /// starts at 1 and goes up by one for every change in the map.
+    pub seq: u64,
 }
 
 impl VoxelMap {
@@ -42,15 +69,162 @@ impl VoxelMap {
         Self {
             chunk_size,
             chunk_list,
+            blocks: BlockRegistry::default(),
+            journal: None,
+            edit_seq: 0,
+            fall_checks: None,
+            game_tick: 0,
+        }
+    }
+    /// starts recording every voxel change, used by the server to replicate edits.
+    pub fn enable_journal(&mut self) {
+        if self.journal.is_none() {
+            self.journal = Some(vec![]);
+        }
+    }
+    /// the changes recorded since the last call, oldest first.
+    pub fn drain_journal(&mut self) -> Vec<VoxelChange> {
+        match &mut self.journal {
+            Some(j) => std::mem::take(j),
+            None => vec![],
+        }
+    }
+    /// starts queueing falling blocks that `set_voxel` may have left without support.
+    pub fn enable_falling(&mut self) {
+        if self.fall_checks.is_none() {
+            self.fall_checks = Some(vec![]);
         }
     }
+    /// the voxels queued since the last call, see falling.rs.
+    pub fn drain_fall_checks(&mut self) -> Vec<(i32, i32, i32)> {
+        match &mut self.fall_checks {
+            Some(f) => std::mem::take(f),
+            None => vec![],
+        }
+    }
+    pub fn game_tick(&self) -> u64 {
+        self.game_tick
+    }
+    /// moves the game tick on by one, done by `TickHandlers::run`.
+    pub fn advance_game_tick(&mut self) -> u64 {
+        self.game_tick += 1;
+        self.game_tick
+    }
+    /// schedules a tick of the voxel `delay` ticks from now, at least 1.
+    /// a voxel has one scheduled tick at most, the earlier one is kept. false if its chunk is not loaded.
+    pub fn schedule_tick(&mut self, x: i32, y: i32, z: i32, delay: u64) -> bool {
+        let due = self.game_tick + delay.max(1);
+        let key = self.chunk_key(x, y, z);
+        let size = self.chunk_size.0;
+        let c = match self.chunk_list.get_mut(&key) {
+            Some(c) => c,
+            None => return false,
+        };
+        let index =
+            xyz_to_index!(x & (size - 1), y & (size - 1), z & (size - 1), size, size) as u16;
+        match c.scheduled_ticks.entry(index) {
+            Entry::Vacant(e) => {
+                e.insert(due);
+            }
+            Entry::Occupied(mut e) => {
+                if due < *e.get() {
+                    e.insert(due);
+                }
+            }
+        }
+        c.save_dirty = true;
+        true
+    }
+    /// takes the scheduled ticks due by the current game tick out of the chunks, ordered by due tick then position.
+    pub fn take_due_ticks(&mut self) -> Vec<(i32, i32, i32)> {
+        let now = self.game_tick;
+        let size = self.chunk_size.0;
+        let mut due = vec![];
+        for (key, c) in self.chunk_list.iter_mut() {
+            let ready: Vec<(u16, u64)> = c
+                .scheduled_ticks
+                .iter()
+                .filter(|(_, t)| **t <= now)
+                .map(|(i, t)| (*i, *t))
+                .collect();
+            for (index, tick) in ready {
+                c.scheduled_ticks.remove(&index);
+                c.save_dirty = true;
+                let (lx, ly, lz) = index_to_xyz(index as i32, size);
+                due.push((
+                    tick,
+                    (key.0 * size + lx, key.1 * size + ly, key.2 * size + lz),
+                ));
+            }
+        }
+        due.sort_unstable();
+        due.into_iter().map(|(_, pos)| pos).collect()
+    }
+    /// sequence of the last recorded change, 0 if nothing was recorded.
+    pub fn edit_seq(&self) -> u64 {
+        self.edit_seq
+    }
     pub fn add_chunk(&mut self, x: i32, y: i32, z: i32, chunk: Chunk) {
         self.chunk_list.insert((x, y, z), chunk);
     }
     pub fn remove_chunk(&mut self, x: i32, y: i32, z: i32) -> Option<Chunk> {
         self.chunk_list.remove(&(x, y, z))
     }
+    /// key of the chunk that holds the voxel at the world position.
+    pub fn chunk_key(&self, x: i32, y: i32, z: i32) -> (i32, i32, i32) {
+        (x >> 4, y >> 4, z >> 4)
+    }
+    pub fn mark_dirty(&mut self, key: (i32, i32, i32)) {
+        if let Some(c) = self.chunk_list.get_mut(&key) {
+            c.set_is_dirty(true);
+        }
+    }
+    /// marks the 6 chunks around `key` dirty, their border faces depend on this chunk.
+    pub fn mark_neighbors_dirty(&mut self, key: (i32, i32, i32)) {
+        for (x, y, z) in [
+            (1, 0, 0),
+            (-1, 0, 0),
+            (0, 1, 0),
+            (0, -1, 0),
+            (0, 0, 1),
+            (0, 0, -1),
+        ] {
+            self.mark_dirty((key.0 + x, key.1 + y, key.2 + z));
+        }
+    }
+    /// marks the neighbor chunks dirty that touch the voxel, only voxels on a chunk border have any.
+    pub fn mark_border_neighbors_dirty(&mut self, x: i32, y: i32, z: i32) {
+        let key = self.chunk_key(x, y, z);
+        let local_space = (
+            x & (self.chunk_size.0 - 1),
+            y & (self.chunk_size.1 - 1),
+            z & (self.chunk_size.2 - 1),
+        );
+        if local_space.0 == 0 {
+            self.mark_dirty((key.0 - 1, key.1, key.2));
+        }
+        if local_space.0 == self.chunk_size.0 - 1 {
+            self.mark_dirty((key.0 + 1, key.1, key.2));
+        }
+        if local_space.1 == 0 {
+            self.mark_dirty((key.0, key.1 - 1, key.2));
+        }
+        if local_space.1 == self.chunk_size.1 - 1 {
+            self.mark_dirty((key.0, key.1 + 1, key.2));
+        }
+        if local_space.2 == 0 {
+            self.mark_dirty((key.0, key.1, key.2 - 1));
+        }
+        if local_space.2 == self.chunk_size.2 - 1 {
+            self.mark_dirty((key.0, key.1, key.2 + 1));
+        }
+    }
     pub fn set_voxel(&mut self, x: i32, y: i32, z: i32, val: u16) {
+        self.set_voxel_with_level(x, y, z, val, 0);
+    }
+    /// sets the voxel and its fluid level, a level of 0 is no level.
+    /// a change of only the level is journaled too.
+    pub fn set_voxel_with_level(&mut self, x: i32, y: i32, z: i32, val: u16, level: u8) {
         let key = (x >> 4, y >> 4, z >> 4);
         let local_space = (
             x & (self.chunk_size.0 - 1),
@@ -58,6 +232,22 @@ impl VoxelMap {
             z & (self.chunk_size.2 - 1),
         );
         if let Some(c) = self.chunk_list.get_mut(&key) {
+            if let Some(journal) = &mut self.journal {
+                let (lx, ly, lz) = local_space;
+                let old = c.get_voxel(lx, ly, lz, self.chunk_size.0);
+                let old_level = c.get_fluid_level(lx, ly, lz, self.chunk_size.0);
+                // setting a voxel to what it already is changes nothing, so it is not recorded
+                if old != val || old_level != level {
+                    self.edit_seq += 1;
+                    journal.push(VoxelChange {
+                        pos: (x, y, z),
+                        old,
+                        new: val,
+                        level,
+                        seq: self.edit_seq,
+                    });
+                }
+            }
             c.set_voxel(
                 local_space.0,
                 local_space.1,
@@ -65,7 +255,78 @@ impl VoxelMap {
                 self.chunk_size.0,
                 val,
             );
+            if level != 0 {
+                c.set_fluid_level(
+                    local_space.0,
+                    local_space.1,
+                    local_space.2,
+                    self.chunk_size.0,
+                    level,
+                );
+            }
+            if self.fall_checks.is_some() {
+                self.queue_fall_checks(x, y, z, val);
+            }
+        }
+    }
+    fn queue_fall_checks(&mut self, x: i32, y: i32, z: i32, val: u16) {
+        let mut checks = vec![];
+        if self.blocks.falls(val) {
+            checks.push((x, y, z));
+        }
+        // the block above may have stood on this one
+        if !self.blocks.is_solid(val) && self.blocks.falls(self.get_voxel(x, y + 1, z)) {
+            checks.push((x, y + 1, z));
+        }
+        if let Some(f) = &mut self.fall_checks {
+            f.extend(checks);
+        }
+    }
+    /// `set_voxel` for a player edit, unlike ticks and world gen it marks the chunk edited.
+    pub fn edit_voxel(&mut self, x: i32, y: i32, z: i32, val: u16) {
+        self.set_voxel(x, y, z, val);
+        self.mark_edited(x, y, z);
+    }
+    /// `set_fluid` for a player edit.
+    pub fn edit_fluid(&mut self, x: i32, y: i32, z: i32, id: u16, level: u8) {
+        self.set_fluid(x, y, z, id, level);
+        self.mark_edited(x, y, z);
+    }
+    fn mark_edited(&mut self, x: i32, y: i32, z: i32) {
+        let key = self.chunk_key(x, y, z);
+        if let Some(c) = self.chunk_list.get_mut(&key) {
+            c.edited = true;
+        }
+    }
+    /// sets a fluid voxel and its level, a level of 0 makes the voxel air.
+    pub fn set_fluid(&mut self, x: i32, y: i32, z: i32, id: u16, level: u8) {
+        let id = if level == 0 { 0 } else { id };
+        self.set_voxel_with_level(x, y, z, id, level);
+    }
+    /// level of the fluid at the voxel, 0 if it is not a fluid.
+    pub fn get_fluid_level(&self, x: i32, y: i32, z: i32) -> u8 {
+        if !self.blocks.is_fluid(self.get_voxel(x, y, z)) {
+            return 0;
+        }
+        let size = self.chunk_size.0;
+        let level = match self.chunk_list.get(&self.chunk_key(x, y, z)) {
+            Some(c) => c.get_fluid_level(x & (size - 1), y & (size - 1), z & (size - 1), size),
+            None => 0,
+        };
+        // fluid placed without a level, by world gen or an edit, is full
+        if level == 0 {
+            MAX_FLUID_LEVEL
+        } else {
+            level
+        }
+    }
+    /// top of the fluid in the voxel from 0 to 1, full if more of the same fluid is above.
+    pub fn fluid_height(&self, x: i32, y: i32, z: i32) -> f32 {
+        let id = self.get_voxel(x, y, z);
+        if self.get_voxel(x, y + 1, z) == id {
+            return 1.0;
         }
+        self.get_fluid_level(x, y, z) as f32 / MAX_FLUID_LEVEL as f32
     }
     pub fn get_voxel(&self, x: i32, y: i32, z: i32) -> u16 {
         // todo later on use optien insted of just returning a u32
@@ -95,9 +356,11 @@ impl VoxelMap {
         }
     }
     // mesh faces need to be fliped
+    /// only blocks in `layer` are meshed, so each render layer gets its own sub mesh.
     pub fn update_chunk_mesh(
         &self,
         chunk_key: ChunkKey,
+        layer: RenderLayer,
 
         chunk_vertices: &mut Vec<ChunkVertex>,
 
@@ -131,7 +394,7 @@ impl VoxelMap {
                         nz as f32 + quad_size,
                     );
 
-                    if v >= 1 {
+                    if self.blocks.render_layer(v as u16) == Some(layer) {
                         let gv_up = self.get_voxel(x, y + 1, z);
                         let gv_down = self.get_voxel(x, y - 1, z);
                         let gv_right = self.get_voxel(x + 1, y, z);
@@ -164,7 +427,22 @@ impl VoxelMap {
 
                         let fll = ((ll as f32 / 6.0) * 2.0).max(0.5);
 
-                        if gv_up == 0 {
+                        // a fluid that is not full is meshed lower, its sides show against lower fluid
+                        let fluid = self.blocks.is_fluid(v as u16);
+                        let height = if fluid {
+                            self.fluid_height(x, y, z)
+                        } else {
+                            1.0
+                        };
+                        let shown = |n: u16, (sx, sy, sz): (i32, i32, i32)| {
+                            !self.blocks.face_hidden(v as u16, n)
+                                || (fluid
+                                    && n == v as u16
+                                    && self.fluid_height(sx, sy, sz) < height)
+                        };
+                        let first_vertex = chunk_vertices.len();
+
+                        if !self.blocks.face_hidden(v as u16, gv_up) || height < 1.0 {
                             add_quad(
                                 FaceSide::Up,
                                 [fll, fll, fll],
@@ -177,7 +455,7 @@ impl VoxelMap {
                             );
                         }
 
-                        if gv_down == 0 {
+                        if !self.blocks.face_hidden(v as u16, gv_down) {
                             add_quad(
                                 FaceSide::Down,
                                 [fll, fll, fll],
@@ -190,7 +468,7 @@ impl VoxelMap {
                             );
                         }
 
-                        if gv_right == 0 {
+                        if shown(gv_right, (x + 1, y, z)) {
                             add_quad(
                                 FaceSide::Right,
                                 [fll, fll, fll],
@@ -203,7 +481,7 @@ impl VoxelMap {
                             );
                         }
 
-                        if gv_left == 0 {
+                        if shown(gv_left, (x - 1, y, z)) {
                             add_quad(
                                 FaceSide::Left,
                                 [fll, fll, fll],
@@ -216,7 +494,7 @@ impl VoxelMap {
                             );
                         }
 
-                        if gv_front == 0 {
+                        if shown(gv_front, (x, y, z + 1)) {
                             add_quad(
                                 FaceSide::Front,
                                 [fll, fll, fll],
@@ -229,7 +507,7 @@ impl VoxelMap {
                             );
                         }
 
-                        if gv_back == 0 {
+                        if shown(gv_back, (x, y, z - 1)) {
                             add_quad(
                                 FaceSide::Back,
                                 [fll, fll, fll],
@@ -241,6 +519,13 @@ impl VoxelMap {
                                 v - 1,
                             );
                         }
+
+                        if height < 1.0 {
+                            let top = ny - quad_size + height;
+                            for vertex in &mut chunk_vertices[first_vertex..] {
+                                vertex.position[1] = vertex.position[1].min(top);
+                            }
+                        }
                     }
                 }
             }
@@ -259,15 +544,95 @@ impl ChunkKey {
 /// mint to be a simpleafide chunk for saveing and loading, can run a compreson algarithom on data be for saveing
 #[derive(Serialize, Deserialize, Debug)]
 pub struct SaveChunk {
-    pub voxel: Option<Vec<u32>>,
+    /// run length encoded voxel ids, see encode_runs. None if the chunk has no volume.
+    pub voxel: Option<Vec<(u16, u16)>>,
+    /// run length encoded fluid levels, None in files saved before fluids.
+    pub fluid: Option<Vec<(u16, u16)>>,
+    /// (local voxel index, ticks left) of the scheduled ticks, empty in files saved before ticks.
+    pub ticks: Vec<(u16, u64)>,
+}
+
+impl SaveChunk {
+    /// `now` is the game tick of the map, ticks are saved as how long they still have to wait.
+    pub fn from_chunk(chunk: &Chunk, now: u64) -> Self {
+        Self {
+            ticks: chunk
+                .scheduled_ticks
+                .iter()
+                .map(|(i, due)| (*i, due.saturating_sub(now)))
+                .collect(),
+            voxel: chunk.volume.as_ref().map(|v| encode_runs(&v.type_id.layer)),
+            fluid: chunk.volume.as_ref().map(|v| {
+                let levels: Vec<u16> = v.fluid_level.layer.iter().map(|l| *l as u16).collect();
+                encode_runs(&levels)
+            }),
+        }
+    }
+    /// None if the voxel data does not fit a chunk of `size`. `now` is the game tick of the map it goes in.
+    pub fn to_chunk(&self, size: i32, now: u64) -> Option<Chunk> {
+        let mut chunk = Chunk::new(size);
+        for (index, left) in self.ticks.iter() {
+            if *index as i32 >= size * size * size {
+                return None;
+            }
+            chunk.scheduled_ticks.insert(*index, now + left);
+        }
+        if let Some(runs) = &self.voxel {
+            let len = (size * size * size) as usize;
+            let layer = decode_runs(runs);
+            if layer.len() != len {
+                return None;
+            }
+            let mut volume = Volume::new(len);
+            volume.type_id.layer = layer;
+            if let Some(runs) = &self.fluid {
+                let levels = decode_runs(runs);
+                if levels.len() != len {
+                    return None;
+                }
+                volume.fluid_level.layer = levels.iter().map(|l| *l as u8).collect();
+            }
+            chunk.volume = Some(volume);
+        }
+        Some(chunk)
+    }
+}
+
+/// local position of a volume index, the inverse of xyz_to_index.
+pub fn index_to_xyz(index: i32, size: i32) -> (i32, i32, i32) {
+    (index % size, (index / size) % size, index / (size * size))
+}
+
+/// (run length, id) pairs in volume index order.
+pub fn encode_runs(ids: &[u16]) -> Vec<(u16, u16)> {
+    let mut runs: Vec<(u16, u16)> = vec![];
+    for id in ids.iter().copied() {
+        match runs.last_mut() {
+            Some((count, last)) if *last == id && *count < u16::MAX => *count += 1,
+            _ => runs.push((1, id)),
+        }
+    }
+    runs
+}
+
+pub fn decode_runs(runs: &[(u16, u16)]) -> Vec<u16> {
+    let mut ids = Vec::with_capacity(runs.iter().map(|r| r.0 as usize).sum());
+    for (count, id) in runs.iter() {
+        ids.extend(std::iter::repeat(*id).take(*count as usize));
+    }
+    ids
 }
 
 pub struct Chunk {
     pub dirty: bool,
     pub save_dirty: bool, // used to tell when we need to update the mesh
+    /// changed by a player, see `VoxelMap::edit_voxel`. the world generated again keeps it.
+    pub edited: bool,
     pub entity_exist: bool,
     pub size: i32,
     pub volume: Option<Volume>, // Option so you dont use up mimory unless there is a voxel in the chunk
+    /// local voxel index to the game tick its scheduled tick is due, see `VoxelMap::schedule_tick`.
+    pub scheduled_ticks: BTreeMap<u16, u64>,
 }
 // todo : refacter to use new volume type
 impl Chunk {
@@ -276,8 +641,10 @@ impl Chunk {
             volume: None,
             dirty: false,
             save_dirty: false,
+            edited: false,
             entity_exist: false,
             size,
+            scheduled_ticks: BTreeMap::new(),
         }
     }
     pub fn is_dirty(&self) -> bool {
@@ -291,7 +658,10 @@ impl Chunk {
         self.dirty = true;
         match &mut self.volume {
             Some(v) => {
-                v.type_id.layer[xyz_to_index!(x, y, z, size, size) as usize] = val;
+                let i = xyz_to_index!(x, y, z, size, size) as usize;
+                v.type_id.layer[i] = val;
+                // a new block starts without a level, fluid placed like this is full
+                v.fluid_level.layer[i] = 0;
                 self.set_is_dirty(true);
             }
             None => {
@@ -313,6 +683,20 @@ impl Chunk {
             }
         }
     }
+    /// the fluid level layer is only there once the chunk has a volume.
+    pub fn set_fluid_level(&mut self, x: i32, y: i32, z: i32, size: i32, level: u8) {
+        if let Some(v) = &mut self.volume {
+            v.fluid_level.layer[xyz_to_index!(x, y, z, size, size) as usize] = level;
+            self.save_dirty = true;
+            self.dirty = true;
+        }
+    }
+    pub fn get_fluid_level(&self, x: i32, y: i32, z: i32, size: i32) -> u8 {
+        match &self.volume {
+            Some(v) => v.fluid_level.layer[xyz_to_index!(x, y, z, size, size) as usize],
+            None => 0,
+        }
+    }
     pub fn get_voxel(&self, x: i32, y: i32, z: i32, size: i32) -> u16 {
         if (z >= size || z < 0) || (y >= size || y < 0) || (x >= size || x < 0) {
             println!("err vox out of bound: {:?}", (x, y, z));
@@ -0,0 +1,223 @@
+// biomes are picked by two slow noise fields, temperature and humidity. every biome sits at a point
+// of that climate plane and a column belongs to the nearest one. heights are not taken from the
+// nearest biome alone, every biome within `blend` of the nearest adds its height curve by a weight
+// that falls to 0 at that distance, so the height moves smoothly from one biome to the next.
+
+use noise::{NoiseFn, OpenSimplex, Seedable};
+use serde::Deserialize;
+
+use super::decoration::SurfaceFeature;
+use crate::voxel::block::{GRAVEL, SAND};
+
+/// the terrain height of a biome from the two surface noises, both about -1 to 1.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct HeightCurve {
+    pub base: f64,
+    /// the slow noise goes through exp with this, so a few high places get much higher then the rest.
+    pub mountains: f64,
+    /// how high the fast noise moves the ground.
+    pub hills: f64,
+}
+
+impl HeightCurve {
+    pub fn height(&self, large: f64, detail: f64) -> f64 {
+        self.base + (large * self.mountains).exp() + detail * self.hills
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Biome {
+    pub name: String,
+    /// where the biome sits on the climate plane, both -1 to 1.
+    pub temperature: f64,
+    pub humidity: f64,
+    pub height: HeightCurve,
+    /// the top layer, one of them is picked at random per voxel.
+    pub surface: Vec<u16>,
+    /// between the top layer and the stone.
+    pub subsurface: Vec<u16>,
+    /// chance of a decoration per surface column.
+    pub decoration_density: f64,
+    /// what a decoration can be, one is picked at random.
+    #[serde(default)]
+    pub features: Vec<SurfaceFeature>,
+}
+
+impl Biome {
+    fn distance(&self, climate: (f64, f64)) -> f64 {
+        ((self.temperature - climate.0).powi(2) + (self.humidity - climate.1).powi(2)).sqrt()
+    }
+}
+
+/// the biomes the generator uses when it is not given any.
+pub fn default_biomes() -> Vec<Biome> {
+    let grass = vec![14, 15];
+    let dirt = vec![1, 2, 3, 4, 5];
+    let stone = vec![6, 7, 8];
+    let biome = |name: &str, climate: (f64, f64), height: (f64, f64, f64)| Biome {
+        name: name.to_string(),
+        temperature: climate.0,
+        humidity: climate.1,
+        height: HeightCurve {
+            base: height.0,
+            mountains: height.1,
+            hills: height.2,
+        },
+        surface: grass.clone(),
+        subsurface: dirt.clone(),
+        decoration_density: 0.01,
+        features: vec![SurfaceFeature::Tree],
+    };
+    vec![
+        // the terrain from before there were biomes
+        Biome {
+            features: vec![SurfaceFeature::Tree, SurfaceFeature::Boulder],
+            ..biome("hills", (-0.1, -0.5), (0.0, 10.0, 8.0))
+        },
+        biome("plains", (0.15, -0.1), (2.0, 3.0, 3.0)),
+        Biome {
+            decoration_density: 0.06,
+            ..biome("forest", (-0.05, 0.45), (3.0, 5.0, 6.0))
+        },
+        Biome {
+            surface: vec![GRAVEL],
+            subsurface: stone,
+            decoration_density: 0.002,
+            features: vec![SurfaceFeature::Boulder],
+            ..biome("tundra", (-0.55, 0.05), (4.0, 8.0, 5.0))
+        },
+        Biome {
+            surface: vec![SAND],
+            subsurface: vec![SAND],
+            decoration_density: 0.002,
+            features: vec![SurfaceFeature::Boulder],
+            ..biome("desert", (0.55, -0.45), (1.0, 2.0, 4.0))
+        },
+        Biome {
+            decoration_density: 0.03,
+            ..biome("swamp", (0.5, 0.4), (-2.0, 1.0, 2.0))
+        },
+    ]
+}
+
+pub struct Biomes {
+    pub biomes: Vec<Biome>,
+    /// of the climate noise, lower is bigger biomes.
+    pub frequency: f64,
+    /// climate distance over which the height of a biome fades out past the nearest one.
+    pub blend: f64,
+    temperature: OpenSimplex,
+    humidity: OpenSimplex,
+}
+
+impl Biomes {
+    pub fn new(seed: u32, biomes: Vec<Biome>) -> Self {
+        assert!(!biomes.is_empty(), "the generator needs a biome");
+        Self {
+            biomes,
+            frequency: 0.0025,
+            blend: 0.15,
+            temperature: OpenSimplex::new().set_seed(seed.wrapping_add(5)),
+            humidity: OpenSimplex::new().set_seed(seed.wrapping_add(6)),
+        }
+    }
+
+    /// temperature and humidity of the column.
+    pub fn climate(&self, x: i32, z: i32) -> (f64, f64) {
+        let p = [x as f64 * self.frequency, z as f64 * self.frequency];
+        (self.temperature.get(p), self.humidity.get(p))
+    }
+
+    /// index and weight of every biome that adds to the height at `climate`, the weights add up to 1.
+    /// the nearest biome is first.
+    pub fn weights(&self, climate: (f64, f64)) -> Vec<(usize, f64)> {
+        let distances: Vec<f64> = self.biomes.iter().map(|b| b.distance(climate)).collect();
+        let nearest = distances.iter().cloned().fold(f64::MAX, f64::min);
+        let mut weights: Vec<(usize, f64)> = distances
+            .iter()
+            .enumerate()
+            .map(|(i, d)| (i, (1.0 - (d - nearest) / self.blend).max(0.0).powi(2)))
+            .filter(|(_, w)| *w > 0.0)
+            .collect();
+        let total: f64 = weights.iter().map(|(_, w)| w).sum();
+        for (_, w) in weights.iter_mut() {
+            *w /= total;
+        }
+        weights.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap().then(a.0.cmp(&b.0)));
+        weights
+    }
+
+    /// the biome the column belongs to, it decides the blocks.
+    pub fn biome_at(&self, x: i32, z: i32) -> &Biome {
+        let climate = self.climate(x, z);
+        self.biomes
+            .iter()
+            .min_by(|a, b| {
+                a.distance(climate)
+                    .partial_cmp(&b.distance(climate))
+                    .unwrap()
+            })
+            .unwrap()
+    }
+
+    /// the height curves of the biomes around the column, blended.
+    pub fn height(&self, x: i32, z: i32, large: f64, detail: f64) -> f64 {
+        self.weights(self.climate(x, z))
+            .iter()
+            .map(|(i, w)| self.biomes[*i].height.height(large, detail) * w)
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    #[test]
+    fn every_default_biome_shows_up() {
+        let biomes = Biomes::new(11, default_biomes());
+        let mut seen = vec![false; biomes.biomes.len()];
+        for x in -64..64 {
+            for z in -64..64 {
+                let name = &biomes.biome_at(x * 32, z * 32).name;
+                let i = biomes.biomes.iter().position(|b| &b.name == name).unwrap();
+                seen[i] = true;
+            }
+        }
+        assert!(seen.iter().all(|s| *s), "{:?}", seen);
+    }
+
+    #[test]
+    fn weights_change_smoothly_over_the_climate_plane() {
+        let biomes = Biomes::new(0, default_biomes());
+        let weight_of = |climate: (f64, f64)| {
+            let mut all = vec![0.0; biomes.biomes.len()];
+            for (i, w) in biomes.weights(climate) {
+                all[i] = w;
+            }
+            all
+        };
+        // a walk across the plane that crosses every border of the default biomes
+        for h in [-0.5, -0.1, 0.2, 0.45] {
+            let mut last = weight_of((-1.0, h));
+            for step in 1..=2000 {
+                let now = weight_of((-1.0 + step as f64 * 0.001, h));
+                let total: f64 = now.iter().sum();
+                assert!((total - 1.0).abs() < 1e-9);
+                for (a, b) in last.iter().zip(now.iter()) {
+                    assert!((a - b).abs() < 0.05, "{:?} to {:?}", last, now);
+                }
+                last = now;
+            }
+        }
+    }
+
+    #[test]
+    fn away_from_borders_only_the_nearest_biome_counts() {
+        let biomes = Biomes::new(0, default_biomes());
+        for b in 0..biomes.biomes.len() {
+            let at = (biomes.biomes[b].temperature, biomes.biomes[b].humidity);
+            assert_eq!(biomes.weights(at), vec![(b, 1.0)]);
+        }
+    }
+}
@@ -0,0 +1,239 @@
+// the cave pass, run on each chunk after the terrain pass. tunnels are where two 3d noise fields are
+// both close to 0, which gives long winding worms, caverns are where a third, squashed noise field
+// is high. caves can open at the surface as overhangs, but never carve at or below the bedrock
+// height, and never get within `ocean_floor` voxels of the water of an ocean column.
+
+use noise::{NoiseFn, OpenSimplex, Seedable};
+use serde::Deserialize;
+
+use crate::voxel::{block::WATER, voxel::Chunk};
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct CaveConfig {
+    pub enabled: bool,
+    /// how often tunnels turn, higher is more and shorter tunnels.
+    pub tunnel_frequency: f64,
+    /// how close to 0 both tunnel noises have to be, higher is wider tunnels.
+    pub tunnel_width: f64,
+    pub cavern_frequency: f64,
+    /// cavern noise above this is carved, lower is more and bigger caverns.
+    pub cavern_threshold: f64,
+    /// how much flatter then wide caverns are.
+    pub cavern_squash: f64,
+    /// nothing at or below this height is carved, the layers under it stay whole.
+    pub bedrock_y: i32,
+    /// solid voxels kept between a cave and the water of an ocean column.
+    pub ocean_floor: i32,
+}
+
+impl Default for CaveConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            tunnel_frequency: 0.02,
+            tunnel_width: 0.05,
+            cavern_frequency: 0.015,
+            cavern_threshold: 0.55,
+            cavern_squash: 2.0,
+            bedrock_y: -128,
+            ocean_floor: 3,
+        }
+    }
+}
+
+pub struct Caves {
+    pub config: CaveConfig,
+    tunnel_a: OpenSimplex,
+    tunnel_b: OpenSimplex,
+    caverns: OpenSimplex,
+}
+
+impl Caves {
+    pub fn new(seed: u32, config: CaveConfig) -> Self {
+        Self {
+            config,
+            tunnel_a: OpenSimplex::new().set_seed(seed.wrapping_add(2)),
+            tunnel_b: OpenSimplex::new().set_seed(seed.wrapping_add(3)),
+            caverns: OpenSimplex::new().set_seed(seed.wrapping_add(4)),
+        }
+    }
+
+    /// true if the cave noise wants the voxel to be air, without looking at what is there.
+    pub fn is_cave(&self, x: i32, y: i32, z: i32) -> bool {
+        let (x, y, z) = (x as f64, y as f64, z as f64);
+        let c = &self.config;
+        let f = c.tunnel_frequency;
+        let p = [x * f, y * f, z * f];
+        if self.tunnel_a.get(p).abs() < c.tunnel_width
+            && self.tunnel_b.get(p).abs() < c.tunnel_width
+        {
+            return true;
+        }
+        let f = c.cavern_frequency;
+        self.caverns.get([x * f, y * f * c.cavern_squash, z * f]) > c.cavern_threshold
+    }
+
+    /// carves the caves of the chunk at `key`, returns how many voxels were carved.
+    /// `surface` is the terrain height of a column, it is asked for the chunk columns and one around them.
+    pub fn carve(
+        &self,
+        chunk: &mut Chunk,
+        key: (i32, i32, i32),
+        size: i32,
+        surface: impl Fn(i32, i32) -> f64,
+        sea_level: Option<i32>,
+    ) -> usize {
+        if !self.config.enabled || chunk.volume.is_none() {
+            return 0;
+        }
+        let (ox, oy, oz) = (key.0 * size, key.1 * size, key.2 * size);
+        let w = size + 2;
+        let mut heights = vec![0.0; (w * w) as usize];
+        if sea_level.is_some() {
+            for i in 0..w {
+                for k in 0..w {
+                    heights[(i + k * w) as usize] = surface(ox + i - 1, oz + k - 1);
+                }
+            }
+        }
+
+        let mut carved = 0;
+        for lx in 0..size {
+            for lz in 0..size {
+                for ly in 0..size {
+                    let (x, y, z) = (ox + lx, oy + ly, oz + lz);
+                    if y <= self.config.bedrock_y {
+                        continue;
+                    }
+                    // the generator places no other fluid
+                    let id = chunk.get_voxel(lx, ly, lz, size);
+                    if id == 0 || id == WATER {
+                        continue;
+                    }
+                    if let Some(sea) = sea_level {
+                        let near_ocean = (0..3).any(|i| {
+                            (0..3).any(|k| {
+                                let h = heights[((lx + i) + (lz + k) * w) as usize];
+                                h < sea as f64
+                                    && y <= sea
+                                    && y as f64 > h - self.config.ocean_floor as f64
+                            })
+                        });
+                        if near_ocean {
+                            continue;
+                        }
+                    }
+                    if self.is_cave(x, y, z) {
+                        chunk.set_voxel(lx, ly, lz, size, 0);
+                        carved += 1;
+                    }
+                }
+            }
+        }
+        carved
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::world::generator::WorldGenerator;
+
+    fn voxels(chunk: &Chunk) -> Vec<u16> {
+        match &chunk.volume {
+            Some(v) => v.type_id.layer.clone(),
+            None => vec![0; 16 * 16 * 16],
+        }
+    }
+
+    fn at(layer: &[u16], x: i32, y: i32, z: i32) -> u16 {
+        layer[(x + y * 16 + z * 256) as usize]
+    }
+
+    #[test]
+    fn carves_some_of_the_underground() {
+        let generator = WorldGenerator::new(7);
+        let flat = WorldGenerator::with_caves(
+            7,
+            CaveConfig {
+                enabled: false,
+                ..CaveConfig::default()
+            },
+        );
+        let (mut solid, mut carved) = (0, 0);
+        for x in -2..2 {
+            for y in -6..-2 {
+                for z in -2..2 {
+                    let a = voxels(&flat.generate_chunk((x, y, z), 16));
+                    let b = voxels(&generator.generate_chunk((x, y, z), 16));
+                    for (a, b) in a.iter().zip(b.iter()) {
+                        if *a != 0 {
+                            solid += 1;
+                            if *b == 0 {
+                                carved += 1;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        let share = carved as f64 / solid as f64;
+        assert!(share > 0.005 && share < 0.3, "{}", share);
+    }
+
+    #[test]
+    fn bedrock_and_ocean_are_never_breached() {
+        // carves everything it is allowed to
+        let config = CaveConfig {
+            cavern_threshold: -2.0,
+            bedrock_y: 4,
+            ..CaveConfig::default()
+        };
+        let mut generator = WorldGenerator::with_caves(3, config.clone());
+        generator.sea_level = Some(12);
+        let mut flat = WorldGenerator::with_caves(
+            3,
+            CaveConfig {
+                enabled: false,
+                ..config
+            },
+        );
+        flat.sea_level = Some(12);
+
+        let mut water = 0;
+        for x in -3..3 {
+            for z in -3..3 {
+                let key = (x, 0, z);
+                let a = voxels(&flat.generate_chunk(key, 16));
+                let b = voxels(&generator.generate_chunk(key, 16));
+                for lx in 0..16 {
+                    for ly in 0..16 {
+                        for lz in 0..16 {
+                            let (va, vb) = (at(&a, lx, ly, lz), at(&b, lx, ly, lz));
+                            if ly <= 4 || va == WATER {
+                                assert_eq!(va, vb, "{:?}", (key, lx, ly, lz));
+                            }
+                            if vb != WATER {
+                                continue;
+                            }
+                            water += 1;
+                            // no carved voxel touches the water
+                            for (dx, dy, dz) in
+                                [(1, 0, 0), (-1, 0, 0), (0, -1, 0), (0, 0, 1), (0, 0, -1)]
+                            {
+                                let (nx, ny, nz) = (lx + dx, ly + dy, lz + dz);
+                                if !(0..16).contains(&nx) || ny < 0 || !(0..16).contains(&nz) {
+                                    continue;
+                                }
+                                let (na, nb) = (at(&a, nx, ny, nz), at(&b, nx, ny, nz));
+                                assert!(na == nb, "{:?} next to water", (key, nx, ny, nz));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        assert!(water > 0, "no ocean in the test chunks");
+    }
+}
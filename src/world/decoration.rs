@@ -0,0 +1,523 @@
+// the decoration pass, run on each chunk after the caves. it plans trees, boulders, ore veins and
+// structures that start in the chunk and turns them into voxel writes. a feature can reach into
+// the chunks around it, writes into other chunks go into PendingWrites under their chunk key: they
+// are written into the chunk now if it is loaded and was generated, and every time it is generated
+// later. chunks from the store are not written into, what was saved wins over what was planned.
+// planning only depends on the seed, so the same world gets the same features in any load order.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::Deserialize;
+
+use super::generator::{chunk_seed, WorldGenerator};
+use crate::voxel::{
+    block::{LEAVES, LOG},
+    voxel::{Chunk, VoxelMap},
+};
+
+/// what boulders are made of.
+const BOULDER: [u16; 5] = [6, 7, 8, 9, 10];
+
+/// the features a biome puts on its surface, see `Biome::features`.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SurfaceFeature {
+    Tree,
+    Boulder,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VeinShape {
+    /// a lump.
+    Blob,
+    /// a winding line.
+    Streak,
+    /// a thin tilted disc.
+    Sheet,
+}
+
+/// what a write is allowed to replace. the rules are picked so features that overlap end the
+/// same whichever was written first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Replace {
+    Air,
+    AirOrLeaves,
+    /// the blocks of the block layers of the preset, for ore.
+    Layer,
+    /// structures replace everything they have a block for.
+    Any,
+}
+
+impl Replace {
+    /// `layers` are the blocks of the preset block layers.
+    pub fn allows(&self, id: u16, layers: &[u16]) -> bool {
+        match self {
+            Replace::Air => id == 0,
+            Replace::AirOrLeaves => id == 0 || id == LEAVES,
+            Replace::Layer => layers.contains(&id),
+            Replace::Any => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FeatureWrite {
+    /// world position.
+    pub pos: (i32, i32, i32),
+    pub id: u16,
+    pub replace: Replace,
+}
+
+/// writes into one chunk, by the key of the chunk that planned them.
+type WritesFrom = BTreeMap<(i32, i32, i32), Vec<FeatureWrite>>;
+
+/// the writes each chunk got from features planned in other chunks.
+#[derive(Debug, Default, Clone)]
+pub struct PendingWrites {
+    writes: HashMap<(i32, i32, i32), WritesFrom>,
+    /// loaded chunks that were generated, not loaded from the store.
+    generated: HashSet<(i32, i32, i32)>,
+}
+
+impl PendingWrites {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// the chunk at `key` came from the store, it is not written into while it is loaded.
+    pub fn loaded_from_store(&mut self, key: (i32, i32, i32)) {
+        self.generated.remove(&key);
+    }
+
+    /// how many writes wait for the chunk at `key`.
+    pub fn waiting(&self, key: (i32, i32, i32)) -> usize {
+        self.writes
+            .get(&key)
+            .map_or(0, |by| by.values().map(|w| w.len()).sum())
+    }
+}
+
+/// generates the chunk at `key` with its decorations and adds it to the map.
+pub fn generate_into(
+    map: &mut VoxelMap,
+    generator: &WorldGenerator,
+    pending: &mut PendingWrites,
+    key: (i32, i32, i32),
+) {
+    let size = map.chunk_size.0;
+    let layers = generator.preset.layer_blocks();
+    let mut chunk = generator.generate_chunk(key, size);
+
+    let mut others = WritesFrom::new();
+    for write in plan(generator, key, size) {
+        let to = map.chunk_key(write.pos.0, write.pos.1, write.pos.2);
+        if to == key {
+            write_into(&mut chunk, key, size, &write, &layers);
+        } else {
+            others.entry(to).or_default().push(write);
+        }
+    }
+    if let Some(by) = pending.writes.get(&key) {
+        for write in by.values().flatten() {
+            write_into(&mut chunk, key, size, write, &layers);
+        }
+    }
+    chunk.save_dirty = false;
+    chunk.set_is_dirty(true);
+    map.add_chunk(key.0, key.1, key.2, chunk);
+    pending.generated.insert(key);
+
+    for (to, writes) in others {
+        if pending.generated.contains(&to) && map.chunk_list.contains_key(&to) {
+            for w in writes.iter() {
+                if w.replace
+                    .allows(map.get_voxel(w.pos.0, w.pos.1, w.pos.2), &layers)
+                {
+                    map.set_voxel(w.pos.0, w.pos.1, w.pos.2, w.id);
+                    map.mark_border_neighbors_dirty(w.pos.0, w.pos.1, w.pos.2);
+                }
+            }
+        }
+        // planning again gives the same writes, so they replace the old ones
+        pending.writes.entry(to).or_default().insert(key, writes);
+    }
+}
+
+/// generates every chunk of the map again that was not edited by a player, after the generator
+/// changed. returns the keys of the generated chunks.
+pub fn generate_again(map: &mut VoxelMap, generator: &WorldGenerator) -> Vec<(i32, i32, i32)> {
+    let keys: Vec<(i32, i32, i32)> = map
+        .chunk_list
+        .iter()
+        .filter(|(_, c)| !c.edited)
+        .map(|(k, _)| *k)
+        .collect();
+    // all of them go first, so no feature is written into a chunk of the old world
+    for key in keys.iter() {
+        map.remove_chunk(key.0, key.1, key.2);
+    }
+    // edited chunks are not in it, so features of the new world are not written into them
+    let mut pending = PendingWrites::new();
+    for key in keys.iter() {
+        generate_into(map, generator, &mut pending, *key);
+    }
+    keys
+}
+
+fn write_into(
+    chunk: &mut Chunk,
+    key: (i32, i32, i32),
+    size: i32,
+    write: &FeatureWrite,
+    layers: &[u16],
+) {
+    let (x, y, z) = (
+        write.pos.0 - key.0 * size,
+        write.pos.1 - key.1 * size,
+        write.pos.2 - key.2 * size,
+    );
+    if write.replace.allows(chunk.get_voxel(x, y, z, size), layers) {
+        chunk.set_voxel(x, y, z, size, write.id);
+    }
+}
+
+/// the writes of every feature that starts in the chunk at `key`, in the same order for a seed.
+pub fn plan(generator: &WorldGenerator, key: (i32, i32, i32), size: i32) -> Vec<FeatureWrite> {
+    let mut rng = StdRng::seed_from_u64(chunk_seed(generator.seed.wrapping_add(7), key));
+    let mut writes = vec![];
+    let (ox, oy, oz) = (key.0 * size, key.1 * size, key.2 * size);
+
+    for lx in 0..size {
+        for lz in 0..size {
+            let (x, z) = (ox + lx, oz + lz);
+            // features stand on the ground, so they start in the chunk with the voxel over it
+            let ground = generator.surface(x, z).0.floor() as i32;
+            if ground + 1 < oy || ground + 1 >= oy + size {
+                continue;
+            }
+            if generator.is_cave(x, ground, z)
+                || matches!(generator.sea_level, Some(sea) if ground < sea)
+            {
+                continue;
+            }
+            let biome = generator.biome(x, z);
+            let at = (x, ground + 1, z);
+            if !biome.features.is_empty() && rng.gen_bool(biome.decoration_density.clamp(0.0, 1.0))
+            {
+                match biome.features[rng.gen_range(0..biome.features.len())] {
+                    SurfaceFeature::Tree => tree(at, &mut rng, &mut writes),
+                    SurfaceFeature::Boulder => boulder(at, &mut rng, &mut writes),
+                }
+            }
+            for structure in generator.structures.iter() {
+                if structure.fits(&biome.name) && rng.gen_bool(structure.chance.clamp(0.0, 1.0)) {
+                    for ((sx, sy, sz), id) in structure.blocks.iter() {
+                        writes.push(FeatureWrite {
+                            pos: (at.0 + sx, at.1 - structure.sink + sy, at.2 + sz),
+                            id: *id,
+                            replace: Replace::Any,
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    for ore in generator.preset.ores.iter() {
+        for _ in 0..ore.veins {
+            let at = (
+                ox + rng.gen_range(0..size),
+                oy + rng.gen_range(0..size),
+                oz + rng.gen_range(0..size),
+            );
+            let shape = match rng.gen_range(0..3) {
+                0 => VeinShape::Blob,
+                1 => VeinShape::Streak,
+                _ => VeinShape::Sheet,
+            };
+            // drawn either way, so the rest of the chunk does not change with the range
+            if ore.contains(at.1) && !ore.blocks.is_empty() {
+                vein(at, shape, &ore.blocks, &mut rng, &mut writes);
+            }
+        }
+    }
+    writes
+}
+
+/// a log trunk with a round crown of leaves, `at` is the voxel over the ground.
+fn tree(at: (i32, i32, i32), rng: &mut StdRng, writes: &mut Vec<FeatureWrite>) {
+    let height = rng.gen_range(4..=6);
+    for dy in height - 2..=height + 1 {
+        // the top two layers are smaller
+        let r: i32 = if dy >= height { 1 } else { 2 };
+        for dx in -r..=r {
+            for dz in -r..=r {
+                // corners are cut, or sometimes left on
+                if dx.abs() == r && dz.abs() == r && (r == 1 || rng.gen_bool(0.5)) {
+                    continue;
+                }
+                writes.push(FeatureWrite {
+                    pos: (at.0 + dx, at.1 + dy, at.2 + dz),
+                    id: LEAVES,
+                    replace: Replace::Air,
+                });
+            }
+        }
+    }
+    for dy in 0..height {
+        writes.push(FeatureWrite {
+            pos: (at.0, at.1 + dy, at.2),
+            id: LOG,
+            replace: Replace::AirOrLeaves,
+        });
+    }
+}
+
+/// a lump of stone half in the ground, `at` is the voxel over the ground.
+fn boulder(at: (i32, i32, i32), rng: &mut StdRng, writes: &mut Vec<FeatureWrite>) {
+    let r: f64 = rng.gen_range(1.2..2.6);
+    let reach = r.ceil() as i32;
+    for dx in -reach..=reach {
+        for dy in -reach..=reach {
+            for dz in -reach..=reach {
+                if ((dx * dx + dy * dy + dz * dz) as f64) > r * r {
+                    continue;
+                }
+                writes.push(FeatureWrite {
+                    pos: (at.0 + dx, at.1 + dy - 1, at.2 + dz),
+                    id: BOULDER[rng.gen_range(0..BOULDER.len())],
+                    replace: Replace::AirOrLeaves,
+                });
+            }
+        }
+    }
+}
+
+/// ore of one kind in the layer blocks around `at`.
+fn vein(
+    at: (i32, i32, i32),
+    shape: VeinShape,
+    ores: &[u16],
+    rng: &mut StdRng,
+    writes: &mut Vec<FeatureWrite>,
+) {
+    let id = ores[rng.gen_range(0..ores.len())];
+    let mut put = |pos: (i32, i32, i32)| {
+        writes.push(FeatureWrite {
+            pos,
+            id,
+            replace: Replace::Layer,
+        })
+    };
+    match shape {
+        VeinShape::Blob => {
+            let r = (
+                rng.gen_range(1.0..3.0f64),
+                rng.gen_range(1.0..2.5f64),
+                rng.gen_range(1.0..3.0f64),
+            );
+            let reach = 3;
+            for dx in -reach..=reach {
+                for dy in -reach..=reach {
+                    for dz in -reach..=reach {
+                        let d = (dx as f64 / r.0).powi(2)
+                            + (dy as f64 / r.1).powi(2)
+                            + (dz as f64 / r.2).powi(2);
+                        if d <= 1.0 {
+                            put((at.0 + dx, at.1 + dy, at.2 + dz));
+                        }
+                    }
+                }
+            }
+        }
+        VeinShape::Streak => {
+            let mut pos = at;
+            let mut dir = (rng.gen_range(-1..=1), rng.gen_range(-1..=1), 1);
+            for _ in 0..rng.gen_range(6..=14) {
+                put(pos);
+                put((pos.0, pos.1 + 1, pos.2));
+                // turns now and then
+                if rng.gen_bool(0.3) {
+                    dir = (rng.gen_range(-1..=1), rng.gen_range(-1..=1), dir.2);
+                }
+                pos = (pos.0 + dir.0, pos.1 + dir.1, pos.2 + dir.2);
+            }
+        }
+        VeinShape::Sheet => {
+            let r = rng.gen_range(2..=4);
+            let tilt = (rng.gen_range(-0.5..0.5f64), rng.gen_range(-0.5..0.5f64));
+            for dx in -r..=r {
+                for dz in -r..=r {
+                    if dx * dx + dz * dz <= r * r {
+                        let dy = (dx as f64 * tilt.0 + dz as f64 * tilt.1).round() as i32;
+                        put((at.0 + dx, at.1 + dy, at.2 + dz));
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::world::{caves::CaveConfig, structure::Structure};
+
+    fn generator() -> WorldGenerator {
+        WorldGenerator::with_caves(5, CaveConfig::default())
+    }
+
+    /// every voxel of the chunks, in key order.
+    fn voxels(map: &VoxelMap, keys: &[(i32, i32, i32)]) -> Vec<u16> {
+        let mut out = vec![];
+        for key in keys {
+            for x in 0..16 {
+                for y in 0..16 {
+                    for z in 0..16 {
+                        out.push(map.get_voxel(key.0 * 16 + x, key.1 * 16 + y, key.2 * 16 + z));
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    /// a column of chunks from the ground up to over the trees, on a hill of seed 5.
+    fn area() -> Vec<(i32, i32, i32)> {
+        let mut keys = vec![];
+        for x in -2..2 {
+            for y in -1..3 {
+                for z in -2..2 {
+                    keys.push((x, y, z));
+                }
+            }
+        }
+        keys
+    }
+
+    #[test]
+    fn the_same_seed_plans_the_same_features() {
+        let (a, b) = (generator(), generator());
+        for key in area() {
+            assert_eq!(plan(&a, key, 16), plan(&b, key, 16));
+        }
+        // the area has something to decorate
+        assert!(area().iter().any(|k| !plan(&a, *k, 16).is_empty()));
+    }
+
+    #[test]
+    fn features_across_borders_do_not_depend_on_load_order() {
+        let generator = generator();
+        let keys = area();
+        let load = |order: &[(i32, i32, i32)]| {
+            let mut map = VoxelMap::new((16, 16, 16));
+            let mut pending = PendingWrites::new();
+            for key in order {
+                generate_into(&mut map, &generator, &mut pending, *key);
+            }
+            voxels(&map, &keys)
+        };
+        let forward = load(&keys);
+        let mut backward = keys.clone();
+        backward.reverse();
+        assert_eq!(forward, load(&backward));
+        // trees grew in the area
+        assert!(forward.contains(&LOG) && forward.contains(&LEAVES));
+    }
+
+    /// a structure on every surface column, reaching 2 voxels along +x.
+    fn with_bars() -> WorldGenerator {
+        let mut generator = generator();
+        generator.structures = vec![Structure {
+            name: "bar".to_string(),
+            chance: 1.0,
+            biomes: vec![],
+            sink: 0,
+            blocks: vec![((0, 0, 0), LOG), ((1, 0, 0), LOG), ((2, 0, 0), LOG)],
+        }];
+        generator
+    }
+
+    /// the chunk holding the voxel over the ground at x 15, z 0.
+    fn edge_key(generator: &WorldGenerator) -> (i32, i32, i32) {
+        let ground = generator.surface(15, 0).0.floor() as i32;
+        (0, (ground + 1) >> 4, 0)
+    }
+
+    #[test]
+    fn writes_over_the_border_reach_a_chunk_generated_later() {
+        let generator = with_bars();
+        let key = edge_key(&generator);
+        let mut map = VoxelMap::new((16, 16, 16));
+        let mut pending = PendingWrites::new();
+        generate_into(&mut map, &generator, &mut pending, key);
+        let next = (1, key.1, 0);
+        assert!(pending.waiting(next) > 0);
+        generate_into(&mut map, &generator, &mut pending, next);
+
+        let y = generator.surface(15, 0).0.floor() as i32 + 1;
+        for x in 15..=17 {
+            assert_eq!(map.get_voxel(x, y, 0), LOG, "{}", x);
+        }
+    }
+
+    #[test]
+    fn saved_chunks_are_not_written_into() {
+        let generator = with_bars();
+        let key = edge_key(&generator);
+        let next = (1, key.1, 0);
+        let mut map = VoxelMap::new((16, 16, 16));
+        let mut pending = PendingWrites::new();
+        // empty, from the store
+        map.add_chunk(next.0, next.1, next.2, Chunk::new(16));
+        pending.loaded_from_store(next);
+
+        generate_into(&mut map, &generator, &mut pending, key);
+        assert!(pending.waiting(next) > 0);
+        assert!(voxels(&map, &[next]).iter().all(|id| *id == 0));
+    }
+
+    #[test]
+    fn only_edited_chunks_are_kept_when_generated_again() {
+        let keys = area();
+        let edited = keys[5];
+        let at = (edited.0 * 16 + 3, edited.1 * 16 + 3, edited.2 * 16 + 3);
+        let mut map = VoxelMap::new((16, 16, 16));
+        let mut pending = PendingWrites::new();
+        for key in keys.iter() {
+            generate_into(&mut map, &generator(), &mut pending, *key);
+        }
+        map.edit_voxel(at.0, at.1, at.2, 16);
+
+        for seed in [6, 7] {
+            let generator = WorldGenerator::with_caves(seed, CaveConfig::default());
+            let generated = generate_again(&mut map, &generator);
+            assert_eq!(generated.len(), keys.len() - 1);
+            assert!(!generated.contains(&edited));
+            assert_eq!(map.get_voxel(at.0, at.1, at.2), 16);
+
+            // the same as a new world of the seed without the edited chunk
+            let mut fresh = VoxelMap::new((16, 16, 16));
+            let mut pending = PendingWrites::new();
+            for key in generated.iter() {
+                generate_into(&mut fresh, &generator, &mut pending, *key);
+            }
+            assert_eq!(voxels(&map, &generated), voxels(&fresh, &generated));
+        }
+    }
+
+    #[test]
+    fn veins_only_replace_layer_blocks() {
+        let mut rng = StdRng::seed_from_u64(3);
+        for shape in [VeinShape::Blob, VeinShape::Streak, VeinShape::Sheet] {
+            let mut writes = vec![];
+            vein((0, -40, 0), shape, &[11, 12], &mut rng, &mut writes);
+            assert!(writes.len() > 2, "{:?}", shape);
+            assert!(writes.iter().all(|w| w.replace == Replace::Layer));
+            assert!(writes.iter().all(|w| w.id == 11 || w.id == 12));
+        }
+        let layers = [6, 7];
+        assert!(Replace::Layer.allows(6, &layers));
+        assert!(!Replace::Layer.allows(0, &layers) && !Replace::Layer.allows(8, &layers));
+    }
+}
@@ -0,0 +1,358 @@
+// the erosion pass, run on the surface height before the terrain pass turns it into voxels. water
+// droplets run downhill, pick up sediment where they speed up and drop it where they slow down, then
+// thermal slumping moves material off slopes steeper then the talus angle.
+//
+// the world is cut into square tiles. every tile is eroded on its own over a window twice its size
+// centred on it, and the height of a column is the results of the 4 tiles around it blended by how
+// near their centres it is. a tile only depends on the seed and where it is, so any region comes
+// out the same whatever was generated before it, and the blend goes to 0 before a window ends.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::Deserialize;
+
+use super::generator::chunk_seed;
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct ErosionConfig {
+    pub enabled: bool,
+    /// columns between tile centres, a window is twice this.
+    pub tile: i32,
+    /// droplets run over every window.
+    pub droplets: usize,
+    /// steps a droplet runs before it dries up.
+    pub lifetime: usize,
+    /// how much of its direction a droplet keeps instead of following the slope, 0 to 1.
+    pub inertia: f64,
+    /// sediment a droplet can carry per unit of speed, water and slope.
+    pub capacity: f64,
+    /// the least slope capacity is worked out with, so flat ground still erodes a bit.
+    pub min_slope: f64,
+    /// part of the sediment over capacity dropped every step.
+    pub deposition: f64,
+    /// part of the free capacity taken from the ground every step.
+    pub erosion: f64,
+    /// part of the water lost every step.
+    pub evaporation: f64,
+    pub gravity: f64,
+    /// passes of thermal slumping after the droplets.
+    pub thermal_iterations: usize,
+    /// the height difference between neighbours that stays put.
+    pub talus: f64,
+    /// part of the difference over talus moved every pass, 0 to 1.
+    pub thermal_rate: f64,
+}
+
+impl Default for ErosionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            tile: 64,
+            droplets: 6000,
+            lifetime: 30,
+            inertia: 0.05,
+            capacity: 4.0,
+            min_slope: 0.01,
+            deposition: 0.3,
+            erosion: 0.3,
+            evaporation: 0.02,
+            gravity: 4.0,
+            thermal_iterations: 10,
+            talus: 1.5,
+            thermal_rate: 0.5,
+        }
+    }
+}
+
+/// heights of a square of columns, x + z * size.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Heightmap {
+    pub size: usize,
+    pub heights: Vec<f64>,
+}
+
+impl Heightmap {
+    pub fn new(size: usize, height: impl Fn(usize, usize) -> f64) -> Self {
+        let mut heights = Vec::with_capacity(size * size);
+        for z in 0..size {
+            for x in 0..size {
+                heights.push(height(x, z));
+            }
+        }
+        Self { size, heights }
+    }
+
+    pub fn get(&self, x: usize, z: usize) -> f64 {
+        self.heights[x + z * self.size]
+    }
+
+    /// the height and its slope along x and z at a point between columns.
+    fn sample(&self, x: f64, z: f64) -> (f64, f64, f64) {
+        let (cx, cz) = (x as usize, z as usize);
+        let (fx, fz) = (x - cx as f64, z - cz as f64);
+        let (a, b) = (self.get(cx, cz), self.get(cx + 1, cz));
+        let (c, d) = (self.get(cx, cz + 1), self.get(cx + 1, cz + 1));
+        let height =
+            a * (1.0 - fx) * (1.0 - fz) + b * fx * (1.0 - fz) + c * (1.0 - fx) * fz + d * fx * fz;
+        let gx = (b - a) * (1.0 - fz) + (d - c) * fz;
+        let gz = (c - a) * (1.0 - fx) + (d - b) * fx;
+        (height, gx, gz)
+    }
+
+    /// adds `amount` to the 4 columns around a point, split by how near they are.
+    fn add(&mut self, x: f64, z: f64, amount: f64) {
+        let (cx, cz) = (x as usize, z as usize);
+        let (fx, fz) = (x - cx as f64, z - cz as f64);
+        let size = self.size;
+        self.heights[cx + cz * size] += amount * (1.0 - fx) * (1.0 - fz);
+        self.heights[cx + 1 + cz * size] += amount * fx * (1.0 - fz);
+        self.heights[cx + (cz + 1) * size] += amount * (1.0 - fx) * fz;
+        self.heights[cx + 1 + (cz + 1) * size] += amount * fx * fz;
+    }
+}
+
+/// runs `config.droplets` droplets from random columns of the map.
+pub fn hydraulic(map: &mut Heightmap, config: &ErosionConfig, r_n: &mut StdRng) {
+    let edge = (map.size - 1) as f64;
+    for _ in 0..config.droplets {
+        let (mut x, mut z) = (r_n.gen_range(0.0..edge), r_n.gen_range(0.0..edge));
+        let (mut dx, mut dz) = (0.0, 0.0);
+        let (mut speed, mut water, mut sediment) = (1.0, 1.0, 0.0);
+
+        for _ in 0..config.lifetime {
+            let (height, gx, gz) = map.sample(x, z);
+            dx = dx * config.inertia - gx * (1.0 - config.inertia);
+            dz = dz * config.inertia - gz * (1.0 - config.inertia);
+            let len = (dx * dx + dz * dz).sqrt();
+            if len < 1e-9 {
+                break;
+            }
+            dx /= len;
+            dz /= len;
+            let (nx, nz) = (x + dx, z + dz);
+            // the sediment it carries is lost with it
+            if !(0.0..edge).contains(&nx) || !(0.0..edge).contains(&nz) {
+                break;
+            }
+
+            let dh = map.sample(nx, nz).0 - height;
+            let capacity = (-dh).max(config.min_slope) * speed * water * config.capacity;
+            if sediment > capacity || dh > 0.0 {
+                // uphill it fills the pit it is leaving, at most up to where it is going
+                let drop = if dh > 0.0 {
+                    dh.min(sediment)
+                } else {
+                    (sediment - capacity) * config.deposition
+                };
+                sediment -= drop;
+                map.add(x, z, drop);
+            } else {
+                // never digs deeper then where it is going
+                let take = ((capacity - sediment) * config.erosion).min(-dh);
+                sediment += take;
+                map.add(x, z, -take);
+            }
+
+            speed = (speed * speed - dh * config.gravity).max(0.0).sqrt();
+            water *= 1.0 - config.evaporation;
+            x = nx;
+            z = nz;
+        }
+    }
+}
+
+/// moves material from every column to its lower neighbours where the difference is over talus.
+/// every pass looks at the heights from before it, so the result does not depend on the order.
+pub fn thermal(map: &mut Heightmap, config: &ErosionConfig) {
+    let size = map.size;
+    let mut moved = vec![0.0; map.heights.len()];
+    for _ in 0..config.thermal_iterations {
+        moved.iter_mut().for_each(|m| *m = 0.0);
+        for z in 0..size {
+            for x in 0..size {
+                let i = x + z * size;
+                let height = map.heights[i];
+                let mut neighbours = [None; 4];
+                if x > 0 {
+                    neighbours[0] = Some(i - 1);
+                }
+                if x + 1 < size {
+                    neighbours[1] = Some(i + 1);
+                }
+                if z > 0 {
+                    neighbours[2] = Some(i - size);
+                }
+                if z + 1 < size {
+                    neighbours[3] = Some(i + size);
+                }
+                for n in neighbours.iter().flatten() {
+                    let over = height - map.heights[*n] - config.talus;
+                    if over > 0.0 {
+                        // a quarter each, so a column never gives away more then it has over
+                        let amount = over * config.thermal_rate * 0.25;
+                        moved[i] -= amount;
+                        moved[*n] += amount;
+                    }
+                }
+            }
+        }
+        for (h, m) in map.heights.iter_mut().zip(moved.iter()) {
+            *h += m;
+        }
+    }
+}
+
+/// the eroded windows of the most recently used tiles.
+const CACHED_TILES: usize = 64;
+
+type TileCache = (HashMap<(i32, i32), Arc<Heightmap>>, VecDeque<(i32, i32)>);
+
+pub struct Erosion {
+    pub config: ErosionConfig,
+    seed: u32,
+    tiles: Mutex<TileCache>,
+}
+
+impl Erosion {
+    pub fn new(seed: u32, config: ErosionConfig) -> Self {
+        Self {
+            config,
+            seed: seed.wrapping_add(10),
+            tiles: Mutex::new((HashMap::new(), VecDeque::new())),
+        }
+    }
+
+    /// the eroded height of the column, `base` is the height before erosion of any column.
+    pub fn height(&self, x: i32, z: i32, base: impl Fn(i32, i32) -> f64) -> f64 {
+        let t = self.config.tile.max(2);
+        let (tx, tz) = (x.div_euclid(t), z.div_euclid(t));
+        let fx = x.rem_euclid(t) as f64 / t as f64;
+        let fz = z.rem_euclid(t) as f64 / t as f64;
+        let mut height = 0.0;
+        for (ix, wx) in [(0, 1.0 - fx), (1, fx)] {
+            for (iz, wz) in [(0, 1.0 - fz), (1, fz)] {
+                let w = wx * wz;
+                if w == 0.0 {
+                    continue;
+                }
+                let tile = (tx + ix, tz + iz);
+                // the window starts a tile before the centre
+                let (wx0, wz0) = ((tile.0 - 1) * t, (tile.1 - 1) * t);
+                let window = self.window(tile, &base);
+                height += window.get((x - wx0) as usize, (z - wz0) as usize) * w;
+            }
+        }
+        height
+    }
+
+    fn window(&self, tile: (i32, i32), base: &impl Fn(i32, i32) -> f64) -> Arc<Heightmap> {
+        if let Some(window) = self.tiles.lock().unwrap().0.get(&tile) {
+            return window.clone();
+        }
+        // not locked while eroding, two threads may both erode a tile but get the same result
+        let t = self.config.tile.max(2);
+        let (x0, z0) = ((tile.0 - 1) * t, (tile.1 - 1) * t);
+        let mut window = Heightmap::new(2 * t as usize, |x, z| base(x0 + x as i32, z0 + z as i32));
+        let mut r_n = StdRng::seed_from_u64(chunk_seed(self.seed, (tile.0, 0, tile.1)));
+        hydraulic(&mut window, &self.config, &mut r_n);
+        thermal(&mut window, &self.config);
+
+        let window = Arc::new(window);
+        let mut tiles = self.tiles.lock().unwrap();
+        if tiles.0.insert(tile, window.clone()).is_none() {
+            tiles.1.push_back(tile);
+        }
+        while tiles.1.len() > CACHED_TILES {
+            let old = tiles.1.pop_front().unwrap();
+            tiles.0.remove(&old);
+        }
+        window
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    /// a slope with ripples, so droplets have somewhere to go.
+    fn slope(x: i32, z: i32) -> f64 {
+        x as f64 * 0.4 + (z as f64 * 0.3).sin() * 3.0 + (x as f64 * 0.17).cos() * 2.0
+    }
+
+    fn config() -> ErosionConfig {
+        ErosionConfig {
+            enabled: true,
+            tile: 16,
+            droplets: 500,
+            ..ErosionConfig::default()
+        }
+    }
+
+    #[test]
+    fn droplets_wear_the_ground_down_the_same_way_every_time() {
+        let before = Heightmap::new(32, |x, z| slope(x as i32, z as i32));
+        let eroded = || {
+            let mut map = before.clone();
+            hydraulic(&mut map, &config(), &mut StdRng::seed_from_u64(3));
+            map
+        };
+        let map = eroded();
+        assert_eq!(map, eroded());
+        assert_ne!(map, before);
+        // sediment is only ever moved or carried off
+        let total = |m: &Heightmap| m.heights.iter().sum::<f64>();
+        assert!(total(&map) <= total(&before) + 1e-6);
+    }
+
+    #[test]
+    fn slumping_keeps_the_material_and_flattens_steep_slopes() {
+        let mut map = Heightmap::new(9, |x, z| if (x, z) == (4, 4) { 20.0 } else { 0.0 });
+        let steepest = |m: &Heightmap| {
+            let mut most: f64 = 0.0;
+            for z in 0..m.size {
+                for x in 1..m.size {
+                    most = most.max((m.get(x, z) - m.get(x - 1, z)).abs());
+                    most = most.max((m.get(z, x) - m.get(z, x - 1)).abs());
+                }
+            }
+            most
+        };
+        thermal(
+            &mut map,
+            &ErosionConfig {
+                thermal_iterations: 200,
+                ..config()
+            },
+        );
+        assert!((map.heights.iter().sum::<f64>() - 20.0).abs() < 1e-9);
+        assert!(steepest(&map) < 2.0, "{}", steepest(&map));
+    }
+
+    #[test]
+    fn regions_match_whatever_order_they_are_made_in() {
+        let (a, b) = (Erosion::new(1, config()), Erosion::new(1, config()));
+        // a walks from the left and b from the right, over the tile borders at 16 and 32
+        let from_a: Vec<f64> = (0..48).map(|x| a.height(x, 5, slope)).collect();
+        let mut from_b: Vec<f64> = (0..48).rev().map(|x| b.height(x, 5, slope)).collect();
+        from_b.reverse();
+        assert_eq!(from_a, from_b);
+        assert!((0..48).any(|x| from_a[x as usize] != slope(x, 5)));
+    }
+
+    #[test]
+    fn the_blend_is_smooth_over_tile_borders() {
+        let erosion = Erosion::new(4, config());
+        for z in [0, 7, 16, 31] {
+            let heights: Vec<f64> = (-40..40).map(|x| erosion.height(x, z, slope)).collect();
+            for pair in heights.windows(2) {
+                // the slope itself is under 1 per column
+                assert!((pair[1] - pair[0]).abs() < 3.0, "{:?}", pair);
+            }
+        }
+    }
+}
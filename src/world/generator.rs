@@ -0,0 +1,349 @@
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use super::{
+    biome::{Biome, Biomes},
+    caves::{CaveConfig, Caves},
+    erosion::Erosion,
+    noise_graph::NoiseGraph,
+    preset::WorldPreset,
+    structure::Structure,
+};
+use crate::voxel::{block::WATER, voxel::Chunk};
+
+/// terrain that only depends on the seed, so any chunk can be generated on its own and in any order.
+pub struct WorldGenerator {
+    pub seed: u32,
+    /// air under this height over the terrain is filled with water, None for no oceans.
+    pub sea_level: Option<i32>,
+    pub preset: WorldPreset,
+    pub biomes: Biomes,
+    /// placed by the decoration pass, see structure::load_structures.
+    pub structures: Vec<Structure>,
+    large: NoiseGraph,
+    detail: NoiseGraph,
+    depth: NoiseGraph,
+    density: Option<NoiseGraph>,
+    erosion: Option<Erosion>,
+    caves: Caves,
+}
+
+impl WorldGenerator {
+    pub fn new(seed: u32) -> Self {
+        Self::from_preset(seed, WorldPreset::default())
+    }
+
+    pub fn with_caves(seed: u32, caves: CaveConfig) -> Self {
+        Self::from_preset(
+            seed,
+            WorldPreset {
+                caves,
+                ..WorldPreset::default()
+            },
+        )
+    }
+
+    /// the seed of the preset is not looked at, the caller decides what it means when there is none.
+    pub fn from_preset(seed: u32, preset: WorldPreset) -> Self {
+        let mut biomes = Biomes::new(seed, preset.biomes.clone());
+        biomes.frequency = preset.biome_frequency;
+        biomes.blend = preset.biome_blend;
+        Self {
+            seed,
+            sea_level: preset.sea_level,
+            biomes,
+            structures: vec![],
+            large: NoiseGraph::new(&preset.surface.large, seed),
+            detail: NoiseGraph::new(&preset.surface.detail, seed),
+            depth: NoiseGraph::new(&preset.surface.depth, seed),
+            density: preset
+                .density
+                .as_ref()
+                .map(|d| NoiseGraph::new(d, seed.wrapping_add(9))),
+            erosion: preset
+                .erosion
+                .enabled
+                .then(|| Erosion::new(seed, preset.erosion.clone())),
+            caves: Caves::new(seed, preset.caves.clone()),
+            preset,
+        }
+    }
+
+    /// height of the terrain at the column, and the depth noise the layers under it use.
+    /// the height is the height curves of the biomes around the column blended together, eroded
+    /// when the preset turns erosion on.
+    pub fn surface(&self, x: i32, z: i32) -> (f64, f64) {
+        self.surfaces(&[(x, z)])[0]
+    }
+
+    /// `surface` of every column, the noise graphs run over all of them at once.
+    pub fn surfaces(&self, columns: &[(i32, i32)]) -> Vec<(f64, f64)> {
+        let mut surfaces = self.base_surfaces(columns);
+        if let Some(erosion) = &self.erosion {
+            for ((x, z), surface) in columns.iter().zip(surfaces.iter_mut()) {
+                surface.0 = erosion.height(*x, *z, |x, z| self.base_surfaces(&[(x, z)])[0].0);
+            }
+        }
+        surfaces
+    }
+
+    fn base_surfaces(&self, columns: &[(i32, i32)]) -> Vec<(f64, f64)> {
+        let points: Vec<[f64; 3]> = columns
+            .iter()
+            .map(|(x, z)| [*x as f64, 0.0, *z as f64])
+            .collect();
+        let large = self.large.eval(&points);
+        let detail = self.detail.eval(&points);
+        let depth = self.depth.eval(&points);
+        columns
+            .iter()
+            .enumerate()
+            .map(|(i, (x, z))| {
+                let mut detail = detail[i];
+                if depth[i] >= 0.0 {
+                    detail = detail.abs()
+                }
+                (self.biomes.height(*x, *z, large[i], detail), depth[i])
+            })
+            .collect()
+    }
+
+    /// the biome the column belongs to.
+    pub fn biome(&self, x: i32, z: i32) -> &Biome {
+        self.biomes.biome_at(x, z)
+    }
+
+    /// true where the cave pass would carve, if there is something to carve.
+    pub fn is_cave(&self, x: i32, y: i32, z: i32) -> bool {
+        self.caves.config.enabled && self.caves.is_cave(x, y, z)
+    }
+
+    /// the terrain pass and then the cave pass.
+    /// the chunk is dirty so it gets meshed, but not save dirty as it can be generated again.
+    pub fn generate_chunk(&self, key: (i32, i32, i32), size: i32) -> Chunk {
+        let mut chunk = self.generate_terrain(key, size);
+        self.caves.carve(
+            &mut chunk,
+            key,
+            size,
+            |x, z| self.surface(x, z).0,
+            self.sea_level,
+        );
+
+        chunk.save_dirty = false;
+        chunk.set_is_dirty(true);
+        chunk
+    }
+
+    /// caves and decorations still go by the height without the density noise.
+    fn generate_terrain(&self, key: (i32, i32, i32), size: i32) -> Chunk {
+        let mut chunk = Chunk::new(size);
+        let mut r_n = StdRng::seed_from_u64(chunk_seed(self.seed, key));
+        let density = self.density.as_ref().map(|d| d.eval_chunk(key, size));
+
+        let (ox, oy, oz) = (key.0 * size, key.1 * size, key.2 * size);
+        let mut columns = Vec::with_capacity((size * size) as usize);
+        for lx in 0..size {
+            for lz in 0..size {
+                columns.push((ox + lx, oz + lz));
+            }
+        }
+        let surfaces = self.surfaces(&columns);
+
+        for lx in 0..size {
+            for lz in 0..size {
+                let (x, z) = (ox + lx, oz + lz);
+                let (height, depth) = surfaces[(lx * size + lz) as usize];
+                let biome = self.biome(x, z);
+
+                for ly in 0..size {
+                    let y = oy + ly;
+                    let mut o = 0;
+                    // how far under the surface the voxel is
+                    let under = match &density {
+                        Some(d) => height + d[(lx + ly * size + lz * size * size) as usize],
+                        None => height,
+                    } - y as f64;
+
+                    if under >= 0.0 {
+                        o = pick(&mut r_n, &biome.surface);
+                        if under >= ((depth * 2.0) + 1.0).abs() {
+                            o = pick(&mut r_n, &biome.subsurface);
+                        }
+                    } else if matches!(self.sea_level, Some(sea) if y <= sea) {
+                        o = WATER;
+                    }
+                    if o != 0 && o != WATER {
+                        // the lowest layer the voxel is in
+                        let layer = self
+                            .preset
+                            .layers
+                            .iter()
+                            .rev()
+                            .find(|l| y as f64 <= l.top + l.wobble * depth);
+                        if let Some(layer) = layer {
+                            o = pick(&mut r_n, &layer.blocks);
+                            for ore in self.preset.ores.iter().filter(|o| o.contains(y)) {
+                                if r_n.gen_bool(ore.chance.clamp(0.0, 1.0)) {
+                                    o = pick(&mut r_n, &ore.blocks);
+                                    break;
+                                }
+                            }
+                        }
+                    }
+
+                    // air chunks never get a volume
+                    if o != 0 {
+                        chunk.set_voxel(lx, ly, lz, size, o);
+                    }
+                }
+            }
+        }
+        chunk
+    }
+}
+
+fn pick(r_n: &mut StdRng, blocks: &[u16]) -> u16 {
+    blocks[r_n.gen_range(0..blocks.len())]
+}
+
+/// mixes the world seed with the chunk key so every chunk gets its own random stream.
+pub fn chunk_seed(seed: u32, key: (i32, i32, i32)) -> u64 {
+    let mut h = seed as u64 ^ 0x9e37_79b9_7f4a_7c15;
+    for v in [key.0, key.1, key.2] {
+        h = (h ^ v as u32 as u64).wrapping_mul(0x0000_0100_0000_01b3);
+        h ^= h >> 29;
+    }
+    h
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::world::{erosion::ErosionConfig, noise_graph::NoiseDef};
+
+    #[test]
+    fn same_seed_same_chunk() {
+        let a = WorldGenerator::new(42);
+        let b = WorldGenerator::new(42);
+        for key in [(0, 0, 0), (-1, -2, 3), (2, -1, -2)] {
+            let (ca, cb) = (a.generate_chunk(key, 16), b.generate_chunk(key, 16));
+            assert_eq!(
+                ca.volume.map(|v| v.type_id.layer),
+                cb.volume.map(|v| v.type_id.layer)
+            );
+        }
+    }
+
+    #[test]
+    fn generated_chunks_are_not_save_dirty() {
+        let chunk = WorldGenerator::new(1).generate_chunk((0, -1, 0), 16);
+        assert!(chunk.is_dirty());
+        assert!(!chunk.save_dirty);
+    }
+
+    #[test]
+    fn the_top_layer_comes_from_the_biome() {
+        let generator = WorldGenerator::with_caves(
+            11,
+            CaveConfig {
+                enabled: false,
+                ..CaveConfig::default()
+            },
+        );
+        // the top layer can be thinner then a voxel, then the voxel under the surface is subsurface
+        let (mut checked, mut on_top) = (0, 0);
+        for x in -20..20 {
+            for z in -20..20 {
+                let (x, z) = (x * 96, z * 96);
+                let biome = generator.biome(x, z);
+                let top = generator.surface(x, z).0.floor() as i32;
+                // the stone layer reaches up to about y 4
+                if top <= 4 {
+                    continue;
+                }
+                let key = (x >> 4, top >> 4, z >> 4);
+                let chunk = generator.generate_chunk(key, 16);
+                let id = chunk.get_voxel(x & 15, top & 15, z & 15, 16);
+                assert!(
+                    biome.surface.contains(&id) || biome.subsurface.contains(&id),
+                    "{} got {}",
+                    biome.name,
+                    id
+                );
+                checked += 1;
+                if biome.surface.contains(&id) {
+                    on_top += 1;
+                }
+            }
+        }
+        assert!(
+            checked > 200 && on_top * 2 > checked,
+            "{} of {}",
+            on_top,
+            checked
+        );
+    }
+
+    #[test]
+    fn density_moves_the_ground() {
+        let caves = CaveConfig {
+            enabled: false,
+            ..CaveConfig::default()
+        };
+        let plain = WorldGenerator::with_caves(3, caves.clone());
+        let raised = WorldGenerator::from_preset(
+            3,
+            WorldPreset {
+                caves,
+                density: Some(NoiseDef::Constant(20.0)),
+                ..WorldPreset::default()
+            },
+        );
+        for (x, z) in [(0, 0), (40, -7), (-300, 123)] {
+            let top = plain.surface(x, z).0.floor() as i32;
+            let at = |g: &WorldGenerator, y: i32| {
+                let key = (x >> 4, y >> 4, z >> 4);
+                g.generate_chunk(key, 16)
+                    .get_voxel(x & 15, y & 15, z & 15, 16)
+            };
+            assert_ne!(at(&raised, top + 20), 0);
+            assert_eq!(at(&raised, top + 21), 0);
+            assert_eq!(at(&plain, top + 1), 0);
+        }
+    }
+
+    #[test]
+    fn the_terrain_is_built_on_the_eroded_surface() {
+        let preset = WorldPreset {
+            caves: CaveConfig {
+                enabled: false,
+                ..CaveConfig::default()
+            },
+            erosion: ErosionConfig {
+                enabled: true,
+                tile: 16,
+                droplets: 400,
+                ..ErosionConfig::default()
+            },
+            ..WorldPreset::default()
+        };
+        let generator = WorldGenerator::from_preset(6, preset);
+        let mut moved = 0;
+        for x in 0..16 {
+            let (height, _) = generator.surface(x, 3);
+            if height != generator.base_surfaces(&[(x, 3)])[0].0 {
+                moved += 1;
+            }
+            let top = height.floor() as i32;
+            let at = |y: i32| {
+                let key = (0, y >> 4, 0);
+                generator
+                    .generate_chunk(key, 16)
+                    .get_voxel(x, y & 15, 3, 16)
+            };
+            assert_ne!(at(top), 0);
+            assert_eq!(at(top + 1), 0);
+        }
+        assert!(moved > 0);
+    }
+}
@@ -0,0 +1,37 @@
+// world generation and persistence, shared by the client and the headless server.
+
+pub mod biome;
+pub mod caves;
+pub mod decoration;
+pub mod erosion;
+pub mod generator;
+pub mod noise_graph;
+pub mod preset;
+pub mod store;
+pub mod structure;
+
+use decoration::{generate_into, PendingWrites};
+use generator::WorldGenerator;
+use store::ChunkStore;
+
+use crate::voxel::voxel::VoxelMap;
+
+/// loads the chunk from the store, or generates it with its decorations if it was never saved.
+pub fn load_or_generate_chunk(
+    map: &mut VoxelMap,
+    store: &ChunkStore,
+    generator: &WorldGenerator,
+    pending: &mut PendingWrites,
+    key: (i32, i32, i32),
+) -> anyhow::Result<()> {
+    let size = map.chunk_size.0;
+    match store.load(key, size, map.game_tick())? {
+        Some(mut chunk) => {
+            chunk.set_is_dirty(true);
+            map.add_chunk(key.0, key.1, key.2, chunk);
+            pending.loaded_from_store(key);
+        }
+        None => generate_into(map, generator, pending, key),
+    }
+    Ok(())
+}
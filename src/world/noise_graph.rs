@@ -0,0 +1,441 @@
+// a small noise graph defined in data. a NoiseDef is read from RON, NoiseGraph::new seeds it and
+// eval runs it over a batch of points: every node fills the values of the whole batch before its
+// parent uses them, so a chunk is one call per node instead of one walk of the graph per voxel.
+
+use std::collections::HashMap;
+
+use noise::{NoiseFn, OpenSimplex, Seedable};
+use serde::Deserialize;
+
+/// simplex noise summed over octaves, every octave at `lacunarity` times the frequency and
+/// `persistence` times the amplitude of the one before.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct Octaves {
+    /// added to the world seed, so two nodes can have different noise.
+    pub seed: u32,
+    pub frequency: f64,
+    pub octaves: u32,
+    pub amplitude: f64,
+    pub persistence: f64,
+    pub lacunarity: f64,
+}
+
+impl Default for Octaves {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            frequency: 0.01,
+            octaves: 1,
+            amplitude: 1.0,
+            persistence: 0.5,
+            lacunarity: 2.0,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub enum NoiseDef {
+    Constant(f64),
+    /// the height of the point.
+    Y,
+    /// plain octaves, about -amplitude to amplitude.
+    Fractal(Octaves),
+    /// sharp crests where the noise crosses 0, 0 to amplitude.
+    Ridged(Octaves),
+    /// round bumps with creases between them, about -amplitude to amplitude.
+    Billow(Octaves),
+    /// `input` at the point moved by `by`, sampled 3 times at offsets for x, y and z.
+    Warp {
+        input: Box<NoiseDef>,
+        by: Box<NoiseDef>,
+        strength: f64,
+    },
+    Add(Vec<NoiseDef>),
+    Mul(Vec<NoiseDef>),
+    Clamp {
+        input: Box<NoiseDef>,
+        min: f64,
+        max: f64,
+    },
+    /// maps `input` through the (in, out) points, straight between them and flat past the ends.
+    Spline {
+        input: Box<NoiseDef>,
+        points: Vec<(f64, f64)>,
+    },
+    /// `input` once per x and z of the batch at y 0, for inputs that do not depend on the height.
+    Cache(Box<NoiseDef>),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Shape {
+    Fractal,
+    Ridged,
+    Billow,
+}
+
+enum Node {
+    Constant(f64),
+    Y,
+    Octaves(Shape, Octaves, Box<OpenSimplex>),
+    Warp(Box<Node>, Box<Node>, f64),
+    Add(Vec<Node>),
+    Mul(Vec<Node>),
+    Clamp(Box<Node>, f64, f64),
+    Spline(Box<Node>, Vec<(f64, f64)>),
+    Cache(Box<Node>),
+}
+
+/// where the warp samples `by` for each axis, far enough apart to be unrelated.
+const WARP_OFFSETS: [[f64; 3]; 3] = [[0.0, 0.0, 0.0], [5.2, 1.3, 7.7], [-3.1, 9.4, 2.8]];
+
+impl Node {
+    fn new(def: &NoiseDef, seed: u32) -> Self {
+        let boxed = |def: &NoiseDef| Box::new(Node::new(def, seed));
+        let octaves = |shape: Shape, o: &Octaves| {
+            Node::Octaves(
+                shape,
+                *o,
+                Box::new(OpenSimplex::new().set_seed(seed.wrapping_add(o.seed))),
+            )
+        };
+        match def {
+            NoiseDef::Constant(c) => Node::Constant(*c),
+            NoiseDef::Y => Node::Y,
+            NoiseDef::Fractal(o) => octaves(Shape::Fractal, o),
+            NoiseDef::Ridged(o) => octaves(Shape::Ridged, o),
+            NoiseDef::Billow(o) => octaves(Shape::Billow, o),
+            NoiseDef::Warp {
+                input,
+                by,
+                strength,
+            } => Node::Warp(boxed(input), boxed(by), *strength),
+            NoiseDef::Add(defs) => Node::Add(defs.iter().map(|d| Node::new(d, seed)).collect()),
+            NoiseDef::Mul(defs) => Node::Mul(defs.iter().map(|d| Node::new(d, seed)).collect()),
+            NoiseDef::Clamp { input, min, max } => {
+                // f64::clamp panics on a NaN bound or min over max, a NaN bound is no bound
+                let min = if min.is_nan() {
+                    f64::NEG_INFINITY
+                } else {
+                    *min
+                };
+                let max = if max.is_nan() { f64::INFINITY } else { *max };
+                Node::Clamp(boxed(input), min.min(max), max.max(min))
+            }
+            NoiseDef::Spline { input, points } => {
+                // RON reads NaN and inf, a point there has no place on the curve
+                let mut points: Vec<(f64, f64)> =
+                    points.iter().filter(|p| p.0.is_finite()).cloned().collect();
+                points.sort_by(|a, b| a.0.total_cmp(&b.0));
+                Node::Spline(boxed(input), points)
+            }
+            NoiseDef::Cache(input) => Node::Cache(boxed(input)),
+        }
+    }
+
+    /// fills `out` with the value at every point, `out` is as long as `points`.
+    fn eval(&self, points: &[[f64; 3]], out: &mut [f64]) {
+        match self {
+            Node::Constant(c) => out.fill(*c),
+            Node::Y => {
+                for (o, p) in out.iter_mut().zip(points) {
+                    *o = p[1];
+                }
+            }
+            Node::Octaves(shape, o, noise) => {
+                for (v, p) in out.iter_mut().zip(points) {
+                    *v = octaves(*shape, o, noise, *p);
+                }
+            }
+            Node::Warp(input, by, strength) => {
+                let mut moved = points.to_vec();
+                let mut offset = vec![0.0; points.len()];
+                for (axis, shift) in WARP_OFFSETS.iter().enumerate() {
+                    let at: Vec<[f64; 3]> = points
+                        .iter()
+                        .map(|p| [p[0] + shift[0], p[1] + shift[1], p[2] + shift[2]])
+                        .collect();
+                    by.eval(&at, &mut offset);
+                    for (m, d) in moved.iter_mut().zip(offset.iter()) {
+                        m[axis] += d * strength;
+                    }
+                }
+                input.eval(&moved, out);
+            }
+            Node::Add(nodes) => {
+                out.fill(0.0);
+                let mut values = vec![0.0; points.len()];
+                for node in nodes {
+                    node.eval(points, &mut values);
+                    for (o, v) in out.iter_mut().zip(values.iter()) {
+                        *o += v;
+                    }
+                }
+            }
+            Node::Mul(nodes) => {
+                out.fill(1.0);
+                let mut values = vec![0.0; points.len()];
+                for node in nodes {
+                    node.eval(points, &mut values);
+                    for (o, v) in out.iter_mut().zip(values.iter()) {
+                        *o *= v;
+                    }
+                }
+            }
+            Node::Clamp(input, min, max) => {
+                input.eval(points, out);
+                for o in out.iter_mut() {
+                    *o = o.clamp(*min, *max);
+                }
+            }
+            Node::Spline(input, curve) => {
+                input.eval(points, out);
+                for o in out.iter_mut() {
+                    *o = spline(curve, *o);
+                }
+            }
+            Node::Cache(input) => {
+                // the columns in the order they are first seen, and which one each point is
+                let mut columns: HashMap<(u64, u64), usize> = HashMap::new();
+                let mut at = vec![];
+                let mut column_of = Vec::with_capacity(points.len());
+                for p in points {
+                    let i = *columns
+                        .entry((p[0].to_bits(), p[2].to_bits()))
+                        .or_insert_with(|| {
+                            at.push([p[0], 0.0, p[2]]);
+                            at.len() - 1
+                        });
+                    column_of.push(i);
+                }
+                let mut values = vec![0.0; at.len()];
+                input.eval(&at, &mut values);
+                for (o, i) in out.iter_mut().zip(column_of) {
+                    *o = values[i];
+                }
+            }
+        }
+    }
+}
+
+fn octaves(shape: Shape, o: &Octaves, noise: &OpenSimplex, p: [f64; 3]) -> f64 {
+    let (mut f, mut a, mut sum) = (o.frequency, 1.0, 0.0);
+    for _ in 0..o.octaves {
+        let n = noise.get([p[0] * f, p[1] * f, p[2] * f]);
+        sum += a * match shape {
+            Shape::Fractal => n,
+            Shape::Ridged => (1.0 - n.abs()).powi(2),
+            Shape::Billow => n.abs() * 2.0 - 1.0,
+        };
+        f *= o.lacunarity;
+        a *= o.persistence;
+    }
+    sum * o.amplitude
+}
+
+fn spline(points: &[(f64, f64)], v: f64) -> f64 {
+    match points {
+        [] => v,
+        [(_, out)] => *out,
+        _ => {
+            let (first, last) = (points[0], points[points.len() - 1]);
+            if v <= first.0 {
+                return first.1;
+            }
+            if v >= last.0 {
+                return last.1;
+            }
+            match points.iter().position(|p| p.0 > v) {
+                Some(i) => {
+                    let (a, b) = (points[i - 1], points[i]);
+                    a.1 + (b.1 - a.1) * (v - a.0) / (b.0 - a.0)
+                }
+                // only a NaN input gets here
+                None => v,
+            }
+        }
+    }
+}
+
+/// a NoiseDef seeded and ready to be evaluated.
+pub struct NoiseGraph {
+    root: Node,
+}
+
+impl NoiseGraph {
+    pub fn new(def: &NoiseDef, seed: u32) -> Self {
+        Self {
+            root: Node::new(def, seed),
+        }
+    }
+
+    pub fn get(&self, point: [f64; 3]) -> f64 {
+        let mut out = [0.0];
+        self.root.eval(&[point], &mut out);
+        out[0]
+    }
+
+    /// the value at every point, in order.
+    pub fn eval(&self, points: &[[f64; 3]]) -> Vec<f64> {
+        let mut out = vec![0.0; points.len()];
+        self.root.eval(points, &mut out);
+        out
+    }
+
+    /// the value at every voxel of the chunk at `key`, indexed like the chunk volume.
+    pub fn eval_chunk(&self, key: (i32, i32, i32), size: i32) -> Vec<f64> {
+        let mut points = Vec::with_capacity((size * size * size) as usize);
+        for z in 0..size {
+            for y in 0..size {
+                for x in 0..size {
+                    points.push([
+                        (key.0 * size + x) as f64,
+                        (key.1 * size + y) as f64,
+                        (key.2 * size + z) as f64,
+                    ]);
+                }
+            }
+        }
+        self.eval(&points)
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    fn parse(text: &str) -> NoiseDef {
+        ron::de::from_str(text).unwrap()
+    }
+
+    fn grid() -> Vec<[f64; 3]> {
+        let mut points = vec![];
+        for i in 0..200 {
+            points.push([(i % 7) as f64 * 3.1, (i / 7) as f64 * 1.7, i as f64 * 0.9]);
+        }
+        points
+    }
+
+    /// a bit of everything, read from RON.
+    const TERRAIN: &str = r#"Add([
+        Mul([Constant(-1.0), Y]),
+        Cache(Spline(
+            input: Fractal((frequency: 0.02, octaves: 4, amplitude: 1.0)),
+            points: [(-1.0, 0.0), (0.0, 10.0), (1.0, 40.0)],
+        )),
+        Warp(
+            input: Ridged((seed: 1, frequency: 0.05, octaves: 3, amplitude: 6.0)),
+            by: Billow((seed: 2, frequency: 0.03)),
+            strength: 8.0,
+        ),
+        Clamp(input: Fractal((seed: 3, frequency: 0.1, amplitude: 4.0)), min: -1.0, max: 1.0),
+    ])"#;
+
+    #[test]
+    fn a_batch_gives_what_single_points_give() {
+        let graph = NoiseGraph::new(&parse(TERRAIN), 4);
+        let points = grid();
+        let batch = graph.eval(&points);
+        for (p, v) in points.iter().zip(batch.iter()) {
+            assert_eq!(graph.get(*p), *v);
+        }
+        // the same seed is the same graph
+        assert_eq!(NoiseGraph::new(&parse(TERRAIN), 4).eval(&points), batch);
+        assert_ne!(NoiseGraph::new(&parse(TERRAIN), 5).eval(&points), batch);
+    }
+
+    #[test]
+    fn chunk_values_are_in_volume_order() {
+        let graph = NoiseGraph::new(&NoiseDef::Y, 0);
+        let values = graph.eval_chunk((0, 2, 0), 16);
+        // x + y * 16 + z * 256
+        assert_eq!(values[0], 32.0);
+        assert_eq!(values[5 + 3 * 16 + 7 * 256], 35.0);
+    }
+
+    #[test]
+    fn cache_is_one_value_per_column() {
+        let fractal = parse("Fractal((frequency: 0.05, octaves: 2))");
+        let cached = NoiseGraph::new(&NoiseDef::Cache(Box::new(fractal.clone())), 1);
+        let plain = NoiseGraph::new(&fractal, 1);
+        let values = cached.eval_chunk((1, -1, 0), 16);
+        for z in 0..16 {
+            for x in 0..16 {
+                let column = plain.get([(16 + x) as f64, 0.0, z as f64]);
+                for y in 0..16 {
+                    assert_eq!(values[(x + y * 16 + z * 256) as usize], column);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn shapes_keep_to_their_range() {
+        let o = Octaves {
+            frequency: 0.07,
+            octaves: 3,
+            amplitude: 2.0,
+            ..Octaves::default()
+        };
+        // the octave amplitudes add up to 1.75
+        let ridged = NoiseGraph::new(&NoiseDef::Ridged(o), 0).eval(&grid());
+        assert!(ridged.iter().all(|v| *v >= 0.0 && *v <= 3.5));
+        let billow = NoiseGraph::new(&NoiseDef::Billow(o), 0).eval(&grid());
+        assert!(billow.iter().all(|v| *v >= -3.5 && *v <= 3.5));
+        let clamp = NoiseDef::Clamp {
+            input: Box::new(NoiseDef::Fractal(o)),
+            min: -0.1,
+            max: 0.2,
+        };
+        let clamped = NoiseGraph::new(&clamp, 0).eval(&grid());
+        assert!(clamped.iter().all(|v| *v >= -0.1 && *v <= 0.2));
+    }
+
+    #[test]
+    fn splines_are_straight_between_points_and_flat_past_the_ends() {
+        let def = |v: f64| NoiseDef::Spline {
+            input: Box::new(NoiseDef::Constant(v)),
+            // out of order on purpose
+            points: vec![(1.0, 4.0), (-1.0, 0.0), (0.0, 1.0)],
+        };
+        let at = |v: f64| NoiseGraph::new(&def(v), 0).get([0.0; 3]);
+        assert_eq!(at(-5.0), 0.0);
+        assert_eq!(at(-0.5), 0.5);
+        assert_eq!(at(0.5), 2.5);
+        assert_eq!(at(3.0), 4.0);
+    }
+
+    #[test]
+    fn splines_with_nan_do_not_panic() {
+        let def =
+            parse("Spline(input: Y, points: [(NaN, 1.0), (0.0, 0.0), (inf, 9.0), (1.0, 2.0)])");
+        let graph = NoiseGraph::new(&def, 0);
+        assert_eq!(graph.get([0.0, 0.5, 0.0]), 1.0);
+        assert_eq!(graph.get([0.0, 7.0, 0.0]), 2.0);
+        assert!(graph.get([0.0, f64::NAN, 0.0]).is_nan());
+    }
+
+    #[test]
+    fn clamps_with_bad_bounds_do_not_panic() {
+        let graph = NoiseGraph::new(&parse("Clamp(input: Y, min: 1.0, max: -1.0)"), 0);
+        assert_eq!(graph.get([0.0, 5.0, 0.0]), 1.0);
+        assert_eq!(graph.get([0.0, -5.0, 0.0]), -1.0);
+        assert_eq!(graph.get([0.0, 0.5, 0.0]), 0.5);
+        let graph = NoiseGraph::new(&parse("Clamp(input: Y, min: NaN, max: 2.0)"), 0);
+        assert_eq!(graph.get([0.0, -50.0, 0.0]), -50.0);
+        assert_eq!(graph.get([0.0, 5.0, 0.0]), 2.0);
+    }
+
+    #[test]
+    fn warp_moves_the_point_it_samples() {
+        let input = parse("Fractal((frequency: 0.05))");
+        let warped = NoiseDef::Warp {
+            input: Box::new(input.clone()),
+            by: Box::new(NoiseDef::Constant(1.0)),
+            strength: 2.0,
+        };
+        let graph = NoiseGraph::new(&warped, 0);
+        let plain = NoiseGraph::new(&input, 0);
+        assert_eq!(graph.get([1.0, 2.0, 3.0]), plain.get([3.0, 4.0, 5.0]));
+    }
+}
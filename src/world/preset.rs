@@ -0,0 +1,262 @@
+// everything the world generator can be tuned with, read from a RON preset. the client reads it
+// from assets/data/world.preset.ron and reloads it when the file changes, the server reads the
+// file named in its config. every field has a default, so a preset only needs what it changes.
+
+use std::{fs, path::Path};
+
+use anyhow::{bail, Context};
+use serde::Deserialize;
+
+use super::{
+    biome::{default_biomes, Biome},
+    caves::CaveConfig,
+    erosion::ErosionConfig,
+    noise_graph::{NoiseDef, Octaves},
+};
+
+/// the preset file, relative to the assets directory.
+pub const WORLD_PRESET: &str = "data/world.preset.ron";
+
+/// the noise graphs of the terrain height, sampled at y 0. the biomes turn them into a height with
+/// their curves, which expect about -1 to 1.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct SurfaceNoise {
+    /// mountains and valleys.
+    pub large: NoiseDef,
+    /// hills.
+    pub detail: NoiseDef,
+    /// moves the block layers up and down, and makes the top layer thicker or thinner.
+    pub depth: NoiseDef,
+}
+
+impl Default for SurfaceNoise {
+    fn default() -> Self {
+        Self {
+            large: NoiseDef::Fractal(Octaves {
+                frequency: 0.00613,
+                ..Octaves::default()
+            }),
+            detail: NoiseDef::Fractal(Octaves {
+                frequency: 0.0313,
+                ..Octaves::default()
+            }),
+            depth: NoiseDef::Fractal(Octaves {
+                seed: 8,
+                frequency: 0.03,
+                octaves: 2,
+                amplitude: 1.5,
+                ..Octaves::default()
+            }),
+        }
+    }
+}
+
+/// voxels at or under `top`, moved by `wobble` times the depth noise, are one of `blocks`.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct BlockLayer {
+    pub top: f64,
+    pub wobble: f64,
+    pub blocks: Vec<u16>,
+}
+
+/// ore in the block layers between `min_y` and `max_y`, None for no limit.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct OreLayer {
+    pub blocks: Vec<u16>,
+    #[serde(default)]
+    pub min_y: Option<i32>,
+    #[serde(default)]
+    pub max_y: Option<i32>,
+    /// chance of a single layer voxel being ore.
+    #[serde(default)]
+    pub chance: f64,
+    /// veins the decoration pass plans per chunk in the range.
+    #[serde(default)]
+    pub veins: usize,
+}
+
+impl OreLayer {
+    pub fn contains(&self, y: i32) -> bool {
+        !matches!(self.min_y, Some(min) if y < min) && !matches!(self.max_y, Some(max) if y > max)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct WorldPreset {
+    /// None picks a new seed every start. the server uses the seed of its own config instead.
+    pub seed: Option<u32>,
+    /// chunks the local world generates around the origin, (x and z, y).
+    pub world_size: (i32, i32),
+    /// air under this height over the terrain is filled with water, None for no oceans.
+    pub sea_level: Option<i32>,
+    pub surface: SurfaceNoise,
+    /// added to the height above every voxel, a voxel is solid while that stays at or over 0.
+    /// this is what gives overhangs and floating land, None for plain height map terrain.
+    pub density: Option<NoiseDef>,
+    /// wears the surface height down before the terrain pass, off by default.
+    pub erosion: ErosionConfig,
+    /// from the top down, a lower layer replaces the one above it.
+    pub layers: Vec<BlockLayer>,
+    /// looked at in order, the first one a voxel rolls is the ore it gets.
+    pub ores: Vec<OreLayer>,
+    pub caves: CaveConfig,
+    /// of the temperature and humidity noise, lower is bigger biomes.
+    pub biome_frequency: f64,
+    /// climate distance over which biome heights are blended.
+    pub biome_blend: f64,
+    pub biomes: Vec<Biome>,
+}
+
+impl Default for WorldPreset {
+    fn default() -> Self {
+        let ores = vec![11, 12, 13];
+        Self {
+            seed: None,
+            world_size: (4, 9),
+            sea_level: None,
+            surface: SurfaceNoise::default(),
+            density: None,
+            erosion: ErosionConfig::default(),
+            layers: vec![
+                BlockLayer {
+                    top: -10.0,
+                    wobble: 6.0,
+                    blocks: vec![6, 7, 8],
+                },
+                BlockLayer {
+                    top: -100.0,
+                    wobble: 26.0,
+                    blocks: vec![9, 10],
+                },
+            ],
+            ores: vec![
+                OreLayer {
+                    blocks: ores.clone(),
+                    min_y: Some(-100),
+                    // the stone layer never gets much higher
+                    max_y: Some(0),
+                    chance: 1.0 / 38.0,
+                    veins: 2,
+                },
+                OreLayer {
+                    blocks: ores,
+                    min_y: None,
+                    max_y: Some(-101),
+                    chance: 4.0 / 36.0,
+                    veins: 2,
+                },
+            ],
+            caves: CaveConfig::default(),
+            biome_frequency: 0.0025,
+            biome_blend: 0.15,
+            biomes: default_biomes(),
+        }
+    }
+}
+
+impl WorldPreset {
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        let preset: Self = ron::de::from_str(text)?;
+        preset.check()?;
+        Ok(preset)
+    }
+
+    /// chances are rolled with `gen_bool`, which panics on NaN.
+    fn check(&self) -> anyhow::Result<()> {
+        for (i, ore) in self.ores.iter().enumerate() {
+            if ore.chance.is_nan() {
+                bail!("chance of ore {} is NaN", i);
+            }
+        }
+        for biome in self.biomes.iter() {
+            if biome.decoration_density.is_nan() {
+                bail!("decoration_density of biome {:?} is NaN", biome.name);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let text =
+            fs::read_to_string(path).with_context(|| format!("could not read {:?}", path))?;
+        Self::parse(&text).with_context(|| format!("bad world preset {:?}", path))
+    }
+
+    /// every block of the block layers, what ore veins may replace.
+    pub fn layer_blocks(&self) -> Vec<u16> {
+        let mut blocks: Vec<u16> = self.layers.iter().flat_map(|l| l.blocks.clone()).collect();
+        blocks.sort_unstable();
+        blocks.dedup();
+        blocks
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    #[test]
+    fn a_preset_only_needs_what_it_changes() {
+        let preset = WorldPreset::parse(
+            "(seed: Some(9), surface: (detail: Ridged((octaves: 3))), caves: (enabled: false))",
+        )
+        .unwrap();
+        assert_eq!(preset.seed, Some(9));
+        let detail = Octaves {
+            octaves: 3,
+            ..Octaves::default()
+        };
+        assert_eq!(preset.surface.detail, NoiseDef::Ridged(detail));
+        assert_eq!(preset.surface.large, SurfaceNoise::default().large);
+        assert!(!preset.caves.enabled);
+        assert_eq!(preset.layers, WorldPreset::default().layers);
+    }
+
+    #[test]
+    fn ore_ranges_are_inclusive_and_open_ended() {
+        let preset = WorldPreset::default();
+        assert!(preset.ores[0].contains(-100) && preset.ores[0].contains(0));
+        assert!(!preset.ores[0].contains(-101));
+        assert!(preset.ores[1].contains(-101) && preset.ores[1].contains(-5000));
+        assert_eq!(preset.layer_blocks(), vec![6, 7, 8, 9, 10]);
+    }
+
+    #[test]
+    fn the_shipped_preset_loads() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("assets")
+            .join(WORLD_PRESET);
+        let preset = WorldPreset::load(path).unwrap();
+        // written out in full, the same as the defaults
+        let default = WorldPreset::default();
+        assert_eq!(preset.biomes, default.biomes);
+        assert_eq!(preset.layers, default.layers);
+        assert_eq!(preset.surface, default.surface);
+        assert_eq!(preset.caves, default.caves);
+        assert_eq!(preset.density, default.density);
+        assert_eq!(preset.erosion, default.erosion);
+    }
+
+    #[test]
+    fn nan_chances_are_refused() {
+        assert!(WorldPreset::parse("(ores: [(blocks: [11], chance: NaN)])").is_err());
+        assert!(WorldPreset::parse("(ores: [(blocks: [11], chance: 0.5)])").is_ok());
+        let biome = "(name: \"a\", temperature: 0.0, humidity: 0.0, surface: [14], \
+                     height: (base: 0.0, mountains: 1.0, hills: 1.0), \
+                     subsurface: [1], decoration_density: NaN)";
+        assert!(WorldPreset::parse(&format!("(biomes: [{}])", biome)).is_err());
+        let biome = biome.replace("NaN", "0.5");
+        assert!(WorldPreset::parse(&format!("(biomes: [{}])", biome)).is_ok());
+    }
+
+    #[test]
+    fn density_is_a_noise_graph() {
+        let preset =
+            WorldPreset::parse("(density: Some(Mul([Constant(2.0), Fractal((frequency: 0.05))])))")
+                .unwrap();
+        assert!(matches!(preset.density, Some(NoiseDef::Mul(ref nodes)) if nodes.len() == 2));
+    }
+}
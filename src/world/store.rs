@@ -0,0 +1,186 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context};
+
+use crate::voxel::voxel::{Chunk, SaveChunk, VoxelMap};
+
+/// one bincode encoded SaveChunk file per chunk in the world directory.
+pub struct ChunkStore {
+    dir: PathBuf,
+}
+
+impl ChunkStore {
+    pub fn open<P: AsRef<Path>>(dir: P) -> anyhow::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("could not create world dir {:?}", dir))?;
+        Ok(Self { dir })
+    }
+
+    fn path(&self, key: (i32, i32, i32)) -> PathBuf {
+        self.dir
+            .join(format!("{}_{}_{}.chunk", key.0, key.1, key.2))
+    }
+
+    /// Ok(None) if the chunk was never saved. `now` is the game tick of the map the chunk goes in.
+    pub fn load(&self, key: (i32, i32, i32), size: i32, now: u64) -> anyhow::Result<Option<Chunk>> {
+        let path = self.path(key);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let bytes = fs::read(&path).with_context(|| format!("could not read {:?}", path))?;
+        let save = decode(&bytes).ok_or_else(|| anyhow!("bad chunk file {:?}", path))?;
+        match save.to_chunk(size, now) {
+            Some(chunk) => Ok(Some(chunk)),
+            None => Err(anyhow!(
+                "chunk file {:?} does not fit a chunk of {}",
+                path,
+                size
+            )),
+        }
+    }
+
+    pub fn save(&self, key: (i32, i32, i32), save: &SaveChunk) -> anyhow::Result<()> {
+        let path = self.path(key);
+        // write to a temp file first so a crash mid write does not lose the old chunk
+        let tmp = path.with_extension("chunk.tmp");
+        fs::write(&tmp, bincode::serialize(save)?)
+            .with_context(|| format!("could not write {:?}", tmp))?;
+        fs::rename(&tmp, &path).with_context(|| format!("could not write {:?}", path))?;
+        Ok(())
+    }
+
+    /// saves every chunk with `save_dirty` set and clears the flag, returns how many were saved.
+    pub fn save_dirty(&self, map: &mut VoxelMap) -> anyhow::Result<usize> {
+        let now = map.game_tick();
+        let mut saved = 0;
+        for (key, chunk) in map.chunk_list.iter_mut() {
+            if !chunk.save_dirty {
+                continue;
+            }
+            self.save(*key, &SaveChunk::from_chunk(chunk, now))?;
+            chunk.save_dirty = false;
+            saved += 1;
+        }
+        Ok(saved)
+    }
+}
+
+/// reads the newest layout, then the older ones, fields an old file does not have are left empty.
+fn decode(bytes: &[u8]) -> Option<SaveChunk> {
+    type Runs = Option<Vec<(u16, u16)>>;
+    if let Ok(save) = bincode::deserialize::<SaveChunk>(bytes) {
+        return Some(save);
+    }
+    // before scheduled ticks
+    if let Ok((voxel, fluid)) = bincode::deserialize::<(Runs, Runs)>(bytes) {
+        return Some(SaveChunk {
+            voxel,
+            fluid,
+            ticks: vec![],
+        });
+    }
+    // before fluids, only the voxel runs
+    let voxel = bincode::deserialize::<Runs>(bytes).ok()?;
+    Some(SaveChunk {
+        voxel,
+        fluid: None,
+        ticks: vec![],
+    })
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::voxel::block::WATER;
+
+    #[test]
+    fn dirty_chunks_round_trip() {
+        let dir = std::env::temp_dir().join(format!("vox_net_store_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let store = ChunkStore::open(&dir).unwrap();
+
+        let mut map = VoxelMap::new((16, 16, 16));
+        map.add_chunk(0, 0, 0, Chunk::new(16));
+        map.add_chunk(1, 0, 0, Chunk::new(16));
+        map.set_voxel(3, 4, 5, 9);
+
+        assert_eq!(store.save_dirty(&mut map).unwrap(), 1);
+        assert_eq!(store.save_dirty(&mut map).unwrap(), 0);
+
+        let chunk = store.load((0, 0, 0), 16, 0).unwrap().unwrap();
+        assert_eq!(chunk.get_voxel(3, 4, 5, 16), 9);
+        assert!(store.load((1, 0, 0), 16, 0).unwrap().is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fluid_levels_round_trip_and_old_files_load() {
+        let dir = std::env::temp_dir().join(format!("vox_net_store_fluid_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let store = ChunkStore::open(&dir).unwrap();
+
+        let mut map = VoxelMap::new((16, 16, 16));
+        map.add_chunk(0, 0, 0, Chunk::new(16));
+        map.set_fluid(2, 2, 2, WATER, 3);
+        store.save_dirty(&mut map).unwrap();
+        let chunk = store.load((0, 0, 0), 16, 0).unwrap().unwrap();
+        assert_eq!(chunk.get_voxel(2, 2, 2, 16), WATER);
+        assert_eq!(chunk.get_fluid_level(2, 2, 2, 16), 3);
+
+        // a file from before fluids, water in it is full
+        let old = SaveChunk::from_chunk(map.chunk_list.get(&(0, 0, 0)).unwrap(), 0);
+        fs::write(
+            store.path((0, 0, 0)),
+            bincode::serialize(&old.voxel).unwrap(),
+        )
+        .unwrap();
+        let chunk = store.load((0, 0, 0), 16, 0).unwrap().unwrap();
+        assert_eq!(chunk.get_voxel(2, 2, 2, 16), WATER);
+        assert_eq!(chunk.get_fluid_level(2, 2, 2, 16), 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn scheduled_ticks_keep_their_wait_across_a_restart() {
+        let dir = std::env::temp_dir().join(format!("vox_net_store_ticks_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let store = ChunkStore::open(&dir).unwrap();
+
+        let mut map = VoxelMap::new((16, 16, 16));
+        map.add_chunk(0, 0, 0, Chunk::new(16));
+        for _ in 0..100 {
+            map.advance_game_tick();
+        }
+        assert!(map.schedule_tick(1, 2, 3, 30));
+        store.save_dirty(&mut map).unwrap();
+
+        // a new map starts at tick 0, the tick is still 30 away
+        let mut map = VoxelMap::new((16, 16, 16));
+        let chunk = store.load((0, 0, 0), 16, map.game_tick()).unwrap().unwrap();
+        map.add_chunk(0, 0, 0, chunk);
+        for _ in 0..29 {
+            map.advance_game_tick();
+        }
+        assert!(map.take_due_ticks().is_empty());
+        map.advance_game_tick();
+        assert_eq!(map.take_due_ticks(), vec![(1, 2, 3)]);
+
+        // a file from before ticks, with voxel and fluid runs
+        let old = SaveChunk::from_chunk(map.chunk_list.get(&(0, 0, 0)).unwrap(), 0);
+        fs::write(
+            store.path((0, 0, 0)),
+            bincode::serialize(&(old.voxel, old.fluid)).unwrap(),
+        )
+        .unwrap();
+        let chunk = store.load((0, 0, 0), 16, 0).unwrap().unwrap();
+        assert!(chunk.scheduled_ticks.is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
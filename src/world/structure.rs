@@ -0,0 +1,161 @@
+// hand made structures the decoration pass places on the surface, one RON file each in the
+// structures directory. blocks are drawn as text: every layer is a list of rows along z, every
+// character of a row is a voxel along x, and the palette says which block a character is.
+// ' ' is air and '.' leaves whatever is there.
+
+use std::{fs, path::Path};
+
+use anyhow::{anyhow, Context};
+use serde::Deserialize;
+
+use crate::voxel::block::BlockRegistry;
+
+/// where the client and the server look for structure files by default.
+pub const STRUCTURE_DIR: &str = "assets/data/structures";
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct StructureFile {
+    pub name: String,
+    /// chance per surface column.
+    pub chance: f64,
+    /// names of the biomes it is placed in, empty for all.
+    #[serde(default)]
+    pub biomes: Vec<String>,
+    /// how many layers go under the ground.
+    #[serde(default)]
+    pub sink: i32,
+    pub palette: Vec<(char, String)>,
+    /// from the bottom up.
+    pub layers: Vec<Vec<String>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Structure {
+    pub name: String,
+    pub chance: f64,
+    pub biomes: Vec<String>,
+    pub sink: i32,
+    /// offset from the corner on the ground and the id, air included.
+    pub blocks: Vec<((i32, i32, i32), u16)>,
+}
+
+impl Structure {
+    /// resolves the palette with the block names of `registry`.
+    pub fn from_file(file: StructureFile, registry: &BlockRegistry) -> anyhow::Result<Self> {
+        // rolled with `gen_bool`, which panics on NaN
+        if file.chance.is_nan() {
+            return Err(anyhow!("chance is NaN"));
+        }
+        let mut blocks = vec![];
+        for (y, layer) in file.layers.iter().enumerate() {
+            for (z, row) in layer.iter().enumerate() {
+                for (x, c) in row.chars().enumerate() {
+                    let id = match c {
+                        '.' => continue,
+                        ' ' => 0,
+                        c => {
+                            let name = file
+                                .palette
+                                .iter()
+                                .find(|(p, _)| *p == c)
+                                .map(|(_, name)| name)
+                                .ok_or_else(|| anyhow!("{:?} is not in the palette", c))?;
+                            registry
+                                .id_of(name)
+                                .ok_or_else(|| anyhow!("no block named {:?}", name))?
+                        }
+                    };
+                    blocks.push(((x as i32, y as i32, z as i32), id));
+                }
+            }
+        }
+        Ok(Self {
+            name: file.name,
+            chance: file.chance,
+            biomes: file.biomes,
+            sink: file.sink,
+            blocks,
+        })
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P, registry: &BlockRegistry) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let text =
+            fs::read_to_string(path).with_context(|| format!("could not read {:?}", path))?;
+        let file: StructureFile =
+            ron::de::from_str(&text).with_context(|| format!("bad structure {:?}", path))?;
+        Self::from_file(file, registry).with_context(|| format!("bad structure {:?}", path))
+    }
+
+    pub fn fits(&self, biome: &str) -> bool {
+        self.biomes.is_empty() || self.biomes.iter().any(|b| b == biome)
+    }
+}
+
+/// every .ron file in `dir`, in file name order so the placement does not depend on the file system.
+pub fn load_structures<P: AsRef<Path>>(
+    dir: P,
+    registry: &BlockRegistry,
+) -> anyhow::Result<Vec<Structure>> {
+    let dir = dir.as_ref();
+    let mut paths: Vec<_> = fs::read_dir(dir)
+        .with_context(|| format!("could not read {:?}", dir))?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| matches!(p.extension(), Some(e) if e == "ron"))
+        .collect();
+    paths.sort();
+    paths.iter().map(|p| Structure::load(p, registry)).collect()
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    const HUT: &str = r#"(
+        name: "hut",
+        chance: 0.01,
+        biomes: ["plains"],
+        sink: 1,
+        palette: [('c', "cobblestone"), ('g', "glass")],
+        layers: [
+            ["ccc", "ccc"],
+            ["c.c", "cgc"],
+        ],
+    )"#;
+
+    #[test]
+    fn layers_are_read_bottom_up_with_names_resolved() {
+        let registry = BlockRegistry::default();
+        let file: StructureFile = ron::de::from_str(HUT).unwrap();
+        let hut = Structure::from_file(file, &registry).unwrap();
+        let cobble = registry.id_of("cobblestone").unwrap();
+        let glass = registry.id_of("glass").unwrap();
+        // the '.' is not a block
+        assert_eq!(hut.blocks.len(), 11);
+        assert!(hut.blocks.contains(&((0, 0, 0), cobble)));
+        assert!(hut.blocks.contains(&((1, 1, 1), glass)));
+        assert!(!hut.blocks.iter().any(|(p, _)| *p == (1, 1, 0)));
+        assert!(hut.fits("plains") && !hut.fits("desert"));
+    }
+
+    #[test]
+    fn unknown_blocks_are_refused() {
+        let registry = BlockRegistry::default();
+        let file: StructureFile = ron::de::from_str(&HUT.replace("glass", "marble")).unwrap();
+        assert!(Structure::from_file(file, &registry).is_err());
+        let file: StructureFile = ron::de::from_str(&HUT.replace("cgc", "cxc")).unwrap();
+        assert!(Structure::from_file(file, &registry).is_err());
+        let file: StructureFile = ron::de::from_str(&HUT.replace("0.01", "NaN")).unwrap();
+        assert!(Structure::from_file(file, &registry).is_err());
+    }
+
+    #[test]
+    fn the_shipped_structures_load() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join(STRUCTURE_DIR);
+        let structures = load_structures(dir, &BlockRegistry::default()).unwrap();
+        assert!(!structures.is_empty());
+        for s in structures {
+            assert!(s.chance > 0.0 && !s.blocks.is_empty(), "{}", s.name);
+        }
+    }
+}